@@ -0,0 +1,265 @@
+// Keeps the dashboard up to date with the status pushed over a WebSocket
+const liveState = document.getElementById('live-state');
+
+function formatBytes(bytes) {
+   const units = ['B', 'KiB', 'MiB', 'GiB', 'TiB'];
+   let value = bytes;
+   let unit = 0;
+   while (value >= 1024 && unit < units.length - 1) {
+      value /= 1024;
+      unit += 1;
+   }
+   return unit === 0 ? `${bytes} B` : `${value.toFixed(1)} ${units[unit]}`;
+}
+
+function percent(part, whole) {
+   return whole === 0 ? 0 : Math.round(Math.min(part, whole) / whole * 100);
+}
+
+function formatUptime(seconds) {
+   const days = Math.floor(seconds / 86400);
+   const hours = Math.floor(seconds % 86400 / 3600);
+   const minutes = Math.floor(seconds % 3600 / 60);
+   if (days > 0) {
+      return `${days}d ${hours}h ${minutes}m`;
+   }
+   return hours > 0 ? `${hours}h ${minutes}m` : `${minutes}m`;
+}
+
+function formatDuration(seconds) {
+   return `${Math.floor(seconds / 3600)}h ${Math.floor(seconds % 3600 / 60)}m`;
+}
+
+function batteryState(battery) {
+   const state = battery.state.charAt(0).toUpperCase() + battery.state.slice(1);
+   let text = `${state}, ${battery.energy_rate.toFixed(1)} W`;
+   if (battery.state === 'charging' && battery.time_to_full !== null) {
+      text += `, ${formatDuration(battery.time_to_full)} until full`;
+   } else if (battery.state === 'discharging' && battery.time_to_empty !== null) {
+      text += `, ${formatDuration(battery.time_to_empty)} until empty`;
+   }
+   return text;
+}
+
+function batteryHealth(battery) {
+   let text = `Health ${battery.health}% (${battery.energy_full.toFixed(1)} of ${battery.energy_full_design.toFixed(1)} Wh)`;
+   if (battery.cycle_count !== null) {
+      text += `, ${battery.cycle_count} cycles`;
+   }
+   if (battery.temperature !== null) {
+      text += `, ${battery.temperature.toFixed(1)} °C`;
+   }
+   return text;
+}
+
+function upsState(ups) {
+   let text = ups.on_battery ? 'On battery' : 'On line power';
+   if (ups.low_battery) {
+      text += ', battery low';
+   }
+   if (ups.load !== null) {
+      text += `, ${ups.load}% load`;
+   }
+   if (ups.runtime !== null) {
+      text += `, ${formatDuration(ups.runtime)} runtime`;
+   }
+   return text;
+}
+
+function temperatureLimits(sensor) {
+   const limits = [];
+   if (sensor.max !== null) {
+      limits.push(`high ${sensor.max.toFixed(1)} °C`);
+   }
+   if (sensor.critical !== null) {
+      limits.push(`critical ${sensor.critical.toFixed(1)} °C`);
+   }
+   return limits.join(', ');
+}
+
+// Builds an element with classes and text without parsing any HTML
+function element(tag, className, text) {
+   const node = document.createElement(tag);
+   if (className) {
+      node.className = className;
+   }
+   if (text !== undefined) {
+      node.textContent = text;
+   }
+   return node;
+}
+
+function bar(value, low) {
+   const outer = element('div', 'w-full bg-background border border-border rounded h-2');
+   const inner = element('div', `h-2 rounded ${low ? 'bg-error' : 'bg-primary'}`);
+   inner.style.width = `${value}%`;
+   outer.appendChild(inner);
+   return outer;
+}
+
+function setGauge(id, value, detail) {
+   const color = value >= 90 ? 'var(--error)' : 'var(--primary)';
+   document.getElementById(`${id}-gauge`).style.background =
+      `conic-gradient(${color} ${value}%, var(--border) 0)`;
+   document.getElementById(`${id}-percent`).textContent = `${value}%`;
+   document.getElementById(`${id}-detail`).textContent = detail;
+}
+
+function update(status) {
+   const system = status.system;
+   const load = `${system.load.one.toFixed(2)} ${system.load.five.toFixed(2)} ${system.load.fifteen.toFixed(2)}`;
+   setGauge('cpu', Math.round(system.cpu_usage), load);
+   setGauge('memory', percent(system.memory.used, system.memory.total),
+      `${formatBytes(system.memory.used)} of ${formatBytes(system.memory.total)}`);
+   setGauge('swap', percent(system.swap.used, system.swap.total),
+      `${formatBytes(system.swap.used)} of ${formatBytes(system.swap.total)}`);
+
+   const cores = document.getElementById('cores');
+   cores.replaceChildren(...system.cores.map((core) => {
+      const usage = Math.round(core.usage);
+      const row = element('div', 'flex items-center gap-2 text-sm');
+      row.appendChild(element('span', 'w-16 text-muted', core.name));
+      const track = bar(usage, usage >= 90);
+      track.classList.replace('w-full', 'flex-1');
+      row.appendChild(track);
+      row.appendChild(element('span', 'w-12 text-right', `${usage}%`));
+      return row;
+   }));
+   document.getElementById('uptime').textContent =
+      `Up ${formatUptime(system.uptime)}, booted ${system.boot_time}. Load average ${load}.`;
+
+   const mounts = document.getElementById('mounts');
+   const lowStorage = document.getElementById('low-storage');
+   const lowNodes = [];
+   mounts.replaceChildren(...status.mounts.map((mount) => {
+      const used = mount.total - Math.min(mount.available, mount.total);
+      const free = percent(mount.available, mount.total);
+      const inodesFree = mount.inodes_total === 0 ? 100 : percent(mount.inodes_free, mount.inodes_total);
+      const low = status.low_storage.includes(mount.mount_point);
+      const item = element('div');
+      const header = element('div', 'flex justify-between gap-2 text-sm');
+      const name = element('span');
+      name.appendChild(element('span', 'font-medium', mount.mount_point));
+      name.appendChild(document.createTextNode(' '));
+      name.appendChild(element('span', 'text-muted',
+         `${mount.device} (${mount.fs_type}${mount.read_only ? ', read-only' : ''})`));
+      header.appendChild(name);
+      header.appendChild(element('span', 'text-muted', `${formatBytes(used)} of ${formatBytes(mount.total)}`));
+      item.appendChild(header);
+      const track = bar(100 - free, low);
+      track.classList.add('mt-1');
+      item.appendChild(track);
+      if (mount.inodes_total > 0) {
+         item.appendChild(element('p', 'text-xs text-muted mt-1',
+            `${inodesFree}% of ${mount.inodes_total} inodes free`));
+      }
+      if (low) {
+         const line = element('p', 'text-lg text-error', 'Low Storage on ');
+         line.appendChild(element('span', 'font-medium', mount.mount_point));
+         let text = `: ${free}% free`;
+         if (inodesFree < free) {
+            text += `, ${inodesFree}% of inodes free`;
+         }
+         line.appendChild(document.createTextNode(text));
+         lowNodes.push(line);
+      }
+      return item;
+   }));
+   if (status.mounts.length === 0) {
+      mounts.appendChild(element('p', 'text-muted', 'No disks found'));
+   }
+   if (lowNodes.length === 0) {
+      const line = element('p', 'text-lg', 'Low Storage: ');
+      line.appendChild(element('span', 'font-medium', 'false'));
+      lowNodes.push(line);
+   }
+   lowStorage.replaceChildren(...lowNodes);
+
+   const power = document.getElementById('power');
+   const powerNodes = [];
+   if (status.power.ac_online !== null) {
+      powerNodes.push(element('p', 'text-sm',
+         status.power.ac_online ? 'Power adapter plugged in' : 'Running on batteries'));
+   }
+   const ups = status.power.ups;
+   if (ups) {
+      const item = element('div');
+      const header = element('div', 'flex justify-between gap-2 text-sm');
+      const name = element('span');
+      name.appendChild(element('span', 'font-medium', `UPS ${ups.name}`));
+      name.appendChild(document.createTextNode(' '));
+      name.appendChild(element('span', 'text-muted', ups.model));
+      header.appendChild(name);
+      header.appendChild(element('span', 'text-muted', upsState(ups)));
+      item.appendChild(header);
+      if (ups.charge !== null) {
+         const track = bar(ups.charge, ups.on_battery);
+         track.classList.add('mt-1');
+         item.appendChild(track);
+         item.appendChild(element('p', 'text-xs text-muted mt-1', `${ups.charge}% charged`));
+      }
+      powerNodes.push(item);
+   }
+   status.power.batteries.forEach((battery, index) => {
+      const item = element('div');
+      const header = element('div', 'flex justify-between gap-2 text-sm');
+      const name = element('span');
+      name.appendChild(element('span', 'font-medium', `Battery ${index + 1}`));
+      name.appendChild(document.createTextNode(' '));
+      name.appendChild(element('span', 'text-muted', battery.model));
+      header.appendChild(name);
+      header.appendChild(element('span', 'text-muted', batteryState(battery)));
+      item.appendChild(header);
+      const track = bar(battery.charge, status.low_power);
+      track.classList.add('mt-1');
+      item.appendChild(track);
+      item.appendChild(element('p', 'text-xs text-muted mt-1', `${battery.charge}% charged. ${batteryHealth(battery)}`));
+      powerNodes.push(item);
+   });
+   if (status.power.batteries.length === 0) {
+      powerNodes.push(element('p', 'text-muted', 'No batteries found'));
+   }
+   power.replaceChildren(...powerNodes);
+
+   const temperatures = document.getElementById('temperatures');
+   const temperatureNodes = status.temperatures.map(sensor => {
+      const row = element('div', 'flex justify-between gap-2 text-sm');
+      row.appendChild(element('span', 'font-medium', sensor.label));
+      const value = element('span');
+      const hot = status.high_temperature.includes(sensor.label);
+      value.appendChild(element('span', hot ? 'text-error font-semibold' : '', `${sensor.current.toFixed(1)} °C`));
+      value.appendChild(document.createTextNode(' '));
+      value.appendChild(element('span', 'text-muted', temperatureLimits(sensor)));
+      row.appendChild(value);
+      return row;
+   });
+   if (temperatureNodes.length === 0) {
+      temperatureNodes.push(element('p', 'text-muted', 'No temperature sensors found'));
+   }
+   temperatures.replaceChildren(...temperatureNodes);
+
+   document.getElementById('low-power').textContent = status.low_power;
+   document.getElementById('updates').textContent = status.updates !== null && status.updates > 0;
+   document.getElementById('restart-pending').textContent = status.restart_pending;
+   liveState.textContent = `Live, updated ${new Date(system.taken).toLocaleTimeString()}`;
+}
+
+let retryDelay = 1000;
+
+function connect() {
+   const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
+   const socket = new WebSocket(`${protocol}//${window.location.host}/ws/status`);
+   socket.addEventListener('open', () => {
+      retryDelay = 1000;
+   });
+   socket.addEventListener('message', (event) => {
+      update(JSON.parse(event.data));
+   });
+   socket.addEventListener('close', () => {
+      liveState.textContent = 'Not live, reconnecting...';
+      setTimeout(connect, retryDelay);
+      retryDelay = Math.min(retryDelay * 2, 30000);
+   });
+}
+
+connect();
@@ -0,0 +1,1584 @@
+//! # Alerts
+//!
+//! This module watches the status of the system and notifies people when it breaks a rule.
+//!
+//! A rule has to be broken for its whole duration before its alert fires, and the alert only
+//! resolves once the value is back past the threshold by the hysteresis of the rule, so a value
+//! hovering around the threshold does not flap. Every subject of a rule, like a single mount
+//! point, has its own alert, and each alert notifies at most once per cooldown.
+//!
+//! The rules and channels are stored in `alerts.json`, the fired and resolved alerts are kept in
+//! a history next to it.
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    process::Stdio,
+    sync::LazyLock,
+    time::Duration,
+};
+
+use axum::{http::StatusCode, response::IntoResponse};
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::header::ContentType,
+    transport::smtp::authentication::Credentials,
+};
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::{fs, io::AsyncWriteExt, process::Command, sync::Mutex};
+
+use crate::{
+    persist,
+    sampler::Reports,
+    settings::{self, Threasholds},
+    status::StatusReport,
+};
+
+/// The path to the rules and channels.
+#[cfg(not(debug_assertions))]
+const ALERTS_PATH: &str = "/var/lib/system_manager_server/alerts.json";
+#[cfg(debug_assertions)]
+const ALERTS_PATH: &str = "./alerts.json";
+
+/// The path to the history of fired and resolved alerts.
+#[cfg(not(debug_assertions))]
+const ALERT_HISTORY_PATH: &str = "/var/lib/system_manager_server/alert_history.jsonl";
+#[cfg(debug_assertions)]
+const ALERT_HISTORY_PATH: &str = "./alert_history.jsonl";
+
+/// The size at which the history is trimmed in bytes.
+const MAX_HISTORY_SIZE: u64 = 1024 * 1024;
+/// The number of events kept when the history is trimmed.
+const TRIMMED_HISTORY_LEN: usize = 1000;
+/// How long a channel may take to accept a notification.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest name of a rule or channel.
+const MAX_NAME_LEN: usize = 64;
+
+/// Makes sure only one task reads and updates the rules and channels at a time.
+static ALERTS_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+/// Makes sure only one task writes to (or trims) the history at a time.
+static HISTORY_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+/// The state of every alert by rule name and subject.
+static STATES: LazyLock<Mutex<HashMap<(String, String), AlertState>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// The client used for every HTTP channel.
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(SEND_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+/// A value of the status that rules can watch.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// The usage of all cores together in percent.
+    Cpu,
+    /// The memory in use in percent.
+    Memory,
+    /// The swap in use in percent.
+    Swap,
+    /// The load average over one minute.
+    Load,
+    /// The free space of every mount in percent.
+    StorageFree,
+    /// The free inodes of every mount in percent.
+    InodesFree,
+    /// The temperature of every sensor in °C.
+    Temperature,
+    /// The charge of all batteries together in percent.
+    Battery,
+    /// The charge of the UPS in percent.
+    UpsCharge,
+    /// The runtime of the UPS on battery in minutes.
+    UpsRuntime,
+    /// The load of the UPS in percent.
+    UpsLoad,
+    /// `1` if the UPS runs on its battery.
+    UpsOnBattery,
+    /// The number of packages with updates.
+    Updates,
+    /// `1` if the settings changed since the server started.
+    RestartPending,
+}
+impl Metric {
+    pub const ALL: [Metric; 14] = [
+        Self::Cpu,
+        Self::Memory,
+        Self::Swap,
+        Self::Load,
+        Self::StorageFree,
+        Self::InodesFree,
+        Self::Temperature,
+        Self::Battery,
+        Self::UpsCharge,
+        Self::UpsRuntime,
+        Self::UpsLoad,
+        Self::UpsOnBattery,
+        Self::Updates,
+        Self::RestartPending,
+    ];
+
+    /// The name used in forms.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu",
+            Self::Memory => "memory",
+            Self::Swap => "swap",
+            Self::Load => "load",
+            Self::StorageFree => "storage_free",
+            Self::InodesFree => "inodes_free",
+            Self::Temperature => "temperature",
+            Self::Battery => "battery",
+            Self::UpsCharge => "ups_charge",
+            Self::UpsRuntime => "ups_runtime",
+            Self::UpsLoad => "ups_load",
+            Self::UpsOnBattery => "ups_on_battery",
+            Self::Updates => "updates",
+            Self::RestartPending => "restart_pending",
+        }
+    }
+
+    /// Formats a value of the metric.
+    pub fn format(&self, value: f64) -> String {
+        match self {
+            Self::Load => format!("{value:.2}"),
+            Self::Temperature => format!("{value:.1} °C"),
+            Self::UpsRuntime => format!("{value:.0} min"),
+            Self::Updates | Self::RestartPending | Self::UpsOnBattery => format!("{value}"),
+            _ => format!("{value:.0}%"),
+        }
+    }
+
+    /// Reads the values of the metric from a report along with the subject they belong to.
+    ///
+    /// Values of the whole system have an empty subject, a missing battery or UPS has no value.
+    fn values(&self, report: &StatusReport) -> Vec<(String, f64)> {
+        let system = &report.system;
+        let value = match self {
+            Self::Cpu => system.cpu_usage as f64,
+            Self::Memory => system.memory.percent() as f64,
+            Self::Swap => system.swap.percent() as f64,
+            Self::Load => system.load.one,
+            Self::StorageFree => {
+                return report
+                    .mounts
+                    .iter()
+                    .map(|mount| (mount.mount_point.clone(), mount.free_percent() as f64))
+                    .collect();
+            }
+            Self::InodesFree => {
+                return report
+                    .mounts
+                    .iter()
+                    .filter(|mount| mount.inodes_total > 0)
+                    .map(|mount| {
+                        (
+                            mount.mount_point.clone(),
+                            mount.inodes_free_percent() as f64,
+                        )
+                    })
+                    .collect();
+            }
+            Self::Temperature => {
+                return report
+                    .temperatures
+                    .iter()
+                    .map(|sensor| (sensor.label.clone(), sensor.current as f64))
+                    .collect();
+            }
+            Self::Battery => match report.power.charge() {
+                Some(charge) => charge as f64,
+                None => return Vec::new(),
+            },
+            Self::UpsCharge | Self::UpsRuntime | Self::UpsLoad | Self::UpsOnBattery => {
+                let Some(ups) = &report.power.ups else {
+                    return Vec::new();
+                };
+                let value = match self {
+                    Self::UpsCharge => ups.charge.map(|value| value as f64),
+                    Self::UpsRuntime => ups.runtime.map(|value| value as f64 / 60.0),
+                    Self::UpsLoad => ups.load.map(|value| value as f64),
+                    _ => Some(ups.on_battery as u8 as f64),
+                };
+                return value
+                    .map(|value| (ups.name.clone(), value))
+                    .into_iter()
+                    .collect();
+            }
+            Self::Updates => match report.updates {
+                Some(updates) => updates as f64,
+                None => return Vec::new(),
+            },
+            Self::RestartPending => report.restart_pending as u8 as f64,
+        };
+        vec![(String::new(), value)]
+    }
+}
+impl std::fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cpu => write!(f, "CPU usage"),
+            Self::Memory => write!(f, "Memory usage"),
+            Self::Swap => write!(f, "Swap usage"),
+            Self::Load => write!(f, "Load average"),
+            Self::StorageFree => write!(f, "Free storage"),
+            Self::InodesFree => write!(f, "Free inodes"),
+            Self::Temperature => write!(f, "Temperature"),
+            Self::Battery => write!(f, "Battery charge"),
+            Self::UpsCharge => write!(f, "UPS charge"),
+            Self::UpsRuntime => write!(f, "UPS runtime"),
+            Self::UpsLoad => write!(f, "UPS load"),
+            Self::UpsOnBattery => write!(f, "UPS on battery"),
+            Self::Updates => write!(f, "Pending updates"),
+            Self::RestartPending => write!(f, "Restart pending"),
+        }
+    }
+}
+
+/// Which side of the threshold breaks a rule.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Above,
+    Below,
+}
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Above => write!(f, "above"),
+            Self::Below => write!(f, "below"),
+        }
+    }
+}
+
+/// How urgent an alert is.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+impl Severity {
+    pub const ALL: [Severity; 3] = [Self::Info, Self::Warning, Self::Critical];
+
+    /// The name used in forms.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Critical => "critical",
+        }
+    }
+
+    /// The priority of a notification on ntfy, from 1 to 5.
+    fn ntfy_priority(self) -> &'static str {
+        match self {
+            Self::Info => "3",
+            Self::Warning => "4",
+            Self::Critical => "5",
+        }
+    }
+
+    /// The priority of a notification on Gotify, from 0 to 10.
+    fn gotify_priority(self) -> u8 {
+        match self {
+            Self::Info => 2,
+            Self::Warning => 5,
+            Self::Critical => 8,
+        }
+    }
+}
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Info => write!(f, "Info"),
+            Self::Warning => write!(f, "Warning"),
+            Self::Critical => write!(f, "Critical"),
+        }
+    }
+}
+
+/// When an alert fires.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: Metric,
+    /// Only watch this subject, like a mount point, empty for all of them.
+    #[serde(default)]
+    pub subject: String,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// How long the threshold has to be crossed before the alert fires in seconds.
+    pub duration: u64,
+    /// How far the value has to be back past the threshold for the alert to resolve.
+    pub hysteresis: f64,
+    /// The least time between notifications of an alert in minutes.
+    pub cooldown: u64,
+    pub severity: Severity,
+    /// The channels that are notified, empty for all of them.
+    #[serde(default)]
+    pub channels: Vec<String>,
+}
+impl AlertRule {
+    /// Checks if a value breaks the rule.
+    fn breached(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+
+    /// Checks if a value is far enough back for a fired alert to resolve.
+    fn recovered(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value <= self.threshold - self.hysteresis,
+            Comparison::Below => value >= self.threshold + self.hysteresis,
+        }
+    }
+
+    /// Checks if a channel is notified by the rule.
+    fn notifies(&self, channel: &Channel, severity: Severity) -> bool {
+        (self.channels.is_empty() || self.channels.contains(&channel.name))
+            && severity >= channel.min_severity
+    }
+
+    /// Describes the condition for display.
+    pub fn condition(&self) -> String {
+        format!(
+            "{} {} {}",
+            self.metric,
+            self.comparison,
+            self.metric.format(self.threshold)
+        )
+    }
+
+    /// Lists the notified channels for display.
+    pub fn channels_text(&self) -> String {
+        if self.channels.is_empty() {
+            String::from("All")
+        } else {
+            self.channels.join(", ")
+        }
+    }
+}
+
+/// How the connection to a mail server is secured.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Plain text, only for servers on the same machine or network.
+    None,
+    /// Upgrade a plain connection, usually on port 587.
+    #[default]
+    StartTls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+}
+
+/// Where notifications are sent.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChannelKind {
+    /// Email sent through an SMTP server.
+    Email {
+        server: String,
+        port: u16,
+        security: SmtpSecurity,
+        /// The login on the server, empty to send without one.
+        username: String,
+        password: String,
+        from: String,
+        /// The recipients separated by commas.
+        to: String,
+    },
+    /// The event posted as JSON to a URL.
+    Webhook { url: String },
+    /// A push notification through ntfy, the URL includes the topic.
+    Ntfy {
+        url: String,
+        /// An access token, empty for public topics.
+        token: String,
+    },
+    /// A push notification through a Gotify server.
+    Gotify { url: String, token: String },
+    /// A message on the terminals of everyone logged into this machine.
+    Wall,
+}
+
+/// A configured place notifications are sent to.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Channel {
+    pub name: String,
+    /// The least severity that is sent to this channel.
+    pub min_severity: Severity,
+    #[serde(flatten)]
+    pub kind: ChannelKind,
+}
+impl Channel {
+    /// The type of the channel for display.
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            ChannelKind::Email { .. } => "Email",
+            ChannelKind::Webhook { .. } => "Webhook",
+            ChannelKind::Ntfy { .. } => "ntfy",
+            ChannelKind::Gotify { .. } => "Gotify",
+            ChannelKind::Wall => "wall",
+        }
+    }
+
+    /// Where notifications go for display, without any secrets.
+    pub fn target(&self) -> String {
+        match &self.kind {
+            ChannelKind::Email {
+                server, port, to, ..
+            } => format!("{to} via {server}:{port}"),
+            ChannelKind::Webhook { url }
+            | ChannelKind::Ntfy { url, .. }
+            | ChannelKind::Gotify { url, .. } => url.clone(),
+            ChannelKind::Wall => String::from("Terminals on this machine"),
+        }
+    }
+
+    /// Checks that the channel can be used.
+    fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(AlertError::InvalidChannel(message.to_string()));
+        match &self.kind {
+            ChannelKind::Email {
+                server, from, to, ..
+            } => {
+                if server.trim().is_empty() {
+                    return invalid("The mail server is missing");
+                }
+                if from.parse::<lettre::message::Mailbox>().is_err() {
+                    return invalid("The sender is not an email address");
+                }
+                if recipients(to).next().is_none()
+                    || recipients(to).any(|to| to.parse::<lettre::message::Mailbox>().is_err())
+                {
+                    return invalid("The recipients are not email addresses");
+                }
+            }
+            ChannelKind::Webhook { url }
+            | ChannelKind::Ntfy { url, .. }
+            | ChannelKind::Gotify { url, .. } => {
+                if !reqwest::Url::parse(url)
+                    .is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+                {
+                    return invalid("The URL has to start with http:// or https://");
+                }
+                if let ChannelKind::Gotify { token, .. } = &self.kind
+                    && token.is_empty()
+                {
+                    return invalid("Gotify needs an application token");
+                }
+            }
+            ChannelKind::Wall => {}
+        }
+        Ok(())
+    }
+
+    /// Sends a notification to the channel.
+    async fn send(&self, event: &AlertEvent) -> std::result::Result<(), NotifyError> {
+        let title = event.title();
+        match &self.kind {
+            ChannelKind::Email {
+                server,
+                port,
+                security,
+                username,
+                password,
+                from,
+                to,
+            } => {
+                let builder = match security {
+                    SmtpSecurity::None => {
+                        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(server)
+                    }
+                    SmtpSecurity::StartTls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(server)?
+                    }
+                    SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(server)?,
+                };
+                let mut builder = builder.port(*port).timeout(Some(SEND_TIMEOUT));
+                if !username.is_empty() {
+                    builder =
+                        builder.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                let mut message = Message::builder().from(from.parse()?).subject(title);
+                for to in recipients(to) {
+                    message = message.to(to.parse()?);
+                }
+                let message = message
+                    .header(ContentType::TEXT_PLAIN)
+                    .body(event.message.clone())?;
+                builder.build().send(message).await?;
+            }
+            ChannelKind::Webhook { url } => {
+                CLIENT
+                    .post(url)
+                    .json(event)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            ChannelKind::Ntfy { url, token } => {
+                let mut request = CLIENT
+                    .post(url)
+                    .header("Title", title)
+                    .header("Priority", event.severity.ntfy_priority())
+                    .header("Tags", event.kind.tag())
+                    .body(event.message.clone());
+                if !token.is_empty() {
+                    request = request.bearer_auth(token);
+                }
+                request.send().await?.error_for_status()?;
+            }
+            ChannelKind::Gotify { url, token } => {
+                CLIENT
+                    .post(format!("{}/message", url.trim_end_matches('/')))
+                    .header("X-Gotify-Key", token)
+                    .json(&serde_json::json!({
+                        "title": title,
+                        "message": event.message,
+                        "priority": event.severity.gotify_priority(),
+                    }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            ChannelKind::Wall => {
+                let mut child = Command::new("wall")
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin
+                        .write_all(format!("{title}\n{}\n", event.message).as_bytes())
+                        .await?;
+                }
+                let output = child.wait_with_output().await?;
+                if !output.status.success() {
+                    return Err(NotifyError::IoError(io::Error::other(format!(
+                        "wall failed: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ))));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits the recipients of an email channel.
+fn recipients(to: &str) -> impl Iterator<Item = &str> {
+    to.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// The rules and channels.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AlertConfig {
+    #[serde(default)]
+    pub rules: Vec<AlertRule>,
+    #[serde(default)]
+    pub channels: Vec<Channel>,
+}
+impl AlertConfig {
+    /// The rules used before any were configured, taken from the thresholds of the dashboard.
+    fn from_thresholds(threatsholds: &Threasholds) -> Self {
+        Self {
+            rules: vec![
+                AlertRule {
+                    name: String::from("Low battery"),
+                    metric: Metric::Battery,
+                    subject: String::new(),
+                    comparison: Comparison::Below,
+                    threshold: threatsholds.low_power as f64,
+                    duration: 60,
+                    hysteresis: 5.0,
+                    cooldown: 60,
+                    severity: Severity::Critical,
+                    channels: Vec::new(),
+                },
+                AlertRule {
+                    name: String::from("UPS on battery"),
+                    metric: Metric::UpsOnBattery,
+                    subject: String::new(),
+                    comparison: Comparison::Above,
+                    threshold: 0.5,
+                    duration: 10,
+                    hysteresis: 0.0,
+                    cooldown: 60,
+                    severity: Severity::Critical,
+                    channels: Vec::new(),
+                },
+                AlertRule {
+                    name: String::from("Low storage"),
+                    metric: Metric::StorageFree,
+                    subject: String::new(),
+                    comparison: Comparison::Below,
+                    threshold: threatsholds.low_storage as f64,
+                    duration: 300,
+                    hysteresis: 2.0,
+                    cooldown: 24 * 60,
+                    severity: Severity::Warning,
+                    channels: Vec::new(),
+                },
+                AlertRule {
+                    name: String::from("High temperature"),
+                    metric: Metric::Temperature,
+                    subject: String::new(),
+                    comparison: Comparison::Above,
+                    threshold: threatsholds.high_temperature as f64,
+                    duration: 60,
+                    hysteresis: 5.0,
+                    cooldown: 60,
+                    severity: Severity::Warning,
+                    channels: Vec::new(),
+                },
+            ],
+            channels: Vec::new(),
+        }
+    }
+}
+
+/// An error that can occur when managing alerts.
+#[derive(Debug)]
+pub enum AlertError {
+    IoError(io::Error),
+    /// The name is empty or too long.
+    InvalidName,
+    RuleExists,
+    RuleNotFound,
+    InvalidRule(String),
+    ChannelExists,
+    ChannelNotFound,
+    InvalidChannel(String),
+    /// The channel is still notified by a rule.
+    ChannelInUse(String),
+    /// A test notification could not be sent.
+    NotifyError(NotifyError),
+}
+impl From<io::Error> for AlertError {
+    fn from(value: io::Error) -> Self {
+        log::error!("IO Error: {}", value);
+        AlertError::IoError(value)
+    }
+}
+impl From<serde_json::Error> for AlertError {
+    fn from(value: serde_json::Error) -> Self {
+        AlertError::from(io::Error::from(value))
+    }
+}
+impl From<NotifyError> for AlertError {
+    fn from(value: NotifyError) -> Self {
+        AlertError::NotifyError(value)
+    }
+}
+impl std::fmt::Display for AlertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(error) => write!(f, "IO Error: {error}"),
+            Self::InvalidName => write!(f, "Invalid Name (use 1 to {MAX_NAME_LEN} characters)"),
+            Self::RuleExists => write!(f, "Rule Already Exists"),
+            Self::RuleNotFound => write!(f, "Rule Not Found"),
+            Self::InvalidRule(message) => write!(f, "Invalid Rule: {message}"),
+            Self::ChannelExists => write!(f, "Channel Already Exists"),
+            Self::ChannelNotFound => write!(f, "Channel Not Found"),
+            Self::InvalidChannel(message) => write!(f, "Invalid Channel: {message}"),
+            Self::ChannelInUse(rule) => write!(f, "Channel Is Used By The Rule {rule}"),
+            Self::NotifyError(error) => write!(f, "Failed To Notify: {error}"),
+        }
+    }
+}
+impl std::error::Error for AlertError {}
+impl AlertError {
+    /// The status code to respond with for this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidName | Self::InvalidRule(_) | Self::InvalidChannel(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::RuleExists | Self::ChannelExists | Self::ChannelInUse(_) => StatusCode::CONFLICT,
+            Self::RuleNotFound | Self::ChannelNotFound => StatusCode::NOT_FOUND,
+            Self::NotifyError(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+impl IntoResponse for AlertError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::IoError(_) => (self.status(), "IO Error").into_response(),
+            _ => (self.status(), self.to_string()).into_response(),
+        }
+    }
+}
+type Result<T> = std::result::Result<T, AlertError>;
+
+/// An error that can occur when sending a notification.
+#[derive(Debug)]
+pub enum NotifyError {
+    IoError(io::Error),
+    Http(reqwest::Error),
+    Smtp(lettre::transport::smtp::Error),
+    /// An email address could not be parsed.
+    Address(lettre::address::AddressError),
+    /// The email could not be built.
+    Email(lettre::error::Error),
+}
+impl From<io::Error> for NotifyError {
+    fn from(value: io::Error) -> Self {
+        NotifyError::IoError(value)
+    }
+}
+impl From<reqwest::Error> for NotifyError {
+    fn from(value: reqwest::Error) -> Self {
+        NotifyError::Http(value)
+    }
+}
+impl From<lettre::transport::smtp::Error> for NotifyError {
+    fn from(value: lettre::transport::smtp::Error) -> Self {
+        NotifyError::Smtp(value)
+    }
+}
+impl From<lettre::address::AddressError> for NotifyError {
+    fn from(value: lettre::address::AddressError) -> Self {
+        NotifyError::Address(value)
+    }
+}
+impl From<lettre::error::Error> for NotifyError {
+    fn from(value: lettre::error::Error) -> Self {
+        NotifyError::Email(value)
+    }
+}
+impl std::fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(error) => write!(f, "{error}"),
+            Self::Http(error) => match std::error::Error::source(error) {
+                // The error itself only names the URL
+                Some(source) => write!(f, "{error}: {source}"),
+                None => write!(f, "{error}"),
+            },
+            Self::Smtp(error) => write!(f, "{error}"),
+            Self::Address(error) => write!(f, "Invalid email address: {error}"),
+            Self::Email(error) => write!(f, "{error}"),
+        }
+    }
+}
+impl std::error::Error for NotifyError {}
+
+/// Loads the rules and channels, the caller must hold [`ALERTS_LOCK`].
+///
+/// Until any are saved the rules follow the thresholds in the settings.
+async fn load() -> io::Result<AlertConfig> {
+    match persist::read(ALERTS_PATH).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            match persist::read_backup(ALERTS_PATH).await {
+                Ok(data) => Ok(serde_json::from_slice(&data)?),
+                Err(_) => Ok(AlertConfig::from_thresholds(
+                    &settings::load_settings().await.threatsholds,
+                )),
+            }
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Saves the rules and channels, the caller must hold [`ALERTS_LOCK`].
+async fn save(config: &AlertConfig) -> io::Result<()> {
+    persist::write(ALERTS_PATH, serde_json::to_vec_pretty(config)?).await
+}
+
+/// Gets the rules and channels.
+pub async fn config() -> Result<AlertConfig> {
+    let _guard = ALERTS_LOCK.lock().await;
+    Ok(load().await?)
+}
+
+/// Trims a name and checks that it can be used.
+fn valid_name(name: &str) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AlertError::InvalidName);
+    }
+    Ok(name.to_string())
+}
+
+/// Adds a rule.
+pub async fn add_rule(mut rule: AlertRule) -> Result<AlertRule> {
+    rule.name = valid_name(&rule.name)?;
+    rule.subject = rule.subject.trim().to_string();
+    if !rule.threshold.is_finite() {
+        return Err(AlertError::InvalidRule(String::from(
+            "The threshold is not a number",
+        )));
+    }
+    if !rule.hysteresis.is_finite() || rule.hysteresis < 0.0 {
+        return Err(AlertError::InvalidRule(String::from(
+            "The hysteresis can not be negative",
+        )));
+    }
+    let _guard = ALERTS_LOCK.lock().await;
+    let mut config = load().await?;
+    if config.rules.iter().any(|value| value.name == rule.name) {
+        return Err(AlertError::RuleExists);
+    }
+    if let Some(channel) = rule
+        .channels
+        .iter()
+        .find(|name| !config.channels.iter().any(|value| &value.name == *name))
+    {
+        return Err(AlertError::InvalidRule(format!(
+            "There is no channel named {channel}"
+        )));
+    }
+    config.rules.push(rule.clone());
+    save(&config).await?;
+    Ok(rule)
+}
+
+/// Deletes a rule, its alert resolves without a notification.
+pub async fn delete_rule(name: &str) -> Result<AlertRule> {
+    let _guard = ALERTS_LOCK.lock().await;
+    let mut config = load().await?;
+    let index = config
+        .rules
+        .iter()
+        .position(|value| value.name == name)
+        .ok_or(AlertError::RuleNotFound)?;
+    let rule = config.rules.remove(index);
+    save(&config).await?;
+    Ok(rule)
+}
+
+/// Adds a channel.
+pub async fn add_channel(mut channel: Channel) -> Result<Channel> {
+    channel.name = valid_name(&channel.name)?;
+    channel.validate()?;
+    let _guard = ALERTS_LOCK.lock().await;
+    let mut config = load().await?;
+    if config
+        .channels
+        .iter()
+        .any(|value| value.name == channel.name)
+    {
+        return Err(AlertError::ChannelExists);
+    }
+    config.channels.push(channel.clone());
+    save(&config).await?;
+    Ok(channel)
+}
+
+/// Deletes a channel that no rule names.
+pub async fn delete_channel(name: &str) -> Result<Channel> {
+    let _guard = ALERTS_LOCK.lock().await;
+    let mut config = load().await?;
+    let index = config
+        .channels
+        .iter()
+        .position(|value| value.name == name)
+        .ok_or(AlertError::ChannelNotFound)?;
+    if let Some(rule) = config
+        .rules
+        .iter()
+        .find(|rule| rule.channels.iter().any(|value| value == name))
+    {
+        return Err(AlertError::ChannelInUse(rule.name.clone()));
+    }
+    let channel = config.channels.remove(index);
+    save(&config).await?;
+    Ok(channel)
+}
+
+/// Sends a test notification to a channel.
+pub async fn test_channel(name: &str) -> Result<()> {
+    let channel = config()
+        .await?
+        .channels
+        .into_iter()
+        .find(|value| value.name == name)
+        .ok_or(AlertError::ChannelNotFound)?;
+    let event = AlertEvent {
+        timestamp: OffsetDateTime::now_utc(),
+        host: sysinfo::System::host_name().unwrap_or_default(),
+        rule: String::from("Test"),
+        subject: String::new(),
+        severity: Severity::Info,
+        kind: EventKind::Test,
+        message: format!("This is a test notification for the channel {name}."),
+        value: 0.0,
+        threshold: 0.0,
+        notified: Vec::new(),
+        failed: Vec::new(),
+        suppressed: false,
+    };
+    Ok(channel.send(&event).await?)
+}
+
+/// What happened to an alert.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Firing,
+    Resolved,
+    /// A test notification, these are not recorded.
+    Test,
+}
+impl EventKind {
+    /// The ntfy tag shown as an emoji.
+    fn tag(self) -> &'static str {
+        match self {
+            Self::Firing => "rotating_light",
+            Self::Resolved => "white_check_mark",
+            Self::Test => "bell",
+        }
+    }
+}
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Firing => write!(f, "Firing"),
+            Self::Resolved => write!(f, "Resolved"),
+            Self::Test => write!(f, "Test"),
+        }
+    }
+}
+
+/// An alert that fired or resolved, this is what channels are sent.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AlertEvent {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub host: String,
+    pub rule: String,
+    /// What the alert is about, like a mount point, empty for the whole system.
+    pub subject: String,
+    pub severity: Severity,
+    pub kind: EventKind,
+    pub message: String,
+    pub value: f64,
+    pub threshold: f64,
+    /// The channels the notification was delivered to.
+    #[serde(default)]
+    pub notified: Vec<String>,
+    /// The channels that failed along with their error.
+    #[serde(default)]
+    pub failed: Vec<String>,
+    /// If no notification was sent because of the cooldown.
+    #[serde(default)]
+    pub suppressed: bool,
+}
+impl AlertEvent {
+    /// Creates an event of a rule for a subject.
+    fn new(
+        rule: &AlertRule,
+        report: &StatusReport,
+        subject: &str,
+        value: f64,
+        kind: EventKind,
+    ) -> Self {
+        let what = if subject.is_empty() {
+            rule.metric.to_string()
+        } else {
+            format!("{} of {}", rule.metric, subject)
+        };
+        let message = match kind {
+            EventKind::Resolved => format!("{what} is back at {}.", rule.metric.format(value)),
+            _ => format!(
+                "{what} is {}, {} the threshold of {}.",
+                rule.metric.format(value),
+                rule.comparison,
+                rule.metric.format(rule.threshold)
+            ),
+        };
+        Self {
+            timestamp: report.system.taken,
+            host: report.system.hostname.clone(),
+            rule: rule.name.clone(),
+            subject: subject.to_string(),
+            severity: rule.severity,
+            kind,
+            message,
+            value,
+            threshold: rule.threshold,
+            notified: Vec::new(),
+            failed: Vec::new(),
+            suppressed: false,
+        }
+    }
+
+    /// The subject line of a notification.
+    pub fn title(&self) -> String {
+        match self.kind {
+            EventKind::Resolved => format!("Resolved: {} on {}", self.rule, self.host),
+            _ => format!("{}: {} on {}", self.severity, self.rule, self.host),
+        }
+    }
+
+    /// Formats the timestamp for display.
+    pub fn time(&self) -> String {
+        self.timestamp
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| self.timestamp.to_string())
+    }
+}
+
+/// What is tracked for every rule and subject.
+#[derive(Debug, Clone)]
+struct AlertState {
+    rule: AlertRule,
+    value: f64,
+    /// Since when the rule is broken, if it has not fired yet.
+    pending_since: Option<OffsetDateTime>,
+    /// Since when the rule was broken, if it fired.
+    firing_since: Option<OffsetDateTime>,
+    /// When a notification was last sent, for the cooldown.
+    last_notified: Option<OffsetDateTime>,
+    /// If the firing was notified, only then is resolving it notified.
+    notified: bool,
+}
+
+/// An alert that fired or is about to.
+#[derive(Debug, Clone)]
+pub struct ActiveAlert {
+    pub rule: AlertRule,
+    pub subject: String,
+    pub value: f64,
+    pub since: OffsetDateTime,
+    /// If the alert fired, otherwise the rule is broken but not for long enough yet.
+    pub firing: bool,
+}
+impl ActiveAlert {
+    /// Formats the value for display.
+    pub fn value_text(&self) -> String {
+        self.rule.metric.format(self.value)
+    }
+
+    /// Formats the start for display.
+    pub fn since_time(&self) -> String {
+        self.since
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| self.since.to_string())
+    }
+}
+
+/// Lists the alerts that fired or are pending, firing and the most severe first.
+pub async fn active() -> Vec<ActiveAlert> {
+    let mut alerts: Vec<ActiveAlert> = STATES
+        .lock()
+        .await
+        .iter()
+        .filter_map(|((_, subject), state)| {
+            let (since, firing) = match (state.firing_since, state.pending_since) {
+                (Some(since), _) => (since, true),
+                (None, Some(since)) => (since, false),
+                (None, None) => return None,
+            };
+            Some(ActiveAlert {
+                rule: state.rule.clone(),
+                subject: subject.clone(),
+                value: state.value,
+                since,
+                firing,
+            })
+        })
+        .collect();
+    alerts.sort_by(|a, b| {
+        (b.firing, b.rule.severity)
+            .cmp(&(a.firing, a.rule.severity))
+            .then(a.since.cmp(&b.since))
+    });
+    alerts
+}
+
+/// Checks every rule against a report.
+///
+/// # Returns
+///
+/// The rules of the alerts that fired or resolved, with their events.
+async fn evaluate(config: &AlertConfig, report: &StatusReport) -> Vec<(AlertRule, AlertEvent)> {
+    check_rules(&mut *STATES.lock().await, config, report)
+}
+
+/// Checks every rule against a report and updates the state of the alerts, see [`evaluate`].
+fn check_rules(
+    states: &mut HashMap<(String, String), AlertState>,
+    config: &AlertConfig,
+    report: &StatusReport,
+) -> Vec<(AlertRule, AlertEvent)> {
+    let now = report.system.taken;
+    let mut events = Vec::new();
+    let mut seen = HashSet::new();
+    for rule in &config.rules {
+        for (subject, value) in rule.metric.values(report) {
+            if !rule.subject.is_empty() && rule.subject != subject {
+                continue;
+            }
+            let key = (rule.name.clone(), subject.clone());
+            seen.insert(key.clone());
+            let state = states.entry(key).or_insert_with(|| AlertState {
+                rule: rule.clone(),
+                value,
+                pending_since: None,
+                firing_since: None,
+                last_notified: None,
+                notified: false,
+            });
+            state.rule = rule.clone();
+            state.value = value;
+            if state.firing_since.is_some() {
+                if rule.recovered(value) {
+                    state.firing_since = None;
+                    let mut event =
+                        AlertEvent::new(rule, report, &subject, value, EventKind::Resolved);
+                    event.suppressed = !state.notified;
+                    events.push((rule.clone(), event));
+                }
+                continue;
+            }
+            if !rule.breached(value) {
+                state.pending_since = None;
+                continue;
+            }
+            let since = *state.pending_since.get_or_insert(now);
+            if (now - since).whole_seconds() < rule.duration as i64 {
+                continue;
+            }
+            state.pending_since = None;
+            state.firing_since = Some(since);
+            state.notified = state
+                .last_notified
+                .is_none_or(|last| (now - last).whole_minutes() >= rule.cooldown as i64);
+            if state.notified {
+                state.last_notified = Some(now);
+            }
+            let mut event = AlertEvent::new(rule, report, &subject, value, EventKind::Firing);
+            event.suppressed = !state.notified;
+            events.push((rule.clone(), event));
+        }
+    }
+    // Deleted rules and subjects that went away, like an unmounted filesystem, are forgotten
+    states.retain(|key, _| seen.contains(key));
+    events
+}
+
+/// Notifies the channels of a rule about an event and records it.
+async fn dispatch(channels: Vec<Channel>, rule: AlertRule, mut event: AlertEvent) {
+    if !event.suppressed {
+        for channel in channels
+            .iter()
+            .filter(|channel| rule.notifies(channel, event.severity))
+        {
+            match channel.send(&event).await {
+                Ok(()) => event.notified.push(channel.name.clone()),
+                Err(error) => {
+                    log::error!("Failed to notify {}: {}", channel.name, error);
+                    event.failed.push(format!("{}: {}", channel.name, error));
+                }
+            }
+        }
+    }
+    if let Err(error) = record(&event).await {
+        log::error!("Failed to record alert: {}", error);
+    }
+}
+
+/// Checks every report of the sampler until the server stops.
+pub fn spawn(mut reports: Reports) {
+    tokio::spawn(async move {
+        loop {
+            let report = reports.borrow_and_update().clone();
+            match config().await {
+                Ok(config) => {
+                    for (rule, event) in evaluate(&config, &report).await {
+                        log::warn!("{}: {}", event.title(), event.message);
+                        // Slow channels must not hold back the next report
+                        tokio::spawn(dispatch(config.channels.clone(), rule, event));
+                    }
+                }
+                Err(error) => log::error!("Failed to load alert rules: {}", error),
+            }
+            if reports.changed().await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Trims the history if it grew too large, the caller must hold [`HISTORY_LOCK`].
+async fn trim() -> io::Result<()> {
+    if fs::metadata(ALERT_HISTORY_PATH).await?.len() < MAX_HISTORY_SIZE {
+        return Ok(());
+    }
+    let events = read_history().await?;
+    let mut content = String::new();
+    for event in &events[events.len().saturating_sub(TRIMMED_HISTORY_LEN)..] {
+        content.push_str(&serde_json::to_string(event)?);
+        content.push('\n');
+    }
+    persist::write(ALERT_HISTORY_PATH, content).await
+}
+
+/// Appends an event to the history.
+async fn record(event: &AlertEvent) -> io::Result<()> {
+    let mut line = serde_json::to_string(event)?;
+    line.push('\n');
+    let _guard = HISTORY_LOCK.lock().await;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ALERT_HISTORY_PATH)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    file.sync_data().await?;
+    trim().await
+}
+
+/// Reads the whole history oldest first, the caller must hold [`HISTORY_LOCK`].
+async fn read_history() -> io::Result<Vec<AlertEvent>> {
+    let content = match fs::read_to_string(ALERT_HISTORY_PATH).await {
+        Ok(value) => value,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Gets the newest events of the history, newest first.
+pub async fn history(limit: usize) -> io::Result<Vec<AlertEvent>> {
+    let _guard = HISTORY_LOCK.lock().await;
+    Ok(read_history()
+        .await?
+        .into_iter()
+        .rev()
+        .take(limit)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    fn cpu_rule(duration: u64, hysteresis: f64, cooldown: u64) -> AlertRule {
+        AlertRule {
+            name: String::from("High CPU"),
+            metric: Metric::Cpu,
+            subject: String::new(),
+            comparison: Comparison::Above,
+            threshold: 80.0,
+            duration,
+            hysteresis,
+            cooldown,
+            severity: Severity::Warning,
+            channels: Vec::new(),
+        }
+    }
+
+    fn config(rule: AlertRule) -> AlertConfig {
+        AlertConfig {
+            rules: vec![rule],
+            channels: Vec::new(),
+        }
+    }
+
+    /// A report taken `seconds` after the fixture with the CPU at `cpu` percent.
+    fn report(seconds: i64, cpu: f32) -> StatusReport {
+        let mut report = StatusReport::fixture();
+        report.system.taken += time::Duration::seconds(seconds);
+        report.system.cpu_usage = cpu;
+        report
+    }
+
+    /// The kind of every event and if it was suppressed.
+    fn kinds(events: &[(AlertRule, AlertEvent)]) -> Vec<(EventKind, bool)> {
+        events
+            .iter()
+            .map(|(_, event)| (event.kind, event.suppressed))
+            .collect()
+    }
+
+    #[test]
+    fn fires_once_broken_for_the_duration() {
+        let mut states = HashMap::new();
+        let config = config(cpu_rule(60, 0.0, 0));
+        assert!(check_rules(&mut states, &config, &report(0, 90.0)).is_empty());
+        assert!(check_rules(&mut states, &config, &report(30, 90.0)).is_empty());
+        // Dropping below the threshold starts the duration over
+        assert!(check_rules(&mut states, &config, &report(40, 70.0)).is_empty());
+        assert!(check_rules(&mut states, &config, &report(50, 90.0)).is_empty());
+        assert!(check_rules(&mut states, &config, &report(100, 90.0)).is_empty());
+        let events = check_rules(&mut states, &config, &report(110, 90.0));
+        assert_eq!(kinds(&events), [(EventKind::Firing, false)]);
+        let event = &events[0].1;
+        assert_eq!(event.title(), "Warning: High CPU on host");
+        assert_eq!(
+            event.message,
+            "CPU usage is 90%, above the threshold of 80%."
+        );
+        // A firing alert does not fire again
+        assert!(check_rules(&mut states, &config, &report(120, 95.0)).is_empty());
+    }
+
+    #[test]
+    fn resolves_past_the_hysteresis() {
+        let mut states = HashMap::new();
+        let config = config(cpu_rule(0, 5.0, 0));
+        let events = check_rules(&mut states, &config, &report(0, 90.0));
+        assert_eq!(kinds(&events), [(EventKind::Firing, false)]);
+        // Below the threshold but not by the hysteresis
+        assert!(check_rules(&mut states, &config, &report(10, 78.0)).is_empty());
+        let events = check_rules(&mut states, &config, &report(20, 75.0));
+        assert_eq!(kinds(&events), [(EventKind::Resolved, false)]);
+        assert_eq!(events[0].1.title(), "Resolved: High CPU on host");
+        assert_eq!(events[0].1.message, "CPU usage is back at 75%.");
+    }
+
+    #[test]
+    fn suppresses_notifications_within_the_cooldown() {
+        let mut states = HashMap::new();
+        let config = config(cpu_rule(0, 0.0, 10));
+        let mut check =
+            |seconds, cpu| kinds(&check_rules(&mut states, &config, &report(seconds, cpu)));
+        assert_eq!(check(0, 90.0), [(EventKind::Firing, false)]);
+        assert_eq!(check(60, 70.0), [(EventKind::Resolved, false)]);
+        assert_eq!(check(120, 90.0), [(EventKind::Firing, true)]);
+        // Resolving an alert nobody heard about is not worth a notification either
+        assert_eq!(check(180, 70.0), [(EventKind::Resolved, true)]);
+        assert_eq!(check(600, 90.0), [(EventKind::Firing, false)]);
+    }
+
+    #[test]
+    fn tracks_every_subject() {
+        let mut states = HashMap::new();
+        let mut rule = AlertRule {
+            name: String::from("Low storage"),
+            metric: Metric::StorageFree,
+            comparison: Comparison::Below,
+            threshold: 30.0,
+            ..cpu_rule(0, 0.0, 0)
+        };
+        let mut report = report(0, 0.0);
+        let mut home = report.mounts[0].clone();
+        home.mount_point = String::from("/home");
+        home.available = 900;
+        report.mounts.push(home);
+        let events = check_rules(&mut states, &config(rule.clone()), &report);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].1.subject, "/");
+        assert_eq!(
+            events[0].1.message,
+            "Free storage of / is 25%, below the threshold of 30%."
+        );
+
+        rule.subject = String::from("/home");
+        assert!(check_rules(&mut states, &config(rule), &report).is_empty());
+        // The alert of / belongs to a rule that no longer watches it
+        assert_eq!(states.len(), 1);
+        assert!(states.contains_key(&(String::from("Low storage"), String::from("/home"))));
+    }
+
+    fn event() -> AlertEvent {
+        let rule = cpu_rule(0, 0.0, 0);
+        AlertEvent::new(&rule, &StatusReport::fixture(), "", 90.0, EventKind::Firing)
+    }
+
+    fn channel(kind: ChannelKind) -> Channel {
+        Channel {
+            name: String::from("test"),
+            min_severity: Severity::Info,
+            kind,
+        }
+    }
+
+    /// Starts an HTTP server that answers one request with `status` and returns its address
+    /// along with the request it got.
+    async fn http_stand_in(status: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(&mut stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                // Header names are case insensitive, the values are kept as they are
+                match line.split_once(':') {
+                    Some((name, value)) => {
+                        head.push_str(&format!("{}:{value}", name.to_lowercase()))
+                    }
+                    None => head.push_str(&line),
+                }
+            }
+            let length = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse().unwrap())
+                })
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).await.unwrap();
+            stream
+                .write_all(
+                    format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                        .as_bytes(),
+                )
+                .await
+                .unwrap();
+            format!("{}\r\n{}", head, String::from_utf8(body).unwrap())
+        });
+        (address, request)
+    }
+
+    #[tokio::test]
+    async fn posts_webhooks() {
+        let (url, request) = http_stand_in("200 OK").await;
+        let channel = channel(ChannelKind::Webhook {
+            url: format!("{url}/hook"),
+        });
+        channel.send(&event()).await.unwrap();
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.contains("content-type: application/json\r\n"));
+        let body: serde_json::Value =
+            serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["rule"], "High CPU");
+        assert_eq!(body["kind"], "firing");
+        assert_eq!(body["severity"], "warning");
+        assert_eq!(body["value"], 90.0);
+    }
+
+    #[tokio::test]
+    async fn reports_failed_webhooks() {
+        let (url, request) = http_stand_in("500 Internal Server Error").await;
+        let channel = channel(ChannelKind::Webhook { url });
+        assert!(matches!(
+            channel.send(&event()).await,
+            Err(NotifyError::Http(_))
+        ));
+        request.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pushes_to_ntfy() {
+        let (url, request) = http_stand_in("200 OK").await;
+        let channel = channel(ChannelKind::Ntfy {
+            url: format!("{url}/alerts"),
+            token: String::from("tk_secret"),
+        });
+        channel.send(&event()).await.unwrap();
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /alerts HTTP/1.1\r\n"));
+        assert!(request.contains("title: Warning: High CPU on host\r\n"));
+        assert!(request.contains("priority: 4\r\n"));
+        assert!(request.contains("tags: rotating_light\r\n"));
+        assert!(request.contains("authorization: Bearer tk_secret\r\n"));
+        assert!(request.ends_with("\r\n\r\nCPU usage is 90%, above the threshold of 80%."));
+    }
+
+    #[tokio::test]
+    async fn pushes_to_gotify() {
+        let (url, request) = http_stand_in("200 OK").await;
+        let channel = channel(ChannelKind::Gotify {
+            url: format!("{url}/"),
+            token: String::from("app-token"),
+        });
+        channel.send(&event()).await.unwrap();
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /message HTTP/1.1\r\n"));
+        assert!(request.contains("x-gotify-key: app-token\r\n"));
+        let body: serde_json::Value =
+            serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["title"], "Warning: High CPU on host");
+        assert_eq!(body["priority"], 5);
+    }
+
+    /// Starts an SMTP server that accepts one mail and returns its port along with the
+    /// commands and data it got.
+    async fn smtp_stand_in() -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let lines = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.split();
+            let mut reader = BufReader::new(reader);
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut lines = Vec::new();
+            let mut data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    return lines;
+                }
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = if data {
+                    if line == "." {
+                        data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        b""
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250 localhost\r\n"
+                } else if line == "DATA" {
+                    data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    lines.push(line);
+                    return lines;
+                } else {
+                    b"250 ok\r\n"
+                };
+                lines.push(line);
+                writer.write_all(reply).await.unwrap();
+            }
+        });
+        (port, lines)
+    }
+
+    #[tokio::test]
+    async fn sends_email() {
+        let (port, lines) = smtp_stand_in().await;
+        let channel = channel(ChannelKind::Email {
+            server: String::from("127.0.0.1"),
+            port,
+            security: SmtpSecurity::None,
+            username: String::new(),
+            password: String::new(),
+            from: String::from("alerts@example.com"),
+            to: String::from("admin@example.com, ops@example.com"),
+        });
+        channel.send(&event()).await.unwrap();
+        let lines = lines.await.unwrap();
+        assert!(lines.contains(&String::from("MAIL FROM:<alerts@example.com>")));
+        assert!(lines.contains(&String::from("RCPT TO:<admin@example.com>")));
+        assert!(lines.contains(&String::from("RCPT TO:<ops@example.com>")));
+        assert!(lines.contains(&String::from("Subject: Warning: High CPU on host")));
+        assert!(lines.contains(&String::from(
+            "CPU usage is 90%, above the threshold of 80%."
+        )));
+    }
+}
@@ -0,0 +1,257 @@
+//! # API Tokens
+//!
+//! This module contains the long-lived tokens users create for programs that can't log in
+//! through the web interface, like WebDAV clients.
+//!
+//! Tokens are sent instead of the password, either as the password of HTTP basic auth or as a
+//! bearer token. Like the reset tokens only a SHA-256 hash of each token is stored.
+use std::{collections::HashMap, io, net::SocketAddr, sync::LazyLock};
+
+use axum::http::{HeaderMap, header};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::Mutex;
+
+use crate::{
+    logins::{self, AuthMethod, LoginRecord},
+    persist, settings, tokens,
+    users::{self, User, UserError},
+};
+
+/// The path to the API tokens file.
+#[cfg(not(debug_assertions))]
+const API_TOKENS_PATH: &str = "/var/lib/system_manager_server/api_tokens.json";
+#[cfg(debug_assertions)]
+const API_TOKENS_PATH: &str = "./api_tokens.json";
+
+/// How often the last use of a token is written to disk at most.
+const LAST_USED_RESOLUTION: Duration = Duration::minutes(5);
+
+/// Makes sure only one task reads and updates the API tokens file at a time.
+static API_TOKENS_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// A stored API token, without the token itself.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiToken {
+    /// A short public id used to revoke the token.
+    pub id: String,
+    pub username: String,
+    /// What the user named the token.
+    pub name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub last_used: Option<OffsetDateTime>,
+}
+impl ApiToken {
+    /// Formats the creation time for display.
+    pub fn created_time(&self) -> String {
+        format_time(self.created)
+    }
+
+    /// Formats the last use for display.
+    pub fn last_used_time(&self) -> String {
+        self.last_used
+            .map(format_time)
+            .unwrap_or_else(|| "never".to_string())
+    }
+}
+
+fn format_time(value: OffsetDateTime) -> String {
+    value.format(&Rfc3339).unwrap_or_else(|_| value.to_string())
+}
+
+/// Hashes a token for storage.
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Loads the tokens by hash, the caller must hold [`API_TOKENS_LOCK`].
+async fn load() -> io::Result<HashMap<String, ApiToken>> {
+    match persist::read(API_TOKENS_PATH).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Saves the tokens, the caller must hold [`API_TOKENS_LOCK`].
+async fn save(tokens: &HashMap<String, ApiToken>) -> io::Result<()> {
+    persist::write(API_TOKENS_PATH, serde_json::to_vec(tokens)?).await
+}
+
+/// Creates a token for a user.
+///
+/// # Returns
+///
+/// The token, this is the only time it is available.
+pub async fn create(username: &str, name: &str) -> io::Result<String> {
+    let _guard = API_TOKENS_LOCK.lock().await;
+    let mut tokens = load().await?;
+    let token = tokens::generate();
+    let hashed = hash(&token);
+    tokens.insert(
+        hashed.clone(),
+        ApiToken {
+            id: hashed[..12].to_string(),
+            username: username.to_string(),
+            name: name.trim().to_string(),
+            created: OffsetDateTime::now_utc(),
+            last_used: None,
+        },
+    );
+    save(&tokens).await?;
+    Ok(token)
+}
+
+/// Lists the tokens of a user, newest first.
+pub async fn list(username: &str) -> io::Result<Vec<ApiToken>> {
+    let _guard = API_TOKENS_LOCK.lock().await;
+    let mut tokens: Vec<ApiToken> = load()
+        .await?
+        .into_values()
+        .filter(|token| token.username == username)
+        .collect();
+    tokens.sort_by_key(|token| std::cmp::Reverse(token.created));
+    Ok(tokens)
+}
+
+/// Revokes a token of a user by its id.
+///
+/// # Returns
+///
+/// The revoked token, or `None` if the user has no token with that id.
+pub async fn revoke(username: &str, id: &str) -> io::Result<Option<ApiToken>> {
+    let _guard = API_TOKENS_LOCK.lock().await;
+    let mut tokens = load().await?;
+    let Some(hashed) = tokens
+        .iter()
+        .find(|(_, token)| token.username == username && token.id == id)
+        .map(|(hashed, _)| hashed.clone())
+    else {
+        return Ok(None);
+    };
+    let token = tokens.remove(&hashed);
+    save(&tokens).await?;
+    Ok(token)
+}
+
+/// Revokes every token of a user, used when the user is deleted.
+pub async fn forget(username: &str) -> io::Result<()> {
+    let _guard = API_TOKENS_LOCK.lock().await;
+    let mut tokens = load().await?;
+    let before = tokens.len();
+    tokens.retain(|_, token| token.username != username);
+    if tokens.len() != before {
+        save(&tokens).await?;
+    }
+    Ok(())
+}
+
+/// Looks up a token and records that it was used.
+pub async fn authenticate(token: &str) -> io::Result<Option<ApiToken>> {
+    let _guard = API_TOKENS_LOCK.lock().await;
+    let mut tokens = load().await?;
+    let Some(record) = tokens.get_mut(&hash(token)) else {
+        return Ok(None);
+    };
+    let now = OffsetDateTime::now_utc();
+    // Clients send the token with every request, so the file is only updated now and then
+    if record
+        .last_used
+        .is_none_or(|last_used| now - last_used > LAST_USED_RESOLUTION)
+    {
+        record.last_used = Some(now);
+        let record = record.clone();
+        save(&tokens).await?;
+        return Ok(Some(record));
+    }
+    Ok(Some(record.clone()))
+}
+
+/// Authenticates a request from a program by its `Authorization` header.
+///
+/// Basic auth takes either the user's password or one of their API tokens as the password, a
+/// bearer token has to be an API token. Failed attempts for existing users are added to their
+/// login history, and like on the login form too many of them lock the user out for a while.
+pub async fn authorize(headers: &HeaderMap, address: SocketAddr) -> Result<User, UserError> {
+    let value = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        let token = authenticate(token.trim())
+            .await?
+            .ok_or(UserError::PasswordError)?;
+        let user = User::fetch_user(&token.username).await?;
+        user.check_active()?;
+        return Ok(user);
+    }
+    let Some((username, secret)) = value
+        .strip_prefix("Basic ")
+        .and_then(|value| STANDARD.decode(value.trim()).ok())
+        .and_then(|value| String::from_utf8(value).ok())
+        .and_then(|value| {
+            value
+                .split_once(':')
+                .map(|(username, secret)| (username.to_string(), secret.to_string()))
+        })
+    else {
+        return Err(UserError::PasswordError);
+    };
+    if !users::valid_username(&username) {
+        logins::count_failure();
+        return Err(UserError::PasswordError);
+    }
+    let lockout_key = logins::login_key(&username, address);
+    let _turn = logins::take_turn(&lockout_key).await;
+    if let Some(wait) = logins::begin_attempt(&lockout_key).await {
+        log::warn!(
+            "Refused basic auth for locked out user {} from {}",
+            username,
+            address
+        );
+        return Err(UserError::TooManyAttempts(wait));
+    }
+    let result = match authenticate(&secret).await? {
+        Some(token) if token.username == username => match User::fetch_user(&username).await {
+            Ok(user) => user.check_active().map(|_| user),
+            Err(error) => Err(error),
+        },
+        _ => {
+            let policy = settings::load_settings().await.password_policy;
+            User::authenticate(&username, &secret, &policy).await
+        }
+    };
+    match result {
+        Ok(_) => logins::clear_attempts(&lockout_key).await,
+        Err(
+            UserError::PasswordError
+            | UserError::AccountDisabled
+            | UserError::AccountExpired
+            | UserError::PasswordChangeRequired,
+        ) => {
+            let user_agent = headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            let record = LoginRecord::new(address, user_agent, AuthMethod::Basic, false);
+            if let Err(error) = logins::record(&username, record).await {
+                log::error!("Failed to record login for {}: {}", username, error);
+            }
+        }
+        Err(UserError::UserNotFound) => {
+            logins::count_failure();
+            log::warn!(
+                "Failed basic auth for unknown user {} from {}",
+                username,
+                address
+            );
+            return Err(UserError::PasswordError);
+        }
+        Err(_) => {}
+    }
+    result
+}
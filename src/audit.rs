@@ -0,0 +1,419 @@
+//! # Audit
+//!
+//! This module contains the append-only audit log of administrative actions.
+//!
+//! Entries are stored as one JSON object per line, once the log grows past
+//! [`MAX_LOG_SIZE`] it is rotated and only the newest [`MAX_ROTATED_LOGS`] files are kept.
+use std::{collections::BTreeMap, io, net::SocketAddr, path::PathBuf, sync::LazyLock};
+
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+/// The path to the audit log directory.
+#[cfg(not(debug_assertions))]
+const AUDIT_PATH: &str = "/var/lib/system_manager_server/audit";
+#[cfg(debug_assertions)]
+const AUDIT_PATH: &str = "./audit";
+
+/// The name of the log file currently being written to.
+const AUDIT_FILE: &str = "audit.jsonl";
+/// The size at which the log is rotated in bytes.
+const MAX_LOG_SIZE: u64 = 5 * 1024 * 1024;
+/// The number of rotated logs to keep.
+const MAX_ROTATED_LOGS: usize = 10;
+/// The value written in place of secrets.
+const REDACTED: &str = "[redacted]";
+
+/// Makes sure only one task writes to (or rotates) the log at a time.
+static WRITE_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// An action that is recorded in the audit log.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    UpdateSettings,
+    UpdateUser,
+    ChangePassword,
+    ExportAuditLog,
+    RevokeSession,
+    InviteUser,
+    CreateResetLink,
+    UseResetLink,
+    AcceptInvite,
+    CreateGroup,
+    UpdateGroup,
+    DeleteGroup,
+    DeleteUser,
+    ProvisionAccount,
+    BrowseFiles,
+    ModifyFiles,
+    CreateApiToken,
+    RevokeApiToken,
+    CreateShare,
+    RevokeShare,
+    AddSshKey,
+    RemoveSshKey,
+    UpdateAlerts,
+}
+impl AuditAction {
+    /// All the actions, used for filtering.
+    pub const ALL: [AuditAction; 23] = [
+        Self::UpdateSettings,
+        Self::UpdateUser,
+        Self::ChangePassword,
+        Self::ExportAuditLog,
+        Self::RevokeSession,
+        Self::InviteUser,
+        Self::CreateResetLink,
+        Self::UseResetLink,
+        Self::AcceptInvite,
+        Self::CreateGroup,
+        Self::UpdateGroup,
+        Self::DeleteGroup,
+        Self::DeleteUser,
+        Self::ProvisionAccount,
+        Self::BrowseFiles,
+        Self::ModifyFiles,
+        Self::CreateApiToken,
+        Self::RevokeApiToken,
+        Self::CreateShare,
+        Self::RevokeShare,
+        Self::AddSshKey,
+        Self::RemoveSshKey,
+        Self::UpdateAlerts,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UpdateSettings => "update_settings",
+            Self::UpdateUser => "update_user",
+            Self::ChangePassword => "change_password",
+            Self::ExportAuditLog => "export_audit_log",
+            Self::RevokeSession => "revoke_session",
+            Self::InviteUser => "invite_user",
+            Self::CreateResetLink => "create_reset_link",
+            Self::UseResetLink => "use_reset_link",
+            Self::AcceptInvite => "accept_invite",
+            Self::CreateGroup => "create_group",
+            Self::UpdateGroup => "update_group",
+            Self::DeleteGroup => "delete_group",
+            Self::DeleteUser => "delete_user",
+            Self::ProvisionAccount => "provision_account",
+            Self::BrowseFiles => "browse_files",
+            Self::ModifyFiles => "modify_files",
+            Self::CreateApiToken => "create_api_token",
+            Self::RevokeApiToken => "revoke_api_token",
+            Self::CreateShare => "create_share",
+            Self::RevokeShare => "revoke_share",
+            Self::AddSshKey => "add_ssh_key",
+            Self::RemoveSshKey => "remove_ssh_key",
+            Self::UpdateAlerts => "update_alerts",
+        }
+    }
+}
+impl std::fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A single changed field.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Change {
+    pub field: String,
+    pub before: String,
+    pub after: String,
+}
+
+/// An entry in the audit log.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub actor: String,
+    pub ip: String,
+    pub action: AuditAction,
+    pub target: String,
+    #[serde(default)]
+    pub changes: Vec<Change>,
+}
+impl AuditEntry {
+    /// Creates a new entry timestamped now.
+    pub fn new(actor: &str, ip: SocketAddr, action: AuditAction, target: &str) -> Self {
+        Self {
+            timestamp: OffsetDateTime::now_utc(),
+            actor: actor.to_string(),
+            ip: ip.ip().to_string(),
+            action,
+            target: target.to_string(),
+            changes: Vec::new(),
+        }
+    }
+
+    /// Adds the changes between two versions of a value to the entry.
+    pub fn with_diff<T: Serialize>(mut self, before: &T, after: &T) -> Self {
+        self.changes.extend(diff(before, after));
+        self
+    }
+
+    /// Formats the timestamp for display.
+    pub fn time(&self) -> String {
+        self.timestamp
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| self.timestamp.to_string())
+    }
+}
+
+/// The filters that can be applied when reading the log.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct AuditFilter {
+    #[serde(default)]
+    pub actor: String,
+    #[serde(default)]
+    pub action: String,
+    #[serde(default)]
+    pub target: String,
+}
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let contains = |value: &str, filter: &str| {
+            value.to_lowercase().contains(&filter.trim().to_lowercase())
+        };
+        contains(&entry.actor, &self.actor)
+            && contains(&entry.target, &self.target)
+            && (self.action.is_empty() || entry.action.as_str() == self.action)
+    }
+}
+
+/// The fields holding secrets that must be redacted, like password hashes, token values and the
+/// password of a mail server.
+///
+/// Fields are matched by the last part of their dotted name, so `password_changed` or
+/// `password_policy.min_length` stay readable.
+const SECRET_FIELDS: [&str; 3] = ["password", "password_history", "token"];
+
+/// Checks if a field holds a secret that must be redacted.
+fn is_secret(field: &str) -> bool {
+    let name = field.rsplit('.').next().unwrap_or(field);
+    SECRET_FIELDS.contains(&name)
+}
+
+/// Flattens a JSON value into dotted field names.
+fn flatten(prefix: &str, value: serde_json::Value, out: &mut BTreeMap<String, String>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let field = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten(&field, value, out);
+            }
+        }
+        serde_json::Value::String(value) => {
+            out.insert(prefix.to_string(), value);
+        }
+        value => {
+            out.insert(prefix.to_string(), value.to_string());
+        }
+    }
+}
+
+/// Computes the fields that differ between two values, redacting secrets.
+pub fn diff<T: Serialize>(before: &T, after: &T) -> Vec<Change> {
+    let mut old = BTreeMap::new();
+    let mut new = BTreeMap::new();
+    if let Ok(value) = serde_json::to_value(before) {
+        flatten("", value, &mut old);
+    }
+    if let Ok(value) = serde_json::to_value(after) {
+        flatten("", value, &mut new);
+    }
+    let mut fields: Vec<&String> = old.keys().chain(new.keys()).collect();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter(|field| old.get(*field) != new.get(*field))
+        .map(|field| {
+            let (before, after) = if is_secret(field) {
+                (REDACTED.to_string(), REDACTED.to_string())
+            } else {
+                (
+                    old.get(field).cloned().unwrap_or_default(),
+                    new.get(field).cloned().unwrap_or_default(),
+                )
+            };
+            Change {
+                field: field.clone(),
+                before,
+                after,
+            }
+        })
+        .collect()
+}
+
+/// Gets the path of a log file, `0` is the current log.
+fn log_path(index: usize) -> PathBuf {
+    if index == 0 {
+        PathBuf::from(AUDIT_PATH).join(AUDIT_FILE)
+    } else {
+        PathBuf::from(AUDIT_PATH).join(format!("{AUDIT_FILE}.{index}"))
+    }
+}
+
+/// Rotates the logs if the current one is too large.
+async fn rotate() -> io::Result<()> {
+    let size = match fs::metadata(log_path(0)).await {
+        Ok(value) => value.len(),
+        Err(_) => return Ok(()),
+    };
+    if size < MAX_LOG_SIZE {
+        return Ok(());
+    }
+    let _ = fs::remove_file(log_path(MAX_ROTATED_LOGS)).await;
+    for index in (0..MAX_ROTATED_LOGS).rev() {
+        if fs::try_exists(log_path(index)).await.unwrap_or(false) {
+            fs::rename(log_path(index), log_path(index + 1)).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn append(entry: &AuditEntry) -> io::Result<()> {
+    let _guard = WRITE_LOCK.lock().await;
+    fs::create_dir_all(AUDIT_PATH).await?;
+    rotate().await?;
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path(0))
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    file.sync_data().await
+}
+
+/// Records an entry in the audit log.
+///
+/// Failing to record an entry never fails the action itself, but it is logged loudly.
+pub async fn record(entry: AuditEntry) {
+    log::info!(
+        "audit: {} ({}) {} {}",
+        entry.actor,
+        entry.ip,
+        entry.action,
+        entry.target
+    );
+    if let Err(error) = append(&entry).await {
+        log::error!("Failed to write audit log entry due to error: {}", error);
+    }
+}
+
+/// Reads the entries that match the filter, oldest first.
+pub async fn entries(filter: &AuditFilter) -> io::Result<Vec<AuditEntry>> {
+    let mut entries = Vec::new();
+    for index in (0..=MAX_ROTATED_LOGS).rev() {
+        let content = match fs::read_to_string(log_path(index)).await {
+            Ok(value) => value,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error),
+        };
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str::<AuditEntry>(line) {
+                Ok(entry) if filter.matches(&entry) => entries.push(entry),
+                Ok(_) => {}
+                Err(error) => log::warn!("Skipping malformed audit log entry: {}", error),
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Exports the entries that match the filter as JSON lines.
+pub async fn export(filter: &AuditFilter) -> io::Result<String> {
+    let mut output = String::new();
+    for entry in entries(filter).await? {
+        output.push_str(&serde_json::to_string(&entry)?);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn diffs_changed_fields_only() {
+        let before = json!({"storage": 1024, "admin": false, "shell": "/bin/sh"});
+        let after = json!({"storage": 2048, "admin": false, "groups": ["docker"]});
+        let changes: Vec<(String, String, String)> = diff(&before, &after)
+            .into_iter()
+            .map(|change| (change.field, change.before, change.after))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (
+                    String::from("groups"),
+                    String::new(),
+                    String::from("[\"docker\"]")
+                ),
+                (
+                    String::from("shell"),
+                    String::from("/bin/sh"),
+                    String::new()
+                ),
+                (
+                    String::from("storage"),
+                    String::from("1024"),
+                    String::from("2048")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn redacts_only_secrets() {
+        let before = json!({
+            "password": "$argon2id$old",
+            "password_history": [],
+            "password_changed": "2026-01-01T00:00:00Z",
+            "must_change_password": false,
+            "password_policy": {"min_length": 8},
+            "channel": {"token": "old", "password": "hunter2", "url": "https://a"},
+        });
+        let after = json!({
+            "password": "$argon2id$new",
+            "password_history": ["$argon2id$old"],
+            "password_changed": "2026-02-01T00:00:00Z",
+            "must_change_password": true,
+            "password_policy": {"min_length": 12},
+            "channel": {"token": "new", "password": "hunter3", "url": "https://b"},
+        });
+        let changes: Vec<(String, String)> = diff(&before, &after)
+            .into_iter()
+            .map(|change| (change.field, change.after))
+            .collect();
+        let after = |field: &str| {
+            changes
+                .iter()
+                .find(|(value, _)| value == field)
+                .map(|(_, after)| after.as_str())
+        };
+        assert_eq!(after("password"), Some(REDACTED));
+        assert_eq!(after("password_history"), Some(REDACTED));
+        assert_eq!(after("channel.token"), Some(REDACTED));
+        assert_eq!(after("channel.password"), Some(REDACTED));
+        assert_eq!(after("channel.url"), Some("https://b"));
+        assert_eq!(after("password_changed"), Some("2026-02-01T00:00:00Z"));
+        assert_eq!(after("must_change_password"), Some("true"));
+        assert_eq!(after("password_policy.min_length"), Some("12"));
+    }
+}
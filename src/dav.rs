@@ -0,0 +1,670 @@
+//! # DAV
+//!
+//! This module serves the data directory of every user over WebDAV under `/dav/{user}`, so it
+//! can be mounted with file managers and `davfs2`.
+//!
+//! Requests authenticate with HTTP basic auth using the password or an API token, every user
+//! can only reach their own files. All file access goes through the [`files`] module so the
+//! same path checks and quotas apply as in the web interface.
+//!
+//! Classes 1 and 2 are supported, the write locks are kept by the [`locks`] module and every
+//! request that changes a locked path has to submit the lock token. `PROPFIND` refuses infinite
+//! depth.
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+
+use crate::{
+    api_tokens,
+    files::{self, Entry, FileError},
+    tokens,
+    users::{User, UserError},
+};
+
+mod locks;
+
+use locks::{LOCKS, Lock, LockError};
+
+/// The path every user's files are served under.
+const DAV_PREFIX: &str = "/dav/";
+/// The methods that are supported.
+const ALLOWED_METHODS: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, MOVE, COPY, PROPFIND, LOCK, UNLOCK";
+/// The largest `LOCK` request body that is read.
+const MAX_LOCK_BODY: usize = 64 * 1024;
+/// The characters left as is in the hrefs of `PROPFIND` responses.
+const HREF_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Splits a request path into the username and the path of the file.
+///
+/// # Returns
+///
+/// `None` if the path is not below [`DAV_PREFIX`] or is not valid UTF-8.
+fn split_path(path: &str) -> Option<(String, String)> {
+    let path = path.strip_prefix(DAV_PREFIX)?;
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let (username, path) = path.split_once('/').unwrap_or((&path, ""));
+    Some((username.to_string(), path.trim_matches('/').to_string()))
+}
+
+/// Splits a path into the directory it is in and its name.
+fn split_name(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Builds the href of a path.
+fn href(username: &str, path: &str, is_dir: bool) -> String {
+    let mut href = format!("{DAV_PREFIX}{username}/{path}");
+    if is_dir && !href.ends_with('/') {
+        href.push('/');
+    }
+    utf8_percent_encode(&href, HREF_SET).to_string()
+}
+
+/// Escapes text for XML.
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A weak tag of a file from its size and modification time.
+fn etag(entry: &Entry) -> String {
+    format!(
+        "W/\"{:x}-{:x}\"",
+        entry.size,
+        entry
+            .modified
+            .map(|value| value.unix_timestamp())
+            .unwrap_or_default()
+    )
+}
+
+/// The last modification time as an HTTP date.
+fn last_modified(entry: &Entry) -> String {
+    entry
+        .modified
+        .map(|value| httpdate::fmt_http_date(value.into()))
+        .unwrap_or_default()
+}
+
+/// Finds the contents of the first XML element with a name, ignoring namespace prefixes.
+///
+/// Empty elements like `<D:shared/>` have empty contents.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    for (start, _) in xml.match_indices('<') {
+        let tag = &xml[start + 1..];
+        let end = tag.find('>')?;
+        let qualified = tag[..end]
+            .split(|value: char| value.is_whitespace() || value == '/')
+            .next()
+            .unwrap_or_default();
+        if qualified.is_empty() || qualified.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        if tag[..end].ends_with('/') {
+            return Some("");
+        }
+        let inner = &tag[end + 1..];
+        return inner
+            .find(&format!("</{qualified}>"))
+            .map(|index| &inner[..index]);
+    }
+    None
+}
+
+/// Gets the lock tokens a request submitted in its `If` header.
+fn if_tokens(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get("if")
+        .and_then(|value| value.to_str().ok())
+        .map(locks::submitted_tokens)
+        .unwrap_or_default()
+}
+
+/// Answers a request that changes a locked path without submitting the token.
+fn locked(username: &str, path: &str) -> Response {
+    (
+        StatusCode::LOCKED,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?><D:error xmlns:D="DAV:"><D:lock-token-submitted><D:href>{}</D:href></D:lock-token-submitted></D:error>"#,
+            href(username, path, false)
+        ),
+    )
+        .into_response()
+}
+
+/// Checks that a request may change a path, `deep` also checks everything below it.
+async fn check_locks(
+    username: &str,
+    path: &str,
+    deep: bool,
+    tokens: &[String],
+) -> Result<(), Response> {
+    match LOCKS
+        .lock()
+        .await
+        .check(username, path, deep, tokens, Instant::now())
+    {
+        Ok(()) => Ok(()),
+        Err(LockError::Locked(path)) => Err(locked(username, &path)),
+        Err(LockError::PreconditionFailed) => Err(StatusCode::PRECONDITION_FAILED.into_response()),
+    }
+}
+
+/// Turns an error into a response, a missing parent directory is a conflict in WebDAV.
+fn error_response(error: FileError, creating: bool) -> Response {
+    match error {
+        FileError::NotFound if creating => StatusCode::CONFLICT.into_response(),
+        error => error.into_response(),
+    }
+}
+
+/// Handles every WebDAV request.
+pub async fn handle(ConnectInfo(address): ConnectInfo<SocketAddr>, request: Request) -> Response {
+    let user = match api_tokens::authorize(request.headers(), address).await {
+        Ok(value) => value,
+        Err(UserError::IoError(_)) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "IO Error").into_response();
+        }
+        Err(error @ UserError::TooManyAttempts(_)) => return error.into_response(),
+        Err(_) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(
+                    header::WWW_AUTHENTICATE,
+                    "Basic realm=\"System Manager\", charset=\"UTF-8\"",
+                )],
+            )
+                .into_response();
+        }
+    };
+    let Some((username, path)) = split_path(request.uri().path()) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if username != user.name() {
+        return (
+            StatusCode::FORBIDDEN,
+            "Forbidden you can only access your own files",
+        )
+            .into_response();
+    }
+
+    let method = request.method().clone();
+    let headers = request.headers().clone();
+    let tokens = if_tokens(&headers);
+    let deep = matches!(method.as_str(), "DELETE" | "MOVE");
+    if matches!(method.as_str(), "PUT" | "DELETE" | "MKCOL" | "MOVE")
+        && let Err(response) = check_locks(user.name(), &path, deep, &tokens).await
+    {
+        return response;
+    }
+    match method.as_str() {
+        "OPTIONS" => (
+            [
+                (header::ALLOW, ALLOWED_METHODS),
+                (header::HeaderName::from_static("dav"), "1, 2"),
+                (header::HeaderName::from_static("ms-author-via"), "DAV"),
+            ],
+            StatusCode::OK,
+        )
+            .into_response(),
+        "GET" | "HEAD" => get(&user, &path, method == Method::HEAD).await,
+        "PUT" => put(&user, &path, request.into_body()).await,
+        "DELETE" => match files::delete(user.name(), &path).await {
+            Ok(()) => {
+                LOCKS.lock().await.release(user.name(), &path);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            Err(error) => error.into_response(),
+        },
+        "MKCOL" => mkcol(&user, &path, &headers).await,
+        "MOVE" | "COPY" => {
+            transfer(&user, &path, &headers, &tokens, method.as_str() == "COPY").await
+        }
+        "PROPFIND" => propfind(&user, &path, &headers).await,
+        "LOCK" => lock(&user, &path, &headers, request.into_body()).await,
+        "UNLOCK" => unlock(&user, &path, &headers).await,
+        _ => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, ALLOWED_METHODS)],
+        )
+            .into_response(),
+    }
+}
+
+async fn get(user: &User, path: &str, head: bool) -> Response {
+    let entry = match files::entry(user.name(), path).await {
+        Ok(value) => value,
+        Err(error) => return error.into_response(),
+    };
+    if entry.is_dir {
+        return (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, ALLOWED_METHODS)],
+        )
+            .into_response();
+    }
+    let body = if head {
+        Body::empty()
+    } else {
+        let target = match files::resolve(user.name(), path).await {
+            Ok(value) => value,
+            Err(error) => return error.into_response(),
+        };
+        match tokio::fs::File::open(target).await {
+            Ok(file) => Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+            Err(error) => return FileError::from(error).into_response(),
+        }
+    };
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                mime_guess::from_path(&entry.path)
+                    .first_or_octet_stream()
+                    .to_string(),
+            ),
+            (header::CONTENT_LENGTH, entry.size.to_string()),
+            (header::LAST_MODIFIED, last_modified(&entry)),
+            (header::ETAG, etag(&entry)),
+            // Browsers can open these URLs too, uploaded files never run anything
+            (header::X_CONTENT_TYPE_OPTIONS, String::from("nosniff")),
+            (header::CONTENT_SECURITY_POLICY, String::from("sandbox")),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+async fn put(user: &User, path: &str, body: Body) -> Response {
+    let (parent, name) = split_name(path);
+    match files::write(user, parent, name, body.into_data_stream()).await {
+        Ok((_, true)) => StatusCode::CREATED.into_response(),
+        Ok((_, false)) => StatusCode::NO_CONTENT.into_response(),
+        Err(FileError::AlreadyExists) => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        Err(error) => error_response(error, true),
+    }
+}
+
+async fn mkcol(user: &User, path: &str, headers: &HeaderMap) -> Response {
+    // Bodies of MKCOL requests are not supported
+    if headers
+        .get(header::CONTENT_LENGTH)
+        .is_some_and(|value| value != "0")
+    {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let (parent, name) = split_name(path);
+    match files::create_dir(user.name(), parent, name).await {
+        Ok(()) => StatusCode::CREATED.into_response(),
+        Err(FileError::AlreadyExists) => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        Err(error) => error_response(error, true),
+    }
+}
+
+/// Handles `MOVE` and `COPY` requests.
+async fn transfer(
+    user: &User,
+    path: &str,
+    headers: &HeaderMap,
+    tokens: &[String],
+    copy: bool,
+) -> Response {
+    // The destination is usually a full URL, only its path is used
+    let destination = headers
+        .get("destination")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let destination = match destination.find("://") {
+        Some(index) => {
+            let rest = &destination[index + 3..];
+            rest.find('/').map_or("/", |index| &rest[index..])
+        }
+        None => destination,
+    };
+    let Some((username, target)) = split_path(destination) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if username != user.name() {
+        return (
+            StatusCode::FORBIDDEN,
+            "Forbidden you can only access your own files",
+        )
+            .into_response();
+    }
+    if target.is_empty() || target == path {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    // A replaced destination loses everything below it
+    if let Err(response) = check_locks(user.name(), &target, true, tokens).await {
+        return response;
+    }
+    let overwrite = headers
+        .get("overwrite")
+        .is_none_or(|value| !value.as_bytes().eq_ignore_ascii_case(b"F"));
+    let existed = files::entry(user.name(), &target).await.is_ok();
+    if existed && !overwrite {
+        return StatusCode::PRECONDITION_FAILED.into_response();
+    }
+    // An existing destination is only replaced once the copy or move has succeeded, until then
+    // the result is kept under a temporary name next to it
+    let (parent, name) = split_name(&target);
+    let staged = if existed {
+        format!(".transfer-{}", tokens::generate())
+    } else {
+        name.to_string()
+    };
+    let result = if copy {
+        files::copy(user, path, parent, &staged).await
+    } else {
+        files::move_to(user.name(), path, parent, &staged).await
+    };
+    if let Err(error) = result {
+        return error_response(error, true);
+    }
+    // Locks stay with the path, the ones on a moved file are gone
+    if !existed {
+        if !copy {
+            LOCKS.lock().await.release(user.name(), path);
+        }
+        return StatusCode::CREATED.into_response();
+    }
+    let staged = if parent.is_empty() {
+        staged
+    } else {
+        format!("{parent}/{staged}")
+    };
+    match files::replace(user.name(), &staged, &target).await {
+        Ok(()) => {
+            if !copy {
+                LOCKS.lock().await.release(user.name(), path);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(error) => {
+            let (source_parent, source_name) = split_name(path);
+            let undo = if copy {
+                files::delete(user.name(), &staged).await
+            } else {
+                files::move_to(user.name(), &staged, source_parent, source_name).await
+            };
+            if let Err(error) = undo {
+                log::error!("Failed to undo a WebDAV transfer: {}", error);
+            }
+            error.into_response()
+        }
+    }
+}
+
+/// Writes a lock as an `activelock` element.
+fn write_lock(xml: &mut String, username: &str, lock: &Lock) {
+    xml.push_str("<D:activelock><D:locktype><D:write/></D:locktype>");
+    xml.push_str(if lock.exclusive {
+        "<D:lockscope><D:exclusive/></D:lockscope>"
+    } else {
+        "<D:lockscope><D:shared/></D:lockscope>"
+    });
+    xml.push_str(if lock.infinite {
+        "<D:depth>infinity</D:depth>"
+    } else {
+        "<D:depth>0</D:depth>"
+    });
+    if !lock.owner.is_empty() {
+        xml.push_str(&format!(
+            "<D:owner><D:href>{}</D:href></D:owner>",
+            escape(&lock.owner)
+        ));
+    }
+    xml.push_str(&format!(
+        "<D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken>",
+        lock.timeout.as_secs(),
+        lock.token
+    ));
+    xml.push_str(&format!(
+        "<D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        href(username, &lock.path, false)
+    ));
+}
+
+/// Writes the properties of a file as a `response` element.
+fn write_response(xml: &mut String, username: &str, entry: &Entry, locks: &[Lock]) {
+    let name = if entry.path.is_empty() {
+        username
+    } else {
+        &entry.name
+    };
+    xml.push_str("<D:response><D:href>");
+    xml.push_str(&href(username, &entry.path, entry.is_dir));
+    xml.push_str("</D:href><D:propstat><D:prop>");
+    xml.push_str(&format!("<D:displayname>{}</D:displayname>", escape(name)));
+    if entry.is_dir {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        xml.push_str("<D:resourcetype/>");
+        xml.push_str(&format!(
+            "<D:getcontentlength>{}</D:getcontentlength>",
+            entry.size
+        ));
+        xml.push_str(&format!(
+            "<D:getcontenttype>{}</D:getcontenttype>",
+            escape(
+                mime_guess::from_path(&entry.path)
+                    .first_or_octet_stream()
+                    .as_ref()
+            )
+        ));
+        xml.push_str(&format!("<D:getetag>{}</D:getetag>", escape(&etag(entry))));
+    }
+    xml.push_str(&format!(
+        "<D:getlastmodified>{}</D:getlastmodified>",
+        last_modified(entry)
+    ));
+    xml.push_str(concat!(
+        "<D:supportedlock>",
+        "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>",
+        "<D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>",
+        "</D:supportedlock><D:lockdiscovery>",
+    ));
+    for lock in locks
+        .iter()
+        .filter(|lock| lock.covers(username, &entry.path))
+    {
+        write_lock(xml, username, lock);
+    }
+    xml.push_str(
+        "</D:lockdiscovery></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+    );
+}
+
+/// Lists the properties of a file or a directory and its entries.
+///
+/// Every property is always returned no matter which were asked for, which clients accept.
+async fn propfind(user: &User, path: &str, headers: &HeaderMap) -> Response {
+    let entry = match files::entry(user.name(), path).await {
+        Ok(value) => value,
+        Err(error) => return error.into_response(),
+    };
+    // A missing depth means infinity, which is refused as RFC 4918 allows instead of walking
+    // whole trees
+    let depth_zero = match headers.get("depth").map(|value| value.as_bytes()) {
+        Some(b"0") => true,
+        Some(b"1") => false,
+        _ => {
+            return (
+                StatusCode::FORBIDDEN,
+                [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+                r#"<?xml version="1.0" encoding="utf-8"?><D:error xmlns:D="DAV:"><D:propfind-finite-depth/></D:error>"#,
+            )
+                .into_response();
+        }
+    };
+    let locks = LOCKS.lock().await.of_user(user.name(), Instant::now());
+    let mut xml =
+        String::from(r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:">"#);
+    write_response(&mut xml, user.name(), &entry, &locks);
+    if entry.is_dir && !depth_zero {
+        let entries = match files::list(user.name(), path).await {
+            Ok(value) => value,
+            Err(error) => return error.into_response(),
+        };
+        for entry in &entries {
+            write_response(&mut xml, user.name(), entry, &locks);
+        }
+    }
+    xml.push_str("</D:multistatus>");
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        xml,
+    )
+        .into_response()
+}
+
+/// Reads the timeout a client asked for, like `Second-600` or `Infinite`.
+///
+/// The first supported value is used, locks never last longer than [`locks::MAX_TIMEOUT`].
+fn lock_timeout(headers: &HeaderMap) -> Duration {
+    headers
+        .get("timeout")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .find_map(|value| {
+            if value.eq_ignore_ascii_case("infinite") {
+                Some(locks::MAX_TIMEOUT)
+            } else {
+                value
+                    .strip_prefix("Second-")
+                    .and_then(|seconds| seconds.parse().ok())
+                    .map(Duration::from_secs)
+            }
+        })
+        .unwrap_or(locks::MAX_TIMEOUT)
+}
+
+/// Answers a `LOCK` request with the lock.
+fn lock_response(status: StatusCode, username: &str, lock: &Lock) -> Response {
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="utf-8"?><D:prop xmlns:D="DAV:"><D:lockdiscovery>"#,
+    );
+    write_lock(&mut xml, username, lock);
+    xml.push_str("</D:lockdiscovery></D:prop>");
+    (
+        status,
+        [
+            (
+                header::CONTENT_TYPE,
+                String::from("application/xml; charset=utf-8"),
+            ),
+            (
+                header::HeaderName::from_static("lock-token"),
+                format!("<{}>", lock.token),
+            ),
+        ],
+        xml,
+    )
+        .into_response()
+}
+
+/// Takes a new lock or refreshes an existing one when the request has no body.
+///
+/// Locking a path that does not exist creates an empty file, like RFC 4918 asks for.
+async fn lock(user: &User, path: &str, headers: &HeaderMap, body: Body) -> Response {
+    let Ok(body) = axum::body::to_bytes(body, MAX_LOCK_BODY).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let body = String::from_utf8_lossy(&body);
+    let timeout = lock_timeout(headers);
+    let now = Instant::now();
+    if body.trim().is_empty() {
+        let tokens = if_tokens(headers);
+        let lock = LOCKS
+            .lock()
+            .await
+            .refresh(user.name(), path, &tokens, timeout, now);
+        return match lock {
+            Some(lock) => lock_response(StatusCode::OK, user.name(), &lock),
+            None => StatusCode::PRECONDITION_FAILED.into_response(),
+        };
+    }
+    let Some(info) = element(&body, "lockinfo") else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let infinite = match headers.get("depth").map(|value| value.as_bytes()) {
+        None => true,
+        Some(value) if value.eq_ignore_ascii_case(b"infinity") => true,
+        Some(b"0") => false,
+        Some(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let exclusive =
+        element(info, "lockscope").is_none_or(|scope| element(scope, "shared").is_none());
+    let owner = element(info, "owner").unwrap_or_default();
+    let owner = element(owner, "href").unwrap_or(owner).trim().to_string();
+    let lock =
+        match LOCKS
+            .lock()
+            .await
+            .lock(user.name(), path, exclusive, infinite, owner, timeout, now)
+        {
+            Ok(value) => value,
+            Err(conflict) => return locked(user.name(), &conflict),
+        };
+    if files::entry(user.name(), path).await.is_ok() {
+        return lock_response(StatusCode::OK, user.name(), &lock);
+    }
+    let (parent, name) = split_name(path);
+    match files::write(user, parent, name, Body::empty().into_data_stream()).await {
+        Ok(_) => lock_response(StatusCode::CREATED, user.name(), &lock),
+        Err(error) => {
+            LOCKS
+                .lock()
+                .await
+                .unlock(user.name(), path, &lock.token, Instant::now());
+            error_response(error, true)
+        }
+    }
+}
+
+/// Removes the lock named in the `Lock-Token` header.
+async fn unlock(user: &User, path: &str, headers: &HeaderMap) -> Response {
+    let Some(token) = headers
+        .get("lock-token")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| locks::submitted_tokens(value).into_iter().next())
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if LOCKS
+        .lock()
+        .await
+        .unlock(user.name(), path, &token, Instant::now())
+    {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (
+            StatusCode::CONFLICT,
+            [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+            r#"<?xml version="1.0" encoding="utf-8"?><D:error xmlns:D="DAV:"><D:lock-token-matches-request-uri/></D:error>"#,
+        )
+            .into_response()
+    }
+}
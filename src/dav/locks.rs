@@ -0,0 +1,383 @@
+//! # Locks
+//!
+//! This module keeps the WebDAV write locks, they only live in memory so they are gone after a
+//! restart, which clients handle like locks that expired.
+//!
+//! A lock covers its path, and everything below it if it is infinitely deep. Changing a covered
+//! path needs the token of the lock in the `If` header of the request.
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
+use tokio::sync::Mutex;
+
+/// The longest a lock lasts before it has to be refreshed.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(3600);
+/// The scheme of lock tokens.
+const TOKEN_SCHEME: &str = "opaquelocktoken:";
+
+/// The locks of every user.
+pub static LOCKS: LazyLock<Mutex<Locks>> = LazyLock::new(Default::default);
+
+/// A write lock on a file or directory.
+#[derive(Debug, Clone)]
+pub struct Lock {
+    pub token: String,
+    /// The user whose files are locked.
+    pub username: String,
+    /// The locked path relative to the data directory of the user.
+    pub path: String,
+    /// If no other lock may be taken, shared locks only keep out exclusive ones.
+    pub exclusive: bool,
+    /// If everything below the path is locked too.
+    pub infinite: bool,
+    /// Who the client said holds the lock.
+    pub owner: String,
+    pub timeout: Duration,
+    expires: Instant,
+}
+impl Lock {
+    /// Checks if the lock covers a path of a user.
+    pub fn covers(&self, username: &str, path: &str) -> bool {
+        self.username == username && (self.path == path || self.infinite && below(path, &self.path))
+    }
+}
+
+/// Checks if a path is below another one, everything is below the root.
+fn below(path: &str, parent: &str) -> bool {
+    if parent.is_empty() {
+        !path.is_empty()
+    } else {
+        path.strip_prefix(parent)
+            .is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+/// Why a request may not change a path.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LockError {
+    /// The path is locked and the token was not submitted, holds the path of the lock.
+    Locked(String),
+    /// Only tokens of locks that do not exist (anymore) were submitted.
+    PreconditionFailed,
+}
+
+/// Generates a lock token from a random UUID.
+fn generate_token() -> String {
+    let mut bytes = [0u8; 16];
+    rand::rng().fill_bytes(&mut bytes);
+    // Version 4 with the RFC 4122 variant
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!(
+        "{TOKEN_SCHEME}{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Gets the lock tokens from an `If` or `Lock-Token` header.
+///
+/// The conditions of an `If` header are not evaluated, every token in it counts as submitted.
+pub fn submitted_tokens(header: &str) -> Vec<String> {
+    header
+        .split('<')
+        .filter_map(|part| part.split_once('>'))
+        .map(|(value, _)| value.trim())
+        .filter(|value| value.starts_with(TOKEN_SCHEME))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The locks that were taken and have not expired yet.
+#[derive(Debug, Default)]
+pub struct Locks {
+    locks: Vec<Lock>,
+}
+impl Locks {
+    /// Forgets the locks that expired.
+    fn purge(&mut self, now: Instant) {
+        self.locks.retain(|lock| lock.expires > now);
+    }
+
+    /// Locks a path, unless another lock on it or below it conflicts with the new one.
+    ///
+    /// # Returns
+    ///
+    /// The new lock or the path of the lock it conflicts with.
+    #[allow(clippy::too_many_arguments)]
+    pub fn lock(
+        &mut self,
+        username: &str,
+        path: &str,
+        exclusive: bool,
+        infinite: bool,
+        owner: String,
+        timeout: Duration,
+        now: Instant,
+    ) -> Result<Lock, String> {
+        self.purge(now);
+        if let Some(conflict) = self.locks.iter().find(|lock| {
+            (lock.exclusive || exclusive)
+                && (lock.covers(username, path)
+                    || infinite && lock.username == username && below(&lock.path, path))
+        }) {
+            return Err(conflict.path.clone());
+        }
+        let timeout = timeout.min(MAX_TIMEOUT);
+        let lock = Lock {
+            token: generate_token(),
+            username: username.to_string(),
+            path: path.to_string(),
+            exclusive,
+            infinite,
+            owner,
+            timeout,
+            expires: now + timeout,
+        };
+        self.locks.push(lock.clone());
+        Ok(lock)
+    }
+
+    /// Restarts the timeout of a lock on a path whose token was submitted.
+    pub fn refresh(
+        &mut self,
+        username: &str,
+        path: &str,
+        tokens: &[String],
+        timeout: Duration,
+        now: Instant,
+    ) -> Option<Lock> {
+        self.purge(now);
+        let lock = self
+            .locks
+            .iter_mut()
+            .find(|lock| lock.covers(username, path) && tokens.contains(&lock.token))?;
+        lock.timeout = timeout.min(MAX_TIMEOUT);
+        lock.expires = now + lock.timeout;
+        Some(lock.clone())
+    }
+
+    /// Removes a lock if it covers the path.
+    ///
+    /// # Returns
+    ///
+    /// If the lock was removed.
+    pub fn unlock(&mut self, username: &str, path: &str, token: &str, now: Instant) -> bool {
+        self.purge(now);
+        let count = self.locks.len();
+        self.locks
+            .retain(|lock| lock.token != token || !lock.covers(username, path));
+        self.locks.len() != count
+    }
+
+    /// Checks if a request that submitted `tokens` may change a path.
+    ///
+    /// Deleting or moving a directory changes everything below it as well, `deep` checks the
+    /// locks on those too.
+    pub fn check(
+        &mut self,
+        username: &str,
+        path: &str,
+        deep: bool,
+        tokens: &[String],
+        now: Instant,
+    ) -> Result<(), LockError> {
+        self.purge(now);
+        if !tokens.is_empty() && !self.locks.iter().any(|lock| tokens.contains(&lock.token)) {
+            return Err(LockError::PreconditionFailed);
+        }
+        // The token of any of the shared locks on a path is enough
+        let submitted = |lock: &Lock| {
+            self.locks.iter().any(|other| {
+                tokens.contains(&other.token)
+                    && (other.token == lock.token
+                        || !lock.exclusive
+                            && !other.exclusive
+                            && other.username == lock.username
+                            && other.path == lock.path)
+            })
+        };
+        match self.locks.iter().find(|lock| {
+            (lock.covers(username, path)
+                || deep && lock.username == username && below(&lock.path, path))
+                && !submitted(lock)
+        }) {
+            Some(lock) => Err(LockError::Locked(lock.path.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops the locks on a path and everything below it, after it was deleted or moved away.
+    pub fn release(&mut self, username: &str, path: &str) {
+        self.locks.retain(|lock| {
+            lock.username != username || lock.path != path && !below(&lock.path, path)
+        });
+    }
+
+    /// Lists the locks on the files of a user.
+    pub fn of_user(&mut self, username: &str, now: Instant) -> Vec<Lock> {
+        self.purge(now);
+        self.locks
+            .iter()
+            .filter(|lock| lock.username == username)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(
+        locks: &mut Locks,
+        path: &str,
+        exclusive: bool,
+        infinite: bool,
+        now: Instant,
+    ) -> Result<Lock, String> {
+        locks.lock(
+            "alice",
+            path,
+            exclusive,
+            infinite,
+            String::new(),
+            MAX_TIMEOUT,
+            now,
+        )
+    }
+
+    #[test]
+    fn reads_tokens_from_headers() {
+        let token = generate_token();
+        assert!(token.starts_with(TOKEN_SCHEME));
+        assert_eq!(token.len(), TOKEN_SCHEME.len() + 36);
+        assert_eq!(
+            submitted_tokens(&format!(
+                "<http://host/dav/alice/a> (<{token}> [\"etag\"]) (Not <DAV:no-lock>)"
+            )),
+            std::slice::from_ref(&token)
+        );
+        assert_eq!(submitted_tokens(&format!("<{token}>")), [token]);
+        assert!(submitted_tokens("").is_empty());
+    }
+
+    #[test]
+    fn refuses_conflicting_locks() {
+        let now = Instant::now();
+        let mut locks = Locks::default();
+        lock(&mut locks, "docs", true, true, now).unwrap();
+        assert_eq!(
+            lock(&mut locks, "docs/a.txt", true, false, now).unwrap_err(),
+            "docs"
+        );
+        assert!(lock(&mut locks, "docs/a.txt", false, false, now).is_err());
+        assert!(lock(&mut locks, "", true, true, now).is_err());
+        assert!(lock(&mut locks, "", true, false, now).is_ok());
+        assert!(lock(&mut locks, "docsx", true, false, now).is_ok());
+        assert!(
+            locks
+                .lock("bob", "docs", true, true, String::new(), MAX_TIMEOUT, now)
+                .is_ok()
+        );
+
+        lock(&mut locks, "shared", false, false, now).unwrap();
+        assert!(lock(&mut locks, "shared", false, false, now).is_ok());
+        assert!(lock(&mut locks, "shared", true, false, now).is_err());
+    }
+
+    #[test]
+    fn needs_the_token_to_change_locked_paths() {
+        let now = Instant::now();
+        let mut locks = Locks::default();
+        let a = lock(&mut locks, "docs/a.txt", true, false, now).unwrap();
+        let none: &[String] = &[];
+        assert_eq!(
+            locks.check("alice", "docs/a.txt", false, none, now),
+            Err(LockError::Locked(String::from("docs/a.txt")))
+        );
+        assert_eq!(
+            locks.check(
+                "alice",
+                "docs/a.txt",
+                false,
+                std::slice::from_ref(&a.token),
+                now
+            ),
+            Ok(())
+        );
+        assert_eq!(locks.check("alice", "docs/b.txt", false, none, now), Ok(()));
+        assert_eq!(locks.check("bob", "docs/a.txt", false, none, now), Ok(()));
+        // Deleting the directory would delete the locked file
+        assert_eq!(locks.check("alice", "docs", false, none, now), Ok(()));
+        assert_eq!(
+            locks.check("alice", "docs", true, none, now),
+            Err(LockError::Locked(String::from("docs/a.txt")))
+        );
+        assert_eq!(
+            locks.check(
+                "alice",
+                "docs/b.txt",
+                false,
+                &[String::from("opaquelocktoken:gone")],
+                now
+            ),
+            Err(LockError::PreconditionFailed)
+        );
+
+        let first = lock(&mut locks, "shared", false, false, now).unwrap();
+        lock(&mut locks, "shared", false, false, now).unwrap();
+        assert_eq!(
+            locks.check("alice", "shared", false, &[first.token], now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn expires_refreshes_and_unlocks() {
+        let now = Instant::now();
+        let mut locks = Locks::default();
+        let a = locks
+            .lock(
+                "alice",
+                "a.txt",
+                true,
+                false,
+                String::new(),
+                Duration::from_secs(60),
+                now,
+            )
+            .unwrap();
+        let later = now + Duration::from_secs(50);
+        assert!(
+            locks
+                .refresh(
+                    "alice",
+                    "a.txt",
+                    std::slice::from_ref(&a.token),
+                    Duration::from_secs(60),
+                    later
+                )
+                .is_some()
+        );
+        let expired = later + Duration::from_secs(61);
+        assert!(lock(&mut locks, "a.txt", true, false, later).is_err());
+        assert!(lock(&mut locks, "a.txt", true, false, expired).is_ok());
+
+        let b = lock(&mut locks, "b.txt", true, false, now).unwrap();
+        assert!(!locks.unlock("alice", "a.txt", &b.token, now));
+        assert!(locks.unlock("alice", "b.txt", &b.token, now));
+        assert!(!locks.unlock("alice", "b.txt", &b.token, now));
+
+        lock(&mut locks, "dir/c.txt", true, false, now).unwrap();
+        locks.release("alice", "dir");
+        assert!(lock(&mut locks, "dir/c.txt", true, false, now).is_ok());
+    }
+}
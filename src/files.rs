@@ -0,0 +1,559 @@
+//! # Files
+//!
+//! This module contains the file manager for the data directory of every user.
+//!
+//! Paths from requests are always relative to the user's data directory. They are checked
+//! component by component and canonicalized before use, so nothing outside the data directory
+//! can be reached, not even through a symlink.
+use std::{
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use axum::{body::Bytes, extract::multipart::Field, http::StatusCode, response::IntoResponse};
+use futures_util::{Stream, StreamExt};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::{
+    quota::{self, QuotaError},
+    tokens,
+    users::{self, User},
+};
+
+/// Makes sure only one upload at a time checks the quota and takes up its space.
+static COMMIT_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// The most bytes of a text file shown in a preview.
+pub const PREVIEW_LIMIT: u64 = 256 * 1024;
+
+/// An error that can occur when managing files.
+#[derive(Debug)]
+pub enum FileError {
+    IoError(io::Error),
+    /// The path leads outside the user's data directory.
+    OutsideRoot,
+    NotFound,
+    /// The name can not be used as a file name.
+    InvalidName,
+    AlreadyExists,
+    /// The action needs a directory but the path is a file, or the other way round.
+    WrongType,
+    Quota(QuotaError),
+}
+impl From<io::Error> for FileError {
+    fn from(value: io::Error) -> Self {
+        if value.kind() == io::ErrorKind::NotFound {
+            return FileError::NotFound;
+        }
+        log::error!("IO Error: {}", value);
+        FileError::IoError(value)
+    }
+}
+impl From<QuotaError> for FileError {
+    fn from(value: QuotaError) -> Self {
+        FileError::Quota(value)
+    }
+}
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(error) => write!(f, "IO Error: {error}"),
+            Self::OutsideRoot => write!(f, "Path Outside Of Your Files"),
+            Self::NotFound => write!(f, "File Not Found"),
+            Self::InvalidName => write!(f, "Invalid File Name"),
+            Self::AlreadyExists => write!(f, "File Already Exists"),
+            Self::WrongType => write!(f, "Wrong File Type"),
+            Self::Quota(error) => write!(f, "{error}"),
+        }
+    }
+}
+impl std::error::Error for FileError {}
+impl FileError {
+    /// The status code to respond with for this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::OutsideRoot => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::InvalidName | Self::WrongType => StatusCode::BAD_REQUEST,
+            Self::AlreadyExists => StatusCode::CONFLICT,
+            Self::Quota(error) => error.status(),
+        }
+    }
+}
+impl IntoResponse for FileError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::IoError(_) => (self.status(), "IO Error").into_response(),
+            _ => (self.status(), self.to_string()).into_response(),
+        }
+    }
+}
+type Result<T> = std::result::Result<T, FileError>;
+
+/// Checks if a name can be used for a file or directory.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 255
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+}
+
+/// Gets the canonical data directory of a user, creating it if needed.
+async fn root(username: &str) -> Result<PathBuf> {
+    let root = users::data_dir(username);
+    fs::create_dir_all(&root).await?;
+    Ok(fs::canonicalize(root).await?)
+}
+
+/// Resolves a path relative to a user's data directory to an existing file or directory.
+pub async fn resolve(username: &str, path: &str) -> Result<PathBuf> {
+    let root = root(username).await?;
+    let mut candidate = root.clone();
+    for component in path
+        .split('/')
+        .filter(|value| !value.is_empty() && *value != ".")
+    {
+        if !valid_name(component) {
+            return Err(FileError::OutsideRoot);
+        }
+        candidate.push(component);
+    }
+    // Canonicalizing follows symlinks, so a link pointing out of the root is caught here
+    let canonical = fs::canonicalize(&candidate).await?;
+    if canonical.starts_with(&root) {
+        Ok(canonical)
+    } else {
+        Err(FileError::OutsideRoot)
+    }
+}
+
+/// Resolves a new entry called `name` in an existing directory.
+///
+/// The entry itself may or may not exist yet.
+pub async fn resolve_new(username: &str, parent: &str, name: &str) -> Result<PathBuf> {
+    let name = name.trim();
+    if !valid_name(name) {
+        return Err(FileError::InvalidName);
+    }
+    let parent = resolve(username, parent).await?;
+    if !fs::metadata(&parent).await?.is_dir() {
+        return Err(FileError::WrongType);
+    }
+    Ok(parent.join(name))
+}
+
+/// Turns a resolved path back into a path relative to the user's data directory.
+pub async fn relative(username: &str, path: &Path) -> String {
+    let Ok(root) = root(username).await else {
+        return String::new();
+    };
+    path.strip_prefix(root)
+        .map(|value| value.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// How a file can be previewed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preview {
+    Text,
+    Image,
+    None,
+}
+
+/// Works out how a file can be previewed from its name.
+pub fn preview_kind(path: &Path) -> Preview {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    match (mime.type_().as_str(), mime.subtype().as_str()) {
+        // SVGs can run scripts so they are never shown inline
+        ("image", "svg") => Preview::None,
+        ("image", _) => Preview::Image,
+        ("text", _) | ("application", "json" | "toml" | "xml" | "x-sh" | "javascript") => {
+            Preview::Text
+        }
+        _ => Preview::None,
+    }
+}
+
+/// The part of a file asked for with a `Range` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    Full,
+    /// From the first to the last byte, both included.
+    Partial(u64, u64),
+    /// The range starts after the end of the file.
+    Unsatisfiable,
+}
+impl ByteRange {
+    /// Parses a `Range` header for a file of `len` bytes.
+    ///
+    /// Only a single range is supported, anything else gets the whole file.
+    pub fn parse(header: Option<&str>, len: u64) -> Self {
+        let Some((start, end)) = header
+            .and_then(|value| value.trim().strip_prefix("bytes="))
+            .filter(|value| !value.contains(','))
+            .and_then(|value| value.split_once('-'))
+        else {
+            return Self::Full;
+        };
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            // The last bytes of the file
+            return match end.parse::<u64>() {
+                Ok(0) => Self::Unsatisfiable,
+                Ok(_) if len == 0 => Self::Unsatisfiable,
+                Ok(suffix) => Self::Partial(len.saturating_sub(suffix), len - 1),
+                Err(_) => Self::Full,
+            };
+        }
+        let Ok(start) = start.parse::<u64>() else {
+            return Self::Full;
+        };
+        if start >= len {
+            return Self::Unsatisfiable;
+        }
+        let end = if end.is_empty() {
+            len - 1
+        } else {
+            match end.parse::<u64>() {
+                Ok(value) if value >= start => value.min(len - 1),
+                _ => return Self::Full,
+            }
+        };
+        Self::Partial(start, end)
+    }
+
+    /// Checks if the range starts at the beginning of the file.
+    pub fn starts_at_beginning(&self) -> bool {
+        matches!(self, Self::Full | Self::Partial(0, _))
+    }
+}
+
+/// An entry of a directory listing.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    /// The path relative to the data directory.
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<OffsetDateTime>,
+    pub preview: Preview,
+}
+impl Entry {
+    /// Creates an entry from the metadata of a file, `path` is relative to the data directory.
+    fn new(path: String, metadata: &Metadata) -> Self {
+        Self {
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().map(OffsetDateTime::from).ok(),
+            preview: preview_kind(Path::new(&path)),
+            path,
+        }
+    }
+
+    /// Formats the modification time for display.
+    pub fn modified_time(&self) -> String {
+        self.modified
+            .and_then(|value| value.format(&Rfc3339).ok())
+            .unwrap_or_default()
+    }
+
+    /// Formats the size for display.
+    pub fn size_text(&self) -> String {
+        quota::format_bytes(self.size)
+    }
+
+    pub fn previewable(&self) -> bool {
+        self.preview != Preview::None
+    }
+}
+
+/// Lists a directory, directories first and then by name.
+pub async fn list(username: &str, path: &str) -> Result<Vec<Entry>> {
+    let directory = resolve(username, path).await?;
+    if !fs::metadata(&directory).await?.is_dir() {
+        return Err(FileError::WrongType);
+    }
+    let prefix = relative(username, &directory).await;
+    let mut entries = Vec::new();
+    let mut read_dir = fs::read_dir(&directory).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{prefix}/{name}")
+        };
+        entries.push(Entry::new(path, &metadata));
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// Gets the entry of a single file or directory.
+pub async fn entry(username: &str, path: &str) -> Result<Entry> {
+    let target = resolve(username, path).await?;
+    let metadata = fs::metadata(&target).await?;
+    Ok(Entry::new(relative(username, &target).await, &metadata))
+}
+
+/// Measures the size of a file or directory.
+async fn size_of(path: &Path) -> Result<u64> {
+    let path = path.to_path_buf();
+    Ok(
+        tokio::task::spawn_blocking(move || quota::measure_path(&path))
+            .await
+            .map_err(io::Error::other)??,
+    )
+}
+
+/// Creates a directory.
+pub async fn create_dir(username: &str, parent: &str, name: &str) -> Result<()> {
+    let target = resolve_new(username, parent, name).await?;
+    match fs::create_dir(target).await {
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => Err(FileError::AlreadyExists),
+        result => Ok(result?),
+    }
+}
+
+/// Renames a file or directory, it stays in the same directory.
+pub async fn rename(username: &str, path: &str, new_name: &str) -> Result<()> {
+    let source = resolve(username, path).await?;
+    let parent = source.parent().ok_or(FileError::OutsideRoot)?;
+    let parent = relative(username, parent).await;
+    move_to(username, path, &parent, new_name).await
+}
+
+/// Moves a file or directory into another directory under a new name.
+///
+/// The target must not exist yet.
+pub async fn move_to(username: &str, path: &str, parent: &str, name: &str) -> Result<()> {
+    let source = resolve(username, path).await?;
+    if source == root(username).await? {
+        return Err(FileError::OutsideRoot);
+    }
+    let target = resolve_new(username, parent, name).await?;
+    // Moving a directory into itself would make it unreachable
+    if target.starts_with(&source) {
+        return Err(FileError::WrongType);
+    }
+    if fs::symlink_metadata(&target).await.is_ok() {
+        return Err(FileError::AlreadyExists);
+    }
+    fs::rename(source, target).await?;
+    Ok(())
+}
+
+/// Copies a file or directory without following symlinks, they are skipped.
+fn copy_path(source: &Path, target: &Path) -> io::Result<()> {
+    let metadata = std::fs::symlink_metadata(source)?;
+    if metadata.is_dir() {
+        std::fs::create_dir(target)?;
+        for entry in std::fs::read_dir(source)? {
+            let entry = entry?;
+            copy_path(&entry.path(), &target.join(entry.file_name()))?;
+        }
+    } else if metadata.is_file() {
+        std::fs::copy(source, target)?;
+    }
+    Ok(())
+}
+
+/// Copies a file or directory of a user into another directory under a new name.
+///
+/// The target must not exist yet and the copy has to fit in the owner's quota.
+pub async fn copy(owner: &User, path: &str, parent: &str, name: &str) -> Result<()> {
+    let source = resolve(owner.name(), path).await?;
+    let target = resolve_new(owner.name(), parent, name).await?;
+    if target.starts_with(&source) {
+        return Err(FileError::WrongType);
+    }
+    if fs::symlink_metadata(&target).await.is_ok() {
+        return Err(FileError::AlreadyExists);
+    }
+    let size = size_of(&source).await?;
+    quota::check(owner, size).await?;
+    let result = {
+        let target = target.clone();
+        tokio::task::spawn_blocking(move || copy_path(&source, &target))
+            .await
+            .map_err(io::Error::other)?
+    };
+    if let Err(error) = result {
+        // Don't leave half a copy behind
+        let cleanup = match fs::symlink_metadata(&target).await {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&target).await,
+            Ok(_) => fs::remove_file(&target).await,
+            Err(_) => Ok(()),
+        };
+        if let Err(error) = cleanup {
+            log::error!("Failed to remove partial copy: {}", error);
+        }
+        return Err(error.into());
+    }
+    quota::record_change(owner.name(), size as i64).await;
+    Ok(())
+}
+
+/// Deletes a file or a directory with everything in it.
+pub async fn delete(username: &str, path: &str) -> Result<()> {
+    let target = resolve(username, path).await?;
+    if target == root(username).await? {
+        return Err(FileError::OutsideRoot);
+    }
+    let size = size_of(&target).await?;
+    if fs::metadata(&target).await?.is_dir() {
+        fs::remove_dir_all(&target).await?;
+    } else {
+        fs::remove_file(&target).await?;
+    }
+    quota::record_change(username, -(size as i64)).await;
+    Ok(())
+}
+
+/// Puts the file or directory at `path` in place of an existing one at `target`.
+///
+/// A file replacing a file is a single rename. Otherwise the old entry is moved aside first and
+/// only deleted once the new one is in place, it is put back if that fails.
+pub async fn replace(username: &str, path: &str, target: &str) -> Result<()> {
+    let source = resolve(username, path).await?;
+    let (parent, name) = target.rsplit_once('/').unwrap_or(("", target));
+    let target = resolve_new(username, parent, name).await?;
+    let old = fs::symlink_metadata(&target).await?;
+    let size = size_of(&target).await?;
+    if !old.is_dir() && fs::symlink_metadata(&source).await?.is_file() {
+        fs::rename(&source, &target).await?;
+    } else {
+        let aside = target.with_file_name(format!(".replaced-{}", tokens::generate()));
+        fs::rename(&target, &aside).await?;
+        if let Err(error) = fs::rename(&source, &target).await {
+            if let Err(error) = fs::rename(&aside, &target).await {
+                log::error!("Failed to restore replaced file: {}", error);
+            }
+            return Err(error.into());
+        }
+        let cleanup = if old.is_dir() {
+            fs::remove_dir_all(&aside).await
+        } else {
+            fs::remove_file(&aside).await
+        };
+        if let Err(error) = cleanup {
+            log::error!("Failed to remove replaced file: {}", error);
+        }
+    }
+    quota::record_change(username, -(size as i64)).await;
+    Ok(())
+}
+
+/// Streams an uploaded file into a directory of the user.
+///
+/// # Returns
+///
+/// The path of the uploaded file relative to the data directory.
+pub async fn upload(owner: &User, parent: &str, field: Field<'_>) -> Result<String> {
+    let name = field.file_name().unwrap_or_default().to_string();
+    // Browsers may send the path the file was picked from, only the name is used
+    let name = name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let (path, _) = write(owner, parent, &name, field).await?;
+    Ok(path)
+}
+
+/// Streams a file into a directory of the user, replacing the file if it exists.
+///
+/// The file is written next to the data directory first and only moved in place once it is
+/// complete, writing stops as soon as it goes over the quota. Uploads run at the same time, so
+/// the quota is checked again before the file is moved in place.
+///
+/// # Returns
+///
+/// The path of the file relative to the data directory and if it was newly created.
+pub async fn write<S, E>(
+    owner: &User,
+    parent: &str,
+    name: &str,
+    mut stream: S,
+) -> Result<(String, bool)>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let target = resolve_new(owner.name(), parent, name).await?;
+    let replaced = match fs::symlink_metadata(&target).await {
+        Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+        Ok(_) => return Err(FileError::AlreadyExists),
+        Err(_) => None,
+    };
+    // The space of a replaced file is freed once the new one is in place
+    let remaining = quota::remaining(owner).await? + replaced.unwrap_or_default();
+    let temporary =
+        users::user_dir(owner.name()).join(format!("upload-{}.part", tokens::generate()));
+    let mut file = fs::File::create(&temporary).await?;
+    let mut written = 0u64;
+    let result: Result<Option<u64>> = async {
+        while let Some(chunk) = stream
+            .next()
+            .await
+            .transpose()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?
+        {
+            written += chunk.len() as u64;
+            if written > remaining {
+                // Reports which of the quotas is full
+                quota::check(owner, written).await?;
+                return Err(QuotaError::UserQuotaExceeded {
+                    used: written,
+                    limit: remaining,
+                }
+                .into());
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        drop(file);
+        let _guard = COMMIT_LOCK.lock().await;
+        // Another upload may have replaced the file or used up the space in the meantime
+        let replaced = match fs::symlink_metadata(&target).await {
+            Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+            Ok(_) => return Err(FileError::AlreadyExists),
+            Err(_) => None,
+        };
+        let remaining = quota::remaining(owner).await? + replaced.unwrap_or_default();
+        if written > remaining {
+            quota::check(owner, written).await?;
+            return Err(QuotaError::UserQuotaExceeded {
+                used: written,
+                limit: remaining,
+            }
+            .into());
+        }
+        fs::rename(&temporary, &target).await?;
+        quota::record_change(
+            owner.name(),
+            written as i64 - replaced.unwrap_or_default() as i64,
+        )
+        .await;
+        Ok(replaced)
+    }
+    .await;
+    let replaced = match result {
+        Ok(value) => value,
+        Err(error) => {
+            if let Err(error) = fs::remove_file(&temporary).await {
+                log::error!("Failed to remove partial upload: {}", error);
+            }
+            return Err(error);
+        }
+    };
+    Ok((relative(owner.name(), &target).await, replaced.is_none()))
+}
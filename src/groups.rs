@@ -0,0 +1,279 @@
+//! # Groups
+//!
+//! This module contains named groups of users, the permissions their members get and the
+//! storage quota they share.
+//!
+//! Admins have every permission, everyone else only has the permissions of the groups they
+//! are a member of.
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io,
+};
+
+use axum::{http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::{persist, users};
+
+/// The path to the groups file.
+#[cfg(not(debug_assertions))]
+const GROUPS_PATH: &str = "/var/lib/system_manager_server/groups.json";
+#[cfg(debug_assertions)]
+const GROUPS_PATH: &str = "./groups.json";
+
+/// Something a group can allow its members to do.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Manage users, invite them and create reset links.
+    ManageUsers,
+    /// Manage groups and their members.
+    ManageGroups,
+    /// Change the server settings.
+    ManageSettings,
+    /// View and export the audit log.
+    ViewAudit,
+    /// View and revoke the sessions of other users.
+    ManageSessions,
+    /// Browse and change the files of other users.
+    BrowseFiles,
+}
+impl Permission {
+    pub const ALL: [Permission; 6] = [
+        Self::ManageUsers,
+        Self::ManageGroups,
+        Self::ManageSettings,
+        Self::ViewAudit,
+        Self::ManageSessions,
+        Self::BrowseFiles,
+    ];
+
+    /// The name used in forms.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ManageUsers => "manage_users",
+            Self::ManageGroups => "manage_groups",
+            Self::ManageSettings => "manage_settings",
+            Self::ViewAudit => "view_audit",
+            Self::ManageSessions => "manage_sessions",
+            Self::BrowseFiles => "browse_files",
+        }
+    }
+}
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ManageUsers => write!(f, "Manage users"),
+            Self::ManageGroups => write!(f, "Manage groups"),
+            Self::ManageSettings => write!(f, "Manage settings"),
+            Self::ViewAudit => write!(f, "View audit log"),
+            Self::ManageSessions => write!(f, "Manage sessions"),
+            Self::BrowseFiles => write!(f, "Browse the files of other users"),
+        }
+    }
+}
+
+/// An error that can occur when managing groups.
+#[derive(Debug)]
+pub enum GroupError {
+    IoError(io::Error),
+    /// The name can not be used as a group name.
+    InvalidName,
+    GroupExists,
+    GroupNotFound,
+}
+impl From<io::Error> for GroupError {
+    fn from(value: io::Error) -> Self {
+        log::error!("IO Error: {}", value);
+        GroupError::IoError(value)
+    }
+}
+impl std::fmt::Display for GroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(error) => write!(f, "IO Error: {error}"),
+            Self::InvalidName => write!(
+                f,
+                "Invalid Group Name (use up to 32 letters, digits, '-', '_' or '.')"
+            ),
+            Self::GroupExists => write!(f, "Group Already Exists"),
+            Self::GroupNotFound => write!(f, "Group Not Found"),
+        }
+    }
+}
+impl std::error::Error for GroupError {}
+impl GroupError {
+    /// The status code to respond with for this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidName => StatusCode::BAD_REQUEST,
+            Self::GroupExists => StatusCode::CONFLICT,
+            Self::GroupNotFound => StatusCode::NOT_FOUND,
+        }
+    }
+}
+impl IntoResponse for GroupError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::IoError(_) => (self.status(), "IO Error").into_response(),
+            _ => (self.status(), self.to_string()).into_response(),
+        }
+    }
+}
+type Result<T> = std::result::Result<T, GroupError>;
+
+/// A named group of users.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub members: BTreeSet<String>,
+    /// The storage the members share in bytes, `0` for no limit.
+    #[serde(default)]
+    pub storage: usize,
+    #[serde(default)]
+    pub permissions: BTreeSet<Permission>,
+}
+
+impl Group {
+    /// Checks if the group grants any permission that is not in `held`.
+    pub fn grants_beyond(&self, held: &HashSet<Permission>) -> bool {
+        self.permissions
+            .iter()
+            .any(|permission| !held.contains(permission))
+    }
+}
+
+/// Parses the groups by name, no data means there are none yet.
+fn parse(data: Option<&[u8]>) -> io::Result<HashMap<String, Group>> {
+    match data {
+        Some(data) => Ok(serde_json::from_slice(data)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Loads the groups by name.
+async fn load() -> io::Result<HashMap<String, Group>> {
+    match persist::read(GROUPS_PATH).await {
+        Ok(data) => parse(Some(&data)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            match persist::read_backup(GROUPS_PATH).await {
+                Ok(data) => parse(Some(&data)),
+                Err(_) => parse(None),
+            }
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Changes the groups, the file stays locked until the change is saved.
+async fn change<T, F>(change: F) -> Result<T>
+where
+    F: FnOnce(&mut HashMap<String, Group>) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    persist::update(GROUPS_PATH, move |data| {
+        let mut groups = parse(data)?;
+        let value = change(&mut groups)?;
+        let data = serde_json::to_vec_pretty(&groups).map_err(io::Error::from)?;
+        Ok((data, value))
+    })
+    .await
+}
+
+/// Lists every group sorted by name.
+pub async fn list() -> Result<Vec<Group>> {
+    let mut groups: Vec<Group> = load().await?.into_values().collect();
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(groups)
+}
+
+/// Gets a group by name.
+pub async fn get(name: &str) -> Result<Group> {
+    load().await?.remove(name).ok_or(GroupError::GroupNotFound)
+}
+
+/// Creates a new empty group.
+pub async fn create(name: &str) -> Result<Group> {
+    let name = name.trim();
+    // Group names follow the same rules as usernames
+    if !users::valid_username(name) {
+        return Err(GroupError::InvalidName);
+    }
+    let group = Group {
+        name: name.to_string(),
+        members: BTreeSet::new(),
+        storage: 0,
+        permissions: BTreeSet::new(),
+    };
+    change(move |groups| {
+        if groups.contains_key(&group.name) {
+            return Err(GroupError::GroupExists);
+        }
+        groups.insert(group.name.clone(), group.clone());
+        Ok(group)
+    })
+    .await
+}
+
+/// Replaces an existing group.
+pub async fn update(group: Group) -> Result<()> {
+    change(move |groups| match groups.get_mut(&group.name) {
+        Some(value) => {
+            *value = group;
+            Ok(())
+        }
+        None => Err(GroupError::GroupNotFound),
+    })
+    .await
+}
+
+/// Deletes a group, its members keep their accounts.
+pub async fn delete(name: &str) -> Result<Group> {
+    let name = name.to_string();
+    change(move |groups| groups.remove(&name).ok_or(GroupError::GroupNotFound)).await
+}
+
+/// Removes a user from every group, used when the user is deleted.
+pub async fn remove_member(username: &str) -> Result<()> {
+    let username = username.to_string();
+    change(move |groups| {
+        for group in groups.values_mut() {
+            group.members.remove(&username);
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Lists the groups a user is a member of.
+pub async fn of_user(username: &str) -> Result<Vec<Group>> {
+    Ok(list()
+        .await?
+        .into_iter()
+        .filter(|group| group.members.contains(username))
+        .collect())
+}
+
+/// Gets every permission a user has, admins have all of them.
+pub async fn permissions(user: &users::User) -> HashSet<Permission> {
+    if user.admin() {
+        return Permission::ALL.into_iter().collect();
+    }
+    match of_user(user.name()).await {
+        Ok(groups) => groups
+            .into_iter()
+            .flat_map(|group| group.permissions)
+            .collect(),
+        Err(error) => {
+            // Failing closed, nobody gets extra permissions if the groups can't be read
+            log::error!("Failed to read groups of {}: {}", user.name(), error);
+            HashSet::new()
+        }
+    }
+}
+
+/// Checks if a user has a permission, directly as an admin or through a group.
+pub async fn has_permission(user: &users::User, permission: Permission) -> bool {
+    permissions(user).await.contains(&permission)
+}
@@ -0,0 +1,422 @@
+//! # History
+//!
+//! This module stores the sampled status as time series, so it can be looked at later.
+//!
+//! Each series is kept in one ring file per resolution. A slot holds the number of the time bucket
+//! it belongs to and the average value in that bucket, so slots left from an earlier round are
+//! recognized and the files never grow. Minutes are averaged from the samples, hours from the
+//! stored minutes and days from the stored hours, which keeps working across restarts.
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+
+use crate::{sampler::Reports, status::StatusReport};
+
+/// The directory the series are stored in.
+#[cfg(not(debug_assertions))]
+const HISTORY_PATH: &str = "/var/lib/system_manager_server/history";
+#[cfg(debug_assertions)]
+const HISTORY_PATH: &str = "./history";
+
+/// The bytes of a slot, the bucket as a `u32` and the value as an `f32`.
+const SLOT_SIZE: u64 = 8;
+
+/// Makes sure the files are not read while they are written.
+static HISTORY_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// How finely a series is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+impl Resolution {
+    /// The length of a bucket in seconds.
+    pub fn seconds(self) -> i64 {
+        match self {
+            Self::Minute => 60,
+            Self::Hour => 60 * 60,
+            Self::Day => 24 * 60 * 60,
+        }
+    }
+
+    /// How many buckets are kept, a day of minutes, 90 days of hours and two years of days.
+    fn capacity(self) -> u64 {
+        match self {
+            Self::Minute => 24 * 60,
+            Self::Hour => 90 * 24,
+            Self::Day => 2 * 365,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Minute => "minute",
+            Self::Hour => "hour",
+            Self::Day => "day",
+        }
+    }
+
+    /// The finest resolution that still covers everything back to a point in time.
+    pub fn covering(from: OffsetDateTime) -> Self {
+        let age = (OffsetDateTime::now_utc() - from).whole_seconds();
+        [Self::Minute, Self::Hour]
+            .into_iter()
+            .find(|resolution| age <= resolution.seconds() * resolution.capacity() as i64)
+            .unwrap_or(Self::Day)
+    }
+
+    /// The bucket a point in time falls into.
+    fn bucket(self, time: OffsetDateTime) -> u32 {
+        (time.unix_timestamp().max(0) / self.seconds()) as u32
+    }
+}
+
+/// A stored value.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Point {
+    /// The start of the bucket as a Unix timestamp.
+    pub time: i64,
+    pub value: f32,
+}
+
+/// A range of a series.
+#[derive(Debug, Clone, Serialize)]
+pub struct History {
+    pub series: String,
+    pub resolution: Resolution,
+    pub points: Vec<Point>,
+}
+
+/// Gets the file a series is stored in below the history directory.
+fn series_path(root: &Path, resolution: Resolution, series: &str) -> PathBuf {
+    root.join(resolution.as_str()).join(format!(
+        "{}.bin",
+        utf8_percent_encode(series, NON_ALPHANUMERIC)
+    ))
+}
+
+/// Reads every slot of a ring file, a missing file has none.
+fn read_slots(path: &Path) -> io::Result<Vec<(u32, f32)>> {
+    let mut data = Vec::new();
+    match fs::File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut data)?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    Ok(data
+        .chunks_exact(SLOT_SIZE as usize)
+        .filter_map(|slot| {
+            let bucket = u32::from_le_bytes(slot[..4].try_into().ok()?);
+            let value = f32::from_le_bytes(slot[4..].try_into().ok()?);
+            // Slots that were never written are all zeros
+            (bucket != 0).then_some((bucket, value))
+        })
+        .collect())
+}
+
+/// Writes the value of a bucket into its slot of a ring file.
+fn write_slot(
+    root: &Path,
+    resolution: Resolution,
+    series: &str,
+    bucket: u32,
+    value: f32,
+) -> io::Result<()> {
+    let path = series_path(root, resolution, series);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    let size = resolution.capacity() * SLOT_SIZE;
+    if file.metadata()?.len() != size {
+        file.set_len(size)?;
+    }
+    file.seek(SeekFrom::Start(
+        bucket as u64 % resolution.capacity() * SLOT_SIZE,
+    ))?;
+    let mut slot = [0; SLOT_SIZE as usize];
+    slot[..4].copy_from_slice(&bucket.to_le_bytes());
+    slot[4..].copy_from_slice(&value.to_le_bytes());
+    file.write_all(&slot)
+}
+
+/// Averages the stored values of a series that fall into a bucket of a coarser resolution.
+fn average(
+    root: &Path,
+    source: Resolution,
+    target: Resolution,
+    series: &str,
+    bucket: u32,
+) -> io::Result<Option<f32>> {
+    let per_bucket = (target.seconds() / source.seconds()) as u32;
+    let first = bucket * per_bucket;
+    let values: Vec<f32> = read_slots(&series_path(root, source, series))?
+        .into_iter()
+        .filter(|(slot, _)| (first..first + per_bucket).contains(slot))
+        .map(|(_, value)| value)
+        .collect();
+    if values.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(values.iter().sum::<f32>() / values.len() as f32))
+}
+
+/// Stores a finished minute and rolls up the hours and days that finished before `next`.
+///
+/// `rolled` is the first hour that was not rolled up yet. Several hours and days can finish at
+/// once after a pause or a restart, all of them are rolled up as far back as the stored minutes
+/// still reach.
+///
+/// # Returns
+///
+/// The first hour that is not rolled up yet.
+fn flush(
+    root: &Path,
+    minute: u32,
+    next: u32,
+    rolled: u32,
+    values: HashMap<String, f32>,
+) -> io::Result<u32> {
+    for (series, value) in &values {
+        write_slot(root, Resolution::Minute, series, minute, *value)?;
+    }
+    let per_hour = (Resolution::Hour.seconds() / Resolution::Minute.seconds()) as u32;
+    // Hours whose first minutes were overwritten already would only get a partial average
+    let oldest = (minute + 1).saturating_sub(Resolution::Minute.capacity() as u32);
+    let first = rolled.max(oldest.div_ceil(per_hour));
+    let finished = next / per_hour;
+    for hour in first..finished {
+        for series in values.keys() {
+            if let Some(value) = average(root, Resolution::Minute, Resolution::Hour, series, hour)?
+            {
+                write_slot(root, Resolution::Hour, series, hour, value)?;
+            }
+        }
+    }
+    let per_day = (Resolution::Day.seconds() / Resolution::Hour.seconds()) as u32;
+    for day in first / per_day..finished / per_day {
+        for series in values.keys() {
+            if let Some(value) = average(root, Resolution::Hour, Resolution::Day, series, day)? {
+                write_slot(root, Resolution::Day, series, day, value)?;
+            }
+        }
+    }
+    Ok(first.max(finished))
+}
+
+/// Picks the values of a report that are stored.
+fn series_values(report: &StatusReport) -> Vec<(String, f32)> {
+    let system = &report.system;
+    let mut values = vec![
+        (String::from("cpu"), system.cpu_usage),
+        (String::from("memory"), system.memory.percent() as f32),
+        (String::from("swap"), system.swap.percent() as f32),
+        (
+            String::from("network_received"),
+            report.network.received as f32,
+        ),
+        (
+            String::from("network_transmitted"),
+            report.network.transmitted as f32,
+        ),
+    ];
+    if let Some(charge) = report.power.charge() {
+        values.push((String::from("battery"), charge as f32));
+    }
+    values.extend(report.mounts.iter().map(|mount| {
+        (
+            format!("mount:{}", mount.mount_point),
+            mount.used_percent() as f32,
+        )
+    }));
+    values
+}
+
+/// Records every report of the sampler until the server stops.
+pub fn spawn(mut reports: Reports) {
+    tokio::spawn(async move {
+        let mut minute = None;
+        let mut rolled = 0;
+        // The sum and count of every series in the current minute
+        let mut sums: HashMap<String, (f64, u32)> = HashMap::new();
+        loop {
+            let report = reports.borrow_and_update().clone();
+            let now = Resolution::Minute.bucket(report.system.taken);
+            if let Some(current) = minute
+                && current != now
+            {
+                let values = sums
+                    .drain()
+                    .map(|(series, (sum, count))| (series, (sum / count as f64) as f32))
+                    .collect();
+                let _guard = HISTORY_LOCK.lock().await;
+                let root = Path::new(HISTORY_PATH);
+                match tokio::task::spawn_blocking(move || flush(root, current, now, rolled, values))
+                    .await
+                {
+                    Ok(Ok(value)) => rolled = value,
+                    Ok(Err(error)) => log::error!("Failed to store the history: {}", error),
+                    Err(error) => log::error!("Failed to store the history: {}", error),
+                }
+            }
+            minute = Some(now);
+            for (series, value) in series_values(&report) {
+                let entry = sums.entry(series).or_default();
+                entry.0 += value as f64;
+                entry.1 += 1;
+            }
+            if reports.changed().await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Lists the stored series.
+pub async fn list() -> io::Result<Vec<String>> {
+    let _guard = HISTORY_LOCK.lock().await;
+    tokio::task::spawn_blocking(|| {
+        let mut series = BTreeSet::new();
+        for resolution in [Resolution::Minute, Resolution::Hour, Resolution::Day] {
+            let entries = match fs::read_dir(Path::new(HISTORY_PATH).join(resolution.as_str())) {
+                Ok(value) => value,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            };
+            for entry in entries {
+                let name = entry?.file_name();
+                if let Some(name) = name.to_str().and_then(|name| name.strip_suffix(".bin")) {
+                    series.insert(percent_decode_str(name).decode_utf8_lossy().to_string());
+                }
+            }
+        }
+        Ok(series.into_iter().collect())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Reads the values of a series between two points in time.
+///
+/// The finest resolution that still reaches back to `from` is used.
+pub async fn query(series: &str, from: OffsetDateTime, to: OffsetDateTime) -> io::Result<History> {
+    let resolution = Resolution::covering(from);
+    let (first, last) = (resolution.bucket(from), resolution.bucket(to));
+    let path = series_path(Path::new(HISTORY_PATH), resolution, series);
+    let _guard = HISTORY_LOCK.lock().await;
+    let mut points: Vec<Point> = tokio::task::spawn_blocking(move || read_slots(&path))
+        .await
+        .map_err(io::Error::other)??
+        .into_iter()
+        .filter(|(bucket, _)| (first..=last).contains(bucket))
+        .map(|(bucket, value)| Point {
+            time: bucket as i64 * resolution.seconds(),
+            value,
+        })
+        .collect();
+    points.sort_by_key(|point| point.time);
+    Ok(History {
+        series: series.to_string(),
+        resolution,
+        points,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn slots_wrap_around() {
+        let root = tempfile::tempdir().unwrap();
+        let path = series_path(root.path(), Resolution::Minute, "cpu");
+        assert!(read_slots(&path).unwrap().is_empty());
+        let capacity = Resolution::Minute.capacity() as u32;
+        write_slot(root.path(), Resolution::Minute, "cpu", 5, 1.0).unwrap();
+        write_slot(root.path(), Resolution::Minute, "cpu", 6, 2.0).unwrap();
+        // A full round later the same slot is reused
+        write_slot(root.path(), Resolution::Minute, "cpu", 5 + capacity, 3.0).unwrap();
+        let mut slots = read_slots(&path).unwrap();
+        slots.sort_by_key(|(bucket, _)| *bucket);
+        assert_eq!(slots, [(6, 2.0), (5 + capacity, 3.0)]);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            Resolution::Minute.capacity() * SLOT_SIZE
+        );
+    }
+
+    #[test]
+    fn covers_with_the_finest_resolution() {
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(
+            Resolution::covering(now - Duration::hours(1)),
+            Resolution::Minute
+        );
+        assert_eq!(
+            Resolution::covering(now - Duration::hours(23)),
+            Resolution::Minute
+        );
+        assert_eq!(
+            Resolution::covering(now - Duration::days(2)),
+            Resolution::Hour
+        );
+        assert_eq!(
+            Resolution::covering(now - Duration::days(89)),
+            Resolution::Hour
+        );
+        assert_eq!(
+            Resolution::covering(now - Duration::days(100)),
+            Resolution::Day
+        );
+    }
+
+    #[test]
+    fn rolls_up_every_finished_hour_and_day() {
+        let root = tempfile::tempdir().unwrap();
+        let values = |value| HashMap::from([(String::from("cpu"), value)]);
+        // The first hour of a day, stored before a restart
+        let hour = 1000 * 24;
+        write_slot(root.path(), Resolution::Minute, "cpu", hour * 60, 10.0).unwrap();
+        write_slot(root.path(), Resolution::Minute, "cpu", hour * 60 + 30, 20.0).unwrap();
+
+        // The first minute flushed after the restart is two hours later
+        let minute = (hour + 2) * 60 + 5;
+        let rolled = flush(root.path(), minute, minute + 1, 0, values(30.0)).unwrap();
+        assert_eq!(rolled, hour + 2);
+        let hours = read_slots(&series_path(root.path(), Resolution::Hour, "cpu")).unwrap();
+        assert_eq!(hours, [(hour, 15.0)]);
+
+        // Nothing more is rolled up until the next hour is over
+        let rolled = flush(root.path(), minute + 1, minute + 2, rolled, values(30.0)).unwrap();
+        assert_eq!(rolled, hour + 2);
+
+        // The day is rolled up once it is over
+        let minute = (hour + 24) * 60;
+        let rolled = flush(root.path(), minute, minute + 1, rolled, values(40.0)).unwrap();
+        assert_eq!(rolled, hour + 24);
+        let mut hours = read_slots(&series_path(root.path(), Resolution::Hour, "cpu")).unwrap();
+        hours.sort_by_key(|(bucket, _)| *bucket);
+        assert_eq!(hours, [(hour, 15.0), (hour + 2, 30.0)]);
+        let days = read_slots(&series_path(root.path(), Resolution::Day, "cpu")).unwrap();
+        assert_eq!(days, [(1000, 22.5)]);
+    }
+}
@@ -0,0 +1,50 @@
+//! # Live
+//!
+//! This module pushes status reports to dashboards over WebSockets.
+//!
+//! Every dashboard watches the reports of the [`sampler`](crate::sampler), so any number of them
+//! can be connected without probing the system themselves.
+use axum::extract::ws::{Message, WebSocket};
+
+use crate::{sampler::Reports, status::StatusReport};
+
+/// Sends a report to a dashboard as JSON.
+///
+/// # Returns
+///
+/// If the dashboard is still connected.
+async fn send(socket: &mut WebSocket, report: &StatusReport) -> bool {
+    match serde_json::to_string(report) {
+        Ok(json) => socket.send(Message::Text(json.into())).await.is_ok(),
+        Err(error) => {
+            log::error!("Failed to serialize the status: {}", error);
+            false
+        }
+    }
+}
+
+/// Sends the latest and every new report to a dashboard until it disconnects.
+pub async fn serve(mut socket: WebSocket, mut reports: Reports) {
+    let current = reports.borrow_and_update().clone();
+    if !send(&mut socket, &current).await {
+        return;
+    }
+    loop {
+        tokio::select! {
+            changed = reports.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                let current = reports.borrow_and_update().clone();
+                if !send(&mut socket, &current).await {
+                    return;
+                }
+            }
+            message = socket.recv() => match message {
+                // Pings are answered by axum and dashboards send nothing else
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! # Logins
+//!
+//! This module contains the login history that is kept for every user.
+//!
+//! Each user has a `logins.jsonl` file next to their `user.json` with one record per attempt.
+//!
+//! Passwords are locked out for a while after too many failed attempts from the same address,
+//! the login form and HTTP basic auth share the attempts of a user.
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::{
+    fs,
+    sync::{Mutex, OwnedMutexGuard},
+};
+
+use crate::{persist, users};
+
+/// The name of the login history file in a user's directory.
+const LOGINS_FILE: &str = "logins.jsonl";
+/// The size at which the history is trimmed in bytes.
+const MAX_HISTORY_SIZE: u64 = 1024 * 1024;
+/// The number of records kept when the history is trimmed.
+const TRIMMED_HISTORY_LEN: usize = 1000;
+
+/// The failed attempts after which a key is locked out.
+const LOCKOUT_ATTEMPTS: usize = 5;
+/// How long failed attempts count towards a lockout.
+const LOCKOUT_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// The failed login attempts since the server started, including those for unknown users.
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/// The times of recent failed attempts by lockout key.
+static ATTEMPTS: LazyLock<Mutex<HashMap<String, Vec<Instant>>>> = LazyLock::new(Default::default);
+
+/// Makes the attempts for the same lockout key wait for each other.
+static TURNS: LazyLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> = LazyLock::new(Default::default);
+
+/// How a user authenticated.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    Password,
+    /// A password or API token sent with a request by a program, like a WebDAV client.
+    Basic,
+    /// The password of a share link, recorded for the owner of the share.
+    SharePassword,
+}
+impl std::fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Password => write!(f, "password"),
+            Self::Basic => write!(f, "HTTP basic auth"),
+            Self::SharePassword => write!(f, "share password"),
+        }
+    }
+}
+
+/// A single login attempt.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoginRecord {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub ip: String,
+    pub user_agent: String,
+    pub method: AuthMethod,
+    pub success: bool,
+    /// If this was the first successful login from this IP.
+    #[serde(default)]
+    pub new_ip: bool,
+}
+impl LoginRecord {
+    /// Creates a new record timestamped now.
+    pub fn new(ip: SocketAddr, user_agent: &str, method: AuthMethod, success: bool) -> Self {
+        Self {
+            timestamp: OffsetDateTime::now_utc(),
+            ip: ip.ip().to_string(),
+            user_agent: user_agent.to_string(),
+            method,
+            success,
+            new_ip: false,
+        }
+    }
+
+    /// Formats the timestamp for display.
+    pub fn time(&self) -> String {
+        self.timestamp
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| self.timestamp.to_string())
+    }
+}
+
+fn history_path(username: &str) -> PathBuf {
+    users::user_dir(username).join(LOGINS_FILE)
+}
+
+/// Reads the login history of a user, oldest first.
+pub async fn history(username: &str) -> io::Result<Vec<LoginRecord>> {
+    let content = match fs::read_to_string(history_path(username)).await {
+        Ok(value) => value,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Gets the last successful login of a user.
+pub async fn last_login(username: &str) -> Option<LoginRecord> {
+    history(username)
+        .await
+        .ok()?
+        .into_iter()
+        .rev()
+        .find(|record| record.success)
+}
+
+/// Drops the oldest records once the history grows too large.
+///
+/// The history is read and rewritten under its lock so records appended meanwhile are kept.
+async fn trim(username: &str) -> io::Result<()> {
+    let path = history_path(username);
+    if fs::metadata(&path).await?.len() < MAX_HISTORY_SIZE {
+        return Ok(());
+    }
+    persist::update(path, |data| {
+        let history: Vec<LoginRecord> = String::from_utf8_lossy(data.unwrap_or_default())
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        let mut content = String::new();
+        for record in &history[history.len().saturating_sub(TRIMMED_HISTORY_LEN)..] {
+            content.push_str(&serde_json::to_string(record)?);
+            content.push('\n');
+        }
+        Ok((content.into_bytes(), ()))
+    })
+    .await
+}
+
+/// Counts a failed login attempt that has no user to be recorded for.
+pub fn count_failure() {
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Gets the number of failed login attempts since the server started.
+pub fn failures() -> u64 {
+    FAILURES.load(Ordering::Relaxed)
+}
+
+/// The lockout key of the password logins of a user from an address.
+pub fn login_key(username: &str, address: SocketAddr) -> String {
+    format!("login:{}:{}", username, address.ip())
+}
+
+/// Waits until no other attempt for a lockout key is being checked.
+///
+/// Programs send their password with every request and often several at once, taking turns
+/// keeps those from counting against the lockout all at the same time.
+pub async fn take_turn(key: &str) -> OwnedMutexGuard<()> {
+    let turn = {
+        let mut turns = TURNS.lock().await;
+        turns.retain(|_, turn| Arc::strong_count(turn) > 1);
+        turns.entry(key.to_string()).or_default().clone()
+    };
+    turn.lock_owned().await
+}
+
+/// Counts an attempt for a lockout key, like `share:<id>:<ip>`, unless the key is locked out.
+///
+/// Attempts are counted before the password is checked so requests running at the same time can
+/// not get more tries while the hash is being checked, a successful attempt clears them again
+/// with [`clear_attempts`].
+///
+/// # Returns
+///
+/// How long until the key can be tried again, `None` if it can be tried now.
+pub async fn begin_attempt(key: &str) -> Option<Duration> {
+    let mut attempts = ATTEMPTS.lock().await;
+    attempts.retain(|_, times| {
+        times.retain(|time| time.elapsed() < LOCKOUT_WINDOW);
+        !times.is_empty()
+    });
+    let times = attempts.entry(key.to_string()).or_default();
+    if times.len() >= LOCKOUT_ATTEMPTS {
+        // Locked out until the oldest attempt that still counts is out of the window
+        let oldest = times[times.len() - LOCKOUT_ATTEMPTS];
+        return Some(LOCKOUT_WINDOW.saturating_sub(oldest.elapsed()));
+    }
+    times.push(Instant::now());
+    None
+}
+
+/// Forgets the failed attempts for a lockout key after a successful one.
+pub async fn clear_attempts(key: &str) {
+    ATTEMPTS.lock().await.remove(key);
+}
+
+/// Records a login attempt for a user.
+pub async fn record(username: &str, mut record: LoginRecord) -> io::Result<()> {
+    if record.success {
+        let history = history(username).await?;
+        let mut successful = history.iter().filter(|value| value.success).peekable();
+        // The very first login is not worth warning about
+        record.new_ip =
+            successful.peek().is_some() && !successful.any(|value| value.ip == record.ip);
+    } else {
+        count_failure();
+    }
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    persist::append(history_path(username), line).await?;
+    trim(username).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn locks_out_after_too_many_attempts() {
+        let key = "share:locks_out";
+        for _ in 0..LOCKOUT_ATTEMPTS {
+            assert!(begin_attempt(key).await.is_none());
+        }
+        let wait = begin_attempt(key).await.unwrap();
+        assert!(wait > LOCKOUT_WINDOW - Duration::from_secs(60) && wait <= LOCKOUT_WINDOW);
+        // Keys are locked out on their own
+        assert!(begin_attempt("share:other").await.is_none());
+        clear_attempts(key).await;
+        assert!(begin_attempt(key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn counts_attempts_made_at_the_same_time() {
+        let attempts: Vec<_> = (0..LOCKOUT_ATTEMPTS * 4)
+            .map(|_| tokio::spawn(begin_attempt("share:parallel")))
+            .collect();
+        let mut allowed = 0;
+        for attempt in attempts {
+            if attempt.await.unwrap().is_none() {
+                allowed += 1;
+            }
+        }
+        assert_eq!(allowed, LOCKOUT_ATTEMPTS);
+    }
+}
@@ -3,10 +3,30 @@
 //! This is a web server for managing a Linux system.
 
 // Modules
+mod alerts;
+mod api_tokens;
+mod audit;
+mod dav;
+mod files;
+mod groups;
+mod history;
+mod live;
+mod logins;
+mod metrics;
+mod password;
+mod persist;
+mod provision;
+mod quota;
 mod router;
+mod sampler;
+mod sessions;
 mod settings;
+mod shares;
+mod ssh_keys;
 mod status;
+mod tokens;
 mod update_manager;
+mod ups;
 mod users;
 
 // Static variables
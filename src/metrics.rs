@@ -0,0 +1,648 @@
+//! # Metrics
+//!
+//! This module exports the status of the system in the Prometheus text format.
+//!
+//! The metrics are served on `/metrics` of the main server to API tokens, and optionally without
+//! authentication on a separate listener that is only reachable by the monitoring network.
+use std::{
+    fmt::{Display, Write},
+    net::SocketAddr,
+};
+
+use axum::{
+    Router,
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use tokio::net::TcpListener;
+
+use crate::{
+    logins,
+    sampler::Reports,
+    sessions,
+    status::{BatteryState, BatteryStatus, MountUsage, StatusReport, Temperature},
+};
+
+/// The content type of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Reads a value of a mount.
+type MountValue = fn(&MountUsage) -> u64;
+
+/// Reads a value of a battery, `None` if it does not report it.
+type BatteryValue = fn(&BatteryStatus) -> Option<f32>;
+
+/// Reads a limit of a temperature sensor, `None` if it does not report it.
+type TemperatureValue = fn(&Temperature) -> Option<f32>;
+
+/// Builds the text of an exposition.
+struct Exposition(String);
+impl Exposition {
+    /// Starts a metric with its help text and type.
+    fn metric(&mut self, name: &str, kind: &str, help: &str) {
+        // Writing to a string never fails
+        let _ = writeln!(self.0, "# HELP system_manager_{name} {help}");
+        let _ = writeln!(self.0, "# TYPE system_manager_{name} {kind}");
+    }
+
+    /// Adds a sample of the last metric.
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let _ = write!(self.0, "system_manager_{name}");
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+
+    /// Adds a metric with a single sample.
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.metric(name, kind, help);
+        self.sample(name, &[], value);
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders a report and the login state as an exposition.
+pub async fn render(report: &StatusReport) -> String {
+    expose(report, sessions::list(None).await.len(), logins::failures())
+}
+
+/// Builds the exposition of a report, the number of sessions and the failed logins.
+fn expose(report: &StatusReport, sessions: usize, failures: u64) -> String {
+    let mut out = Exposition(String::new());
+    let system = &report.system;
+    out.single(
+        "status_timestamp_seconds",
+        "gauge",
+        "When the status was sampled as a Unix timestamp.",
+        system.taken.unix_timestamp(),
+    );
+    out.single(
+        "uptime_seconds",
+        "gauge",
+        "How long the system has been running.",
+        system.uptime,
+    );
+    out.single(
+        "boot_time_seconds",
+        "gauge",
+        "When the system booted as a Unix timestamp.",
+        system.boot_time.unix_timestamp(),
+    );
+
+    out.metric("load_average", "gauge", "The load average of the system.");
+    for (period, value) in [
+        ("1m", system.load.one),
+        ("5m", system.load.five),
+        ("15m", system.load.fifteen),
+    ] {
+        out.sample("load_average", &[("period", period)], value);
+    }
+    out.single(
+        "cpu_usage_percent",
+        "gauge",
+        "The usage of all cores together.",
+        system.cpu_usage,
+    );
+    out.metric("core_usage_percent", "gauge", "The usage of a single core.");
+    for core in &system.cores {
+        out.sample("core_usage_percent", &[("core", &core.name)], core.usage);
+    }
+    out.metric(
+        "core_frequency_hertz",
+        "gauge",
+        "The frequency of a single core.",
+    );
+    for core in &system.cores {
+        out.sample(
+            "core_frequency_hertz",
+            &[("core", &core.name)],
+            core.frequency * 1_000_000,
+        );
+    }
+
+    for (name, help, value) in [
+        (
+            "memory_total_bytes",
+            "The size of the memory.",
+            system.memory.total,
+        ),
+        (
+            "memory_used_bytes",
+            "The memory in use.",
+            system.memory.used,
+        ),
+        (
+            "swap_total_bytes",
+            "The size of the swap.",
+            system.swap.total,
+        ),
+        ("swap_used_bytes", "The swap in use.", system.swap.used),
+        (
+            "network_receive_bytes_per_second",
+            "The bytes received by all interfaces but loopback.",
+            report.network.received,
+        ),
+        (
+            "network_transmit_bytes_per_second",
+            "The bytes sent by all interfaces but loopback.",
+            report.network.transmitted,
+        ),
+    ] {
+        out.single(name, "gauge", help, value);
+    }
+
+    let metrics: [(&str, &str, MountValue); 5] = [
+        (
+            "filesystem_size_bytes",
+            "The size of a filesystem.",
+            |mount| mount.total,
+        ),
+        (
+            "filesystem_avail_bytes",
+            "The bytes unprivileged users can still write.",
+            |mount| mount.available,
+        ),
+        ("filesystem_files", "The inodes of a filesystem.", |mount| {
+            mount.inodes_total
+        }),
+        (
+            "filesystem_files_free",
+            "The free inodes of a filesystem.",
+            |mount| mount.inodes_free,
+        ),
+        (
+            "filesystem_readonly",
+            "If a filesystem is mounted read-only.",
+            |mount| mount.read_only as u64,
+        ),
+    ];
+    for (name, help, value) in metrics {
+        out.metric(name, "gauge", help);
+        for mount in &report.mounts {
+            let labels = [
+                ("mountpoint", mount.mount_point.as_str()),
+                ("device", mount.device.as_str()),
+                ("fstype", mount.fs_type.as_str()),
+            ];
+            out.sample(name, &labels, value(mount));
+        }
+    }
+    out.metric(
+        "filesystem_low_storage",
+        "gauge",
+        "If a filesystem is below its storage threshold.",
+    );
+    for mount in &report.mounts {
+        out.sample(
+            "filesystem_low_storage",
+            &[("mountpoint", &mount.mount_point)],
+            report.is_low(mount) as u8,
+        );
+    }
+
+    if !report.temperatures.is_empty() {
+        out.metric(
+            "temperature_celsius",
+            "gauge",
+            "The temperature of a sensor.",
+        );
+        for sensor in &report.temperatures {
+            out.sample(
+                "temperature_celsius",
+                &[("sensor", &sensor.label)],
+                sensor.current,
+            );
+        }
+        out.metric(
+            "temperature_high",
+            "gauge",
+            "If a sensor is above the temperature threshold.",
+        );
+        for sensor in &report.temperatures {
+            out.sample(
+                "temperature_high",
+                &[("sensor", &sensor.label)],
+                report.is_hot(sensor) as u8,
+            );
+        }
+    }
+    let metrics: [(&str, &str, TemperatureValue); 2] = [
+        (
+            "temperature_max_celsius",
+            "The temperature the hardware considers high.",
+            |sensor| sensor.max,
+        ),
+        (
+            "temperature_critical_celsius",
+            "The temperature at which the hardware shuts down.",
+            |sensor| sensor.critical,
+        ),
+    ];
+    for (name, help, value) in metrics {
+        let samples: Vec<(&str, f32)> = report
+            .temperatures
+            .iter()
+            .filter_map(|sensor| Some((sensor.label.as_str(), value(sensor)?)))
+            .collect();
+        // Limits no sensor reports are left out
+        if samples.is_empty() {
+            continue;
+        }
+        out.metric(name, "gauge", help);
+        for (sensor, value) in samples {
+            out.sample(name, &[("sensor", sensor)], value);
+        }
+    }
+
+    let batteries: Vec<(String, &BatteryStatus)> = report
+        .power
+        .batteries
+        .iter()
+        .enumerate()
+        .map(|(index, battery)| (index.to_string(), battery))
+        .collect();
+    if !batteries.is_empty() {
+        out.metric("battery_info", "gauge", "The model of a battery, always 1.");
+        for (index, battery) in &batteries {
+            out.sample(
+                "battery_info",
+                &[("battery", index), ("model", &battery.model)],
+                1,
+            );
+        }
+        out.metric(
+            "battery_state",
+            "gauge",
+            "The state of a battery, 1 for the current one.",
+        );
+        for (index, battery) in &batteries {
+            for state in BatteryState::ALL {
+                out.sample(
+                    "battery_state",
+                    &[("battery", index), ("state", state.as_str())],
+                    (state == battery.state) as u8,
+                );
+            }
+        }
+    }
+    let metrics: [(&str, &str, BatteryValue); 10] = [
+        (
+            "battery_charge_percent",
+            "The charge of a battery.",
+            |battery| Some(battery.charge as f32),
+        ),
+        (
+            "battery_energy_watt_hours",
+            "The energy stored in a battery.",
+            |battery| Some(battery.energy),
+        ),
+        (
+            "battery_energy_full_watt_hours",
+            "The energy a battery stores when fully charged.",
+            |battery| Some(battery.energy_full),
+        ),
+        (
+            "battery_energy_full_design_watt_hours",
+            "The energy a battery stored when it was new.",
+            |battery| Some(battery.energy_full_design),
+        ),
+        (
+            "battery_power_watts",
+            "The power flowing into or out of a battery.",
+            |battery| Some(battery.energy_rate),
+        ),
+        (
+            "battery_time_to_full_seconds",
+            "How long until a charging battery is full.",
+            |battery| battery.time_to_full.map(|value| value as f32),
+        ),
+        (
+            "battery_time_to_empty_seconds",
+            "How long until a discharging battery is empty.",
+            |battery| battery.time_to_empty.map(|value| value as f32),
+        ),
+        (
+            "battery_health_percent",
+            "The full capacity of a battery compared to its design capacity.",
+            |battery| Some(battery.health as f32),
+        ),
+        (
+            "battery_cycles",
+            "The charge cycles of a battery.",
+            |battery| battery.cycle_count.map(|value| value as f32),
+        ),
+        (
+            "battery_temperature_celsius",
+            "The temperature of a battery.",
+            |battery| battery.temperature,
+        ),
+    ];
+    for (name, help, value) in metrics {
+        let samples: Vec<(&str, f32)> = batteries
+            .iter()
+            .filter_map(|(index, battery)| Some((index.as_str(), value(battery)?)))
+            .collect();
+        // Metrics no battery reports are left out
+        if samples.is_empty() {
+            continue;
+        }
+        out.metric(name, "gauge", help);
+        for (index, value) in samples {
+            out.sample(name, &[("battery", index)], value);
+        }
+    }
+    if let Some(charge) = report.power.charge() {
+        out.single(
+            "power_charge_percent",
+            "gauge",
+            "The charge of all batteries together.",
+            charge,
+        );
+    }
+    if let Some(online) = report.power.ac_online {
+        out.single(
+            "power_ac_online",
+            "gauge",
+            "If a power adapter is plugged in.",
+            online as u8,
+        );
+    }
+    if let Some(ups) = &report.power.ups {
+        out.metric("ups_info", "gauge", "The model of the UPS, always 1.");
+        out.sample(
+            "ups_info",
+            &[
+                ("ups", &ups.name),
+                ("model", &ups.model),
+                ("status", &ups.status),
+            ],
+            1,
+        );
+        let metrics = [
+            (
+                "ups_charge_percent",
+                "The charge of the UPS battery.",
+                ups.charge.map(u64::from),
+            ),
+            (
+                "ups_runtime_seconds",
+                "How long the UPS can run on its battery.",
+                ups.runtime,
+            ),
+            (
+                "ups_load_percent",
+                "The load of the UPS.",
+                ups.load.map(u64::from),
+            ),
+            (
+                "ups_on_battery",
+                "If the UPS runs on its battery.",
+                Some(ups.on_battery as u64),
+            ),
+            (
+                "ups_low_battery",
+                "If the UPS reports its battery as low.",
+                Some(ups.low_battery as u64),
+            ),
+        ];
+        for (name, help, value) in metrics {
+            if let Some(value) = value {
+                out.metric(name, "gauge", help);
+                out.sample(name, &[("ups", &ups.name)], value);
+            }
+        }
+    }
+    out.single(
+        "low_power",
+        "gauge",
+        "If the system runs on batteries or a UPS below their threshold.",
+        report.low_power as u8,
+    );
+    if let Some(updates) = report.updates {
+        out.single(
+            "updates_pending",
+            "gauge",
+            "The number of packages with updates.",
+            updates,
+        );
+    }
+    out.single(
+        "restart_pending",
+        "gauge",
+        "If the settings changed since the server started.",
+        report.restart_pending as u8,
+    );
+    out.single(
+        "sessions",
+        "gauge",
+        "The number of logged in sessions.",
+        sessions,
+    );
+    out.single(
+        "login_failures_total",
+        "counter",
+        "The failed login attempts since the server started.",
+        failures,
+    );
+    out.0
+}
+
+/// Responds with the latest report as an exposition.
+pub async fn respond(reports: &Reports) -> Response {
+    let report = reports.borrow().clone();
+    (
+        [(header::CONTENT_TYPE, CONTENT_TYPE)],
+        render(&report).await,
+    )
+        .into_response()
+}
+
+async fn handle(State(reports): State<Reports>) -> Response {
+    respond(&reports).await
+}
+
+/// Serves the metrics without authentication on a separate listener.
+pub async fn serve(address: SocketAddr, reports: Reports) {
+    let listener = match TcpListener::bind(address).await {
+        Ok(value) => value,
+        Err(error) => {
+            log::error!(
+                "Failed to bind the metrics listener to {}: {}",
+                address,
+                error
+            );
+            return;
+        }
+    };
+    let router = Router::new()
+        .route("/metrics", get(handle))
+        .with_state(reports);
+    if let Err(error) = axum::serve(listener, router).await {
+        log::error!("Failed to serve metrics: {}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposes_a_report() {
+        let mut report = StatusReport::fixture();
+        report.mounts[0].mount_point = String::from("/mnt/\"a\"\\b\nc");
+        report.low_storage = vec![report.mounts[0].mount_point.clone()];
+        // Leaves out the critical temperature, the updates and all battery and UPS metrics
+        report.temperatures[0].critical = None;
+        report.updates = None;
+        assert_eq!(expose(&report, 2, 3), GOLDEN);
+    }
+
+    #[test]
+    fn leaves_out_what_batteries_and_the_ups_do_not_report() {
+        let mut report = StatusReport::fixture();
+        report.power.batteries.push(BatteryStatus {
+            model: String::from("ACME"),
+            charge: 80,
+            state: BatteryState::Discharging,
+            energy: 40.0,
+            energy_full: 50.0,
+            energy_full_design: 60.0,
+            energy_rate: 10.0,
+            time_to_full: None,
+            time_to_empty: Some(14400),
+            health: 83,
+            cycle_count: None,
+            temperature: None,
+        });
+        report.power.ups = Some(crate::ups::UpsStatus {
+            name: String::from("myups@localhost"),
+            model: String::from("Back-UPS"),
+            status: String::from("OL"),
+            charge: Some(100),
+            runtime: None,
+            load: None,
+            on_battery: false,
+            low_battery: false,
+        });
+        let exposition = expose(&report, 0, 0);
+        for line in [
+            "system_manager_battery_info{battery=\"0\",model=\"ACME\"} 1",
+            "system_manager_battery_state{battery=\"0\",state=\"discharging\"} 1",
+            "system_manager_battery_time_to_empty_seconds{battery=\"0\"} 14400",
+            "system_manager_power_charge_percent 80",
+            "system_manager_ups_charge_percent{ups=\"myups@localhost\"} 100",
+            "system_manager_ups_on_battery{ups=\"myups@localhost\"} 0",
+        ] {
+            assert!(exposition.lines().any(|value| value == line), "{line}");
+        }
+        for name in [
+            "battery_time_to_full_seconds",
+            "battery_cycles",
+            "battery_temperature_celsius",
+            "power_ac_online",
+            "ups_runtime_seconds",
+            "ups_load_percent",
+        ] {
+            assert!(
+                !exposition.contains(&format!("system_manager_{name}")),
+                "{name}"
+            );
+        }
+    }
+
+    const GOLDEN: &str = r#"# HELP system_manager_status_timestamp_seconds When the status was sampled as a Unix timestamp.
+# TYPE system_manager_status_timestamp_seconds gauge
+system_manager_status_timestamp_seconds 1767268800
+# HELP system_manager_uptime_seconds How long the system has been running.
+# TYPE system_manager_uptime_seconds gauge
+system_manager_uptime_seconds 3600
+# HELP system_manager_boot_time_seconds When the system booted as a Unix timestamp.
+# TYPE system_manager_boot_time_seconds gauge
+system_manager_boot_time_seconds 1767265200
+# HELP system_manager_load_average The load average of the system.
+# TYPE system_manager_load_average gauge
+system_manager_load_average{period="1m"} 0.5
+system_manager_load_average{period="5m"} 0.25
+system_manager_load_average{period="15m"} 0.125
+# HELP system_manager_cpu_usage_percent The usage of all cores together.
+# TYPE system_manager_cpu_usage_percent gauge
+system_manager_cpu_usage_percent 50
+# HELP system_manager_core_usage_percent The usage of a single core.
+# TYPE system_manager_core_usage_percent gauge
+system_manager_core_usage_percent{core="cpu0"} 50
+# HELP system_manager_core_frequency_hertz The frequency of a single core.
+# TYPE system_manager_core_frequency_hertz gauge
+system_manager_core_frequency_hertz{core="cpu0"} 2400000000
+# HELP system_manager_memory_total_bytes The size of the memory.
+# TYPE system_manager_memory_total_bytes gauge
+system_manager_memory_total_bytes 1000
+# HELP system_manager_memory_used_bytes The memory in use.
+# TYPE system_manager_memory_used_bytes gauge
+system_manager_memory_used_bytes 400
+# HELP system_manager_swap_total_bytes The size of the swap.
+# TYPE system_manager_swap_total_bytes gauge
+system_manager_swap_total_bytes 0
+# HELP system_manager_swap_used_bytes The swap in use.
+# TYPE system_manager_swap_used_bytes gauge
+system_manager_swap_used_bytes 0
+# HELP system_manager_network_receive_bytes_per_second The bytes received by all interfaces but loopback.
+# TYPE system_manager_network_receive_bytes_per_second gauge
+system_manager_network_receive_bytes_per_second 1000
+# HELP system_manager_network_transmit_bytes_per_second The bytes sent by all interfaces but loopback.
+# TYPE system_manager_network_transmit_bytes_per_second gauge
+system_manager_network_transmit_bytes_per_second 500
+# HELP system_manager_filesystem_size_bytes The size of a filesystem.
+# TYPE system_manager_filesystem_size_bytes gauge
+system_manager_filesystem_size_bytes{mountpoint="/mnt/\"a\"\\b\nc",device="/dev/sda1",fstype="ext4"} 1000
+# HELP system_manager_filesystem_avail_bytes The bytes unprivileged users can still write.
+# TYPE system_manager_filesystem_avail_bytes gauge
+system_manager_filesystem_avail_bytes{mountpoint="/mnt/\"a\"\\b\nc",device="/dev/sda1",fstype="ext4"} 250
+# HELP system_manager_filesystem_files The inodes of a filesystem.
+# TYPE system_manager_filesystem_files gauge
+system_manager_filesystem_files{mountpoint="/mnt/\"a\"\\b\nc",device="/dev/sda1",fstype="ext4"} 100
+# HELP system_manager_filesystem_files_free The free inodes of a filesystem.
+# TYPE system_manager_filesystem_files_free gauge
+system_manager_filesystem_files_free{mountpoint="/mnt/\"a\"\\b\nc",device="/dev/sda1",fstype="ext4"} 50
+# HELP system_manager_filesystem_readonly If a filesystem is mounted read-only.
+# TYPE system_manager_filesystem_readonly gauge
+system_manager_filesystem_readonly{mountpoint="/mnt/\"a\"\\b\nc",device="/dev/sda1",fstype="ext4"} 0
+# HELP system_manager_filesystem_low_storage If a filesystem is below its storage threshold.
+# TYPE system_manager_filesystem_low_storage gauge
+system_manager_filesystem_low_storage{mountpoint="/mnt/\"a\"\\b\nc"} 1
+# HELP system_manager_temperature_celsius The temperature of a sensor.
+# TYPE system_manager_temperature_celsius gauge
+system_manager_temperature_celsius{sensor="coretemp Package id 0"} 45
+# HELP system_manager_temperature_high If a sensor is above the temperature threshold.
+# TYPE system_manager_temperature_high gauge
+system_manager_temperature_high{sensor="coretemp Package id 0"} 0
+# HELP system_manager_temperature_max_celsius The temperature the hardware considers high.
+# TYPE system_manager_temperature_max_celsius gauge
+system_manager_temperature_max_celsius{sensor="coretemp Package id 0"} 80
+# HELP system_manager_low_power If the system runs on batteries or a UPS below their threshold.
+# TYPE system_manager_low_power gauge
+system_manager_low_power 0
+# HELP system_manager_restart_pending If the settings changed since the server started.
+# TYPE system_manager_restart_pending gauge
+system_manager_restart_pending 0
+# HELP system_manager_sessions The number of logged in sessions.
+# TYPE system_manager_sessions gauge
+system_manager_sessions 2
+# HELP system_manager_login_failures_total The failed login attempts since the server started.
+# TYPE system_manager_login_failures_total counter
+system_manager_login_failures_total 3
+"#;
+}
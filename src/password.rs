@@ -0,0 +1,54 @@
+//! # Password
+//!
+//! This module contains the checks new passwords have to pass before they are accepted.
+//!
+//! Checking against previously used passwords needs the stored hashes so it lives in
+//! [`crate::users`], everything that only needs the password itself is here.
+use std::{collections::HashSet, sync::LazyLock};
+
+use crate::settings::PasswordPolicy;
+
+/// The bundled list of common passwords.
+static COMMON_PASSWORDS: LazyLock<HashSet<String>> = LazyLock::new(|| {
+    include_str!("../data/common_passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect()
+});
+
+/// Checks if a password is in the list of common passwords.
+pub fn is_common(password: &str) -> bool {
+    COMMON_PASSWORDS.contains(&password.to_lowercase())
+}
+
+/// Checks a password against the policy.
+///
+/// # Returns
+///
+/// A list of every rule the password breaks, empty if it is acceptable.
+pub fn check(policy: &PasswordPolicy, password: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    // An empty password is never acceptable, whatever the policy says
+    let min_length = policy.min_length.max(1);
+    if password.chars().count() < min_length {
+        problems.push(format!("must be at least {min_length} characters long"));
+    }
+    if policy.require_lowercase && !password.chars().any(char::is_lowercase) {
+        problems.push("must contain a lowercase letter".to_string());
+    }
+    if policy.require_uppercase && !password.chars().any(char::is_uppercase) {
+        problems.push("must contain an uppercase letter".to_string());
+    }
+    if policy.require_digit && !password.chars().any(|value| value.is_ascii_digit()) {
+        problems.push("must contain a digit".to_string());
+    }
+    if policy.require_symbol && password.chars().all(char::is_alphanumeric) {
+        problems.push("must contain a symbol".to_string());
+    }
+    if policy.reject_common && is_common(password) {
+        problems.push("is too common".to_string());
+    }
+    problems
+}
@@ -0,0 +1,273 @@
+//! # Persist
+//!
+//! This module contains helpers for safely reading and writing the files we keep on disk.
+//!
+//! Writes go to a temporary file which is synced and then renamed over the target, so a crash
+//! can never leave a half written file behind. The previous version of the file is kept next to
+//! it with a `.bak` extension and every access holds an advisory lock on a `.lock` file.
+//!
+//! Changes that depend on the current content go through [`update`], which holds the lock from
+//! reading the file until the change is written, so two changes at once can not undo each other.
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use tokio::task;
+
+/// Appends a suffix to the file name of a path (`user.json` -> `user.json.bak`).
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Gets the path of the backup kept for a file.
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+/// Opens the lock file for a path, the lock is released when the file is dropped.
+fn open_lock(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(with_suffix(path, ".lock"))
+}
+
+/// Replaces a file with new data, the caller must hold the lock of the file.
+fn replace(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp_path = with_suffix(path, ".tmp");
+    let mut temp = File::create(&temp_path)?;
+    temp.write_all(data)?;
+    temp.sync_all()?;
+    drop(temp);
+
+    // Keep the previous version around in case the new one turns out to be bad, linking it
+    // under a temporary name first so the backup is swapped in one step as well
+    if path.exists() {
+        let backup_temp_path = with_suffix(path, ".bak.tmp");
+        match fs::remove_file(&backup_temp_path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        fs::hard_link(path, &backup_temp_path)?;
+        fs::rename(&backup_temp_path, backup_path(path))?;
+    }
+    fs::rename(&temp_path, path)?;
+    // Sync the directory so the renames themselves survive a crash
+    if let Some(parent) = path.parent().filter(|value| !value.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Reads a file or the backup if the file is missing, `None` if there is neither.
+///
+/// The caller must hold the lock of the file.
+fn read_current(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    for path in [path.to_path_buf(), backup_path(path)] {
+        match fs::read(&path) {
+            Ok(data) => return Ok(Some(data)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(None)
+}
+
+fn write_blocking(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lock = open_lock(path)?;
+    lock.lock()?;
+    replace(path, data)
+}
+
+fn read_blocking(path: &Path) -> io::Result<Vec<u8>> {
+    let lock = open_lock(path)?;
+    lock.lock_shared()?;
+    fs::read(path)
+}
+
+/// Atomically writes data to a file while holding its lock.
+pub async fn write(path: impl Into<PathBuf>, data: impl Into<Vec<u8>>) -> io::Result<()> {
+    let path = path.into();
+    let data = data.into();
+    task::spawn_blocking(move || write_blocking(&path, &data)).await?
+}
+
+/// Changes a file while holding its lock from reading it until the new data is written.
+///
+/// `change` gets the current data, the data of the backup if the file is missing or `None` if
+/// there is neither. It returns the new data along with a value that is passed on, the file is
+/// only written if the data changed.
+pub async fn update<T, E, F>(path: impl Into<PathBuf>, change: F) -> Result<T, E>
+where
+    F: FnOnce(Option<&[u8]>) -> Result<(Vec<u8>, T), E> + Send + 'static,
+    T: Send + 'static,
+    E: From<io::Error> + Send + 'static,
+{
+    let path = path.into();
+    task::spawn_blocking(move || {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let lock = open_lock(&path)?;
+        lock.lock()?;
+        let old = read_current(&path)?;
+        let (data, value) = change(old.as_deref())?;
+        if old.as_deref() != Some(data.as_slice()) || !path.exists() {
+            replace(&path, &data)?;
+        }
+        Ok(value)
+    })
+    .await
+    .map_err(|error| E::from(io::Error::other(error)))?
+}
+
+/// Appends data to a file while holding its lock, for logs that are only added to.
+///
+/// Unlike [`write`] the file is not replaced, it is only kept from changing underneath by an
+/// [`update`] that is rewriting it.
+pub async fn append(path: impl Into<PathBuf>, data: impl Into<Vec<u8>>) -> io::Result<()> {
+    let path = path.into();
+    let data = data.into();
+    task::spawn_blocking(move || {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let lock = open_lock(&path)?;
+        lock.lock()?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(&data)?;
+        file.sync_data()
+    })
+    .await?
+}
+
+/// Reads a file while holding a shared lock on it.
+pub async fn read(path: impl Into<PathBuf>) -> io::Result<Vec<u8>> {
+    let path = path.into();
+    task::spawn_blocking(move || read_blocking(&path)).await?
+}
+
+/// Reads the backup of a file that was kept by the last write.
+pub async fn read_backup(path: impl Into<PathBuf>) -> io::Result<Vec<u8>> {
+    let path = path.into();
+    task::spawn_blocking(move || {
+        let lock = open_lock(&path)?;
+        lock.lock_shared()?;
+        fs::read(backup_path(&path))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_the_previous_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.json");
+        write(&path, "one").await.unwrap();
+        assert!(read_backup(&path).await.is_err());
+        write(&path, "two").await.unwrap();
+        assert_eq!(read(&path).await.unwrap(), b"two");
+        assert_eq!(read_backup(&path).await.unwrap(), b"one");
+        assert!(!with_suffix(&path, ".tmp").exists());
+        assert!(!with_suffix(&path, ".bak.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn updates_from_the_backup_if_the_file_is_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.json");
+        let first = update(&path, |old| {
+            assert_eq!(old, None);
+            Ok::<_, io::Error>((b"one".to_vec(), 1))
+        })
+        .await
+        .unwrap();
+        assert_eq!(first, 1);
+        write(&path, "two").await.unwrap();
+        fs::remove_file(&path).unwrap();
+        update(&path, |old| {
+            assert_eq!(old, Some(&b"one"[..]));
+            Ok::<_, io::Error>((b"three".to_vec(), ()))
+        })
+        .await
+        .unwrap();
+        assert_eq!(read(&path).await.unwrap(), b"three");
+    }
+
+    #[tokio::test]
+    async fn errors_leave_the_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.json");
+        write(&path, "one").await.unwrap();
+        let result: io::Result<()> = update(&path, |_| Err(io::Error::other("refused"))).await;
+        assert!(result.is_err());
+        assert_eq!(read(&path).await.unwrap(), b"one");
+        assert!(read_backup(&path).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_updates_are_not_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("counter"));
+        write(&*path, "0").await.unwrap();
+        let tasks: Vec<_> = (0..32)
+            .map(|_| {
+                let path = path.clone();
+                tokio::spawn(async move {
+                    update(&*path, |old| {
+                        let count: u32 =
+                            std::str::from_utf8(old.unwrap()).unwrap().parse().unwrap();
+                        Ok::<_, io::Error>(((count + 1).to_string().into_bytes(), ()))
+                    })
+                    .await
+                    .unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(read(&*path).await.unwrap(), b"32");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn appends_are_not_lost_to_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("log"));
+        let tasks: Vec<_> = (0..32)
+            .map(|index| {
+                let path = path.clone();
+                tokio::spawn(async move {
+                    append(&*path, format!("{index}\n")).await.unwrap();
+                    // Rewrites the file like trimming a log does
+                    update(&*path, |old| {
+                        Ok::<_, io::Error>((old.unwrap_or_default().to_vec(), ()))
+                    })
+                    .await
+                    .unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let content = String::from_utf8(read(&*path).await.unwrap()).unwrap();
+        let mut lines: Vec<u32> = content.lines().map(|line| line.parse().unwrap()).collect();
+        lines.sort();
+        assert_eq!(lines, (0..32).collect::<Vec<_>>());
+    }
+}
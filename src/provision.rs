@@ -0,0 +1,783 @@
+//! # Provision
+//!
+//! This module mirrors manager users as Linux accounts using `useradd`, `usermod` and
+//! `userdel`.
+//!
+//! Every command goes through a [`CommandExecutor`] so the logic does not care if the
+//! commands really run, [`SystemExecutor`] runs them and [`DryRunExecutor`] only logs them.
+use std::{collections::HashSet, io, process::Command};
+
+use axum::{http::StatusCode, response::IntoResponse};
+
+use crate::{settings::ProvisioningSettings, users};
+
+/// The path to the file listing the local accounts.
+const PASSWD_PATH: &str = "/etc/passwd";
+/// The path to the file listing the valid login shells.
+const SHELLS_PATH: &str = "/etc/shells";
+/// The first uid given to regular accounts.
+const MIN_UID: u32 = 1000;
+/// The uid of the `nobody` account, which is not a regular account despite its uid.
+const NOBODY_UID: u32 = 65534;
+
+/// The result of a finished command.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    /// The exit code, `None` if the command was killed by a signal.
+    pub status: Option<i32>,
+    pub stderr: String,
+}
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+}
+
+/// Runs system commands.
+pub trait CommandExecutor: Send + Sync {
+    /// Runs a program with arguments and waits for it to finish.
+    fn run(&self, program: &str, args: &[String]) -> io::Result<CommandOutput>;
+}
+
+/// Runs commands on the system.
+pub struct SystemExecutor;
+impl CommandExecutor for SystemExecutor {
+    fn run(&self, program: &str, args: &[String]) -> io::Result<CommandOutput> {
+        log::info!("Running {} {}", program, args.join(" "));
+        let output = Command::new(program).args(args).output()?;
+        Ok(CommandOutput {
+            status: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        })
+    }
+}
+
+/// Logs commands instead of running them.
+pub struct DryRunExecutor;
+impl CommandExecutor for DryRunExecutor {
+    fn run(&self, program: &str, args: &[String]) -> io::Result<CommandOutput> {
+        log::info!("Dry run, not running {} {}", program, args.join(" "));
+        Ok(CommandOutput {
+            status: Some(0),
+            stderr: String::new(),
+        })
+    }
+}
+
+/// Records commands instead of running them, failing the programs it was told to fail.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingExecutor {
+    pub commands: std::sync::Mutex<Vec<Vec<String>>>,
+    pub failing: Vec<&'static str>,
+}
+#[cfg(test)]
+impl CommandExecutor for RecordingExecutor {
+    fn run(&self, program: &str, args: &[String]) -> io::Result<CommandOutput> {
+        let mut argv = vec![program.to_string()];
+        argv.extend_from_slice(args);
+        self.commands.lock().unwrap().push(argv);
+        if self.failing.contains(&program) {
+            Ok(CommandOutput {
+                status: Some(1),
+                stderr: format!("{program}: failed"),
+            })
+        } else {
+            Ok(CommandOutput {
+                status: Some(0),
+                stderr: String::new(),
+            })
+        }
+    }
+}
+
+/// Gets the executor for the settings.
+pub fn executor(settings: &ProvisioningSettings) -> Box<dyn CommandExecutor> {
+    if settings.dry_run {
+        Box::new(DryRunExecutor)
+    } else {
+        Box::new(SystemExecutor)
+    }
+}
+
+/// An error that can occur when managing Linux accounts.
+#[derive(Debug)]
+pub enum ProvisionError {
+    IoError(io::Error),
+    /// A command ran but did not succeed.
+    CommandFailed {
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+    /// The shell is not listed in `/etc/shells`.
+    InvalidShell(String),
+    InvalidGroup(String),
+    /// The group is not one of the groups users may be put in.
+    GroupNotAllowed(String),
+    /// The user may not change the Linux account.
+    Forbidden(&'static str),
+}
+impl From<io::Error> for ProvisionError {
+    fn from(value: io::Error) -> Self {
+        log::error!("IO Error: {}", value);
+        ProvisionError::IoError(value)
+    }
+}
+impl std::fmt::Display for ProvisionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(error) => write!(f, "IO Error: {error}"),
+            Self::CommandFailed {
+                command,
+                status,
+                stderr,
+            } => match status {
+                Some(status) => write!(f, "{command} failed with exit code {status}: {stderr}"),
+                None => write!(f, "{command} was killed: {stderr}"),
+            },
+            Self::InvalidShell(shell) => write!(f, "Invalid Shell {shell}"),
+            Self::InvalidGroup(group) => write!(f, "Invalid Group {group}"),
+            Self::GroupNotAllowed(group) => write!(f, "Group {group} Is Not Allowed"),
+            Self::Forbidden(reason) => write!(f, "Forbidden {reason}"),
+        }
+    }
+}
+impl std::error::Error for ProvisionError {}
+impl ProvisionError {
+    /// The status code to respond with for this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IoError(_) | Self::CommandFailed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidShell(_) | Self::InvalidGroup(_) => StatusCode::BAD_REQUEST,
+            Self::GroupNotAllowed(_) | Self::Forbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+impl IntoResponse for ProvisionError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::IoError(_) => (self.status(), "IO Error").into_response(),
+            _ => (self.status(), self.to_string()).into_response(),
+        }
+    }
+}
+type Result<T> = std::result::Result<T, ProvisionError>;
+
+/// Runs a command and turns a failure into an error.
+fn run(executor: &dyn CommandExecutor, program: &str, args: Vec<String>) -> Result<()> {
+    let output = executor.run(program, &args)?;
+    if output.success() {
+        Ok(())
+    } else {
+        Err(ProvisionError::CommandFailed {
+            command: program.to_string(),
+            status: output.status,
+            stderr: output.stderr,
+        })
+    }
+}
+
+/// Checks the shell and groups of an account before they are passed to a command.
+///
+/// The shell has to be one of `shells`, usually the ones from [`shells`], and the groups have to
+/// be allowed in the settings.
+pub fn check_account(
+    settings: &ProvisioningSettings,
+    shells: &[String],
+    shell: &str,
+    groups: &[String],
+) -> Result<()> {
+    if !shells.iter().any(|value| value == shell) {
+        return Err(ProvisionError::InvalidShell(shell.to_string()));
+    }
+    if let Some(group) = groups.iter().find(|group| !users::valid_username(group)) {
+        return Err(ProvisionError::InvalidGroup(group.to_string()));
+    }
+    // Groups like sudo, wheel or docker are as good as root
+    if let Some(group) = groups
+        .iter()
+        .find(|group| !settings.allowed_groups.contains(group))
+    {
+        return Err(ProvisionError::GroupNotAllowed(group.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks if `editor` may change the login shell and groups of a user from `before` to `after`.
+///
+/// Only admins may change them and nobody may change their own, the groups decide what the
+/// account can do on the system.
+pub fn check_edit(editor: &users::User, before: &users::User, after: &users::User) -> Result<()> {
+    if before.shell() == after.shell() && before.system_groups() == after.system_groups() {
+        return Ok(());
+    }
+    if !editor.admin() {
+        return Err(ProvisionError::Forbidden(
+            "only system admins can change Linux accounts",
+        ));
+    }
+    if editor.name() == before.name() {
+        return Err(ProvisionError::Forbidden(
+            "you can not change your own Linux account",
+        ));
+    }
+    Ok(())
+}
+
+/// Creates the Linux account of a user.
+///
+/// The account is created without a password, users log in to it with SSH keys.
+pub fn create_account(
+    executor: &dyn CommandExecutor,
+    settings: &ProvisioningSettings,
+    shells: &[String],
+    username: &str,
+    shell: &str,
+    groups: &[String],
+) -> Result<()> {
+    check_account(settings, shells, shell, groups)?;
+    let mut args = vec![String::from("--shell"), shell.to_string()];
+    if settings.create_home {
+        args.push(String::from("--create-home"));
+    } else {
+        args.push(String::from("--no-create-home"));
+    }
+    if !groups.is_empty() {
+        args.push(String::from("--groups"));
+        args.push(groups.join(","));
+    }
+    args.push(String::from("--"));
+    args.push(username.to_string());
+    run(executor, "useradd", args)
+}
+
+/// Changes the login shell and supplementary groups of a Linux account.
+pub fn update_account(
+    executor: &dyn CommandExecutor,
+    settings: &ProvisioningSettings,
+    shells: &[String],
+    username: &str,
+    shell: &str,
+    groups: &[String],
+) -> Result<()> {
+    check_account(settings, shells, shell, groups)?;
+    run(
+        executor,
+        "usermod",
+        vec![
+            String::from("--shell"),
+            shell.to_string(),
+            String::from("--groups"),
+            groups.join(","),
+            String::from("--"),
+            username.to_string(),
+        ],
+    )
+}
+
+/// Locks or unlocks a Linux account.
+///
+/// Locking expires the account instead of locking the password so SSH keys stop working too.
+pub fn set_locked(executor: &dyn CommandExecutor, username: &str, locked: bool) -> Result<()> {
+    // An expiry date of 1 is the day after the epoch, an empty one removes the expiry
+    let expiry = if locked { "1" } else { "" };
+    run(
+        executor,
+        "usermod",
+        vec![
+            String::from("--expiredate"),
+            expiry.to_string(),
+            String::from("--"),
+            username.to_string(),
+        ],
+    )
+}
+
+/// Removes the Linux account of a user.
+pub fn delete_account(
+    executor: &dyn CommandExecutor,
+    settings: &ProvisioningSettings,
+    username: &str,
+) -> Result<()> {
+    let mut args = Vec::new();
+    if settings.remove_home {
+        args.push(String::from("--remove"));
+    }
+    args.push(String::from("--"));
+    args.push(username.to_string());
+    run(executor, "userdel", args)
+}
+
+/// Runs provisioning commands on a blocking thread with the executor for the settings.
+async fn blocking<F>(settings: &ProvisioningSettings, task: F) -> Result<()>
+where
+    F: FnOnce(&dyn CommandExecutor, &ProvisioningSettings) -> Result<()> + Send + 'static,
+{
+    let settings = settings.clone();
+    tokio::task::spawn_blocking(move || task(&*executor(&settings), &settings))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Creates the Linux account of a user and marks the user as provisioned.
+///
+/// The user still has to be saved by the caller.
+pub async fn provision(settings: &ProvisioningSettings, user: &mut users::User) -> Result<()> {
+    let username = user.name().to_string();
+    let shell = user.shell().unwrap_or(&settings.default_shell).to_string();
+    let groups = if user.system_groups().is_empty() {
+        settings.default_groups.clone()
+    } else {
+        user.system_groups().to_vec()
+    };
+    {
+        let (shell, groups) = (shell.clone(), groups.clone());
+        blocking(settings, move |executor, settings| {
+            create_account(executor, settings, &shells(), &username, &shell, &groups)
+        })
+        .await?;
+    }
+    *user.provisioned_mut() = true;
+    *user.shell_mut() = Some(shell);
+    *user.system_groups_mut() = groups;
+    apply_quota(settings, user).await;
+    Ok(())
+}
+
+/// Brings the Linux account of a provisioned user in line with the user.
+///
+/// Only what changed since `before` is updated.
+pub async fn sync(
+    settings: &ProvisioningSettings,
+    before: &users::User,
+    after: &users::User,
+) -> Result<()> {
+    if !after.provisioned() {
+        return Ok(());
+    }
+    let username = after.name().to_string();
+    if before.shell() != after.shell() || before.system_groups() != after.system_groups() {
+        let shell = after.shell().unwrap_or(&settings.default_shell).to_string();
+        let groups = after.system_groups().to_vec();
+        let username = username.clone();
+        blocking(settings, move |executor, settings| {
+            update_account(executor, settings, &shells(), &username, &shell, &groups)
+        })
+        .await?;
+    }
+    if before.disabled() != after.disabled() {
+        lock(settings, &username, after.disabled()).await?;
+    }
+    if before.storage() != after.storage() {
+        apply_quota(settings, after).await;
+    }
+    Ok(())
+}
+
+/// Locks or unlocks the Linux account of a user.
+pub async fn lock(settings: &ProvisioningSettings, username: &str, locked: bool) -> Result<()> {
+    let username = username.to_string();
+    blocking(settings, move |executor, _| {
+        set_locked(executor, &username, locked)
+    })
+    .await
+}
+
+/// Removes the Linux account of a user if the manager created it.
+pub async fn deprovision(settings: &ProvisioningSettings, user: &users::User) -> Result<()> {
+    if !user.provisioned() {
+        return Ok(());
+    }
+    let username = user.name().to_string();
+    blocking(settings, move |executor, settings| {
+        delete_account(executor, settings, &username)
+    })
+    .await
+}
+
+/// Sets the filesystem quota of a Linux account on every filesystem with quotas enabled.
+pub fn set_quota(executor: &dyn CommandExecutor, username: &str, limit: u64) -> Result<()> {
+    // setquota counts in blocks of 1 KiB, the soft and hard limits are the same
+    let blocks = (limit / 1024).to_string();
+    run(
+        executor,
+        "setquota",
+        vec![
+            String::from("-u"),
+            username.to_string(),
+            blocks.clone(),
+            blocks,
+            String::from("0"),
+            String::from("0"),
+            String::from("-a"),
+        ],
+    )
+}
+
+/// Applies the storage quota of a provisioned user to their Linux account if enabled.
+///
+/// Not every filesystem supports quotas so failing is only logged.
+async fn apply_quota(settings: &ProvisioningSettings, user: &users::User) {
+    if !settings.filesystem_quota || !user.provisioned() {
+        return;
+    }
+    let username = user.name().to_string();
+    let limit = user.storage() as u64;
+    let result = blocking(settings, move |executor, _| {
+        set_quota(executor, &username, limit)
+    })
+    .await;
+    if let Err(error) = result {
+        log::warn!(
+            "Failed to set filesystem quota of {}: {}",
+            user.name(),
+            error
+        );
+    }
+}
+
+/// A local account from `/etc/passwd`.
+#[derive(Debug, Clone)]
+pub struct SystemAccount {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+impl SystemAccount {
+    /// Checks if the account belongs to a person rather than a system service.
+    pub fn regular(&self) -> bool {
+        self.uid >= MIN_UID && self.uid != NOBODY_UID
+    }
+}
+
+/// Parses the contents of a `passwd` file, skipping malformed lines.
+pub fn parse_passwd(content: &str) -> Vec<SystemAccount> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() != 7 {
+                return None;
+            }
+            Some(SystemAccount {
+                name: fields[0].to_string(),
+                uid: fields[2].parse().ok()?,
+                gid: fields[3].parse().ok()?,
+                home: fields[5].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Lists the local accounts.
+pub fn system_accounts() -> io::Result<Vec<SystemAccount>> {
+    Ok(parse_passwd(&std::fs::read_to_string(PASSWD_PATH)?))
+}
+
+/// Parses the contents of a `shells` file, skipping comments.
+pub fn parse_shells(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with('/'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Lists the valid login shells.
+pub fn shells() -> Vec<String> {
+    match std::fs::read_to_string(SHELLS_PATH) {
+        Ok(content) => parse_shells(&content),
+        Err(error) => {
+            log::error!("Failed to read {}: {}", SHELLS_PATH, error);
+            Vec::new()
+        }
+    }
+}
+
+/// Parses a comma separated list of groups.
+pub fn parse_groups(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The differences between the manager users and the local accounts.
+#[derive(Debug, Clone, Default)]
+pub struct Drift {
+    /// Manager users without a local account.
+    pub missing: Vec<String>,
+    /// Regular local accounts without a manager user.
+    pub unmanaged: Vec<String>,
+}
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unmanaged.is_empty()
+    }
+}
+
+/// Compares the manager users with the local accounts.
+pub fn drift(usernames: &[String], accounts: &[SystemAccount]) -> Drift {
+    let account_names: HashSet<&str> = accounts.iter().map(|value| value.name.as_str()).collect();
+    let usernames_set: HashSet<&str> = usernames.iter().map(String::as_str).collect();
+    let mut drift = Drift {
+        missing: usernames
+            .iter()
+            .filter(|name| !account_names.contains(name.as_str()))
+            .cloned()
+            .collect(),
+        unmanaged: accounts
+            .iter()
+            .filter(|account| account.regular() && !usernames_set.contains(account.name.as_str()))
+            .map(|account| account.name.clone())
+            .collect(),
+    };
+    drift.missing.sort();
+    drift.unmanaged.sort();
+    drift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shells() -> Vec<String> {
+        parse_shells("# /etc/shells: valid login shells\n/bin/sh\n/bin/bash\n\n/usr/bin/zsh\n")
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn commands(executor: &RecordingExecutor) -> Vec<Vec<String>> {
+        executor.commands.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn parses_shells() {
+        assert_eq!(shells(), ["/bin/sh", "/bin/bash", "/usr/bin/zsh"]);
+    }
+
+    fn user(name: &str, admin: bool) -> users::User {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "password": "",
+            "admin": admin,
+            "storage": 0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn creates_accounts() {
+        let executor = RecordingExecutor::default();
+        let settings = ProvisioningSettings {
+            allowed_groups: strings(&["docker", "video"]),
+            ..Default::default()
+        };
+        create_account(
+            &executor,
+            &settings,
+            &shells(),
+            "alice",
+            "/bin/bash",
+            &strings(&["docker", "video"]),
+        )
+        .unwrap();
+        let settings = ProvisioningSettings {
+            create_home: false,
+            ..settings
+        };
+        create_account(&executor, &settings, &shells(), "bob", "/bin/sh", &[]).unwrap();
+        assert_eq!(
+            commands(&executor),
+            [
+                strings(&[
+                    "useradd",
+                    "--shell",
+                    "/bin/bash",
+                    "--create-home",
+                    "--groups",
+                    "docker,video",
+                    "--",
+                    "alice"
+                ]),
+                strings(&[
+                    "useradd",
+                    "--shell",
+                    "/bin/sh",
+                    "--no-create-home",
+                    "--",
+                    "bob"
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_shells_and_groups() {
+        let executor = RecordingExecutor::default();
+        let settings = ProvisioningSettings::default();
+        assert!(matches!(
+            create_account(&executor, &settings, &shells(), "alice", "/tmp/evil", &[]),
+            Err(ProvisionError::InvalidShell(shell)) if shell == "/tmp/evil"
+        ));
+        assert!(matches!(
+            update_account(
+                &executor,
+                &settings,
+                &shells(),
+                "alice",
+                "/bin/bash",
+                &strings(&["wheel", "-o"])
+            ),
+            Err(ProvisionError::InvalidGroup(group)) if group == "-o"
+        ));
+        assert!(commands(&executor).is_empty());
+    }
+
+    #[test]
+    fn only_puts_accounts_in_allowed_groups() {
+        let executor = RecordingExecutor::default();
+        let settings = ProvisioningSettings {
+            allowed_groups: strings(&["video"]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            update_account(
+                &executor,
+                &settings,
+                &shells(),
+                "alice",
+                "/bin/bash",
+                &strings(&["video", "sudo"])
+            ),
+            Err(ProvisionError::GroupNotAllowed(group)) if group == "sudo"
+        ));
+        let settings = ProvisioningSettings {
+            default_groups: strings(&["docker"]),
+            ..Default::default()
+        };
+        let mut alice = user("alice", false);
+        *alice.system_groups_mut() = settings.default_groups.clone();
+        assert!(matches!(
+            check_account(&settings, &shells(), "/bin/bash", alice.system_groups()),
+            Err(ProvisionError::GroupNotAllowed(group)) if group == "docker"
+        ));
+        assert!(commands(&executor).is_empty());
+    }
+
+    #[test]
+    fn only_admins_change_the_accounts_of_others() {
+        let manager = user("manager", false);
+        let mut sudo = manager.clone();
+        *sudo.system_groups_mut() = strings(&["sudo"]);
+        assert!(matches!(
+            check_edit(&manager, &manager, &sudo),
+            Err(ProvisionError::Forbidden(_))
+        ));
+        let bob = user("bob", false);
+        let mut changed = bob.clone();
+        *changed.system_groups_mut() = strings(&["sudo"]);
+        assert!(matches!(
+            check_edit(&manager, &bob, &changed),
+            Err(ProvisionError::Forbidden(_))
+        ));
+        let root = user("root", true);
+        assert!(check_edit(&root, &bob, &changed).is_ok());
+        let mut shell = root.clone();
+        *shell.shell_mut() = Some(String::from("/bin/sh"));
+        assert!(matches!(
+            check_edit(&root, &root, &shell),
+            Err(ProvisionError::Forbidden(_))
+        ));
+        // Saving the other settings of a user keeps their account as it is
+        assert!(check_edit(&manager, &manager, &manager).is_ok());
+    }
+
+    #[test]
+    fn updates_locks_and_deletes_accounts() {
+        let executor = RecordingExecutor::default();
+        update_account(
+            &executor,
+            &ProvisioningSettings::default(),
+            &shells(),
+            "alice",
+            "/usr/bin/zsh",
+            &[],
+        )
+        .unwrap();
+        set_locked(&executor, "alice", true).unwrap();
+        set_locked(&executor, "alice", false).unwrap();
+        let settings = ProvisioningSettings {
+            remove_home: true,
+            ..Default::default()
+        };
+        delete_account(&executor, &settings, "alice").unwrap();
+        assert_eq!(
+            commands(&executor),
+            [
+                strings(&[
+                    "usermod",
+                    "--shell",
+                    "/usr/bin/zsh",
+                    "--groups",
+                    "",
+                    "--",
+                    "alice"
+                ]),
+                strings(&["usermod", "--expiredate", "1", "--", "alice"]),
+                strings(&["usermod", "--expiredate", "", "--", "alice"]),
+                strings(&["userdel", "--remove", "--", "alice"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn sets_quotas_in_blocks() {
+        let executor = RecordingExecutor::default();
+        set_quota(&executor, "alice", 1073741824).unwrap();
+        assert_eq!(
+            commands(&executor),
+            [strings(&[
+                "setquota", "-u", "alice", "1048576", "1048576", "0", "0", "-a"
+            ])]
+        );
+    }
+
+    #[test]
+    fn reports_failed_commands() {
+        let executor = RecordingExecutor {
+            failing: vec!["userdel"],
+            ..Default::default()
+        };
+        let error =
+            delete_account(&executor, &ProvisioningSettings::default(), "alice").unwrap_err();
+        assert!(matches!(
+            error,
+            ProvisionError::CommandFailed { ref command, status: Some(1), ref stderr }
+                if command == "userdel" && stderr == "userdel: failed"
+        ));
+    }
+
+    #[test]
+    fn finds_drift() {
+        let accounts = parse_passwd(concat!(
+            "root:x:0:0:root:/root:/bin/bash\n",
+            "alice:x:1000:1000::/home/alice:/bin/bash\n",
+            "carol:x:1001:1001::/home/carol:/bin/bash\n",
+            "nobody:x:65534:65534::/nonexistent:/usr/sbin/nologin\n",
+            "broken line\n",
+        ));
+        assert_eq!(accounts.len(), 4);
+        let drift = drift(&strings(&["alice", "bob"]), &accounts);
+        assert_eq!(drift.missing, ["bob"]);
+        assert_eq!(drift.unmanaged, ["carol"]);
+    }
+}
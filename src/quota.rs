@@ -0,0 +1,247 @@
+//! # Quota
+//!
+//! This module measures how much of their storage quota every user is using and refuses writes
+//! that would go over it.
+//!
+//! Walking a user's files is slow so the usage is measured by a background task and cached,
+//! writes update the cached value as they happen.
+use std::{collections::HashMap, io, path::Path, sync::LazyLock, time::Duration};
+
+use axum::{http::StatusCode, response::IntoResponse};
+use tokio::sync::RwLock;
+
+use crate::{
+    groups, settings,
+    users::{self, User},
+};
+
+/// The cached bytes used by every user by username.
+static USAGE: LazyLock<RwLock<HashMap<String, u64>>> = LazyLock::new(Default::default);
+
+/// An error that can occur when checking a quota.
+#[derive(Debug)]
+pub enum QuotaError {
+    IoError(io::Error),
+    /// The write would go over the user's own quota.
+    UserQuotaExceeded {
+        used: u64,
+        limit: u64,
+    },
+    /// The write would go over the quota a group of the user shares.
+    GroupQuotaExceeded {
+        group: String,
+        used: u64,
+        limit: u64,
+    },
+}
+impl From<io::Error> for QuotaError {
+    fn from(value: io::Error) -> Self {
+        log::error!("IO Error: {}", value);
+        QuotaError::IoError(value)
+    }
+}
+impl std::fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(error) => write!(f, "IO Error: {error}"),
+            Self::UserQuotaExceeded { used, limit } => write!(
+                f,
+                "Storage Quota Exceeded ({} of {} used)",
+                format_bytes(*used),
+                format_bytes(*limit)
+            ),
+            Self::GroupQuotaExceeded { group, used, limit } => write!(
+                f,
+                "Storage Quota of Group {group} Exceeded ({} of {} used)",
+                format_bytes(*used),
+                format_bytes(*limit)
+            ),
+        }
+    }
+}
+impl std::error::Error for QuotaError {}
+impl QuotaError {
+    /// The status code to respond with for this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::UserQuotaExceeded { .. } | Self::GroupQuotaExceeded { .. } => {
+                StatusCode::INSUFFICIENT_STORAGE
+            }
+        }
+    }
+}
+impl IntoResponse for QuotaError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::IoError(_) => (self.status(), "IO Error").into_response(),
+            _ => (self.status(), self.to_string()).into_response(),
+        }
+    }
+}
+
+/// Formats a number of bytes for display.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// A usage bar for display.
+#[derive(Debug, Clone)]
+pub struct UsageBar {
+    pub used: String,
+    pub limit: String,
+    /// How full the quota is in percent, capped at 100.
+    pub percent: u8,
+}
+impl UsageBar {
+    pub fn new(used: u64, limit: u64) -> Self {
+        // An empty quota is always full
+        let percent = used
+            .saturating_mul(100)
+            .checked_div(limit)
+            .map_or(100, |value| value.min(100)) as u8;
+        Self {
+            used: format_bytes(used),
+            limit: format_bytes(limit),
+            percent,
+        }
+    }
+}
+
+/// Adds up the size of every file below a path without following symlinks.
+pub fn measure_path(path: &Path) -> io::Result<u64> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(value) => value,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error),
+    };
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        total += measure_path(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// Measures the usage of a user now and caches it.
+pub async fn refresh(username: &str) -> io::Result<u64> {
+    let path = users::data_dir(username);
+    let bytes = tokio::task::spawn_blocking(move || measure_path(&path))
+        .await
+        .map_err(io::Error::other)??;
+    USAGE.write().await.insert(username.to_string(), bytes);
+    Ok(bytes)
+}
+
+/// Gets the cached usage of a user, measuring it if it is not cached yet.
+pub async fn usage(username: &str) -> io::Result<u64> {
+    if let Some(bytes) = USAGE.read().await.get(username) {
+        return Ok(*bytes);
+    }
+    refresh(username).await
+}
+
+/// Updates the cached usage of a user after a write, or a delete with a negative change.
+pub async fn record_change(username: &str, change: i64) {
+    if let Some(bytes) = USAGE.write().await.get_mut(username) {
+        *bytes = bytes.saturating_add_signed(change);
+    }
+}
+
+/// Forgets the cached usage of a user, used when the user is deleted.
+pub async fn forget(username: &str) {
+    USAGE.write().await.remove(username);
+}
+
+/// Gets how many bytes a user can still write, the least of what is left of their own quota
+/// and the quotas of their groups.
+pub async fn remaining(user: &User) -> Result<u64, QuotaError> {
+    let mut remaining = (user.storage() as u64).saturating_sub(usage(user.name()).await?);
+    for group in user_groups(user).await {
+        let mut used = 0u64;
+        for member in &group.members {
+            used = used.saturating_add(usage(member).await?);
+        }
+        remaining = remaining.min((group.storage as u64).saturating_sub(used));
+    }
+    Ok(remaining)
+}
+
+/// Gets the groups of a user that have a storage limit.
+async fn user_groups(user: &User) -> Vec<groups::Group> {
+    match groups::of_user(user.name()).await {
+        Ok(value) => value
+            .into_iter()
+            .filter(|group| group.storage != 0)
+            .collect(),
+        Err(error) => {
+            log::error!("Failed to read groups of {}: {}", user.name(), error);
+            Vec::new()
+        }
+    }
+}
+
+/// Checks if a user can write `additional` more bytes without going over their quota or the
+/// quota of any of their groups.
+pub async fn check(user: &User, additional: u64) -> Result<(), QuotaError> {
+    let used = usage(user.name()).await?;
+    let limit = user.storage() as u64;
+    if used.saturating_add(additional) > limit {
+        return Err(QuotaError::UserQuotaExceeded { used, limit });
+    }
+    for group in user_groups(user).await {
+        let mut used = 0u64;
+        for member in &group.members {
+            used = used.saturating_add(usage(member).await?);
+        }
+        let limit = group.storage as u64;
+        if used.saturating_add(additional) > limit {
+            return Err(QuotaError::GroupQuotaExceeded {
+                group: group.name,
+                used,
+                limit,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Measures every user again.
+async fn refresh_all() {
+    let users = match users::get_users().await {
+        Ok(value) => value,
+        Err(error) => {
+            log::error!("Failed to list users for measuring storage: {}", error);
+            return;
+        }
+    };
+    for user in users {
+        if let Err(error) = refresh(user.name()).await {
+            log::error!("Failed to measure storage of {}: {}", user.name(), error);
+        }
+    }
+}
+
+/// Starts the background task measuring the usage of every user.
+pub fn spawn_refresher() {
+    tokio::spawn(async {
+        loop {
+            refresh_all().await;
+            let interval = settings::load_settings().await.quota.refresh_interval;
+            tokio::time::sleep(Duration::from_secs(interval.max(1) * 60)).await;
+        }
+    });
+}
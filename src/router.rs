@@ -1,91 +1,165 @@
+//! # Router
+//!
+//! This module builds the router of the web interface and starts the server.
+//!
+//! The handlers live in one module per feature. The macros they share are in [`macros`], the
+//! helpers and forms they share are here.
+use std::net::SocketAddr;
 
 use askama::Template;
 use axum::{
-    Form, Router,
-    extract::Path,
-    http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
-    routing::{get, post},
+    Router,
+    extract::DefaultBodyLimit,
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse},
+    routing::{any, get, post},
 };
 use serde::Deserialize;
 use tokio::net::TcpListener;
 use tower_http::services::ServeDir;
-use tower_sessions::{MemoryStore, Session, SessionManagerLayer};
+use tower_sessions::{Expiry, MemoryStore, Session, SessionManagerLayer};
 
 use crate::{
-    RESTART_PENDING,
+    dav,
+    groups::Permission,
+    history, metrics, quota,
     router::templates::*,
-    settings::{self, Settings},
-    status,
-    users::{self, UserError},
+    sampler, sessions,
+    settings::PasswordPolicy,
+    ups,
+    users::{User, UserError},
 };
+
+#[macro_use]
+mod macros;
+
+mod alerts;
+mod api_tokens;
+mod audit;
+mod auth;
+mod dashboard;
+mod files;
+mod groups;
+mod settings;
+mod shares;
+mod ssh_keys;
 pub mod templates;
+mod users;
 
-/// Renders a template or returns an error response.
-macro_rules! render {
-    ($template:expr) => {
-        match $template.render() {
-            Ok(value) => value,
-            Err(error) => {
-                log::error!("Failed to render template: {}", error);
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render page")
-                    .into_response();
-            }
-        }
+/// Renders the error page with a status code.
+fn error_page(status: StatusCode, message: impl ToString) -> axum::response::Response {
+    let template = ErrorTemplate {
+        error: message.to_string(),
     };
+    (status, Html(render!(template))).into_response()
 }
 
-/// Spawns a blocking task and returns its result or an error response.
-macro_rules! tokio_blocking {
-    ($task:expr) => {
-        match tokio::task::spawn_blocking($task).await {
-            Ok(value) => value,
-            Err(error) => {
-                log::error!("Failed to handle request due to runtime error: {}", &error);
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Failed to handle request due to runtime error",
-                )
-                    .into_response();
-            }
-        }
-    };
+/// Logs an error and renders the error page for it, internal errors are shown without details.
+fn failure_page(status: StatusCode, error: impl std::fmt::Display) -> axum::response::Response {
+    log::error!("Failed to handle request due to error: {}", error);
+    if status.is_server_error() {
+        error_page(status, "Internal Server Error")
+    } else {
+        error_page(status, error)
+    }
 }
 
-/// Handles a `Result` or returns an error response.
-macro_rules! err_response {
-    ($error:expr) => {
-        match $error {
-            Ok(value) => value,
-            Err(error) => {
-                log::error!("Failed to handle request due to error: {}", &error);
-                return error.into_response();
-            }
+/// Authenticates a request to the API by the session or by the `Authorization` header.
+///
+/// Scripts use an API token or their password, the browser can use its session.
+async fn api_user(
+    session: &Session,
+    headers: &HeaderMap,
+    address: SocketAddr,
+) -> Result<User, axum::response::Response> {
+    if let Some(username) = sessions::validate(session).await
+        && let Ok(user) = User::fetch_user(&username).await
+        && user.check_active().is_ok()
+        && !user.must_change_password()
+    {
+        return Ok(user);
+    }
+    match crate::api_tokens::authorize(headers, address).await {
+        Ok(user) => Ok(user),
+        Err(UserError::IoError(error)) => {
+            log::error!("Failed to authorize API request: {}", error);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "IO Error").into_response())
         }
-    };
+        Err(error @ UserError::TooManyAttempts(_)) => Err(error.into_response()),
+        Err(_) => Err((
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Unauthorized",
+        )
+            .into_response()),
+    }
 }
 
-/// Gets the current user from the session or redirects to the login page.
-macro_rules! get_current_user {
-    ($session:expr) => {
-        if let Some(username) = $session.get::<String>("username").await.unwrap_or_default() {
-            err_response!(users::User::fetch_user(&username).await)
-        } else {
-            return Redirect::to("/").into_response();
-        }
-    };
+/// A form that names a session, share or API token by its id.
+#[derive(Deserialize)]
+struct IdForm {
+    id: String,
+}
+
+/// The state shared by the handlers.
+#[derive(Clone)]
+struct AppState {
+    /// The latest status of the system.
+    status: sampler::Reports,
 }
 
 /// Initializes the router and starts the server.
 pub async fn init_router() {
-    // Create a default user for testing purposes
-    let mut user = users::User::new("linuxman", "!!Oct06Yes").await.unwrap();
-    *user.admin_mut() = true;
-    user.save().await.unwrap();
+    let settings = crate::settings::load_settings().await;
+    if let Err(error) = crate::users::configure_hasher(&settings.argon2) {
+        log::error!("{}, using the default parameters", error);
+    }
+
+    // Create a default user for testing purposes, its fixed password is not held to the
+    // configured policy
+    match User::new("linuxman", "!!Oct06Yes", &PasswordPolicy::default()).await {
+        Ok(mut user) => {
+            *user.admin_mut() = true;
+            if let Err(error) = user.save().await {
+                log::error!("Failed to save the default user: {}", error);
+            }
+        }
+        Err(error) => log::error!("Failed to create the default user: {}", error),
+    }
+
+    quota::spawn_refresher();
+    let state = AppState {
+        status: sampler::spawn().await,
+    };
+    history::spawn(state.status.clone());
+    crate::alerts::spawn(state.status.clone());
+    ups::spawn(state.status.clone());
+    if !settings.metrics.listen.is_empty() {
+        match settings.metrics.listen.parse() {
+            Ok(address) => {
+                tokio::spawn(metrics::serve(address, state.status.clone()));
+            }
+            Err(error) => log::error!(
+                "Invalid metrics address {}: {}",
+                settings.metrics.listen,
+                error
+            ),
+        }
+    }
 
     // Create a session layer
+    let session_settings = settings.sessions;
+    sessions::configure(
+        session_settings.idle_timeout,
+        session_settings.absolute_timeout,
+    );
     let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store).with_secure(false); // Set to true in production with HTTPS
+    let mut session_layer = SessionManagerLayer::new(session_store).with_secure(false); // Set to true in production with HTTPS
+    if session_settings.idle_timeout != 0 {
+        session_layer = session_layer.with_expiry(Expiry::OnInactivity(time::Duration::minutes(
+            session_settings.idle_timeout as i64,
+        )));
+    }
 
     // Bind to a port
     let listener = match TcpListener::bind("0.0.0.0:8080").await {
@@ -98,231 +172,192 @@ pub async fn init_router() {
 
     // Create the router
     let router = Router::new()
-        .route("/", get(index))
-        .route("/login", post(login))
-        .route("/home", get(home))
-        .route("/logout", get(logout))
-        .route("/settings", get(get_settings))
-        .route("/settings", post(post_settings))
-        .route("/manage", get(management))
-        .route("/manage/{user}", get(get_user_settings))
-        .route("/manage/{user}", post(post_user_settings))
+        .route("/", get(auth::index))
+        .route("/login", post(auth::login))
+        .route("/home", get(dashboard::home))
+        .route("/api/status", get(dashboard::api_status))
+        .route("/metrics", get(dashboard::get_metrics))
+        .route("/ws/status", get(dashboard::status_socket))
+        .route("/history", get(dashboard::get_history))
+        .route("/api/history", get(dashboard::api_history))
+        .route("/alerts", get(alerts::get_alerts))
+        .route("/alerts/rules", post(alerts::add_alert_rule))
+        .route("/alerts/rules/delete", post(alerts::delete_alert_rule))
+        .route("/alerts/channels", post(alerts::add_alert_channel))
+        .route(
+            "/alerts/channels/delete",
+            post(alerts::delete_alert_channel),
+        )
+        .route("/alerts/channels/test", post(alerts::test_alert_channel))
+        .route("/logout", get(auth::logout))
+        .route("/settings", get(settings::get_settings))
+        .route("/settings", post(settings::post_settings))
+        .route("/manage", get(users::management))
+        .route("/manage/{user}", get(users::get_user_settings))
+        .route("/manage/{user}", post(users::post_user_settings))
+        .route("/manage/{user}/reset-link", post(users::create_reset_link))
+        .route("/manage/{user}/provision", post(users::provision_user))
+        .route("/manage/{user}/delete", post(users::delete_user))
+        .route("/invite", post(users::invite_user))
+        .route("/reset/{token}", get(auth::get_reset))
+        .route("/reset/{token}", post(auth::post_reset))
+        .route("/groups", get(groups::get_groups))
+        .route("/groups", post(groups::create_group))
+        .route("/groups/{group}", get(groups::get_group))
+        .route("/groups/{group}", post(groups::post_group))
+        .route("/groups/{group}/delete", post(groups::delete_group))
+        .route("/files", get(files::get_files))
+        .route(
+            "/files/upload",
+            // Uploads are limited by the quota instead
+            post(files::upload_files).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/files/download", get(files::download_file))
+        .route("/files/preview", get(files::preview_file))
+        .route("/files/raw", get(files::raw_file))
+        .route("/files/mkdir", post(files::create_folder))
+        .route("/files/rename", post(files::rename_file))
+        .route("/files/delete", post(files::delete_file))
+        .route("/files/share", post(shares::create_share))
+        .route("/shares", get(shares::get_shares))
+        .route("/shares/revoke", post(shares::revoke_share))
+        .route("/s/{token}", get(shares::get_share))
+        .route("/s/{token}", post(shares::unlock_share))
+        .route("/dav/{*path}", any(dav::handle))
+        .route("/ssh-keys", get(ssh_keys::get_ssh_keys))
+        .route("/ssh-keys/add", post(ssh_keys::add_ssh_key))
+        .route("/ssh-keys/delete", post(ssh_keys::delete_ssh_key))
+        .route("/api-tokens", post(api_tokens::create_api_token))
+        .route("/api-tokens/revoke", post(api_tokens::revoke_api_token))
+        .route("/audit", get(audit::get_audit))
+        .route("/audit/export", get(audit::export_audit))
+        .route("/password", get(auth::get_password))
+        .route("/password", post(auth::post_password))
+        .route("/sessions", get(auth::get_sessions))
+        .route("/sessions/revoke", post(auth::revoke_session))
+        .route("/sessions/revoke-others", post(auth::revoke_other_sessions))
         .nest_service("/static/", ServeDir::new("static"))
+        .with_state(state)
         .layer(session_layer);
 
     // Start the server
-    if let Err(error) = axum::serve(listener, router).await {
+    if let Err(error) = axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    {
         log::error!("Failed to start server: {}", error);
         std::process::exit(1);
     }
 }
 
-async fn index(session: Session) -> impl IntoResponse {
-    if session
-        .get::<String>("username")
-        .await
+/// Gets the user agent of a request for the login history.
+fn user_agent(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
         .unwrap_or_default()
-        .is_some()
-    {
-        Redirect::to("/home").into_response()
-    } else {
-        let template = IndexTemplate { error: false };
-        Html(render!(template)).into_response()
-    }
 }
 
-async fn logout(session: Session) -> impl IntoResponse {
-    if let Err(error) = session.delete().await {
-        log::error!("Failed to delete session: {}", error);
+/// Works out whose data a request is for, the data of other users needs a permission.
+///
+/// # Returns
+///
+/// The owner of the data and if it is someone else than the user.
+async fn resource_owner(
+    user: &User,
+    requested: &str,
+    permission: Permission,
+) -> Result<(User, bool), axum::response::Response> {
+    if requested.is_empty() || requested == user.name() {
+        return Ok((user.clone(), false));
     }
-    Redirect::to("/").into_response()
-}
-
-#[derive(Deserialize)]
-struct LoginForm {
-    username: String,
-    password: String,
-}
-
-async fn login(session: Session, Form(form): Form<LoginForm>) -> impl IntoResponse {
-    let username = form.username.trim();
-    let password = form.password.trim();
-    let user = users::User::login(username, password).await;
-    match user {
-        Ok(_) => {
-            // Store only the username
-            session
-                .insert("username", username.to_string())
-                .await
-                .unwrap();
-            Redirect::to("/home").into_response()
-        }
-        Err(error) => {
-            if let UserError::PasswordError | UserError::UserNotFound = error {
-                let template = IndexTemplate { error: true };
-                Html(render!(template)).into_response()
-            } else {
-                error.into_response()
-            }
-        }
+    if !crate::groups::has_permission(user, permission).await {
+        return Err(error_page(
+            StatusCode::FORBIDDEN,
+            "Forbidden you do not have permission to access the data of other users",
+        ));
     }
-}
-
-async fn home(session: Session) -> impl IntoResponse {
-    let user = get_current_user!(session);
-
-    let thresholds = settings::load_settings().await.threatsholds;
-    let low_power = match tokio_blocking!(status::check_power) {
-        Ok(value) => value < thresholds.low_power,
-        Err(error) => {
-            log::error!("Failed to get low power: {}", error);
-            false
-        }
-    };
-    let low_storage = status::check_storage() < thresholds.low_storage;
-    let template = HomeTemplate {
-        username: user.name().to_string(),
-        low_power,
-        low_storage,
-        updates_available: false,
-        restart_pending: RESTART_PENDING.load(std::sync::atomic::Ordering::Relaxed),
-        is_admin: user.admin(),
+    let owner = match User::fetch_user(requested).await {
+        Ok(value) => value,
+        Err(error) => return Err(failure_page(error.status(), error)),
     };
-    Html(render!(template)).into_response()
-}
-
-async fn get_settings(session: Session) -> impl IntoResponse {
-    let user = get_current_user!(session);
-
-    if !user.admin() {
-        return (
-            StatusCode::FORBIDDEN,
-            "Forbidden only system admins can access this page",
-        )
-            .into_response();
+    if let Err(message) = check_owner(user, &owner) {
+        return Err(error_page(StatusCode::FORBIDDEN, message));
     }
-
-    let settings = settings::load_settings().await;
-    let template: SettingsTemplate = settings.into();
-    Html(render!(template)).into_response()
+    Ok((owner, true))
 }
 
-#[derive(Deserialize)]
-struct SettingsForm {
-    low_storage: u8,
-    low_power: u8,
-    #[serde(default)]
-    ignore_update: bool,
-    cert_path: String,
-    key_path: String,
-    hostname: String,
-    port: u16,
-}
-
-impl From<SettingsForm> for Settings {
-    fn from(val: SettingsForm) -> Self {
-        Settings {
-            port: val.port,
-            cert_path: val.cert_path.into(),
-            key_path: val.key_path.into(),
-            hostname: val.hostname,
-            ignore_updates: val.ignore_update,
-            threatsholds: settings::Threasholds {
-                low_power: val.low_power,
-                low_storage: val.low_storage,
-            },
-        }
+/// Checks if a user may reach the data of another user they have the permission for.
+///
+/// The data of admins, like their files, could be used to take over their account so only
+/// admins may reach it.
+fn check_owner(user: &User, owner: &User) -> Result<(), &'static str> {
+    if !user.may_manage(owner) {
+        return Err("Forbidden only system admins can access the data of admins");
     }
+    Ok(())
 }
 
-async fn post_settings(session: Session, Form(form): Form<SettingsForm>) -> impl IntoResponse {
-    let user = get_current_user!(session);
-
-    if !user.admin() {
-        return (
-            StatusCode::FORBIDDEN,
-            "Forbidden only system admins can access this page",
-        )
-            .into_response();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_errors_as_pages() {
+        let response = error_page(StatusCode::NOT_FOUND, "No Such Share");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(
+            response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("text/html"))
+        );
     }
 
-    settings::save_settings(form.into()).await;
-    Redirect::to("/settings").into_response()
-}
-
-async fn management(session: Session) -> impl IntoResponse {
-    let user = get_current_user!(session);
-
-    if !user.admin() {
-        return (
-            StatusCode::FORBIDDEN,
-            "Forbidden only system admins can access this page",
-        )
-            .into_response();
+    #[tokio::test]
+    async fn hides_the_details_of_internal_errors() {
+        let body = |response: axum::response::Response| async {
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(bytes.to_vec()).unwrap()
+        };
+        let internal = failure_page(StatusCode::INTERNAL_SERVER_ERROR, "disk on fire");
+        assert_eq!(internal.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let internal = body(internal).await;
+        assert!(internal.contains("Internal Server Error"));
+        assert!(!internal.contains("disk on fire"));
+        let forbidden = body(failure_page(StatusCode::FORBIDDEN, "Not Allowed")).await;
+        assert!(forbidden.contains("Not Allowed"));
     }
 
-    let users = err_response!(users::get_users().await)
-        .iter()
-        .map(|value| value.name().to_string())
-        .collect();
-    let template = ManageTemplate { users };
-    Html(render!(template)).into_response()
-}
-
-async fn get_user_settings(session: Session, Path(username): Path<String>) -> impl IntoResponse {
-    let user = get_current_user!(session);
-
-    if !user.admin() {
-        return (
-            StatusCode::FORBIDDEN,
-            "Forbidden only system admins can access this page",
-        )
-            .into_response();
+    fn user(name: &str, admin: bool) -> User {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "password": "",
+            "admin": admin,
+            "storage": 0,
+        }))
+        .unwrap()
     }
 
-    // utu stands for user to update
-    let utu = err_response!(users::User::fetch_user(&username).await);
-    let template = UserSettingsTemplate {
-        storage: utu.storage(),
-        admin: utu.admin(),
-        user: username.to_string(),
-    };
-    Html(render!(template)).into_response()
-}
-
-#[derive(Deserialize)]
-struct UserSettingsForm {
-    #[serde(default)]
-    admin: bool,
-    storage: usize,
-    password: String,
-}
-
-async fn post_user_settings(
-    session: Session,
-    Path(username): Path<String>,
-    Form(form): Form<UserSettingsForm>,
-) -> impl IntoResponse {
-    let user = get_current_user!(session);
-
-    if !user.admin() {
-        return (
-            StatusCode::FORBIDDEN,
-            "Forbidden only system admins can access this page",
-        )
-            .into_response();
+    #[test]
+    fn keeps_the_data_of_admins_from_everyone_else() {
+        let manager = user("manager", false);
+        let admin = user("root", true);
+        assert!(check_owner(&manager, &admin).is_err());
+        assert!(check_owner(&manager, &user("other", false)).is_ok());
+        assert!(check_owner(&admin, &user("admin2", true)).is_ok());
     }
 
-    // utu stands for user to update
-    let mut utu = err_response!(users::User::fetch_user(&username).await);
-    log::info!("admin: {}", form.admin);
-    *utu.admin_mut() = form.admin;
-    *utu.storage_mut() = form.storage;
-    if !form.password.trim().is_empty() {
-        let password = form.password.trim().to_string();
-        err_response!(utu.change_password(password).await);
-    } else {
-        err_response!(utu.save().await);
+    #[test]
+    fn reads_the_user_agent() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(user_agent(&headers), "");
+        headers.insert(header::USER_AGENT, "curl/8.5.0".parse().unwrap());
+        assert_eq!(user_agent(&headers), "curl/8.5.0");
     }
-    Redirect::to("/manage").into_response()
 }
@@ -0,0 +1,266 @@
+//! # Alerts
+//!
+//! This module contains the handlers for the pages to manage alert rules and notification channels.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, Query},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{error_page, failure_page, templates::*};
+use crate::{
+    alerts::{self, AlertRule, Channel, ChannelKind, Comparison, Metric, Severity, SmtpSecurity},
+    audit::{self, AuditAction, AuditEntry},
+    groups::{self, Permission},
+    provision, sessions,
+    users::{self, UserError},
+};
+
+/// The number of events shown on the alerts page.
+const ALERT_HISTORY_LEN: usize = 100;
+
+#[derive(Deserialize, Default)]
+pub struct AlertsQuery {
+    /// The channel a test notification was sent to.
+    #[serde(default)]
+    tested: String,
+}
+
+pub async fn get_alerts(session: Session, Query(query): Query<AlertsQuery>) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageSettings);
+
+    let config = err_response!(alerts::config().await);
+    let template = AlertsTemplate {
+        active: alerts::active().await,
+        rules: config.rules,
+        channels: config.channels,
+        history: err_response!(
+            alerts::history(ALERT_HISTORY_LEN)
+                .await
+                .map_err(UserError::from)
+        ),
+        tested: query.tested,
+        metrics: Metric::ALL,
+        severities: Severity::ALL,
+    };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AlertRuleForm {
+    name: String,
+    metric: Metric,
+    #[serde(default)]
+    subject: String,
+    comparison: Comparison,
+    threshold: f64,
+    duration: u64,
+    hysteresis: f64,
+    cooldown: u64,
+    severity: Severity,
+    /// The channels separated by commas, empty for all of them.
+    #[serde(default)]
+    channels: String,
+}
+
+pub async fn add_alert_rule(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<AlertRuleForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageSettings);
+
+    let rule = AlertRule {
+        name: form.name,
+        metric: form.metric,
+        subject: form.subject,
+        comparison: form.comparison,
+        threshold: form.threshold,
+        duration: form.duration,
+        hysteresis: form.hysteresis,
+        cooldown: form.cooldown,
+        severity: form.severity,
+        channels: provision::parse_groups(&form.channels),
+    };
+    let rule = match alerts::add_rule(rule).await {
+        Ok(value) => value,
+        Err(error) => err_response!(error.status(), format!("Failed to add rule: {error}")),
+    };
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::UpdateAlerts,
+        &format!("rule {} ({})", rule.name, rule.condition()),
+    ))
+    .await;
+    Redirect::to("/alerts").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AlertNameForm {
+    name: String,
+}
+
+pub async fn delete_alert_rule(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<AlertNameForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageSettings);
+
+    let rule = err_response!(alerts::delete_rule(&form.name).await);
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::UpdateAlerts,
+        &format!("deleted rule {}", rule.name),
+    ))
+    .await;
+    Redirect::to("/alerts").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AlertChannelForm {
+    name: String,
+    /// The type of the channel (`email`, `webhook`, `ntfy`, `gotify` or `wall`).
+    kind: String,
+    min_severity: Severity,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    server: String,
+    #[serde(default)]
+    port: String,
+    #[serde(default)]
+    security: SmtpSecurity,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    from: String,
+    #[serde(default)]
+    to: String,
+}
+
+pub async fn add_alert_channel(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<AlertChannelForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageSettings);
+
+    let url = form.url.trim().to_string();
+    let kind = match form.kind.as_str() {
+        "email" => {
+            let port = match form.port.trim() {
+                "" => match form.security {
+                    SmtpSecurity::None => 25,
+                    SmtpSecurity::StartTls => 587,
+                    SmtpSecurity::Tls => 465,
+                },
+                port => match port.parse() {
+                    Ok(value) => value,
+                    Err(_) => err_response!(
+                        StatusCode::BAD_REQUEST,
+                        format!("{port} is not a port number")
+                    ),
+                },
+            };
+            ChannelKind::Email {
+                server: form.server.trim().to_string(),
+                port,
+                security: form.security,
+                username: form.username.trim().to_string(),
+                password: form.password,
+                from: form.from.trim().to_string(),
+                to: form.to.trim().to_string(),
+            }
+        }
+        "webhook" => ChannelKind::Webhook { url },
+        "ntfy" => ChannelKind::Ntfy {
+            url,
+            token: form.token.trim().to_string(),
+        },
+        "gotify" => ChannelKind::Gotify {
+            url,
+            token: form.token.trim().to_string(),
+        },
+        "wall" => ChannelKind::Wall,
+        kind => err_response!(
+            StatusCode::BAD_REQUEST,
+            format!("{kind} is not a type of channel")
+        ),
+    };
+    let channel = Channel {
+        name: form.name,
+        min_severity: form.min_severity,
+        kind,
+    };
+    let channel = match alerts::add_channel(channel).await {
+        Ok(value) => value,
+        Err(error) => err_response!(error.status(), format!("Failed to add channel: {error}")),
+    };
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::UpdateAlerts,
+        &format!("channel {} ({})", channel.name, channel.kind_name()),
+    ))
+    .await;
+    Redirect::to("/alerts").into_response()
+}
+
+pub async fn delete_alert_channel(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<AlertNameForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageSettings);
+
+    let channel = match alerts::delete_channel(&form.name).await {
+        Ok(value) => value,
+        Err(error) => err_response!(error.status(), format!("Failed to delete channel: {error}")),
+    };
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::UpdateAlerts,
+        &format!("deleted channel {}", channel.name),
+    ))
+    .await;
+    Redirect::to("/alerts").into_response()
+}
+
+pub async fn test_alert_channel(
+    session: Session,
+    Form(form): Form<AlertNameForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageSettings);
+
+    if let Err(error) = alerts::test_channel(&form.name).await {
+        err_response!(error.status(), format!("Failed to send the test: {error}"));
+    }
+    let query = serde_urlencoded::to_string([("tested", &form.name)]).unwrap_or_default();
+    Redirect::to(&format!("/alerts?{query}")).into_response()
+}
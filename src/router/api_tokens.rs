@@ -0,0 +1,85 @@
+//! # API Tokens
+//!
+//! This module contains the handlers that create and revoke API tokens.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    extract::ConnectInfo,
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{IdForm, error_page, failure_page, templates::*};
+use crate::{
+    api_tokens,
+    audit::{self, AuditAction, AuditEntry},
+    sessions,
+    users::{self, UserError},
+};
+
+#[derive(Deserialize)]
+pub struct ApiTokenForm {
+    name: String,
+}
+
+pub async fn create_api_token(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<ApiTokenForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let name = form.name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        err_response!(
+            StatusCode::BAD_REQUEST,
+            "Token names must be between 1 and 64 characters"
+        );
+    }
+    let token = err_response!(
+        api_tokens::create(user.name(), name)
+            .await
+            .map_err(UserError::from)
+    );
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::CreateApiToken,
+        &format!("{} ({name})", user.name()),
+    ))
+    .await;
+    let template = ApiTokenTemplate {
+        username: user.name().to_string(),
+        name: name.to_string(),
+        token,
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn revoke_api_token(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<IdForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let revoked = err_response!(
+        api_tokens::revoke(user.name(), &form.id)
+            .await
+            .map_err(UserError::from)
+    );
+    if let Some(token) = revoked {
+        audit::record(AuditEntry::new(
+            user.name(),
+            address,
+            AuditAction::RevokeApiToken,
+            &format!("{} ({})", user.name(), token.name),
+        ))
+        .await;
+    }
+    Redirect::to("/sessions").into_response()
+}
@@ -0,0 +1,84 @@
+//! # Audit
+//!
+//! This module contains the handlers for the page of the audit log and its export.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    extract::{ConnectInfo, Query},
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Redirect},
+};
+use tower_sessions::Session;
+
+use super::{error_page, failure_page, templates::*};
+use crate::{
+    audit::{self, AuditAction, AuditEntry, AuditFilter},
+    groups::{self, Permission},
+    sessions, users,
+};
+
+/// The maximum number of audit entries shown on the audit page.
+const AUDIT_PAGE_LIMIT: usize = 500;
+
+pub async fn get_audit(session: Session, Query(filter): Query<AuditFilter>) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ViewAudit);
+
+    let entries = match audit::entries(&filter).await {
+        Ok(value) => value,
+        Err(error) => err_response!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read the audit log: {error}")
+        ),
+    };
+    let entries = entries.into_iter().rev().take(AUDIT_PAGE_LIMIT).collect();
+    let template = AuditTemplate {
+        entries,
+        actions: AuditAction::ALL
+            .iter()
+            .map(|value| value.to_string())
+            .collect(),
+        actor: filter.actor,
+        action: filter.action,
+        target: filter.target,
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn export_audit(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Query(filter): Query<AuditFilter>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ViewAudit);
+
+    let content = match audit::export(&filter).await {
+        Ok(value) => value,
+        Err(error) => err_response!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to export the audit log: {error}")
+        ),
+    };
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::ExportAuditLog,
+        "audit",
+    ))
+    .await;
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"audit.jsonl\"",
+            ),
+        ],
+        content,
+    )
+        .into_response()
+}
@@ -0,0 +1,449 @@
+//! # Auth
+//!
+//! This module contains the handlers for logging in and out, changing and resetting passwords and
+//! the sessions of a user.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, Path},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{IdForm, error_page, failure_page, templates::*, user_agent};
+use crate::{
+    api_tokens,
+    audit::{self, AuditAction, AuditEntry},
+    groups::{self, Permission},
+    logins::{self, AuthMethod, LoginRecord},
+    sessions, settings,
+    tokens::{self, TokenKind},
+    users::{self, UserError},
+};
+
+pub async fn index(session: Session) -> impl IntoResponse {
+    if sessions::validate(&session).await.is_some() {
+        Redirect::to("/home").into_response()
+    } else {
+        let template = IndexTemplate { error: None };
+        Html(render!(template)).into_response()
+    }
+}
+
+pub async fn logout(session: Session) -> impl IntoResponse {
+    if let Err(error) = sessions::end(&session).await {
+        log::error!("Failed to delete session: {}", error);
+    }
+    Redirect::to("/").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+pub async fn login(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<LoginForm>,
+) -> impl IntoResponse {
+    let username = form.username.trim();
+    let password = form.password.trim();
+    let lockout_key = logins::login_key(username, address);
+    if let Some(wait) = logins::begin_attempt(&lockout_key).await {
+        log::warn!(
+            "Refused login for locked out user {} from {}",
+            username,
+            address
+        );
+        let template = IndexTemplate {
+            error: Some(format!(
+                "Too many failed logins, try again in {} minutes",
+                wait.as_secs().div_ceil(60)
+            )),
+        };
+        return (StatusCode::TOO_MANY_REQUESTS, Html(render!(template))).into_response();
+    }
+    let user = users::User::login(username, password).await;
+    // Failed attempts for unknown users have nowhere to be stored so they are only logged
+    if let Ok(_)
+    | Err(UserError::PasswordError | UserError::AccountDisabled | UserError::AccountExpired) =
+        user
+    {
+        let record = LoginRecord::new(
+            address,
+            user_agent(&headers),
+            AuthMethod::Password,
+            user.is_ok(),
+        );
+        if let Err(error) = logins::record(username, record).await {
+            log::error!("Failed to record login for {}: {}", username, error);
+        }
+    } else if let Err(UserError::UserNotFound) = user {
+        logins::count_failure();
+        log::warn!(
+            "Failed login for unknown user {} from {}",
+            username,
+            address
+        );
+    }
+    match user {
+        Ok(mut user) => {
+            logins::clear_attempts(&lockout_key).await;
+            let policy = settings::load_settings().await.password_policy;
+            if user.password_expired(&policy) && !user.must_change_password() {
+                user = err_response!(
+                    users::User::update(user.name(), |user| {
+                        *user.must_change_password_mut() = true;
+                        Ok(())
+                    })
+                    .await
+                );
+            }
+            // Store only the username and session id
+            if let Err(error) =
+                sessions::start(&session, username, address, user_agent(&headers)).await
+            {
+                log::error!("Failed to start session: {}", error);
+                err_response!(StatusCode::INTERNAL_SERVER_ERROR, "Failed to start session");
+            }
+            if user.must_change_password() {
+                Redirect::to("/password").into_response()
+            } else {
+                Redirect::to("/home").into_response()
+            }
+        }
+        Err(error) => {
+            let message = match error {
+                UserError::PasswordError | UserError::UserNotFound => {
+                    "Invalid username or password"
+                }
+                UserError::AccountDisabled => "This account has been disabled",
+                UserError::AccountExpired => "This account has expired",
+                error => return failure_page(error.status(), error),
+            };
+            let template = IndexTemplate {
+                error: Some(message.to_string()),
+            };
+            Html(render!(template)).into_response()
+        }
+    }
+}
+
+/// The message shown for reset links that can not be used.
+const INVALID_LINK: &str = "This link is invalid, expired or has already been used";
+
+pub async fn get_reset(Path(token): Path<String>) -> impl IntoResponse {
+    let record = match tokens::find(&token).await {
+        Ok(Some(value)) => value,
+        Ok(None) => err_response!(StatusCode::NOT_FOUND, INVALID_LINK),
+        Err(error) => err_response!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to check the link: {error}")
+        ),
+    };
+    let policy = settings::load_settings().await.password_policy;
+    let template = ResetTemplate {
+        kind: record.kind,
+        username: record.username,
+        token,
+        rules: policy_rules(&policy),
+        error: None,
+    };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ResetForm {
+    new_password: String,
+    confirm_password: String,
+}
+
+pub async fn post_reset(
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    Form(form): Form<ResetForm>,
+) -> impl IntoResponse {
+    let record = match tokens::find(&token).await {
+        Ok(Some(value)) => value,
+        Ok(None) => err_response!(StatusCode::NOT_FOUND, INVALID_LINK),
+        Err(error) => err_response!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to check the link: {error}")
+        ),
+    };
+    let policy = settings::load_settings().await.password_policy;
+    let new_password = form.new_password.trim().to_string();
+    // The link is only used up once the password is known to be acceptable
+    let result = if new_password != form.confirm_password.trim() {
+        Err("The new passwords do not match".to_string())
+    } else {
+        match users::User::fetch_user(&record.username).await {
+            Ok(utu) => match utu.check_active() {
+                Ok(()) => match utu.check_new_password(&new_password, &policy).await {
+                    Ok(()) => Ok(utu),
+                    Err(error) => Err(error.to_string()),
+                },
+                Err(error) => Err(error.to_string()),
+            },
+            Err(error) => Err(error.to_string()),
+        }
+    };
+    let mut utu = match result {
+        Ok(value) => value,
+        Err(error) => {
+            let template = ResetTemplate {
+                kind: record.kind,
+                username: record.username,
+                token,
+                rules: policy_rules(&policy),
+                error: Some(error),
+            };
+            return (StatusCode::BAD_REQUEST, Html(render!(template))).into_response();
+        }
+    };
+    match tokens::redeem(&token, address).await {
+        Ok(Some(_)) => {}
+        Ok(None) => err_response!(StatusCode::NOT_FOUND, INVALID_LINK),
+        Err(error) => err_response!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to use the link: {error}")
+        ),
+    }
+    *utu.must_change_password_mut() = false;
+    if let Err(error) = utu.change_password(new_password, &policy).await {
+        log::error!(
+            "Failed to set password of {} due to error: {}",
+            record.username,
+            error
+        );
+        err_response!(
+            error.status(),
+            format!("Failed to set the password: {error}")
+        );
+    }
+    let action = match record.kind {
+        TokenKind::Reset => AuditAction::UseResetLink,
+        TokenKind::Invite => AuditAction::AcceptInvite,
+    };
+    audit::record(AuditEntry::new(
+        &record.username,
+        address,
+        action,
+        &record.username,
+    ))
+    .await;
+    // Whoever knew the old password should not stay logged in
+    sessions::revoke_user(&record.username, None).await;
+    Redirect::to("/").into_response()
+}
+
+/// Describes the password policy for the password page.
+fn policy_rules(policy: &settings::PasswordPolicy) -> Vec<String> {
+    let mut rules = vec![format!(
+        "At least {} characters long",
+        policy.min_length.max(1)
+    )];
+    if policy.require_lowercase {
+        rules.push("Contains a lowercase letter".to_string());
+    }
+    if policy.require_uppercase {
+        rules.push("Contains an uppercase letter".to_string());
+    }
+    if policy.require_digit {
+        rules.push("Contains a digit".to_string());
+    }
+    if policy.require_symbol {
+        rules.push("Contains a symbol".to_string());
+    }
+    if policy.reject_common {
+        rules.push("Is not a commonly used password".to_string());
+    }
+    if policy.history != 0 {
+        rules.push(format!(
+            "Is not one of your last {} passwords",
+            policy.history
+        ));
+    }
+    rules
+}
+
+pub async fn get_password(session: Session) -> impl IntoResponse {
+    let user = get_session_user!(session);
+
+    let policy = settings::load_settings().await.password_policy;
+    let template = PasswordTemplate {
+        forced: user.must_change_password(),
+        rules: policy_rules(&policy),
+        error: None,
+    };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    current_password: String,
+    new_password: String,
+    confirm_password: String,
+}
+
+pub async fn post_password(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<PasswordForm>,
+) -> impl IntoResponse {
+    let user = get_session_user!(session);
+
+    let policy = settings::load_settings().await.password_policy;
+    let new_password = form.new_password.trim().to_string();
+    let error = if new_password != form.confirm_password.trim() {
+        Some("The new passwords do not match".to_string())
+    } else {
+        match users::User::login(user.name(), form.current_password.trim()).await {
+            Ok(mut user) => {
+                *user.must_change_password_mut() = false;
+                match user.change_password(new_password, &policy).await {
+                    Ok(_) => None,
+                    Err(error) => Some(error.to_string()),
+                }
+            }
+            Err(UserError::PasswordError) => Some("The current password is wrong".to_string()),
+            Err(error) => Some(error.to_string()),
+        }
+    };
+    if let Some(error) = error {
+        let template = PasswordTemplate {
+            forced: user.must_change_password(),
+            rules: policy_rules(&policy),
+            error: Some(error),
+        };
+        return (StatusCode::BAD_REQUEST, Html(render!(template))).into_response();
+    }
+
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::ChangePassword,
+        user.name(),
+    ))
+    .await;
+    // Everywhere else is logged out since the old password might have been compromised
+    let keep = sessions::current_id(&session).await;
+    sessions::revoke_user(user.name(), keep.as_deref()).await;
+    Redirect::to("/home").into_response()
+}
+
+pub async fn get_sessions(session: Session) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let permissions = groups::permissions(&user).await;
+    let others = if permissions.contains(&Permission::ManageSessions) {
+        sessions::list(None)
+            .await
+            .into_iter()
+            .filter(|info| info.username != user.name())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let template = SessionsTemplate {
+        current: sessions::current_id(&session).await.unwrap_or_default(),
+        sessions: sessions::list(Some(user.name())).await,
+        others,
+        api_tokens: err_response!(api_tokens::list(user.name()).await.map_err(UserError::from)),
+        permissions,
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn revoke_session(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<IdForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let Some(info) = sessions::get(&form.id).await else {
+        return Redirect::to("/sessions").into_response();
+    };
+    if info.username != user.name()
+        && !groups::has_permission(&user, Permission::ManageSessions).await
+    {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden you can only revoke your own sessions"
+        );
+    }
+    sessions::revoke(&form.id).await;
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::RevokeSession,
+        &format!("{} ({})", info.username, info.ip),
+    ))
+    .await;
+    Redirect::to("/sessions").into_response()
+}
+
+pub async fn revoke_other_sessions(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let keep = sessions::current_id(&session).await;
+    let revoked = sessions::revoke_user(user.name(), keep.as_deref()).await;
+    if revoked > 0 {
+        audit::record(AuditEntry::new(
+            user.name(),
+            address,
+            AuditAction::RevokeSession,
+            &format!("{} ({} other sessions)", user.name(), revoked),
+        ))
+        .await;
+    }
+    Redirect::to("/sessions").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_password_rules() {
+        assert_eq!(
+            policy_rules(&settings::PasswordPolicy::default()),
+            [
+                "At least 8 characters long",
+                "Is not a commonly used password",
+                "Is not one of your last 5 passwords",
+            ]
+        );
+        let policy = settings::PasswordPolicy {
+            min_length: 0,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            reject_common: false,
+            history: 0,
+            max_age_days: 90,
+        };
+        assert_eq!(
+            policy_rules(&policy),
+            [
+                "At least 1 characters long",
+                "Contains a lowercase letter",
+                "Contains an uppercase letter",
+                "Contains a digit",
+                "Contains a symbol",
+            ]
+        );
+    }
+}
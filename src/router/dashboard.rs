@@ -0,0 +1,154 @@
+//! # Dashboard
+//!
+//! This module contains the handlers for the home page and the status, metrics and history of the
+//! system.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Json,
+    extract::{ConnectInfo, Query, State, WebSocketUpgrade},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{AppState, api_user, failure_page, templates::*};
+use crate::{
+    alerts, groups, history, live, logins, metrics, quota, sessions,
+    status::StatusReport,
+    users::{self, UserError},
+};
+
+/// The number of recent logins checked for new IPs on the dashboard.
+const RECENT_LOGINS: usize = 10;
+
+pub async fn home(session: Session, State(state): State<AppState>) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let status = StatusReport::clone(&state.status.borrow());
+    let usage = quota::UsageBar::new(
+        quota::usage(user.name()).await.unwrap_or_default(),
+        user.storage() as u64,
+    );
+    let recent_logins = logins::history(user.name()).await.unwrap_or_default();
+    let new_ip_logins = recent_logins
+        .iter()
+        .rev()
+        .filter(|record| record.success)
+        .take(RECENT_LOGINS)
+        .filter(|record| record.new_ip)
+        .cloned()
+        .collect();
+    let template = HomeTemplate {
+        new_ip_logins,
+        username: user.name().to_string(),
+        permissions: groups::permissions(&user).await,
+        usage,
+        status,
+        alerts: alerts::active()
+            .await
+            .into_iter()
+            .filter(|alert| alert.firing)
+            .collect(),
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn status_socket(
+    session: Session,
+    headers: HeaderMap,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    upgrade: WebSocketUpgrade,
+) -> impl IntoResponse {
+    get_api_user!(session, headers, address);
+
+    upgrade
+        .on_upgrade(move |socket| live::serve(socket, state.status))
+        .into_response()
+}
+
+pub async fn api_status(
+    session: Session,
+    headers: HeaderMap,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    get_api_user!(session, headers, address);
+
+    let system = state.status.borrow().system.clone();
+    Json(system).into_response()
+}
+
+pub async fn get_metrics(
+    session: Session,
+    headers: HeaderMap,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    get_api_user!(session, headers, address);
+
+    metrics::respond(&state.status).await
+}
+
+pub async fn get_history(session: Session) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let template = HistoryTemplate {
+        series: err_response!(history::list().await.map_err(UserError::from)),
+        permissions: groups::permissions(&user).await,
+    };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    /// The series to read, empty to list the series.
+    #[serde(default)]
+    series: String,
+    /// The start as a Unix timestamp, a day ago by default.
+    from: Option<i64>,
+    /// The end as a Unix timestamp, now by default.
+    to: Option<i64>,
+}
+
+pub async fn api_history(
+    session: Session,
+    headers: HeaderMap,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Query(query): Query<HistoryQuery>,
+) -> impl IntoResponse {
+    get_api_user!(session, headers, address);
+
+    // Programs get the plain errors instead of the error page
+    if query.series.is_empty() {
+        return match history::list().await {
+            Ok(series) => Json(series).into_response(),
+            Err(error) => UserError::from(error).into_response(),
+        };
+    }
+    let now = time::OffsetDateTime::now_utc();
+    let to = match query.to.map(time::OffsetDateTime::from_unix_timestamp) {
+        None => now,
+        Some(Ok(value)) => value,
+        Some(Err(_)) => return (StatusCode::BAD_REQUEST, "Invalid end").into_response(),
+    };
+    let from = match query.from.map(time::OffsetDateTime::from_unix_timestamp) {
+        None => to - time::Duration::DAY,
+        Some(Ok(value)) => value,
+        Some(Err(_)) => return (StatusCode::BAD_REQUEST, "Invalid start").into_response(),
+    };
+    if from >= to {
+        return (
+            StatusCode::BAD_REQUEST,
+            "The start has to be before the end",
+        )
+            .into_response();
+    }
+    match history::query(&query.series, from, to).await {
+        Ok(history) => Json(history).into_response(),
+        Err(error) => UserError::from(error).into_response(),
+    }
+}
@@ -0,0 +1,486 @@
+//! # Files
+//!
+//! This module contains the handlers for the file manager.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    body::Body,
+    extract::{ConnectInfo, Multipart, Query},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{error_page, failure_page, resource_owner, templates::*};
+use crate::{
+    audit::{self, AuditAction, AuditEntry},
+    files::{self, FileError, Preview},
+    groups::Permission,
+    quota, sessions, shares, users,
+};
+
+#[derive(Deserialize)]
+pub struct FilesQuery {
+    /// The path relative to the owner's data directory.
+    #[serde(default)]
+    path: String,
+    /// Whose files to use, empty for the user's own files.
+    #[serde(default)]
+    user: String,
+}
+
+/// Records access to the files of another user in the audit log.
+async fn audit_files(
+    user: &users::User,
+    address: SocketAddr,
+    action: AuditAction,
+    owner: &str,
+    path: &str,
+) {
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        action,
+        &format!("{owner}:/{}", path.trim_start_matches('/')),
+    ))
+    .await;
+}
+
+/// Builds the link to a directory of the file manager.
+fn files_url(owner: &str, foreign: bool, path: &str) -> String {
+    let mut query = vec![("path", path)];
+    if foreign {
+        query.push(("user", owner));
+    }
+    format!(
+        "/files?{}",
+        serde_urlencoded::to_string(query).unwrap_or_default()
+    )
+}
+
+/// Gets the directory a path is in, for going back after changing it.
+fn parent_path(path: &str) -> String {
+    path.trim_matches('/')
+        .rsplit_once('/')
+        .map(|(parent, _)| parent.to_string())
+        .unwrap_or_default()
+}
+
+pub async fn get_files(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Query(query): Query<FilesQuery>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &query.user, Permission::BrowseFiles);
+
+    let entries = match files::list(owner.name(), &query.path).await {
+        Ok(value) => value,
+        Err(error) => err_response!(error.status(), format!("Failed to list files: {error}")),
+    };
+    if foreign {
+        audit_files(
+            &user,
+            address,
+            AuditAction::BrowseFiles,
+            owner.name(),
+            &query.path,
+        )
+        .await;
+    }
+    let mut crumbs = Vec::new();
+    let mut current = String::new();
+    for component in query.path.split('/').filter(|value| !value.is_empty()) {
+        if !current.is_empty() {
+            current.push('/');
+        }
+        current.push_str(component);
+        crumbs.push((
+            component.to_string(),
+            files_url(owner.name(), foreign, &current),
+        ));
+    }
+    let template = FilesTemplate {
+        owner: owner.name().to_string(),
+        foreign,
+        path: query.path.trim_matches('/').to_string(),
+        root_url: files_url(owner.name(), foreign, ""),
+        crumbs,
+        entries,
+        usage: quota::UsageBar::new(
+            quota::usage(owner.name()).await.unwrap_or_default(),
+            owner.storage() as u64,
+        ),
+        max_share_days: shares::MAX_LIFETIME_DAYS,
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn upload_files(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Query(query): Query<FilesQuery>,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &query.user, Permission::BrowseFiles);
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(value)) => value,
+            Ok(None) => break,
+            Err(error) => err_response!(error.status(), format!("Failed to upload: {error}")),
+        };
+        if field.name() != Some("file") || field.file_name().is_none_or(str::is_empty) {
+            continue;
+        }
+        match files::upload(&owner, &query.path, field).await {
+            Ok(path) => {
+                if foreign {
+                    audit_files(
+                        &user,
+                        address,
+                        AuditAction::ModifyFiles,
+                        owner.name(),
+                        &path,
+                    )
+                    .await;
+                }
+            }
+            Err(error) => err_response!(error.status(), format!("Failed to upload: {error}")),
+        }
+    }
+    Redirect::to(&files_url(owner.name(), foreign, &query.path)).into_response()
+}
+
+/// Makes a file name safe to put in a `Content-Disposition` header.
+fn disposition_name(path: &std::path::Path) -> String {
+    path.file_name()
+        .map(|value| value.to_string_lossy().to_string())
+        .unwrap_or_default()
+        .chars()
+        .map(|value| {
+            if value.is_ascii_graphic() && value != '"' && value != '\\' || value == ' ' {
+                value
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Streams a file of a user, as an attachment or inline.
+///
+/// A single range of the file can be asked for with the `Range` header.
+pub async fn send_file(
+    owner: &str,
+    path: &str,
+    inline: bool,
+    headers: &HeaderMap,
+) -> axum::response::Response {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let target = err_response!(files::resolve(owner, path).await);
+    let mut file = err_response!(
+        tokio::fs::File::open(&target)
+            .await
+            .map_err(FileError::from)
+    );
+    let metadata = err_response!(file.metadata().await.map_err(FileError::from));
+    if metadata.is_dir() {
+        err_response!(StatusCode::BAD_REQUEST, FileError::WrongType);
+    }
+    let len = metadata.len();
+    let range = files::ByteRange::parse(
+        headers
+            .get(header::RANGE)
+            .and_then(|value| value.to_str().ok()),
+        len,
+    );
+    let (status, content_range, start, length) = match range {
+        files::ByteRange::Full => (StatusCode::OK, None, 0, len),
+        files::ByteRange::Partial(start, end) => (
+            StatusCode::PARTIAL_CONTENT,
+            Some(format!("bytes {start}-{end}/{len}")),
+            start,
+            end - start + 1,
+        ),
+        files::ByteRange::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+            )
+                .into_response();
+        }
+    };
+    if start != 0 {
+        err_response!(
+            file.seek(std::io::SeekFrom::Start(start))
+                .await
+                .map_err(FileError::from)
+        );
+    }
+    let content_type = if inline {
+        mime_guess::from_path(&target)
+            .first_or_octet_stream()
+            .to_string()
+    } else {
+        String::from("application/octet-stream")
+    };
+    let disposition = format!(
+        "{}; filename=\"{}\"",
+        if inline { "inline" } else { "attachment" },
+        disposition_name(&target)
+    );
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::ACCEPT_RANGES, String::from("bytes")),
+            // Uploaded files are never allowed to run anything in the browser
+            (header::X_CONTENT_TYPE_OPTIONS, String::from("nosniff")),
+            (header::CONTENT_SECURITY_POLICY, String::from("sandbox")),
+        ],
+        Body::from_stream(tokio_util::io::ReaderStream::new(file.take(length))),
+    )
+        .into_response();
+    if let Some(value) = content_range.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        response.headers_mut().insert(header::CONTENT_RANGE, value);
+    }
+    response
+}
+
+pub async fn download_file(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<FilesQuery>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &query.user, Permission::BrowseFiles);
+
+    if foreign {
+        audit_files(
+            &user,
+            address,
+            AuditAction::BrowseFiles,
+            owner.name(),
+            &query.path,
+        )
+        .await;
+    }
+    send_file(owner.name(), &query.path, false, &headers).await
+}
+
+pub async fn raw_file(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<FilesQuery>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &query.user, Permission::BrowseFiles);
+
+    // Only images are shown inline, anything else could be rendered as a page
+    if files::preview_kind(std::path::Path::new(&query.path)) != Preview::Image {
+        err_response!(StatusCode::BAD_REQUEST, FileError::WrongType);
+    }
+    if foreign {
+        audit_files(
+            &user,
+            address,
+            AuditAction::BrowseFiles,
+            owner.name(),
+            &query.path,
+        )
+        .await;
+    }
+    send_file(owner.name(), &query.path, true, &headers).await
+}
+
+pub async fn preview_file(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Query(query): Query<FilesQuery>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &query.user, Permission::BrowseFiles);
+
+    let target = match files::resolve(owner.name(), &query.path).await {
+        Ok(value) => value,
+        Err(error) => err_response!(error.status(), format!("Failed to open file: {error}")),
+    };
+    let kind = files::preview_kind(&target);
+    let mut truncated = false;
+    let text = if kind == Preview::Text {
+        use tokio::io::AsyncReadExt;
+        let file = err_response!(
+            tokio::fs::File::open(&target)
+                .await
+                .map_err(FileError::from)
+        );
+        let mut content = Vec::new();
+        err_response!(
+            file.take(files::PREVIEW_LIMIT + 1)
+                .read_to_end(&mut content)
+                .await
+                .map_err(FileError::from)
+        );
+        if content.len() as u64 > files::PREVIEW_LIMIT {
+            content.truncate(files::PREVIEW_LIMIT as usize);
+            truncated = true;
+        }
+        String::from_utf8_lossy(&content).to_string()
+    } else {
+        String::new()
+    };
+    if foreign {
+        audit_files(
+            &user,
+            address,
+            AuditAction::BrowseFiles,
+            owner.name(),
+            &query.path,
+        )
+        .await;
+    }
+    let query_string = {
+        let mut query_values = vec![("path", query.path.as_str())];
+        if foreign {
+            query_values.push(("user", owner.name()));
+        }
+        serde_urlencoded::to_string(query_values).unwrap_or_default()
+    };
+    let template = FilePreviewTemplate {
+        name: target
+            .file_name()
+            .map(|value| value.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        back_url: files_url(owner.name(), foreign, &parent_path(&query.path)),
+        query: query_string,
+        kind,
+        text,
+        truncated,
+    };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct FileActionForm {
+    #[serde(default)]
+    user: String,
+    /// The path the action is about, the parent directory when creating a folder.
+    #[serde(default)]
+    path: String,
+    /// The new name when renaming or creating a folder.
+    #[serde(default)]
+    name: String,
+}
+
+pub async fn create_folder(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<FileActionForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &form.user, Permission::BrowseFiles);
+
+    if let Err(error) = files::create_dir(owner.name(), &form.path, &form.name).await {
+        err_response!(error.status(), format!("Failed to create folder: {error}"));
+    }
+    if foreign {
+        audit_files(
+            &user,
+            address,
+            AuditAction::ModifyFiles,
+            owner.name(),
+            &format!("{}/{}", form.path, form.name.trim()),
+        )
+        .await;
+    }
+    Redirect::to(&files_url(owner.name(), foreign, &form.path)).into_response()
+}
+
+pub async fn rename_file(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<FileActionForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &form.user, Permission::BrowseFiles);
+
+    if let Err(error) = files::rename(owner.name(), &form.path, &form.name).await {
+        err_response!(error.status(), format!("Failed to rename: {error}"));
+    }
+    if foreign {
+        audit_files(
+            &user,
+            address,
+            AuditAction::ModifyFiles,
+            owner.name(),
+            &form.path,
+        )
+        .await;
+    }
+    Redirect::to(&files_url(owner.name(), foreign, &parent_path(&form.path))).into_response()
+}
+
+pub async fn delete_file(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<FileActionForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &form.user, Permission::BrowseFiles);
+
+    if let Err(error) = files::delete(owner.name(), &form.path).await {
+        err_response!(error.status(), format!("Failed to delete: {error}"));
+    }
+    if foreign {
+        audit_files(
+            &user,
+            address,
+            AuditAction::ModifyFiles,
+            owner.name(),
+            &form.path,
+        )
+        .await;
+    }
+    Redirect::to(&files_url(owner.name(), foreign, &parent_path(&form.path))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_to_the_files_of_the_owner() {
+        assert_eq!(files_url("alice", false, "a b/c"), "/files?path=a+b%2Fc");
+        assert_eq!(files_url("bob", true, ""), "/files?path=&user=bob");
+    }
+
+    #[test]
+    fn finds_the_parent_directory() {
+        assert_eq!(parent_path("/docs/notes/todo.txt"), "docs/notes");
+        assert_eq!(parent_path("docs/"), "");
+        assert_eq!(parent_path(""), "");
+    }
+
+    #[test]
+    fn quotes_safe_download_names() {
+        assert_eq!(
+            disposition_name(std::path::Path::new("dir/my report.pdf")),
+            "my report.pdf"
+        );
+        assert_eq!(
+            disposition_name(std::path::Path::new("say \"hi\"\\ü.txt")),
+            "say _hi___.txt"
+        );
+        assert_eq!(disposition_name(std::path::Path::new("/")), "");
+    }
+}
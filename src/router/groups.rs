@@ -0,0 +1,178 @@
+//! # Groups
+//!
+//! This module contains the handlers for the pages to manage groups and their permissions.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, Path},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{error_page, failure_page, templates::*};
+use crate::{
+    audit::{self, AuditAction, AuditEntry},
+    groups::{self, Permission},
+    quota, sessions, users,
+};
+
+pub async fn get_groups(session: Session) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageGroups);
+
+    let mut list = Vec::new();
+    for group in err_response!(groups::list().await) {
+        let usage = if group.storage == 0 {
+            None
+        } else {
+            let mut used = 0u64;
+            for member in &group.members {
+                used = used.saturating_add(quota::usage(member).await.unwrap_or_default());
+            }
+            Some(quota::UsageBar::new(used, group.storage as u64))
+        };
+        list.push((group, usage));
+    }
+    let template = GroupsTemplate { groups: list };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct CreateGroupForm {
+    name: String,
+}
+
+pub async fn create_group(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<CreateGroupForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageGroups);
+
+    let group = match groups::create(&form.name).await {
+        Ok(value) => value,
+        Err(error) => err_response!(
+            error.status(),
+            format!("Failed to create group {}: {error}", form.name.trim())
+        ),
+    };
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::CreateGroup,
+        &group.name,
+    ))
+    .await;
+    Redirect::to(&format!("/groups/{}", group.name)).into_response()
+}
+
+pub async fn get_group(session: Session, Path(name): Path<String>) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageGroups);
+
+    let group = err_response!(groups::get(&name).await);
+    let mut users: Vec<String> = err_response!(users::get_users().await)
+        .iter()
+        .map(|value| value.name().to_string())
+        .collect();
+    users.sort();
+    let template = GroupTemplate {
+        group,
+        users,
+        can_edit_permissions: user.admin(),
+    };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct GroupForm {
+    storage: usize,
+    #[serde(default)]
+    members: Vec<String>,
+    #[serde(default)]
+    permissions: Vec<Permission>,
+}
+
+pub async fn post_group(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    axum_extra::extract::Form(form): axum_extra::extract::Form<GroupForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageGroups);
+
+    let before = err_response!(groups::get(&name).await);
+    let mut after = before.clone();
+    after.storage = form.storage;
+    after.members = form.members.into_iter().collect();
+    after.permissions = form.permissions.into_iter().collect();
+    // Granting permissions is reserved to admins, or members could grant themselves anything
+    if after.permissions != before.permissions && !user.admin() {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can change group permissions"
+        );
+    }
+    // Adding someone to a group hands out its permissions, so that is limited the same way
+    if after.members != before.members && before.grants_beyond(&groups::permissions(&user).await) {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can change the members of groups with permissions you do not have"
+        );
+    }
+    for member in &after.members {
+        if let Err(error) = users::User::fetch_user(member).await {
+            err_response!(error.status(), format!("Can not add {member}: {error}"));
+        }
+    }
+    let entry = AuditEntry::new(user.name(), address, AuditAction::UpdateGroup, &name)
+        .with_diff(&before, &after);
+    if let Err(error) = groups::update(after).await {
+        err_response!(
+            error.status(),
+            format!("Failed to save group {name}: {error}")
+        );
+    }
+    if !entry.changes.is_empty() {
+        audit::record(entry).await;
+    }
+    Redirect::to("/groups").into_response()
+}
+
+pub async fn delete_group(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageGroups);
+
+    let group = err_response!(groups::get(&name).await);
+    // Deleting takes the group's permissions away from its members
+    if !group.permissions.is_empty() && !user.admin() {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can delete groups with permissions"
+        );
+    }
+    err_response!(groups::delete(&name).await);
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::DeleteGroup,
+        &name,
+    ))
+    .await;
+    Redirect::to("/groups").into_response()
+}
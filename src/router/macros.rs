@@ -0,0 +1,115 @@
+//! # Macros
+//!
+//! This module contains the macros shared by the handlers.
+
+/// Renders a template or returns an error response.
+macro_rules! render {
+    ($template:expr) => {
+        match $template.render() {
+            Ok(value) => value,
+            Err(error) => {
+                log::error!("Failed to render template: {}", error);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render page")
+                    .into_response();
+            }
+        }
+    };
+}
+
+/// Returns the error page from a handler.
+///
+/// Takes a status code and a message, or a `Result` that is unwrapped. Errors of a `Result` are
+/// logged and shown with their own status code, internal ones without their details.
+macro_rules! err_response {
+    ($status:expr, $message:expr) => {
+        return error_page($status, $message)
+    };
+    ($result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(error) => return failure_page(error.status(), error),
+        }
+    };
+}
+
+/// Spawns a blocking task and returns its result or an error response.
+macro_rules! tokio_blocking {
+    ($task:expr) => {
+        match tokio::task::spawn_blocking($task).await {
+            Ok(value) => value,
+            Err(error) => {
+                log::error!("Failed to handle request due to runtime error: {}", &error);
+                err_response!(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to handle request due to runtime error"
+                );
+            }
+        }
+    };
+}
+
+/// Gets the user logged into the session or redirects to the login page.
+///
+/// Sessions of users that were disabled or expired since logging in are ended here.
+macro_rules! get_session_user {
+    ($session:expr) => {
+        if let Some(username) = sessions::validate(&$session).await {
+            let user = err_response!(users::User::fetch_user(&username).await);
+            if let Err(error) = user.check_active() {
+                log::info!("Ending session of {}: {}", username, error);
+                if let Err(error) = sessions::end(&$session).await {
+                    log::error!("Failed to end session: {}", error);
+                }
+                return Redirect::to("/").into_response();
+            }
+            user
+        } else {
+            return Redirect::to("/").into_response();
+        }
+    };
+}
+
+/// Gets the current user from the session or redirects to the login page.
+///
+/// Users that have to change their password are sent to the password page instead.
+macro_rules! get_current_user {
+    ($session:expr) => {{
+        let user = get_session_user!($session);
+        if user.must_change_password() {
+            return Redirect::to("/password").into_response();
+        }
+        user
+    }};
+}
+
+/// Gets the user of an API request or returns the error response.
+macro_rules! get_api_user {
+    ($session:expr, $headers:expr, $address:expr) => {
+        match api_user(&$session, &$headers, $address).await {
+            Ok(value) => value,
+            Err(response) => return response,
+        }
+    };
+}
+
+/// Returns a forbidden response if the user does not have a permission.
+macro_rules! require_permission {
+    ($user:expr, $permission:expr) => {
+        if !groups::has_permission(&$user, $permission).await {
+            err_response!(
+                StatusCode::FORBIDDEN,
+                "Forbidden you do not have permission to access this page"
+            );
+        }
+    };
+}
+
+/// Gets the owner of the data for a request or returns the error response.
+macro_rules! get_owner {
+    ($user:expr, $requested:expr, $permission:expr) => {
+        match resource_owner(&$user, $requested, $permission).await {
+            Ok(value) => value,
+            Err(response) => return response,
+        }
+    };
+}
@@ -0,0 +1,261 @@
+//! # Settings
+//!
+//! This module contains the handlers for the settings page.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    extract::ConnectInfo,
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{error_page, failure_page, templates::*};
+use crate::{
+    audit::{self, AuditAction, AuditEntry},
+    groups::{self, Permission},
+    provision, sessions,
+    settings::{self, Settings},
+    status, users,
+};
+
+pub async fn get_settings(session: Session) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageSettings);
+
+    let settings = settings::load_settings().await;
+    let mut template: SettingsTemplate = settings.into();
+    template.edit_accounts = user.admin();
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct SettingsForm {
+    low_storage: u8,
+    low_power: u8,
+    high_temperature: u8,
+    #[serde(default)]
+    ignore_update: bool,
+    cert_path: String,
+    key_path: String,
+    hostname: String,
+    port: u16,
+    idle_timeout: u64,
+    absolute_timeout: u64,
+    min_length: usize,
+    #[serde(default)]
+    require_lowercase: bool,
+    #[serde(default)]
+    require_uppercase: bool,
+    #[serde(default)]
+    require_digit: bool,
+    #[serde(default)]
+    require_symbol: bool,
+    #[serde(default)]
+    reject_common: bool,
+    password_history: usize,
+    max_age_days: u32,
+    memory_cost: u32,
+    time_cost: u32,
+    parallelism: u32,
+    #[serde(default)]
+    provisioning_enabled: bool,
+    #[serde(default)]
+    provisioning_dry_run: bool,
+    default_shell: String,
+    /// The default groups separated by commas.
+    #[serde(default)]
+    default_groups: String,
+    /// The groups accounts may be put in separated by commas.
+    #[serde(default)]
+    allowed_groups: String,
+    #[serde(default)]
+    create_home: bool,
+    #[serde(default)]
+    remove_home: bool,
+    quota_refresh_interval: u64,
+    #[serde(default)]
+    filesystem_quota: bool,
+    /// The thresholds of single mounts as `mount=percent` separated by commas.
+    #[serde(default)]
+    mount_thresholds: String,
+    #[serde(default)]
+    exclude_filesystems: String,
+    #[serde(default)]
+    exclude_mounts: String,
+    sample_interval: u64,
+    #[serde(default)]
+    metrics_listen: String,
+    #[serde(default)]
+    ups_server: String,
+    #[serde(default)]
+    ups_name: String,
+    #[serde(default)]
+    ups_shutdown_runtime: u64,
+    #[serde(default)]
+    ups_shutdown_command: settings::ShutdownCommand,
+}
+
+impl From<SettingsForm> for Settings {
+    fn from(val: SettingsForm) -> Self {
+        Settings {
+            port: val.port,
+            cert_path: val.cert_path.into(),
+            key_path: val.key_path.into(),
+            hostname: val.hostname,
+            ignore_updates: val.ignore_update,
+            threatsholds: settings::Threasholds {
+                low_power: val.low_power,
+                low_storage: val.low_storage,
+                // Parsed separately since it can fail
+                mounts: Default::default(),
+                high_temperature: val.high_temperature,
+            },
+            sessions: settings::SessionSettings {
+                idle_timeout: val.idle_timeout,
+                absolute_timeout: val.absolute_timeout,
+            },
+            password_policy: settings::PasswordPolicy {
+                min_length: val.min_length,
+                require_lowercase: val.require_lowercase,
+                require_uppercase: val.require_uppercase,
+                require_digit: val.require_digit,
+                require_symbol: val.require_symbol,
+                reject_common: val.reject_common,
+                history: val.password_history,
+                max_age_days: val.max_age_days,
+            },
+            argon2: settings::Argon2Settings {
+                memory_cost: val.memory_cost,
+                time_cost: val.time_cost,
+                parallelism: val.parallelism,
+            },
+            provisioning: settings::ProvisioningSettings {
+                enabled: val.provisioning_enabled,
+                dry_run: val.provisioning_dry_run,
+                default_shell: val.default_shell.trim().to_string(),
+                default_groups: provision::parse_groups(&val.default_groups),
+                allowed_groups: provision::parse_groups(&val.allowed_groups),
+                create_home: val.create_home,
+                remove_home: val.remove_home,
+                filesystem_quota: val.filesystem_quota,
+            },
+            quota: settings::QuotaSettings {
+                refresh_interval: val.quota_refresh_interval,
+            },
+            storage: settings::StorageSettings {
+                exclude_filesystems: provision::parse_groups(&val.exclude_filesystems),
+                exclude_mounts: provision::parse_groups(&val.exclude_mounts),
+            },
+            status: settings::StatusSettings {
+                sample_interval: val.sample_interval.max(1),
+            },
+            metrics: settings::MetricsSettings {
+                listen: val.metrics_listen.trim().to_string(),
+            },
+            ups: settings::UpsSettings {
+                server: val.ups_server.trim().to_string(),
+                name: val.ups_name.trim().to_string(),
+                shutdown_runtime: val.ups_shutdown_runtime,
+                shutdown_command: val.ups_shutdown_command,
+            },
+        }
+    }
+}
+
+pub async fn post_settings(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<SettingsForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageSettings);
+
+    let mounts = match status::parse_mount_thresholds(&form.mount_thresholds) {
+        Ok(value) => value,
+        Err(error) => err_response!(StatusCode::BAD_REQUEST, error),
+    };
+    let mut after: Settings = form.into();
+    after.threatsholds.mounts = mounts;
+    if !after.metrics.listen.is_empty() && after.metrics.listen.parse::<SocketAddr>().is_err() {
+        err_response!(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "{} is not an address like 127.0.0.1:9100",
+                after.metrics.listen
+            )
+        );
+    }
+    if !after.ups.server.is_empty() && after.ups.name.is_empty() {
+        err_response!(StatusCode::BAD_REQUEST, "The name of the UPS is missing");
+    }
+    // The groups of accounts decide what they can do on the system, sudo or docker give root
+    let current = settings::load_settings().await.provisioning;
+    if !user.admin()
+        && (after.provisioning.default_shell != current.default_shell
+            || after.provisioning.default_groups != current.default_groups
+            || after.provisioning.allowed_groups != current.allowed_groups)
+    {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can change the shell and groups of Linux accounts"
+        );
+    }
+    if after.provisioning.enabled {
+        let shells = tokio_blocking!(provision::shells);
+        if !shells.contains(&after.provisioning.default_shell) {
+            err_response!(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "{} is not listed in /etc/shells",
+                    after.provisioning.default_shell
+                )
+            );
+        }
+        if let Some(group) = after
+            .provisioning
+            .allowed_groups
+            .iter()
+            .find(|group| !users::valid_username(group))
+        {
+            err_response!(StatusCode::BAD_REQUEST, format!("Invalid group {group}"));
+        }
+        let provisioning = &after.provisioning;
+        err_response!(provision::check_account(
+            provisioning,
+            &shells,
+            &provisioning.default_shell,
+            &provisioning.default_groups
+        ));
+    }
+    // The hashing parameters apply right away so bad ones are refused before they are saved
+    if let Err(error) = users::configure_hasher(&after.argon2) {
+        err_response!(StatusCode::BAD_REQUEST, error);
+    }
+    let before = match settings::save_settings(after.clone()).await {
+        Ok(value) => value,
+        Err(error) => {
+            log::error!("Failed to save settings due to error: {}", error);
+            err_response!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to save settings: {error}")
+            );
+        }
+    };
+    audit::record(
+        AuditEntry::new(
+            user.name(),
+            address,
+            AuditAction::UpdateSettings,
+            "settings",
+        )
+        .with_diff(&before, &after),
+    )
+    .await;
+    Redirect::to("/settings").into_response()
+}
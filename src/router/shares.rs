@@ -0,0 +1,249 @@
+//! # Shares
+//!
+//! This module contains the handlers for share links and the public pages they lead to.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, Path},
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{IdForm, error_page, failure_page, files::send_file, templates::*, user_agent};
+use crate::{
+    audit::{self, AuditAction, AuditEntry},
+    files,
+    logins::{self, AuthMethod, LoginRecord},
+    sessions, shares,
+    users::{self, UserError},
+};
+
+#[derive(Deserialize)]
+pub struct ShareForm {
+    path: String,
+    expires_in_days: u32,
+    #[serde(default)]
+    password: String,
+    /// Empty for no limit.
+    #[serde(default)]
+    max_downloads: String,
+}
+
+pub async fn create_share(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<ShareForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    if !(1..=shares::MAX_LIFETIME_DAYS).contains(&form.expires_in_days) {
+        err_response!(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Shares can be valid for 1 to {} days",
+                shares::MAX_LIFETIME_DAYS
+            )
+        );
+    }
+    let max_downloads = match form.max_downloads.trim() {
+        "" => None,
+        value => match value.parse::<u32>() {
+            Ok(value) if value > 0 => Some(value),
+            _ => {
+                err_response!(
+                    StatusCode::BAD_REQUEST,
+                    "The download limit has to be a positive number"
+                );
+            }
+        },
+    };
+    let entry = match files::entry(user.name(), &form.path).await {
+        Ok(value) => value,
+        Err(error) => err_response!(error.status(), format!("Failed to share: {error}")),
+    };
+    if entry.is_dir {
+        err_response!(StatusCode::BAD_REQUEST, "Only files can be shared");
+    }
+    let password = if form.password.is_empty() {
+        None
+    } else {
+        Some(err_response!(users::hash_password(form.password).await))
+    };
+    let token = err_response!(
+        shares::create(
+            user.name(),
+            &entry.path,
+            form.expires_in_days,
+            password,
+            max_downloads
+        )
+        .await
+        .map_err(UserError::from)
+    );
+    let Some(share) = err_response!(shares::find(&token).await.map_err(UserError::from)) else {
+        err_response!(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create share");
+    };
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::CreateShare,
+        &format!("{}:/{}", user.name(), entry.path),
+    ))
+    .await;
+    let template = ShareLinkTemplate {
+        share,
+        path: format!("/s/{token}"),
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn get_shares(session: Session) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let template = SharesTemplate {
+        shares: err_response!(shares::list(user.name()).await.map_err(UserError::from)),
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn revoke_share(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<IdForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    let revoked = err_response!(
+        shares::revoke(user.name(), &form.id)
+            .await
+            .map_err(UserError::from)
+    );
+    if let Some(share) = revoked {
+        audit::record(AuditEntry::new(
+            user.name(),
+            address,
+            AuditAction::RevokeShare,
+            &format!("{}:/{}", user.name(), share.path),
+        ))
+        .await;
+    }
+    Redirect::to("/shares").into_response()
+}
+
+/// The message shown for share links that can't be used, it doesn't say why.
+const SHARE_GONE: &str = "This link has expired or does not exist";
+
+pub async fn get_share(
+    session: Session,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(share) = err_response!(shares::find(&token).await.map_err(UserError::from)) else {
+        err_response!(StatusCode::NOT_FOUND, SHARE_GONE);
+    };
+    if share.password.is_some() && !shares::unlocked(&session, &share.id).await {
+        let template = SharePasswordTemplate {
+            name: share.name().to_string(),
+            error: None,
+        };
+        return Html(render!(template)).into_response();
+    }
+    // Shares of disabled or expired accounts stop working with the account
+    let active = users::User::fetch_user(&share.owner)
+        .await
+        .and_then(|owner| owner.check_active());
+    let Ok(entry) = files::entry(&share.owner, &share.path).await else {
+        err_response!(StatusCode::NOT_FOUND, SHARE_GONE);
+    };
+    if active.is_err() || entry.is_dir {
+        err_response!(StatusCode::NOT_FOUND, SHARE_GONE);
+    }
+    // Resumed downloads ask for the rest of the file and are not counted again
+    let range = files::ByteRange::parse(
+        headers
+            .get(header::RANGE)
+            .and_then(|value| value.to_str().ok()),
+        entry.size,
+    );
+    if range.starts_at_beginning() {
+        let recorded = err_response!(
+            shares::record_download(&token)
+                .await
+                .map_err(UserError::from)
+        );
+        if recorded.is_none() {
+            err_response!(StatusCode::NOT_FOUND, SHARE_GONE);
+        }
+    }
+    send_file(&share.owner, &share.path, false, &headers).await
+}
+
+#[derive(Deserialize)]
+pub struct SharePasswordForm {
+    password: String,
+}
+
+pub async fn unlock_share(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+    Form(form): Form<SharePasswordForm>,
+) -> impl IntoResponse {
+    let Some(share) = err_response!(shares::find(&token).await.map_err(UserError::from)) else {
+        err_response!(StatusCode::NOT_FOUND, SHARE_GONE);
+    };
+    let Some(hash) = share.password.clone() else {
+        return Redirect::to(&format!("/s/{token}")).into_response();
+    };
+    // Guesses are limited per visitor so guessing from one address does not lock out the rest
+    let lockout_key = format!("share:{}:{}", share.id, address.ip());
+    if let Some(wait) = logins::begin_attempt(&lockout_key).await {
+        log::warn!(
+            "Refused password for locked share {} of {} from {}",
+            share.id,
+            share.owner,
+            address
+        );
+        let template = SharePasswordTemplate {
+            name: share.name().to_string(),
+            error: Some(format!(
+                "Too many wrong passwords, try again in {} minutes",
+                wait.as_secs().div_ceil(60)
+            )),
+        };
+        return (StatusCode::TOO_MANY_REQUESTS, Html(render!(template))).into_response();
+    }
+    if !err_response!(users::verify_password(form.password, hash).await) {
+        log::warn!(
+            "Wrong password for share {} of {} from {}",
+            share.id,
+            share.owner,
+            address
+        );
+        let record = LoginRecord::new(
+            address,
+            user_agent(&headers),
+            AuthMethod::SharePassword,
+            false,
+        );
+        if let Err(error) = logins::record(&share.owner, record).await {
+            log::error!("Failed to record login for {}: {}", share.owner, error);
+        }
+        let template = SharePasswordTemplate {
+            name: share.name().to_string(),
+            error: Some("Wrong password".to_string()),
+        };
+        return (StatusCode::UNAUTHORIZED, Html(render!(template))).into_response();
+    }
+    logins::clear_attempts(&lockout_key).await;
+    if let Err(error) = shares::unlock(&session, &share.id).await {
+        log::error!("Failed to unlock share: {}", error);
+        err_response!(StatusCode::INTERNAL_SERVER_ERROR, "Failed to unlock share");
+    }
+    Redirect::to(&format!("/s/{token}")).into_response()
+}
@@ -0,0 +1,169 @@
+//! # SSH Keys
+//!
+//! This module contains the handlers for the pages to manage the SSH keys of users.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, Query},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{error_page, failure_page, resource_owner, templates::*};
+use crate::{
+    audit::{self, AuditAction, AuditEntry},
+    groups::Permission,
+    sessions, settings,
+    ssh_keys::{self, SshKeyError},
+    users,
+};
+
+#[derive(Deserialize)]
+pub struct SshKeysQuery {
+    /// Whose keys to use, empty for the user's own keys.
+    #[serde(default)]
+    user: String,
+}
+
+/// Builds the link to the SSH keys page of a user.
+fn ssh_keys_url(owner: &str, foreign: bool) -> String {
+    if foreign {
+        format!(
+            "/ssh-keys?{}",
+            serde_urlencoded::to_string([("user", owner)]).unwrap_or_default()
+        )
+    } else {
+        String::from("/ssh-keys")
+    }
+}
+
+pub async fn get_ssh_keys(
+    session: Session,
+    Query(query): Query<SshKeysQuery>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &query.user, Permission::ManageUsers);
+
+    let template = SshKeysTemplate {
+        owner: owner.name().to_string(),
+        foreign,
+        keys: err_response!(ssh_keys::list(owner.name()).await),
+        provisioning: settings::load_settings().await.provisioning.enabled,
+    };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct SshKeyForm {
+    #[serde(default)]
+    user: String,
+    key: String,
+    #[serde(default)]
+    label: String,
+    #[serde(default)]
+    options: String,
+}
+
+/// Writes the keys of a user into their Linux account or shows why it failed.
+macro_rules! sync_ssh_keys {
+    ($user:expr) => {
+        let provisioning = settings::load_settings().await.provisioning;
+        if let Err(error) = ssh_keys::sync(&provisioning, $user).await {
+            log::error!("Failed to write SSH keys of {}: {}", $user.name(), error);
+            err_response!(
+                error.status(),
+                format!("The keys were saved but writing authorized_keys failed: {error}")
+            );
+        }
+    };
+}
+
+pub async fn add_ssh_key(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<SshKeyForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &form.user, Permission::ManageUsers);
+    // A key is a way into the account, so only admins can change the keys of admins
+    if !user.may_manage(&owner) {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can change the SSH keys of admins"
+        );
+    }
+
+    let key = match ssh_keys::parse(&form.key, &form.options, &form.label) {
+        Ok(value) => value,
+        Err(error) => err_response!(error.status(), format!("Failed to add key: {error}")),
+    };
+    let key = match ssh_keys::add(owner.name(), key, user.name()).await {
+        Ok(value) => value,
+        Err(error) => err_response!(error.status(), format!("Failed to add key: {error}")),
+    };
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::AddSshKey,
+        &format!("{} ({} {})", owner.name(), key.label, key.fingerprint),
+    ))
+    .await;
+    sync_ssh_keys!(&owner);
+    Redirect::to(&ssh_keys_url(owner.name(), foreign)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct DeleteSshKeyForm {
+    #[serde(default)]
+    user: String,
+    fingerprint: String,
+}
+
+pub async fn delete_ssh_key(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<DeleteSshKeyForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+    let (owner, foreign) = get_owner!(user, &form.user, Permission::ManageUsers);
+    // A key is a way into the account, so only admins can change the keys of admins
+    if !user.may_manage(&owner) {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can change the SSH keys of admins"
+        );
+    }
+
+    let key = match ssh_keys::remove(owner.name(), &form.fingerprint).await {
+        Ok(value) => value,
+        // Removing a key twice is not an error
+        Err(SshKeyError::KeyNotFound) => {
+            return Redirect::to(&ssh_keys_url(owner.name(), foreign)).into_response();
+        }
+        Err(error) => return failure_page(error.status(), error),
+    };
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::RemoveSshKey,
+        &format!("{} ({} {})", owner.name(), key.label, key.fingerprint),
+    ))
+    .await;
+    sync_ssh_keys!(&owner);
+    Redirect::to(&ssh_keys_url(owner.name(), foreign)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_to_the_keys_of_the_owner() {
+        assert_eq!(ssh_keys_url("alice", false), "/ssh-keys");
+        assert_eq!(ssh_keys_url("bob smith", true), "/ssh-keys?user=bob+smith");
+    }
+}
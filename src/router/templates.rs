@@ -1,39 +1,295 @@
+use std::collections::HashSet;
+
 use askama::Template;
+
+use crate::{
+    alerts::{ActiveAlert, AlertEvent, AlertRule, Channel, Metric, Severity},
+    api_tokens::ApiToken,
+    audit::AuditEntry,
+    files::{Entry, Preview},
+    groups::{Group, Permission},
+    logins::LoginRecord,
+    provision::Drift,
+    quota::UsageBar,
+    sessions::SessionInfo,
+    settings::ShutdownCommand,
+    shares::Share,
+    ssh_keys::SshKey,
+    status::StatusReport,
+    tokens::{TokenKind, TokenRecord},
+};
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct IndexTemplate {
-    pub error: bool,
+    pub error: Option<String>,
+}
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorTemplate {
+    pub error: String,
 }
 #[derive(Template)]
 #[template(path = "home.html")]
 pub struct HomeTemplate {
     pub username: String,
-    pub low_power: bool,
-    pub low_storage: bool,
-    pub updates_available: bool,
-    pub restart_pending: bool,
-    pub is_admin: bool,
+    pub new_ip_logins: Vec<LoginRecord>,
+    pub permissions: HashSet<Permission>,
+    pub usage: UsageBar,
+    pub status: StatusReport,
+    /// The alerts that fired.
+    pub alerts: Vec<ActiveAlert>,
 }
 #[derive(Template)]
 #[template(path = "settings.html")]
 pub struct SettingsTemplate {
     pub low_storage: u8,
     pub low_power: u8,
+    /// The temperature in °C above which a sensor is too hot.
+    pub high_temperature: u8,
     pub ignore_update: bool,
     pub cert_path: String,
     pub key_path: String,
     pub port: u16,
     pub hostname: String,
+    pub idle_timeout: u64,
+    pub absolute_timeout: u64,
+    pub min_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub reject_common: bool,
+    pub password_history: usize,
+    pub max_age_days: u32,
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+    pub provisioning_enabled: bool,
+    pub provisioning_dry_run: bool,
+    pub default_shell: String,
+    /// The default groups separated by commas.
+    pub default_groups: String,
+    /// The groups accounts may be put in separated by commas.
+    pub allowed_groups: String,
+    /// If the shell and groups of accounts can be changed, only admins can change them.
+    pub edit_accounts: bool,
+    pub create_home: bool,
+    pub remove_home: bool,
+    pub quota_refresh_interval: u64,
+    pub filesystem_quota: bool,
+    /// The thresholds of single mounts as `mount=percent` separated by commas.
+    pub mount_thresholds: String,
+    pub exclude_filesystems: String,
+    pub exclude_mounts: String,
+    pub sample_interval: u64,
+    /// The address of the separate metrics listener, empty if there is none.
+    pub metrics_listen: String,
+    /// The NUT server, empty if no UPS is monitored.
+    pub ups_server: String,
+    pub ups_name: String,
+    pub ups_shutdown_runtime: u64,
+    pub ups_shutdown_command: ShutdownCommand,
+    pub shutdown_commands: [ShutdownCommand; 2],
 }
 #[derive(Template)]
 #[template(path = "management.html")]
 pub struct ManageTemplate {
-    pub users: Vec<String>,
+    pub users: Vec<UserSummary>,
+    /// The number of users whose password hash still uses old parameters.
+    pub outdated_hashes: usize,
+    /// The status the list is filtered by, empty for all users.
+    pub status: String,
+    /// The differences with the local accounts, `None` unless Linux accounts are managed.
+    pub drift: Option<Drift>,
+}
+/// A row in the user list on the management page.
+pub struct UserSummary {
+    pub name: String,
+    pub last_login: Option<LoginRecord>,
+    pub outdated_hash: bool,
+    pub disabled: bool,
+    pub expired: bool,
+    pub expires_at: Option<String>,
+    pub usage: UsageBar,
 }
 #[derive(Template)]
 #[template(path = "user_settings.html")]
 pub struct UserSettingsTemplate {
+    pub logins: Vec<LoginRecord>,
+    pub sessions: Vec<SessionInfo>,
+    pub tokens: Vec<TokenRecord>,
+    pub usage: UsageBar,
+    /// The names of the groups the user is a member of.
+    pub groups: Vec<String>,
+    /// If Linux accounts are managed.
+    pub provisioning: bool,
+    /// If the manager created the Linux account of the user.
+    pub provisioned: bool,
+    /// If the shell and groups can be changed, only admins can change them for other users.
+    pub edit_account: bool,
+    /// If the user has a Linux account.
+    pub system_account: bool,
+    pub shell: String,
+    pub shells: Vec<String>,
+    /// The supplementary groups separated by commas.
+    pub system_groups: String,
     pub storage: usize,
     pub admin: bool,
+    pub must_change_password: bool,
+    pub disabled: bool,
+    /// The expiry date as `YYYY-MM-DD`, empty for no expiry.
+    pub expires_at: String,
     pub user: String,
 }
+#[derive(Template)]
+#[template(path = "audit.html")]
+pub struct AuditTemplate {
+    pub entries: Vec<AuditEntry>,
+    pub actions: Vec<String>,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+}
+#[derive(Template)]
+#[template(path = "sessions.html")]
+pub struct SessionsTemplate {
+    pub current: String,
+    pub sessions: Vec<SessionInfo>,
+    pub others: Vec<SessionInfo>,
+    pub api_tokens: Vec<ApiToken>,
+    pub permissions: HashSet<Permission>,
+}
+#[derive(Template)]
+#[template(path = "password.html")]
+pub struct PasswordTemplate {
+    pub forced: bool,
+    pub rules: Vec<String>,
+    pub error: Option<String>,
+}
+#[derive(Template)]
+#[template(path = "token_link.html")]
+pub struct TokenLinkTemplate {
+    pub kind: TokenKind,
+    pub username: String,
+    /// The path of the link, the page adds the origin.
+    pub path: String,
+    pub expires: String,
+}
+#[derive(Template)]
+#[template(path = "api_token.html")]
+pub struct ApiTokenTemplate {
+    pub username: String,
+    pub name: String,
+    pub token: String,
+}
+#[derive(Template)]
+#[template(path = "reset.html")]
+pub struct ResetTemplate {
+    pub kind: TokenKind,
+    pub username: String,
+    pub token: String,
+    pub rules: Vec<String>,
+    pub error: Option<String>,
+}
+#[derive(Template)]
+#[template(path = "groups.html")]
+pub struct GroupsTemplate {
+    /// Every group with the usage of its shared storage, if it has a limit.
+    pub groups: Vec<(Group, Option<UsageBar>)>,
+}
+#[derive(Template)]
+#[template(path = "group.html")]
+pub struct GroupTemplate {
+    pub group: Group,
+    /// Every username, members or not.
+    pub users: Vec<String>,
+    pub can_edit_permissions: bool,
+}
+#[derive(Template)]
+#[template(path = "files.html")]
+pub struct FilesTemplate {
+    pub owner: String,
+    /// If the files belong to someone else than the user.
+    pub foreign: bool,
+    pub path: String,
+    pub root_url: String,
+    /// The name and link of every directory in the path.
+    pub crumbs: Vec<(String, String)>,
+    pub entries: Vec<Entry>,
+    pub usage: UsageBar,
+    pub max_share_days: u32,
+}
+#[derive(Template)]
+#[template(path = "file_preview.html")]
+pub struct FilePreviewTemplate {
+    pub name: String,
+    pub back_url: String,
+    /// The query string pointing at the file.
+    pub query: String,
+    pub kind: Preview,
+    pub text: String,
+    pub truncated: bool,
+}
+#[derive(Template)]
+#[template(path = "shares.html")]
+pub struct SharesTemplate {
+    pub shares: Vec<Share>,
+}
+#[derive(Template)]
+#[template(path = "share_link.html")]
+pub struct ShareLinkTemplate {
+    pub share: Share,
+    /// The path of the link, the page adds the origin.
+    pub path: String,
+}
+#[derive(Template)]
+#[template(path = "share.html")]
+pub struct SharePasswordTemplate {
+    pub name: String,
+    pub error: Option<String>,
+}
+#[derive(Template)]
+#[template(path = "ssh_keys.html")]
+pub struct SshKeysTemplate {
+    pub owner: String,
+    /// If the keys belong to someone else than the user.
+    pub foreign: bool,
+    pub keys: Vec<SshKey>,
+    /// If the keys are written into the user's Linux account.
+    pub provisioning: bool,
+}
+#[derive(Template)]
+#[template(path = "history.html")]
+pub struct HistoryTemplate {
+    /// The stored series.
+    pub series: Vec<String>,
+    pub permissions: HashSet<Permission>,
+}
+impl HistoryTemplate {
+    /// Checks if a series is stored.
+    pub fn has(&self, series: &str) -> bool {
+        self.series.iter().any(|value| value == series)
+    }
+
+    /// The mount points with a stored series.
+    pub fn mounts(&self) -> Vec<&str> {
+        self.series
+            .iter()
+            .filter_map(|value| value.strip_prefix("mount:"))
+            .collect()
+    }
+}
+#[derive(Template)]
+#[template(path = "alerts.html")]
+pub struct AlertsTemplate {
+    /// The alerts that fired or are pending.
+    pub active: Vec<ActiveAlert>,
+    pub rules: Vec<AlertRule>,
+    pub channels: Vec<Channel>,
+    /// The newest events, newest first.
+    pub history: Vec<AlertEvent>,
+    /// The channel a test notification was just sent to.
+    pub tested: String,
+    pub metrics: [Metric; 14],
+    pub severities: [Severity; 3],
+}
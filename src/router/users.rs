@@ -0,0 +1,524 @@
+//! # Users
+//!
+//! This module contains the handlers for the pages to manage users, their accounts and invite and
+//! reset links.
+use std::net::SocketAddr;
+
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, Path, Query},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect},
+};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use super::{error_page, failure_page, templates::*};
+use crate::{
+    api_tokens,
+    audit::{self, AuditAction, AuditEntry},
+    groups::{self, Permission},
+    logins, provision, quota, sessions, settings, shares, ssh_keys,
+    tokens::{self, TokenKind},
+    users,
+};
+
+#[derive(Deserialize, Default)]
+pub struct ManageQuery {
+    /// Only show users with this status (`active`, `disabled` or `expired`).
+    #[serde(default)]
+    status: String,
+}
+
+pub async fn management(session: Session, Query(query): Query<ManageQuery>) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageUsers);
+
+    let mut users = Vec::new();
+    for utu in err_response!(users::get_users().await) {
+        let shown = match query.status.as_str() {
+            "active" => utu.check_active().is_ok(),
+            "disabled" => utu.disabled(),
+            "expired" => utu.expired(),
+            _ => true,
+        };
+        if !shown {
+            continue;
+        }
+        users.push(UserSummary {
+            name: utu.name().to_string(),
+            last_login: logins::last_login(utu.name()).await,
+            outdated_hash: utu.hash_outdated(),
+            disabled: utu.disabled(),
+            expired: utu.expired(),
+            expires_at: utu.expires_at().map(format_date),
+            usage: quota::UsageBar::new(
+                quota::usage(utu.name()).await.unwrap_or_default(),
+                utu.storage() as u64,
+            ),
+        });
+    }
+    let outdated_hashes = users.iter().filter(|value| value.outdated_hash).count();
+    let drift = if settings::load_settings().await.provisioning.enabled {
+        let usernames: Vec<String> = err_response!(users::get_users().await)
+            .iter()
+            .map(|value| value.name().to_string())
+            .collect();
+        match tokio_blocking!(provision::system_accounts) {
+            Ok(accounts) => Some(provision::drift(&usernames, &accounts)),
+            Err(error) => {
+                log::error!("Failed to read the local accounts: {}", error);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let template = ManageTemplate {
+        users,
+        outdated_hashes,
+        status: query.status,
+        drift,
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn get_user_settings(
+    session: Session,
+    Path(username): Path<String>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageUsers);
+
+    // utu stands for user to update
+    let utu = err_response!(users::User::fetch_user(&username).await);
+    let mut logins = logins::history(&username).await.unwrap_or_default();
+    logins.reverse();
+    let provisioning = settings::load_settings().await.provisioning;
+    let system_account = if provisioning.enabled {
+        let accounts = tokio_blocking!(provision::system_accounts).unwrap_or_default();
+        accounts.iter().any(|account| account.name == utu.name())
+    } else {
+        false
+    };
+    let template = UserSettingsTemplate {
+        provisioning: provisioning.enabled,
+        provisioned: utu.provisioned(),
+        edit_account: user.admin() && user.name() != utu.name(),
+        system_account,
+        shell: utu
+            .shell()
+            .unwrap_or(&provisioning.default_shell)
+            .to_string(),
+        shells: tokio_blocking!(provision::shells),
+        system_groups: utu.system_groups().join(", "),
+        logins,
+        sessions: sessions::list(Some(&username)).await,
+        tokens: tokens::list(&username).await.unwrap_or_default(),
+        usage: quota::UsageBar::new(
+            quota::usage(&username).await.unwrap_or_default(),
+            utu.storage() as u64,
+        ),
+        groups: groups::of_user(&username)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|group| group.name)
+            .collect(),
+        storage: utu.storage(),
+        admin: utu.admin(),
+        must_change_password: utu.must_change_password(),
+        disabled: utu.disabled(),
+        expires_at: utu.expires_at().map(format_date).unwrap_or_default(),
+        user: username.to_string(),
+    };
+    Html(render!(template)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct UserSettingsForm {
+    #[serde(default)]
+    admin: bool,
+    storage: usize,
+    password: String,
+    #[serde(default)]
+    must_change_password: bool,
+    #[serde(default)]
+    disabled: bool,
+    /// The expiry date as `YYYY-MM-DD`, empty for no expiry.
+    #[serde(default)]
+    expires_at: String,
+    /// The login shell of the Linux account, empty to keep the current one.
+    #[serde(default)]
+    shell: String,
+    /// The supplementary groups of the Linux account separated by commas.
+    #[serde(default)]
+    system_groups: Option<String>,
+}
+
+/// The format of dates in forms.
+const DATE_FORMAT: &[time::format_description::FormatItem<'static>] =
+    time::macros::format_description!("[year]-[month]-[day]");
+
+/// Formats a date for a form.
+fn format_date(value: time::OffsetDateTime) -> String {
+    value.format(DATE_FORMAT).unwrap_or_default()
+}
+
+/// Parses a date from a form, accounts expire at the start of the day (UTC).
+fn parse_date(value: &str) -> Option<time::OffsetDateTime> {
+    time::Date::parse(value.trim(), DATE_FORMAT)
+        .ok()
+        .map(|date| date.midnight().assume_utc())
+}
+
+pub async fn post_user_settings(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+    Form(form): Form<UserSettingsForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageUsers);
+
+    // utu stands for user to update
+    let mut utu = err_response!(users::User::fetch_user(&username).await);
+    let before = utu.clone();
+    // Anyone else could take over an admin by changing their password
+    if !user.may_manage(&utu) {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can change admins"
+        );
+    }
+    // Only admins can hand out (or take away) admin, everyone else could make themselves one
+    if form.admin != utu.admin() && !user.admin() {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can change who is an admin"
+        );
+    }
+    *utu.admin_mut() = form.admin;
+    *utu.storage_mut() = form.storage;
+    *utu.must_change_password_mut() = form.must_change_password;
+    *utu.disabled_mut() = form.disabled;
+    *utu.expires_at_mut() = if form.expires_at.trim().is_empty() {
+        None
+    } else {
+        match parse_date(&form.expires_at) {
+            Some(value) => Some(value),
+            None => err_response!(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid expiry date {}", form.expires_at)
+            ),
+        }
+    };
+    let provisioning = settings::load_settings().await.provisioning;
+    // The form shows the default shell for users without one, that is not a change
+    let shell = form.shell.trim();
+    if !shell.is_empty() && shell != utu.shell().unwrap_or(&provisioning.default_shell) {
+        *utu.shell_mut() = Some(shell.to_string());
+    }
+    if let Some(system_groups) = &form.system_groups {
+        *utu.system_groups_mut() = provision::parse_groups(system_groups);
+    }
+    err_response!(provision::check_edit(&user, &before, &utu));
+    let password = form.password.trim().to_string();
+    let password_changed = !password.is_empty();
+    let policy = settings::load_settings().await.password_policy;
+    // Checked before anything is changed so a bad password does not leave half an update
+    if password_changed && let Err(error) = utu.check_new_password(&password, &policy).await {
+        err_response!(
+            error.status(),
+            format!("Failed to save user {username}: {error}")
+        );
+    }
+    if provisioning.enabled
+        && let Err(error) = provision::sync(&provisioning, &before, &utu).await
+    {
+        log::error!("Failed to update account {}: {}", username, error);
+        err_response!(
+            error.status(),
+            format!("Failed to update the Linux account of {username}: {error}")
+        );
+    }
+    let mut entries = vec![
+        AuditEntry::new(user.name(), address, AuditAction::UpdateUser, &username)
+            .with_diff(&before, &utu),
+    ];
+    if password_changed {
+        entries.push(AuditEntry::new(
+            user.name(),
+            address,
+            AuditAction::ChangePassword,
+            &username,
+        ));
+    }
+    let changed = utu.clone();
+    let mut saved = users::User::update(&username, move |user| {
+        user.apply_settings(&changed);
+        Ok(())
+    })
+    .await
+    .map(|_| ());
+    if password_changed && saved.is_ok() {
+        saved = utu.change_password(password, &policy).await.map(|_| ());
+    }
+    if let Err(error) = saved {
+        log::error!("Failed to save user {} due to error: {}", username, error);
+        err_response!(
+            error.status(),
+            format!("Failed to save user {username}: {error}")
+        );
+    }
+    for entry in entries {
+        if entry.action != AuditAction::UpdateUser || !entry.changes.is_empty() {
+            audit::record(entry).await;
+        }
+    }
+    // Losing admin, getting a new password or being disabled logs the user out everywhere (but
+    // not the admin doing the change out of their own session)
+    if password_changed || (before.admin() && !form.admin) || form.disabled {
+        let keep = sessions::current_id(&session).await;
+        let revoked = sessions::revoke_user(&username, keep.as_deref()).await;
+        log::info!("Revoked {} sessions of {}", revoked, username);
+    }
+    Redirect::to("/manage").into_response()
+}
+
+pub async fn provision_user(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageUsers);
+
+    let provisioning = settings::load_settings().await.provisioning;
+    if !provisioning.enabled {
+        err_response!(
+            StatusCode::BAD_REQUEST,
+            "Managing Linux accounts is disabled in the settings".to_string()
+        );
+    }
+    let mut utu = err_response!(users::User::fetch_user(&username).await);
+    if !user.may_manage(&utu) {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can create accounts for admins"
+        );
+    }
+    if let Err(error) = provision::provision(&provisioning, &mut utu).await {
+        log::error!("Failed to create account {}: {}", username, error);
+        err_response!(
+            error.status(),
+            format!("Failed to create the Linux account of {username}: {error}")
+        );
+    }
+    // A disabled user should not get a working account
+    if utu.disabled()
+        && let Err(error) = provision::lock(&provisioning, &username, true).await
+    {
+        log::error!("Failed to lock account {}: {}", username, error);
+    }
+    if let Err(error) = ssh_keys::sync(&provisioning, &utu).await {
+        log::error!("Failed to write SSH keys of {}: {}", username, error);
+    }
+    err_response!(
+        users::User::update(&username, move |user| {
+            user.apply_settings(&utu);
+            Ok(())
+        })
+        .await
+    );
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::ProvisionAccount,
+        &username,
+    ))
+    .await;
+    Redirect::to(&format!("/manage/{username}")).into_response()
+}
+
+pub async fn delete_user(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageUsers);
+
+    let utu = err_response!(users::User::fetch_user(&username).await);
+    if utu.name() == user.name() {
+        err_response!(
+            StatusCode::BAD_REQUEST,
+            "You can not delete your own account".to_string()
+        );
+    }
+    if !user.may_manage(&utu) {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can delete admins"
+        );
+    }
+    let provisioning = settings::load_settings().await.provisioning;
+    if provisioning.enabled
+        && let Err(error) = provision::deprovision(&provisioning, &utu).await
+    {
+        log::error!("Failed to remove account {}: {}", username, error);
+        err_response!(
+            error.status(),
+            format!("Failed to remove the Linux account of {username}: {error}")
+        );
+    }
+    err_response!(utu.delete().await);
+    quota::forget(&username).await;
+    if let Err(error) = shares::forget(&username).await {
+        log::error!("Failed to revoke the shares of {}: {}", username, error);
+    }
+    if let Err(error) = api_tokens::forget(&username).await {
+        log::error!("Failed to revoke the API tokens of {}: {}", username, error);
+    }
+    if let Err(error) = groups::remove_member(&username).await {
+        log::error!("Failed to remove {} from their groups: {}", username, error);
+    }
+    sessions::revoke_user(&username, None).await;
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::DeleteUser,
+        &username,
+    ))
+    .await;
+    Redirect::to("/manage").into_response()
+}
+
+/// Creates a token and renders the page showing its link.
+async fn token_link(kind: TokenKind, username: &str, created_by: &str) -> axum::response::Response {
+    let token = match tokens::create(kind, username, created_by).await {
+        Ok(value) => value,
+        Err(error) => {
+            log::error!("Failed to create {} link for {}: {}", kind, username, error);
+            err_response!(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to create the {kind} link: {error}")
+            )
+        }
+    };
+    let expires = time::OffsetDateTime::now_utc() + kind.lifetime();
+    let template = TokenLinkTemplate {
+        kind,
+        username: username.to_string(),
+        path: format!("/reset/{token}"),
+        expires: expires
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default(),
+    };
+    Html(render!(template)).into_response()
+}
+
+pub async fn create_reset_link(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(username): Path<String>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageUsers);
+
+    // Make sure the user exists before handing out a link for it
+    let utu = err_response!(users::User::fetch_user(&username).await);
+    // The link sets a new password, so it is as good as the password itself
+    if !user.may_manage(&utu) {
+        err_response!(
+            StatusCode::FORBIDDEN,
+            "Forbidden only system admins can reset the password of admins"
+        );
+    }
+    let response = token_link(TokenKind::Reset, utu.name(), user.name()).await;
+    if response.status().is_success() {
+        audit::record(AuditEntry::new(
+            user.name(),
+            address,
+            AuditAction::CreateResetLink,
+            utu.name(),
+        ))
+        .await;
+    }
+    response
+}
+
+#[derive(Deserialize)]
+pub struct InviteForm {
+    username: String,
+}
+
+pub async fn invite_user(
+    session: Session,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Form(form): Form<InviteForm>,
+) -> impl IntoResponse {
+    let user = get_current_user!(session);
+
+    require_permission!(user, Permission::ManageUsers);
+
+    let mut invited = match users::User::invite(&form.username).await {
+        Ok(value) => value,
+        Err(error) => err_response!(
+            error.status(),
+            format!("Failed to invite {}: {error}", form.username.trim())
+        ),
+    };
+    let provisioning = settings::load_settings().await.provisioning;
+    if provisioning.enabled {
+        // Without its Linux account the user is not created at all
+        if let Err(error) = provision::provision(&provisioning, &mut invited).await {
+            log::error!("Failed to create account {}: {}", invited.name(), error);
+            let name = invited.name().to_string();
+            if let Err(error) = invited.delete().await {
+                log::error!("Failed to remove user {} again: {}", name, error);
+            }
+            err_response!(
+                error.status(),
+                format!("Failed to create the Linux account of {name}: {error}")
+            );
+        }
+        let provisioned = invited.clone();
+        err_response!(
+            users::User::update(invited.name(), move |user| {
+                user.apply_settings(&provisioned);
+                Ok(())
+            })
+            .await
+        );
+    }
+    audit::record(AuditEntry::new(
+        user.name(),
+        address,
+        AuditAction::InviteUser,
+        invited.name(),
+    ))
+    .await;
+    token_link(TokenKind::Invite, invited.name(), user.name()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_dates_it_formats() {
+        let date = parse_date(" 2026-03-09 ").unwrap();
+        assert_eq!(date, time::macros::datetime!(2026-03-09 00:00 UTC));
+        assert_eq!(format_date(date), "2026-03-09");
+        assert!(parse_date("").is_none());
+        assert!(parse_date("09/03/2026").is_none());
+    }
+}
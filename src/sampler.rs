@@ -0,0 +1,126 @@
+//! # Sampler
+//!
+//! This module keeps the status of the system up to date in the background.
+//!
+//! One task refreshes a shared [`System`], [`Disks`], [`Networks`] and batteries at the configured
+//! interval and publishes each report through a watch channel. Pages, the API and live dashboards
+//! read the latest report instead of probing the system on every request. The UPS is polled by a
+//! task of its own, each report takes its latest state.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use sysinfo::{Disks, MINIMUM_CPU_UPDATE_INTERVAL, Networks, System};
+use tokio::sync::watch;
+
+use crate::{
+    settings::{self, Settings},
+    status::{self, NetworkRate, PowerProbe, PowerStatus, StatusReport},
+    ups::{self, UpsReports},
+};
+
+/// The receiving end of the reports, always holding the latest one.
+pub type Reports = watch::Receiver<Arc<StatusReport>>;
+
+/// What is refreshed for every sample.
+struct Probes {
+    system: System,
+    disks: Disks,
+    networks: Networks,
+    /// `None` if the batteries can not be read on this system.
+    power: Option<PowerProbe>,
+    /// When the networks were last refreshed, to turn their counters into rates.
+    refreshed: Instant,
+}
+impl Probes {
+    /// Creates the probes, the CPU usage and network rates are only known after a refresh.
+    fn new() -> Self {
+        let mut system = System::new();
+        system.refresh_cpu_all();
+        Self {
+            system,
+            disks: Disks::new_with_refreshed_list(),
+            networks: Networks::new_with_refreshed_list(),
+            power: PowerProbe::new()
+                .inspect_err(|error| log::error!("Failed to read the batteries: {}", error))
+                .ok(),
+            refreshed: Instant::now(),
+        }
+    }
+}
+
+/// Refreshes the probes and builds a report from them.
+async fn sample(
+    mut probes: Probes,
+    settings: Settings,
+    ups: &UpsReports,
+) -> Result<(Probes, StatusReport), tokio::task::JoinError> {
+    let updates = status::pending_updates(&settings).await;
+    let ups = ups.borrow().clone();
+    tokio::task::spawn_blocking(move || {
+        probes.system.refresh_cpu_all();
+        probes.system.refresh_memory();
+        // Also picks up filesystems and interfaces that were added or removed
+        probes.disks.refresh(true);
+        probes.networks.refresh(true);
+        let network = NetworkRate::measure(&probes.networks, probes.refreshed.elapsed());
+        probes.refreshed = Instant::now();
+        let mut power = match &probes.power {
+            Some(power) => power.check().unwrap_or_else(|error| {
+                log::error!("Failed to get low power: {}", error);
+                PowerStatus::default()
+            }),
+            None => PowerStatus::default(),
+        };
+        power.ups = ups;
+        let report = status::report(
+            &probes.system,
+            &probes.disks,
+            network,
+            power,
+            &settings,
+            updates,
+        );
+        (probes, report)
+    })
+    .await
+}
+
+/// Takes the first report and starts sampling in the background.
+///
+/// This waits for [`MINIMUM_CPU_UPDATE_INTERVAL`] to measure the CPU usage.
+pub async fn spawn() -> Reports {
+    let probes = Probes::new();
+    let ups = ups::poll().await;
+    tokio::time::sleep(MINIMUM_CPU_UPDATE_INTERVAL).await;
+    let settings = settings::load_settings().await;
+    let mut interval = settings.status.sample_interval;
+    let (mut probes, report) = match sample(probes, settings, &ups).await {
+        Ok(value) => value,
+        Err(error) => {
+            log::error!("Failed to sample the status: {}", error);
+            std::process::exit(1);
+        }
+    };
+    let (sender, receiver) = watch::channel(Arc::new(report));
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+            let settings = settings::load_settings().await;
+            interval = settings.status.sample_interval;
+            match sample(probes, settings, &ups).await {
+                Ok((refreshed, report)) => {
+                    probes = refreshed;
+                    sender.send_replace(Arc::new(report));
+                }
+                Err(error) => {
+                    // The probes were lost with the task, the next report starts over
+                    log::error!("Failed to sample the status: {}", error);
+                    probes = Probes::new();
+                }
+            }
+        }
+    });
+    receiver
+}
@@ -0,0 +1,188 @@
+//! # Sessions
+//!
+//! This module keeps track of the logged in sessions of every user so they can be listed and
+//! revoked.
+//!
+//! Each session stores a random session id next to the username, a session is only valid while
+//! its id is still in the registry, so removing it logs the session out on its next request.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::RwLock;
+use tower_sessions::Session;
+
+use crate::tokens;
+
+/// The key the session id is stored under in the session.
+const SESSION_ID_KEY: &str = "sid";
+/// The key the username is stored under in the session.
+const USERNAME_KEY: &str = "username";
+
+/// The idle timeout in minutes, `0` disables it.
+static IDLE_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+/// The absolute timeout in minutes, `0` disables it.
+static ABSOLUTE_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// All the active sessions by session id.
+static SESSIONS: LazyLock<RwLock<HashMap<String, SessionInfo>>> = LazyLock::new(Default::default);
+
+/// Information about a logged in session.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub username: String,
+    pub created: OffsetDateTime,
+    pub last_activity: OffsetDateTime,
+    pub ip: String,
+    pub user_agent: String,
+}
+impl SessionInfo {
+    /// Formats the creation time for display.
+    pub fn created_time(&self) -> String {
+        self.created
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| self.created.to_string())
+    }
+
+    /// Formats the last activity time for display.
+    pub fn last_activity_time(&self) -> String {
+        self.last_activity
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| self.last_activity.to_string())
+    }
+
+    /// Checks if the session has timed out.
+    fn expired(&self, now: OffsetDateTime) -> bool {
+        let idle = IDLE_TIMEOUT.load(Ordering::Relaxed);
+        let absolute = ABSOLUTE_TIMEOUT.load(Ordering::Relaxed);
+        (idle != 0 && now - self.last_activity > Duration::minutes(idle as i64))
+            || (absolute != 0 && now - self.created > Duration::minutes(absolute as i64))
+    }
+}
+
+/// Sets the session timeouts in minutes, `0` disables a timeout.
+pub fn configure(idle_timeout: u64, absolute_timeout: u64) {
+    IDLE_TIMEOUT.store(idle_timeout, Ordering::Relaxed);
+    ABSOLUTE_TIMEOUT.store(absolute_timeout, Ordering::Relaxed);
+}
+
+/// Logs a user into a session and registers it.
+pub async fn start(
+    session: &Session,
+    username: &str,
+    address: SocketAddr,
+    user_agent: &str,
+) -> Result<(), tower_sessions::session::Error> {
+    // Never reuse a session id from before the login
+    session.cycle_id().await?;
+    let id = tokens::generate();
+    session.insert(USERNAME_KEY, username.to_string()).await?;
+    session.insert(SESSION_ID_KEY, id.clone()).await?;
+    let now = OffsetDateTime::now_utc();
+    let mut sessions = SESSIONS.write().await;
+    // Drop sessions that timed out without ever coming back
+    sessions.retain(|_, info| !info.expired(now));
+    sessions.insert(
+        id.clone(),
+        SessionInfo {
+            id,
+            username: username.to_string(),
+            created: now,
+            last_activity: now,
+            ip: address.ip().to_string(),
+            user_agent: user_agent.to_string(),
+        },
+    );
+    Ok(())
+}
+
+/// Gets the session id of the current session.
+pub async fn current_id(session: &Session) -> Option<String> {
+    session
+        .get::<String>(SESSION_ID_KEY)
+        .await
+        .unwrap_or_default()
+}
+
+/// Validates the current session and returns the logged in username.
+///
+/// Sessions that were revoked or timed out are flushed.
+pub async fn validate(session: &Session) -> Option<String> {
+    let username = session
+        .get::<String>(USERNAME_KEY)
+        .await
+        .unwrap_or_default()?;
+    let id = current_id(session).await;
+    let valid = {
+        let mut sessions = SESSIONS.write().await;
+        let now = OffsetDateTime::now_utc();
+        match id.as_ref().and_then(|id| sessions.get_mut(id)) {
+            Some(info) if info.username == username && !info.expired(now) => {
+                info.last_activity = now;
+                true
+            }
+            _ => {
+                if let Some(id) = &id {
+                    sessions.remove(id);
+                }
+                false
+            }
+        }
+    };
+    if valid {
+        Some(username)
+    } else {
+        if let Err(error) = session.flush().await {
+            log::error!("Failed to flush session: {}", error);
+        }
+        None
+    }
+}
+
+/// Ends the current session.
+pub async fn end(session: &Session) -> Result<(), tower_sessions::session::Error> {
+    if let Some(id) = current_id(session).await {
+        SESSIONS.write().await.remove(&id);
+    }
+    session.delete().await
+}
+
+/// Lists the active sessions of a user, or of every user if `None`.
+pub async fn list(username: Option<&str>) -> Vec<SessionInfo> {
+    let now = OffsetDateTime::now_utc();
+    let mut sessions: Vec<SessionInfo> = SESSIONS
+        .read()
+        .await
+        .values()
+        .filter(|info| username.is_none_or(|username| info.username == username))
+        .filter(|info| !info.expired(now))
+        .cloned()
+        .collect();
+    sessions.sort_by_key(|info| std::cmp::Reverse(info.last_activity));
+    sessions
+}
+
+/// Gets a session by id.
+pub async fn get(id: &str) -> Option<SessionInfo> {
+    SESSIONS.read().await.get(id).cloned()
+}
+
+/// Revokes a single session.
+pub async fn revoke(id: &str) -> Option<SessionInfo> {
+    SESSIONS.write().await.remove(id)
+}
+
+/// Revokes every session of a user except the one given, returns how many were revoked.
+pub async fn revoke_user(username: &str, keep: Option<&str>) -> usize {
+    let mut sessions = SESSIONS.write().await;
+    let before = sessions.len();
+    sessions.retain(|id, info| info.username != username || Some(id.as_str()) == keep);
+    before - sessions.len()
+}
@@ -1,12 +1,11 @@
 //! # Settings
 //!
 //! This module contains the logic for loading and saving the application settings.
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
-use tokio::fs;
 
-use crate::{RESTART_PENDING, router::templates::SettingsTemplate};
+use crate::{RESTART_PENDING, persist, router::templates::SettingsTemplate};
 
 /// The path to the settings file.
 #[cfg(not(debug_assertions))]
@@ -23,14 +22,201 @@ pub struct Settings {
     pub hostname: String,
     pub ignore_updates: bool,
     pub threatsholds: Threasholds,
+    #[serde(default)]
+    pub sessions: SessionSettings,
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    #[serde(default)]
+    pub argon2: Argon2Settings,
+    #[serde(default)]
+    pub provisioning: ProvisioningSettings,
+    #[serde(default)]
+    pub quota: QuotaSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+    #[serde(default)]
+    pub status: StatusSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub ups: UpsSettings,
 }
 impl Settings {}
 
+/// The rules new passwords have to follow.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Reject passwords found in the bundled list of common passwords.
+    pub reject_common: bool,
+    /// The number of previous passwords that can not be reused.
+    pub history: usize,
+    /// The number of days before a password has to be changed, `0` disables expiry.
+    pub max_age_days: u32,
+}
+
+/// The argon2 parameters used for hashing passwords.
+///
+/// Small boxes may want to lower these, big servers can afford to raise them.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Argon2Settings {
+    /// The memory cost in KiB.
+    pub memory_cost: u32,
+    /// The number of iterations.
+    pub time_cost: u32,
+    /// The degree of parallelism.
+    pub parallelism: u32,
+}
+
+/// How manager users are mirrored as Linux accounts.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ProvisioningSettings {
+    /// Create, lock and remove a Linux account along with each user.
+    pub enabled: bool,
+    /// Log the commands instead of running them.
+    pub dry_run: bool,
+    /// The login shell of new accounts.
+    pub default_shell: String,
+    /// The supplementary groups of new accounts.
+    pub default_groups: Vec<String>,
+    /// The supplementary groups accounts may be put in, no others are handed out since groups
+    /// like `sudo` give root.
+    pub allowed_groups: Vec<String>,
+    /// Create the home directory of new accounts.
+    pub create_home: bool,
+    /// Remove the home directory when an account is deleted.
+    pub remove_home: bool,
+    /// Also apply the storage quota to the account with `setquota`, needs quotas enabled on the
+    /// filesystem.
+    pub filesystem_quota: bool,
+}
+
+/// How storage quotas are measured and applied.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct QuotaSettings {
+    /// How often the storage of every user is measured in minutes.
+    pub refresh_interval: u64,
+}
+
+/// Which mounts are left out of the storage status.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StorageSettings {
+    /// Filesystem types that are never reported, like `tmpfs`.
+    pub exclude_filesystems: Vec<String>,
+    /// Mount points that are never reported, along with everything mounted below them.
+    pub exclude_mounts: Vec<String>,
+}
+
+/// How the system status is kept up to date.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StatusSettings {
+    /// How often the status is sampled in seconds.
+    pub sample_interval: u64,
+}
+
+/// How the Prometheus metrics are served.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// The address of a separate listener that serves `/metrics` without authentication.
+    ///
+    /// Empty to only serve them on the main port to API tokens.
+    pub listen: String,
+}
+
+/// How a UPS is monitored through Network UPS Tools.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct UpsSettings {
+    /// The `upsd` server as `host` or `host:port`, empty to not monitor a UPS.
+    pub server: String,
+    /// The name of the UPS on the server.
+    pub name: String,
+    /// Shut down once the UPS is on battery with less runtime left in seconds, `0` never shuts
+    /// down.
+    pub shutdown_runtime: u64,
+    /// The command that shuts the system down.
+    pub shutdown_command: ShutdownCommand,
+}
+
+/// The commands the system can be shut down with.
+///
+/// Only these are offered so the settings can not be used to run arbitrary commands as the
+/// server.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownCommand {
+    #[default]
+    Shutdown,
+    Poweroff,
+}
+impl ShutdownCommand {
+    pub const ALL: [ShutdownCommand; 2] = [Self::Shutdown, Self::Poweroff];
+
+    /// The name used in forms.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Shutdown => "shutdown",
+            Self::Poweroff => "poweroff",
+        }
+    }
+
+    /// The program and its arguments.
+    pub fn argv(&self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            Self::Shutdown => ("shutdown", &["-h", "+0"]),
+            Self::Poweroff => ("systemctl", &["poweroff"]),
+        }
+    }
+}
+impl std::fmt::Display for ShutdownCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (program, args) = self.argv();
+        write!(f, "{} {}", program, args.join(" "))
+    }
+}
+
+/// The timeouts for logged in sessions in minutes, `0` disables a timeout.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SessionSettings {
+    /// How long a session may go without a request.
+    pub idle_timeout: u64,
+    /// How long a session may last at all.
+    pub absolute_timeout: u64,
+}
+
 /// The thresholds for the system status.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Threasholds {
     pub low_power: u8,
+    /// The free space in percent below which a mount is low on storage.
     pub low_storage: u8,
+    /// Thresholds for single mount points that replace `low_storage`.
+    #[serde(default)]
+    pub mounts: BTreeMap<String, u8>,
+    /// The temperature in °C above which a sensor is too hot.
+    #[serde(default = "default_high_temperature")]
+    pub high_temperature: u8,
+}
+impl Threasholds {
+    /// Gets the storage threshold of a mount point.
+    pub fn low_storage_for(&self, mount_point: &str) -> u8 {
+        self.mounts
+            .get(mount_point)
+            .copied()
+            .unwrap_or(self.low_storage)
+    }
 }
 
 impl Default for Settings {
@@ -42,6 +228,105 @@ impl Default for Settings {
             hostname: String::from("0.0.0.0"),
             ignore_updates: false,
             threatsholds: Threasholds::default(),
+            sessions: SessionSettings::default(),
+            password_policy: PasswordPolicy::default(),
+            argon2: Argon2Settings::default(),
+            provisioning: ProvisioningSettings::default(),
+            quota: QuotaSettings::default(),
+            storage: StorageSettings::default(),
+            status: StatusSettings::default(),
+            metrics: MetricsSettings::default(),
+            ups: UpsSettings::default(),
+        }
+    }
+}
+
+impl Default for Argon2Settings {
+    fn default() -> Self {
+        Self {
+            memory_cost: argon2::Params::DEFAULT_M_COST,
+            time_cost: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Default for ProvisioningSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dry_run: false,
+            default_shell: String::from("/bin/bash"),
+            default_groups: Vec::new(),
+            allowed_groups: Vec::new(),
+            create_home: true,
+            remove_home: false,
+            filesystem_quota: false,
+        }
+    }
+}
+
+impl Default for QuotaSettings {
+    fn default() -> Self {
+        Self {
+            refresh_interval: 10,
+        }
+    }
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            exclude_filesystems: [
+                "tmpfs", "devtmpfs", "ramfs", "squashfs", "overlay", "proc", "sysfs", "devpts",
+                "cgroup", "cgroup2", "efivarfs", "autofs",
+            ]
+            .map(String::from)
+            .to_vec(),
+            exclude_mounts: ["/snap", "/proc", "/sys", "/dev", "/run"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+impl Default for StatusSettings {
+    fn default() -> Self {
+        Self { sample_interval: 2 }
+    }
+}
+
+impl Default for UpsSettings {
+    fn default() -> Self {
+        Self {
+            server: String::new(),
+            name: String::from("ups"),
+            shutdown_runtime: 0,
+            shutdown_command: ShutdownCommand::default(),
+        }
+    }
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            reject_common: true,
+            history: 5,
+            max_age_days: 0,
+        }
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            idle_timeout: 60,
+            absolute_timeout: 12 * 60,
         }
     }
 }
@@ -51,52 +336,144 @@ impl Default for Threasholds {
         Self {
             low_power: 15,
             low_storage: 15,
+            mounts: BTreeMap::new(),
+            high_temperature: default_high_temperature(),
         }
     }
 }
+
+fn default_high_temperature() -> u8 {
+    80
+}
 impl From<Settings> for SettingsTemplate {
     fn from(val: Settings) -> Self {
         SettingsTemplate {
             low_storage: val.threatsholds.low_storage,
             low_power: val.threatsholds.low_power,
+            high_temperature: val.threatsholds.high_temperature,
             ignore_update: val.ignore_updates,
             cert_path: val.cert_path.to_string_lossy().to_string(),
             key_path: val.key_path.to_string_lossy().to_string(),
             port: val.port,
             hostname: val.hostname.to_string(),
+            idle_timeout: val.sessions.idle_timeout,
+            absolute_timeout: val.sessions.absolute_timeout,
+            min_length: val.password_policy.min_length,
+            require_lowercase: val.password_policy.require_lowercase,
+            require_uppercase: val.password_policy.require_uppercase,
+            require_digit: val.password_policy.require_digit,
+            require_symbol: val.password_policy.require_symbol,
+            reject_common: val.password_policy.reject_common,
+            password_history: val.password_policy.history,
+            max_age_days: val.password_policy.max_age_days,
+            memory_cost: val.argon2.memory_cost,
+            time_cost: val.argon2.time_cost,
+            parallelism: val.argon2.parallelism,
+            provisioning_enabled: val.provisioning.enabled,
+            provisioning_dry_run: val.provisioning.dry_run,
+            default_shell: val.provisioning.default_shell,
+            default_groups: val.provisioning.default_groups.join(", "),
+            allowed_groups: val.provisioning.allowed_groups.join(", "),
+            edit_accounts: false,
+            create_home: val.provisioning.create_home,
+            remove_home: val.provisioning.remove_home,
+            quota_refresh_interval: val.quota.refresh_interval,
+            filesystem_quota: val.provisioning.filesystem_quota,
+            mount_thresholds: val
+                .threatsholds
+                .mounts
+                .iter()
+                .map(|(mount_point, threshold)| format!("{mount_point}={threshold}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            exclude_filesystems: val.storage.exclude_filesystems.join(", "),
+            exclude_mounts: val.storage.exclude_mounts.join(", "),
+            sample_interval: val.status.sample_interval,
+            metrics_listen: val.metrics.listen,
+            ups_server: val.ups.server,
+            ups_name: val.ups.name,
+            ups_shutdown_runtime: val.ups.shutdown_runtime,
+            ups_shutdown_command: val.ups.shutdown_command,
+            shutdown_commands: ShutdownCommand::ALL,
         }
     }
 }
 
-/// Loads the settings from the settings file.
-pub async fn load_settings() -> Settings {
-    let content = match fs::read_to_string(SETTINGS_PATH).await {
+/// Parses the settings from the raw contents of a settings file.
+fn parse_settings(content: &[u8]) -> Option<Settings> {
+    let content = match std::str::from_utf8(content) {
         Ok(value) => value,
         Err(error) => {
-            log::error!(
-                "Failed to read settings due to error: {}\nUsing default settings",
-                error
-            );
-            toml::to_string(&Settings::default()).unwrap()
+            log::error!("Settings are not valid UTF-8: {}", error);
+            return None;
         }
     };
-    match toml::from_str(&content) {
-        Ok(value) => value,
+    match toml::from_str(content) {
+        Ok(value) => Some(value),
         Err(error) => {
-            log::error!(
-                "Failed to parse settings due to error: {}\nUsing default settings",
-                error
-            );
-            Settings::default()
+            log::error!("Failed to parse settings due to error: {}", error);
+            None
         }
     }
 }
 
+/// Loads the settings from the settings file.
+///
+/// If the settings file is missing or corrupt the backup from the last save is used, only if
+/// that fails too do we fall back to the default settings.
+pub async fn load_settings() -> Settings {
+    match persist::read(SETTINGS_PATH).await {
+        Ok(content) => {
+            if let Some(settings) = parse_settings(&content) {
+                return settings;
+            }
+        }
+        Err(error) => log::error!("Failed to read settings due to error: {}", error),
+    }
+    if let Ok(content) = persist::read_backup(SETTINGS_PATH).await
+        && let Some(settings) = parse_settings(&content)
+    {
+        log::warn!("Using settings from the backup file");
+        return settings;
+    }
+    log::error!("Using default settings");
+    Settings::default()
+}
+
 /// Saves the settings to the settings file.
-pub async fn save_settings(settings: Settings) {
-    RESTART_PENDING.store(true, std::sync::atomic::Ordering::Relaxed); // Set restart pending to true so they know to restart the system
+///
+/// # Returns
+///
+/// The settings that were replaced, read while the file stays locked so they are exactly the
+/// ones the new settings took the place of.
+pub async fn save_settings(settings: Settings) -> std::io::Result<Settings> {
     let content = toml::to_string(&settings).unwrap(); // This should never fail
-    if let Err(error) = fs::write(SETTINGS_PATH, content).await {
-        log::error!("Failed to save settings due to error: {}", error);
+    let before = persist::update(SETTINGS_PATH, move |data| {
+        let before = data.and_then(parse_settings).unwrap_or_default();
+        Ok::<_, std::io::Error>((content.into_bytes(), before))
+    })
+    .await?;
+    RESTART_PENDING.store(true, std::sync::atomic::Ordering::Relaxed); // Set restart pending to true so they know to restart the system
+    Ok(before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_in_missing_session_settings() {
+        let content = format!(
+            "{}\n[sessions]\nidle_timeout = 5\n",
+            toml::to_string(&Settings::default())
+                .unwrap()
+                .replace("[sessions]", "[old_sessions]")
+        );
+        let settings = parse_settings(content.as_bytes()).unwrap();
+        assert_eq!(settings.sessions.idle_timeout, 5);
+        assert_eq!(
+            settings.sessions.absolute_timeout,
+            SessionSettings::default().absolute_timeout
+        );
     }
 }
@@ -0,0 +1,226 @@
+//! # Shares
+//!
+//! This module contains the public links users create to share a file with someone who has no
+//! account.
+//!
+//! A share always expires and can also have a password and a download limit. Like the reset
+//! tokens only a SHA-256 hash of the token in the link is stored. Shares point at a path in the
+//! owner's data directory, so a file that is moved or deleted can no longer be downloaded.
+use std::{collections::HashMap, io, sync::LazyLock};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::Mutex;
+use tower_sessions::Session;
+
+use crate::{persist, tokens};
+
+/// The path to the shares file.
+#[cfg(not(debug_assertions))]
+const SHARES_PATH: &str = "/var/lib/system_manager_server/shares.json";
+#[cfg(debug_assertions)]
+const SHARES_PATH: &str = "./shares.json";
+
+/// The key the ids of the shares unlocked with a password are stored under in the session.
+const UNLOCKED_KEY: &str = "unlocked_shares";
+/// The longest a share can stay valid in days.
+pub const MAX_LIFETIME_DAYS: u32 = 365;
+
+/// Makes sure only one task reads and updates the shares file at a time.
+static SHARES_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// A stored share, without the token itself.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Share {
+    /// A short public id used to revoke the share.
+    pub id: String,
+    pub owner: String,
+    /// The path of the file relative to the owner's data directory.
+    pub path: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires: OffsetDateTime,
+    /// The hash of the password, if the share has one.
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+    #[serde(default)]
+    pub downloads: u32,
+}
+impl Share {
+    /// Checks if the share can still be used.
+    pub fn usable(&self) -> bool {
+        self.expires > OffsetDateTime::now_utc()
+            && self
+                .max_downloads
+                .is_none_or(|limit| self.downloads < limit)
+    }
+
+    /// Formats the expiry for display.
+    pub fn expires_time(&self) -> String {
+        format_time(self.expires)
+    }
+
+    /// Describes the downloads for display.
+    pub fn downloads_text(&self) -> String {
+        match self.max_downloads {
+            Some(limit) => format!("{} of {limit}", self.downloads),
+            None => self.downloads.to_string(),
+        }
+    }
+
+    /// The name of the shared file.
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or_default()
+    }
+}
+
+fn format_time(value: OffsetDateTime) -> String {
+    value.format(&Rfc3339).unwrap_or_else(|_| value.to_string())
+}
+
+/// Hashes a token for storage.
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Loads the shares by hash, the caller must hold [`SHARES_LOCK`].
+async fn load() -> io::Result<HashMap<String, Share>> {
+    match persist::read(SHARES_PATH).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Saves the shares, the caller must hold [`SHARES_LOCK`].
+async fn save(shares: &HashMap<String, Share>) -> io::Result<()> {
+    persist::write(SHARES_PATH, serde_json::to_vec(shares)?).await
+}
+
+/// Creates a share of a file, the path has to be checked by the caller.
+///
+/// `password` is the hash of the password, if the share should have one.
+///
+/// # Returns
+///
+/// The token for the link, this is the only time it is available.
+pub async fn create(
+    owner: &str,
+    path: &str,
+    lifetime_days: u32,
+    password: Option<String>,
+    max_downloads: Option<u32>,
+) -> io::Result<String> {
+    let _guard = SHARES_LOCK.lock().await;
+    let mut shares = load().await?;
+    // Shares that can never be used again are only kept until the next one is created
+    shares.retain(|_, share| share.usable());
+    let token = tokens::generate();
+    let hashed = hash(&token);
+    let now = OffsetDateTime::now_utc();
+    shares.insert(
+        hashed.clone(),
+        Share {
+            id: hashed[..12].to_string(),
+            owner: owner.to_string(),
+            path: path.to_string(),
+            created: now,
+            expires: now + Duration::days(lifetime_days.clamp(1, MAX_LIFETIME_DAYS) as i64),
+            password,
+            max_downloads,
+            downloads: 0,
+        },
+    );
+    save(&shares).await?;
+    Ok(token)
+}
+
+/// Lists the shares of a user that can still be used, newest first.
+pub async fn list(owner: &str) -> io::Result<Vec<Share>> {
+    let _guard = SHARES_LOCK.lock().await;
+    let mut shares: Vec<Share> = load()
+        .await?
+        .into_values()
+        .filter(|share| share.owner == owner && share.usable())
+        .collect();
+    shares.sort_by_key(|share| std::cmp::Reverse(share.created));
+    Ok(shares)
+}
+
+/// Revokes a share of a user by its id.
+///
+/// # Returns
+///
+/// The revoked share, or `None` if the user has no share with that id.
+pub async fn revoke(owner: &str, id: &str) -> io::Result<Option<Share>> {
+    let _guard = SHARES_LOCK.lock().await;
+    let mut shares = load().await?;
+    let Some(hashed) = shares
+        .iter()
+        .find(|(_, share)| share.owner == owner && share.id == id)
+        .map(|(hashed, _)| hashed.clone())
+    else {
+        return Ok(None);
+    };
+    let share = shares.remove(&hashed);
+    save(&shares).await?;
+    Ok(share)
+}
+
+/// Revokes every share of a user, used when the user is deleted.
+pub async fn forget(owner: &str) -> io::Result<()> {
+    let _guard = SHARES_LOCK.lock().await;
+    let mut shares = load().await?;
+    let before = shares.len();
+    shares.retain(|_, share| share.owner != owner);
+    if shares.len() != before {
+        save(&shares).await?;
+    }
+    Ok(())
+}
+
+/// Looks up a share that can still be used.
+pub async fn find(token: &str) -> io::Result<Option<Share>> {
+    let _guard = SHARES_LOCK.lock().await;
+    Ok(load().await?.remove(&hash(token)).filter(Share::usable))
+}
+
+/// Counts a download of a share.
+///
+/// # Returns
+///
+/// The share, or `None` if it can not be used (anymore).
+pub async fn record_download(token: &str) -> io::Result<Option<Share>> {
+    let _guard = SHARES_LOCK.lock().await;
+    let mut shares = load().await?;
+    let Some(share) = shares.get_mut(&hash(token)).filter(|share| share.usable()) else {
+        return Ok(None);
+    };
+    share.downloads += 1;
+    let share = share.clone();
+    save(&shares).await?;
+    Ok(Some(share))
+}
+
+/// Remembers in the session that the password of a share was entered.
+pub async fn unlock(session: &Session, id: &str) -> Result<(), tower_sessions::session::Error> {
+    let mut unlocked: Vec<String> = session.get(UNLOCKED_KEY).await?.unwrap_or_default();
+    if !unlocked.iter().any(|value| value == id) {
+        unlocked.push(id.to_string());
+    }
+    session.insert(UNLOCKED_KEY, unlocked).await
+}
+
+/// Checks if the password of a share was entered in this session.
+pub async fn unlocked(session: &Session, id: &str) -> bool {
+    session
+        .get::<Vec<String>>(UNLOCKED_KEY)
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|unlocked| unlocked.iter().any(|value| value == id))
+}
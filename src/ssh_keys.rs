@@ -0,0 +1,514 @@
+//! # SSH Keys
+//!
+//! This module contains the SSH public keys of every user and writes them into the
+//! `authorized_keys` file of their Linux account.
+//!
+//! The keys are stored in `ssh_keys.json` next to the user's `user.json`. In `authorized_keys`
+//! they are kept in a block between two marker comments, lines outside of the block are left as
+//! they are so keys added by hand keep working.
+use std::{
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+
+use axum::{http::StatusCode, response::IntoResponse};
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::Mutex;
+
+use crate::{
+    persist,
+    provision::{self, SystemAccount},
+    settings::ProvisioningSettings,
+    users,
+};
+
+/// The name of the keys file in a user's directory.
+const KEYS_FILE: &str = "ssh_keys.json";
+/// The line starting the managed block in `authorized_keys`.
+const BEGIN_MARKER: &str =
+    "# BEGIN system_manager_server managed keys, changes inside this block are overwritten";
+/// The line ending the managed block in `authorized_keys`.
+const END_MARKER: &str = "# END system_manager_server managed keys";
+
+/// The key types that are accepted, DSA keys are not supported by OpenSSH anymore.
+const KEY_TYPES: [&str; 7] = [
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+/// The options that take a quoted value, see `sshd(8)`. Option names are not case sensitive.
+const VALUE_OPTIONS: [&str; 8] = [
+    "command",
+    "environment",
+    "expiry-time",
+    "from",
+    "permitlisten",
+    "permitopen",
+    "principals",
+    "tunnel",
+];
+/// The options that are plain flags, see `sshd(8)`.
+const FLAG_OPTIONS: [&str; 14] = [
+    "agent-forwarding",
+    "cert-authority",
+    "no-agent-forwarding",
+    "no-port-forwarding",
+    "no-pty",
+    "no-touch-required",
+    "no-user-rc",
+    "no-x11-forwarding",
+    "port-forwarding",
+    "pty",
+    "restrict",
+    "user-rc",
+    "verify-required",
+    "x11-forwarding",
+];
+
+/// Makes sure only one task reads and updates a keys file at a time.
+static KEYS_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// An error that can occur when managing SSH keys.
+#[derive(Debug)]
+pub enum SshKeyError {
+    IoError(io::Error),
+    /// The key could not be parsed, with the reason.
+    InvalidKey(&'static str),
+    UnsupportedOption(String),
+    DuplicateKey,
+    KeyNotFound,
+}
+impl From<io::Error> for SshKeyError {
+    fn from(value: io::Error) -> Self {
+        log::error!("IO Error: {}", value);
+        SshKeyError::IoError(value)
+    }
+}
+impl std::fmt::Display for SshKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(error) => write!(f, "IO Error: {error}"),
+            Self::InvalidKey(reason) => write!(f, "Invalid Key ({reason})"),
+            Self::UnsupportedOption(option) => write!(f, "Unsupported Key Option {option}"),
+            Self::DuplicateKey => write!(f, "Key Already Added"),
+            Self::KeyNotFound => write!(f, "Key Not Found"),
+        }
+    }
+}
+impl std::error::Error for SshKeyError {}
+impl SshKeyError {
+    /// The status code to respond with for this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidKey(_) | Self::UnsupportedOption(_) => StatusCode::BAD_REQUEST,
+            Self::DuplicateKey => StatusCode::CONFLICT,
+            Self::KeyNotFound => StatusCode::NOT_FOUND,
+        }
+    }
+}
+impl IntoResponse for SshKeyError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::IoError(_) => (self.status(), "IO Error").into_response(),
+            _ => (self.status(), self.to_string()).into_response(),
+        }
+    }
+}
+type Result<T> = std::result::Result<T, SshKeyError>;
+
+/// A stored SSH public key.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SshKey {
+    pub label: String,
+    /// The options in front of the key, empty for none.
+    #[serde(default)]
+    pub options: String,
+    pub key_type: String,
+    /// The base64 encoded key.
+    pub key: String,
+    /// The fingerprint in the format `ssh-keygen -l` shows.
+    pub fingerprint: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub added: OffsetDateTime,
+    pub added_by: String,
+}
+impl SshKey {
+    /// Formats the time the key was added for display.
+    pub fn added_time(&self) -> String {
+        self.added
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| self.added.to_string())
+    }
+
+    /// The line for `authorized_keys`, the label is used as the comment.
+    pub fn line(&self) -> String {
+        let mut line = String::new();
+        if !self.options.is_empty() {
+            line.push_str(&self.options);
+            line.push(' ');
+        }
+        line.push_str(&format!("{} {} {}", self.key_type, self.key, self.label));
+        line.trim_end().to_string()
+    }
+}
+
+/// Splits off the first field of a line, spaces inside double quotes don't end it.
+fn split_field(line: &str) -> (&str, &str) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, character) in line.char_indices() {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ' ' | '\t' if !quoted => return (&line[..index], line[index..].trim_start()),
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+/// Checks the options of a key line.
+fn check_options(options: &str) -> Result<()> {
+    let mut option = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut list = Vec::new();
+    for character in options.chars() {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                list.push(std::mem::take(&mut option));
+                continue;
+            }
+            _ => {}
+        }
+        option.push(character);
+    }
+    if quoted {
+        return Err(SshKeyError::InvalidKey("unclosed quote in the options"));
+    }
+    list.push(option);
+    for option in list {
+        match option.split_once('=') {
+            Some((name, value)) => {
+                if !VALUE_OPTIONS.contains(&name.to_ascii_lowercase().as_str()) {
+                    return Err(SshKeyError::UnsupportedOption(name.to_string()));
+                }
+                if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+                    return Err(SshKeyError::InvalidKey("option values have to be quoted"));
+                }
+            }
+            None if FLAG_OPTIONS.contains(&option.to_ascii_lowercase().as_str()) => {}
+            None => return Err(SshKeyError::UnsupportedOption(option)),
+        }
+    }
+    Ok(())
+}
+
+/// Computes the SHA-256 fingerprint of a decoded key.
+fn fingerprint(blob: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(blob)))
+}
+
+/// Parses a line of `authorized_keys` into a key, `options` are put in front of it.
+///
+/// The label defaults to the comment of the key.
+pub fn parse(line: &str, options: &str, label: &str) -> Result<SshKey> {
+    let line = line.trim();
+    let options = options.trim();
+    if line.is_empty() {
+        return Err(SshKeyError::InvalidKey("the key is empty"));
+    }
+    if [line, options, label]
+        .iter()
+        .any(|value| value.contains(['\n', '\r', '\0']))
+    {
+        return Err(SshKeyError::InvalidKey("keys have to be a single line"));
+    }
+    let (first, rest) = split_field(line);
+    let (line_options, key_type, rest) = if KEY_TYPES.contains(&first) {
+        ("", first, rest)
+    } else {
+        let (key_type, rest) = split_field(rest);
+        (first, key_type, rest)
+    };
+    if !KEY_TYPES.contains(&key_type) {
+        return Err(SshKeyError::InvalidKey("unsupported key type"));
+    }
+    let options = match (line_options, options) {
+        ("", options) | (options, "") => options.to_string(),
+        (line_options, options) => format!("{options},{line_options}"),
+    };
+    if !options.is_empty() {
+        check_options(&options)?;
+    }
+    let (key, comment) = split_field(rest);
+    let blob = STANDARD
+        .decode(key)
+        .map_err(|_| SshKeyError::InvalidKey("the key is not valid base64"))?;
+    // The key starts with its type as a length prefixed string
+    let embedded = blob
+        .get(..4)
+        .map(|value| u32::from_be_bytes([value[0], value[1], value[2], value[3]]) as usize)
+        .and_then(|length| blob.get(4..4 + length));
+    if embedded != Some(key_type.as_bytes()) {
+        return Err(SshKeyError::InvalidKey("the key does not match its type"));
+    }
+    let label = match label.trim() {
+        "" => comment.trim(),
+        label => label,
+    };
+    Ok(SshKey {
+        label: if label.is_empty() {
+            key_type.to_string()
+        } else {
+            label.to_string()
+        },
+        options,
+        key_type: key_type.to_string(),
+        key: key.to_string(),
+        fingerprint: fingerprint(&blob),
+        added: OffsetDateTime::now_utc(),
+        added_by: String::new(),
+    })
+}
+
+fn keys_path(username: &str) -> PathBuf {
+    users::user_dir(username).join(KEYS_FILE)
+}
+
+/// Loads the keys of a user, the caller must hold [`KEYS_LOCK`].
+async fn load(username: &str) -> io::Result<Vec<SshKey>> {
+    match persist::read(keys_path(username)).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Saves the keys of a user, the caller must hold [`KEYS_LOCK`].
+async fn save(username: &str, keys: &[SshKey]) -> io::Result<()> {
+    persist::write(keys_path(username), serde_json::to_vec_pretty(keys)?).await
+}
+
+/// Lists the keys of a user in the order they were added.
+pub async fn list(username: &str) -> Result<Vec<SshKey>> {
+    let _guard = KEYS_LOCK.lock().await;
+    Ok(load(username).await?)
+}
+
+/// Adds a key to a user.
+pub async fn add(username: &str, mut key: SshKey, added_by: &str) -> Result<SshKey> {
+    let _guard = KEYS_LOCK.lock().await;
+    let mut keys = load(username).await?;
+    if keys
+        .iter()
+        .any(|value| value.fingerprint == key.fingerprint)
+    {
+        return Err(SshKeyError::DuplicateKey);
+    }
+    key.added_by = added_by.to_string();
+    keys.push(key.clone());
+    save(username, &keys).await?;
+    Ok(key)
+}
+
+/// Removes a key of a user by its fingerprint.
+pub async fn remove(username: &str, fingerprint: &str) -> Result<SshKey> {
+    let _guard = KEYS_LOCK.lock().await;
+    let mut keys = load(username).await?;
+    let index = keys
+        .iter()
+        .position(|value| value.fingerprint == fingerprint)
+        .ok_or(SshKeyError::KeyNotFound)?;
+    let key = keys.remove(index);
+    save(username, &keys).await?;
+    Ok(key)
+}
+
+/// Replaces the managed block of an `authorized_keys` file, keeping every other line.
+///
+/// The block is left out if there are no keys.
+pub fn merge(existing: &str, keys: &[SshKey]) -> String {
+    let mut lines = Vec::new();
+    let mut managed = false;
+    for line in existing.lines() {
+        match line.trim() {
+            BEGIN_MARKER => managed = true,
+            END_MARKER if managed => managed = false,
+            _ if !managed => lines.push(line.to_string()),
+            _ => {}
+        }
+    }
+    if !keys.is_empty() {
+        lines.push(BEGIN_MARKER.to_string());
+        lines.extend(keys.iter().map(SshKey::line));
+        lines.push(END_MARKER.to_string());
+    }
+    let mut content = lines.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    content
+}
+
+/// Checks that a path is a real directory owned by the account, not a symlink.
+fn owned_directory(path: &Path, account: &SystemAccount) -> io::Result<bool> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() && metadata.uid() == account.uid => Ok(true),
+        Ok(_) => Err(io::Error::other(format!(
+            "{} is not a directory owned by {}",
+            path.display(),
+            account.name
+        ))),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Writes the keys into the `authorized_keys` file of an account.
+///
+/// This runs as root in the user's home directory, so nothing that is a symlink is followed
+/// and the file is replaced instead of written in place.
+fn write_authorized_keys(account: &SystemAccount, keys: &[SshKey]) -> io::Result<()> {
+    let home = Path::new(&account.home);
+    if !home.is_absolute() || !owned_directory(home, account)? {
+        return Err(io::Error::other(format!(
+            "the home directory of {} is missing",
+            account.name
+        )));
+    }
+    let ssh_dir = home.join(".ssh");
+    if !owned_directory(&ssh_dir, account)? {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        std::fs::DirBuilder::new().mode(0o700).create(&ssh_dir)?;
+        std::os::unix::fs::chown(&ssh_dir, Some(account.uid), Some(account.gid))?;
+    }
+    let path = ssh_dir.join("authorized_keys");
+    let existing = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_file() => std::fs::read_to_string(&path)?,
+        Ok(_) => {
+            return Err(io::Error::other(format!(
+                "{} is not a regular file",
+                path.display()
+            )));
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(error),
+    };
+    let content = merge(&existing, keys);
+    if content == existing {
+        return Ok(());
+    }
+    let temporary = ssh_dir.join(".authorized_keys.system_manager");
+    if let Err(error) = std::fs::remove_file(&temporary)
+        && error.kind() != io::ErrorKind::NotFound
+    {
+        return Err(error);
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temporary)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    std::os::unix::fs::chown(&temporary, Some(account.uid), Some(account.gid))?;
+    std::fs::rename(&temporary, &path)
+}
+
+/// Finds the Linux account the keys of a user go to.
+///
+/// Only accounts the manager created are written to, anyone could be given a manager user named
+/// after an existing account, and keys are never written for root or system services.
+fn target_account(user: &users::User, accounts: Vec<SystemAccount>) -> Option<SystemAccount> {
+    if !user.provisioned() {
+        return None;
+    }
+    accounts
+        .into_iter()
+        .find(|account| account.name == user.name())
+        .filter(SystemAccount::regular)
+}
+
+/// Writes the keys of a user into their Linux account, if provisioning is enabled and the
+/// manager created the account.
+pub async fn sync(settings: &ProvisioningSettings, user: &users::User) -> Result<()> {
+    if !settings.enabled {
+        return Ok(());
+    }
+    let username = user.name();
+    let accounts = tokio::task::spawn_blocking(provision::system_accounts)
+        .await
+        .map_err(io::Error::other)??;
+    let Some(account) = target_account(user, accounts) else {
+        log::info!(
+            "Not writing SSH keys of {}, no Linux account created by the manager",
+            username
+        );
+        return Ok(());
+    };
+    let keys = list(username).await?;
+    if settings.dry_run {
+        log::info!(
+            "Dry run: would write {} SSH keys to {}/.ssh/authorized_keys",
+            keys.len(),
+            account.home
+        );
+        return Ok(());
+    }
+    tokio::task::spawn_blocking(move || write_authorized_keys(&account, &keys))
+        .await
+        .map_err(io::Error::other)??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, provisioned: bool) -> users::User {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "password": "",
+            "admin": false,
+            "storage": 0,
+            "provisioned": provisioned,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_writes_to_accounts_the_manager_created() {
+        let accounts = provision::parse_passwd(concat!(
+            "root:x:0:0:root:/root:/bin/bash\n",
+            "alice:x:1000:1000::/home/alice:/bin/bash\n",
+            "carol:x:1001:1001::/home/carol:/bin/bash\n",
+        ));
+        assert!(target_account(&user("carol", false), accounts.clone()).is_none());
+        assert!(target_account(&user("root", true), accounts.clone()).is_none());
+        assert!(target_account(&user("bob", true), accounts.clone()).is_none());
+        assert_eq!(
+            target_account(&user("alice", true), accounts).map(|account| account.home),
+            Some(String::from("/home/alice"))
+        );
+    }
+}
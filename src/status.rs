@@ -2,35 +2,1049 @@
 //!
 //! This module contains functions for checking the system status.
 
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
 use battery::Manager;
-use sysinfo::Disks;
+use serde::Serialize;
+use sysinfo::{Disks, Networks, System};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::Mutex;
+
+use crate::{
+    RESTART_PENDING,
+    quota::format_bytes,
+    settings::{Settings, StorageSettings, Threasholds},
+    update_manager,
+    ups::UpsStatus,
+};
+
+/// The usage of a mounted filesystem.
+#[derive(Debug, Clone, Serialize)]
+pub struct MountUsage {
+    pub mount_point: String,
+    pub device: String,
+    pub fs_type: String,
+    /// The size in bytes.
+    pub total: u64,
+    /// The bytes unprivileged users can still write.
+    pub available: u64,
+    pub inodes_total: u64,
+    pub inodes_free: u64,
+    pub read_only: bool,
+}
+impl MountUsage {
+    /// The used bytes.
+    pub fn used(&self) -> u64 {
+        self.total.saturating_sub(self.available)
+    }
+
+    /// How much of the space is used in percent.
+    pub fn used_percent(&self) -> u8 {
+        100 - self.free_percent()
+    }
+
+    /// How much of the space is free in percent, a full filesystem has 0.
+    pub fn free_percent(&self) -> u8 {
+        percent(self.available, self.total)
+    }
+
+    /// How many of the inodes are free in percent, 100 if the filesystem has no inode limit.
+    pub fn inodes_free_percent(&self) -> u8 {
+        if self.inodes_total == 0 {
+            return 100;
+        }
+        percent(self.inodes_free, self.inodes_total)
+    }
+
+    /// Describes the usage for display.
+    pub fn text(&self) -> String {
+        format!(
+            "{} of {}",
+            format_bytes(self.used()),
+            format_bytes(self.total)
+        )
+    }
+
+    /// Checks if the mount is below a threshold of free space or free inodes in percent.
+    pub fn is_low(&self, threshold: u8) -> bool {
+        self.free_percent() < threshold || self.inodes_free_percent() < threshold
+    }
+}
+
+/// Calculates a part of a whole in percent, 0 if the whole is empty.
+fn percent(part: u64, whole: u64) -> u8 {
+    if whole == 0 {
+        return 0;
+    }
+    (part.min(whole) as f64 / whole as f64 * 100.0).round() as u8
+}
+
+/// Checks if a mount is left out of the storage status.
+fn excluded(settings: &StorageSettings, mount_point: &str, fs_type: &str) -> bool {
+    settings
+        .exclude_filesystems
+        .iter()
+        .any(|value| value.eq_ignore_ascii_case(fs_type))
+        || settings.exclude_mounts.iter().any(|value| {
+            let value = value.trim_end_matches('/');
+            mount_point == value
+                || mount_point
+                    .strip_prefix(value)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+}
+
+/// Reads the inode counts of a filesystem.
+///
+/// # Returns
+///
+/// The total and free inodes, both 0 if they are unknown.
+fn inodes(mount_point: &Path) -> (u64, u64) {
+    let Ok(path) = CString::new(mount_point.as_os_str().as_bytes()) else {
+        return (0, 0);
+    };
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and statvfs only writes to the buffer it is given
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return (0, 0);
+    }
+    // SAFETY: statvfs succeeded so it filled the buffer
+    let stat = unsafe { stat.assume_init() };
+    // The counts are 32 bits wide on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    (stat.f_files as u64, stat.f_ffree as u64)
+}
+
+/// Checks the storage of every refreshed mount that is not excluded.
+///
+/// # Returns
+///
+/// The usage of each mount point, sorted by mount point.
+pub fn check_storage(disks: &Disks, settings: &StorageSettings) -> Vec<MountUsage> {
+    let mut mounts: Vec<MountUsage> = disks
+        .iter()
+        .filter_map(|disk| {
+            let mount_point = disk.mount_point().to_string_lossy().to_string();
+            let fs_type = disk.file_system().to_string_lossy().to_string();
+            // Pseudo filesystems without a size can not run out of space
+            if disk.total_space() == 0 || excluded(settings, &mount_point, &fs_type) {
+                return None;
+            }
+            let (inodes_total, inodes_free) = inodes(disk.mount_point());
+            Some(MountUsage {
+                mount_point,
+                device: disk.name().to_string_lossy().to_string(),
+                fs_type,
+                total: disk.total_space(),
+                available: disk.available_space(),
+                inodes_total,
+                inodes_free,
+                read_only: disk.is_read_only(),
+            })
+        })
+        .collect();
+    mounts.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+    // The same mount point can be listed more than once when something is mounted over it
+    mounts.dedup_by(|a, b| a.mount_point == b.mount_point);
+    mounts
+}
+
+/// Picks the mounts that are low on storage.
+pub fn low_storage(mounts: &[MountUsage], thresholds: &Threasholds) -> Vec<MountUsage> {
+    mounts
+        .iter()
+        .filter(|mount| mount.is_low(thresholds.low_storage_for(&mount.mount_point)))
+        .cloned()
+        .collect()
+}
+
+/// Parses thresholds of single mounts, written as `mount=percent` separated by commas.
+pub fn parse_mount_thresholds(value: &str) -> Result<BTreeMap<String, u8>, String> {
+    value
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|entry| {
+            let (mount_point, threshold) = entry
+                .rsplit_once('=')
+                .ok_or_else(|| format!("{entry} is not written as mount=percent"))?;
+            let mount_point = mount_point.trim();
+            if !mount_point.starts_with('/') {
+                return Err(format!("{mount_point} is not an absolute mount point"));
+            }
+            match threshold.trim().parse::<u8>() {
+                Ok(threshold) if threshold <= 100 => Ok((mount_point.to_string(), threshold)),
+                _ => Err(format!("{threshold} is not a percentage")),
+            }
+        })
+        .collect()
+}
+
+/// The state of a battery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatteryState {
+    Unknown,
+    Charging,
+    Discharging,
+    Empty,
+    Full,
+}
+impl BatteryState {
+    /// Every state, in the order they are exported.
+    pub const ALL: [Self; 5] = [
+        Self::Unknown,
+        Self::Charging,
+        Self::Discharging,
+        Self::Empty,
+        Self::Full,
+    ];
 
-/// Checks the storage status of the system.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Charging => "charging",
+            Self::Discharging => "discharging",
+            Self::Empty => "empty",
+            Self::Full => "full",
+        }
+    }
+}
+impl std::fmt::Display for BatteryState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown => write!(f, "Unknown"),
+            Self::Charging => write!(f, "Charging"),
+            Self::Discharging => write!(f, "Discharging"),
+            Self::Empty => write!(f, "Empty"),
+            Self::Full => write!(f, "Full"),
+        }
+    }
+}
+impl From<battery::State> for BatteryState {
+    fn from(state: battery::State) -> Self {
+        match state {
+            battery::State::Charging => Self::Charging,
+            battery::State::Discharging => Self::Discharging,
+            battery::State::Empty => Self::Empty,
+            battery::State::Full => Self::Full,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// The state of a single battery.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatteryStatus {
+    /// The vendor and model, if the battery reports them.
+    pub model: String,
+    /// The charge in percent.
+    pub charge: u8,
+    pub state: BatteryState,
+    /// The energy stored in watt hours.
+    pub energy: f32,
+    /// The energy stored when fully charged in watt hours.
+    pub energy_full: f32,
+    /// The energy the battery stored when it was new in watt hours.
+    pub energy_full_design: f32,
+    /// The power flowing into or out of the battery in watts.
+    pub energy_rate: f32,
+    /// How long until the battery is full in seconds, if it is charging.
+    pub time_to_full: Option<u64>,
+    /// How long until the battery is empty in seconds, if it is discharging.
+    pub time_to_empty: Option<u64>,
+    /// The full capacity compared to the design capacity in percent.
+    pub health: u8,
+    pub cycle_count: Option<u32>,
+    /// The temperature in degrees Celsius.
+    pub temperature: Option<f32>,
+}
+impl BatteryStatus {
+    /// Reads the state of a battery.
+    fn read(battery: &battery::Battery) -> Self {
+        use battery::units::{
+            energy::watt_hour, power::watt, thermodynamic_temperature::degree_celsius, time::second,
+        };
+
+        let model = [battery.vendor(), battery.model()]
+            .into_iter()
+            .flatten()
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Self {
+            model,
+            charge: ratio_percent(battery.state_of_charge().value),
+            state: battery.state().into(),
+            energy: battery.energy().get::<watt_hour>(),
+            energy_full: battery.energy_full().get::<watt_hour>(),
+            energy_full_design: battery.energy_full_design().get::<watt_hour>(),
+            energy_rate: battery.energy_rate().get::<watt>(),
+            time_to_full: battery
+                .time_to_full()
+                .map(|time| time.get::<second>() as u64),
+            time_to_empty: battery
+                .time_to_empty()
+                .map(|time| time.get::<second>() as u64),
+            health: ratio_percent(battery.state_of_health().value),
+            cycle_count: battery.cycle_count(),
+            temperature: battery
+                .temperature()
+                .map(|value| value.get::<degree_celsius>()),
+        }
+    }
+
+    /// Formats the state, power and remaining time for display.
+    pub fn state_text(&self) -> String {
+        let mut text = format!("{}, {:.1} W", self.state, self.energy_rate);
+        if let Some(time) = self.time_text() {
+            text.push_str(", ");
+            text.push_str(&time);
+        }
+        text
+    }
+
+    /// Formats the health, cycles and temperature for display.
+    pub fn health_text(&self) -> String {
+        let mut text = format!(
+            "Health {}% ({:.1} of {:.1} Wh)",
+            self.health, self.energy_full, self.energy_full_design
+        );
+        if let Some(cycles) = self.cycle_count {
+            text.push_str(&format!(", {cycles} cycles"));
+        }
+        if let Some(temperature) = self.temperature {
+            text.push_str(&format!(", {temperature:.1} °C"));
+        }
+        text
+    }
+
+    /// Formats the time until the battery is full or empty for display.
+    fn time_text(&self) -> Option<String> {
+        match (self.time_to_full, self.time_to_empty) {
+            (Some(seconds), _) if self.state == BatteryState::Charging => {
+                Some(format!("{} until full", format_duration(seconds)))
+            }
+            (_, Some(seconds)) if self.state == BatteryState::Discharging => {
+                Some(format!("{} until empty", format_duration(seconds)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Formats a number of seconds as hours and minutes.
+pub fn format_duration(seconds: u64) -> String {
+    format!("{}h {}m", seconds / 3600, seconds % 3600 / 60)
+}
+
+/// Turns a ratio into whole percent.
+fn ratio_percent(ratio: f32) -> u8 {
+    (ratio.clamp(0.0, 1.0) * 100.0).round() as u8
+}
+
+/// The batteries and power adapters of the system.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PowerStatus {
+    pub batteries: Vec<BatteryStatus>,
+    /// If a power adapter is plugged in, `None` if the system reports none.
+    pub ac_online: Option<bool>,
+    /// The UPS the system is plugged into, `None` if none is monitored or it can not be read.
+    pub ups: Option<UpsStatus>,
+}
+impl PowerStatus {
+    /// The charge of all batteries together in percent, `None` without batteries.
+    ///
+    /// Larger batteries count for more, so a small empty battery next to a full one is not low.
+    pub fn charge(&self) -> Option<u8> {
+        if self.batteries.is_empty() {
+            return None;
+        }
+        let full: f32 = self.batteries.iter().map(|value| value.energy_full).sum();
+        if full <= 0.0 {
+            let sum: u32 = self.batteries.iter().map(|value| value.charge as u32).sum();
+            return Some((sum / self.batteries.len() as u32) as u8);
+        }
+        let energy: f32 = self.batteries.iter().map(|value| value.energy).sum();
+        Some(ratio_percent(energy / full))
+    }
+
+    /// Describes the power adapter for display, `None` if there is none.
+    pub fn ac_text(&self) -> Option<&'static str> {
+        self.ac_online.map(|online| {
+            if online {
+                "Power adapter plugged in"
+            } else {
+                "Running on batteries"
+            }
+        })
+    }
+
+    /// Checks if the system runs on batteries or a UPS that are below a threshold.
+    ///
+    /// Batteries are never low on a power adapter, but the adapter may hang off a UPS that is.
+    pub fn is_low(&self, threshold: u8) -> bool {
+        let batteries =
+            self.ac_online != Some(true) && self.charge().is_some_and(|charge| charge < threshold);
+        batteries || self.ups.as_ref().is_some_and(|ups| ups.is_low(threshold))
+    }
+}
+
+/// The directory the kernel lists batteries and power adapters in.
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Checks if a power adapter is plugged in.
 ///
 /// # Returns
 ///
-/// The amount of free space in percent as a `u8`.
-pub fn check_storage() -> u8 {
-    let disks = Disks::new_with_refreshed_list();
-    let available_space: u64 = disks.iter().map(|disk| disk.available_space()).sum();
-    let total_space: u64 = disks.iter().map(|disk| disk.total_space()).sum();
-    100 - (total_space / available_space) as u8 // Should never give a value larger then 255 100% max
+/// `None` if the system has no power adapter, like most servers and desktops.
+fn check_ac() -> Option<bool> {
+    let entries = std::fs::read_dir(POWER_SUPPLY_PATH).ok()?;
+    let mut online = None;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let kind = std::fs::read_to_string(path.join("type")).unwrap_or_default();
+        // USB-C chargers show up as USB power supplies
+        if !matches!(kind.trim(), "Mains" | "USB") {
+            continue;
+        }
+        if let Ok(value) = std::fs::read_to_string(path.join("online")) {
+            online = Some(online.unwrap_or(false) || value.trim() == "1");
+        }
+    }
+    online
+}
+
+/// Reads the batteries of the system, kept by the sampler between samples.
+pub struct PowerProbe(Manager);
+// SAFETY: `Manager` is not `Send` because it holds an `Rc`. The `Rc` is only cloned into the
+// iterator `batteries` returns, which is dropped before `check` returns, so no clone outlives a
+// call and moving the probe to another thread between calls can not race on the count.
+unsafe impl Send for PowerProbe {}
+impl PowerProbe {
+    pub fn new() -> Result<Self, battery::Error> {
+        Ok(Self(Manager::new()?))
+    }
+
+    /// Checks the power status of the system.
+    ///
+    /// # Returns
+    ///
+    /// Every battery of the system and if a power adapter is plugged in, a system that does not
+    /// run on batteries has none. The UPS is polled separately and left empty.
+    pub fn check(&self) -> Result<PowerStatus, battery::Error> {
+        let mut batteries = Vec::new();
+        for battery in self.0.batteries()? {
+            batteries.push(BatteryStatus::read(&battery?));
+        }
+        Ok(PowerStatus {
+            batteries,
+            ac_online: check_ac(),
+            ups: None,
+        })
+    }
+}
+
+/// A temperature sensor.
+#[derive(Debug, Clone, Serialize)]
+pub struct Temperature {
+    /// The chip and channel, like `coretemp Core 0`, or the type of a thermal zone.
+    pub label: String,
+    /// The current temperature in °C.
+    pub current: f32,
+    /// The temperature the hardware considers high in °C.
+    pub max: Option<f32>,
+    /// The temperature at which the hardware shuts down in °C.
+    pub critical: Option<f32>,
+}
+impl Temperature {
+    /// Checks if the sensor is above a threshold or its critical temperature.
+    pub fn is_high(&self, threshold: u8) -> bool {
+        self.current >= threshold as f32 || self.critical.is_some_and(|value| self.current >= value)
+    }
+
+    /// Formats the limits of the sensor for display.
+    pub fn limits_text(&self) -> String {
+        let mut limits = Vec::new();
+        if let Some(max) = self.max {
+            limits.push(format!("high {max:.1} °C"));
+        }
+        if let Some(critical) = self.critical {
+            limits.push(format!("critical {critical:.1} °C"));
+        }
+        limits.join(", ")
+    }
+}
+
+/// The directory the kernel lists hardware monitoring chips in.
+const HWMON_PATH: &str = "/sys/class/hwmon";
+/// The directory the kernel lists thermal zones in.
+const THERMAL_PATH: &str = "/sys/class/thermal";
+
+/// Reads a temperature in millidegrees as °C.
+fn read_millidegrees(path: &Path) -> Option<f32> {
+    let value: i64 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(value as f32 / 1000.0)
+}
+
+/// Reads the temperature channels of every hardware monitoring chip.
+fn read_hwmon(path: &Path) -> Vec<(String, Temperature)> {
+    let mut chips: Vec<PathBuf> = std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    // Keeps the numbering of chips with the same name stable
+    chips.sort();
+    let mut temperatures = Vec::new();
+    for chip in chips {
+        let name = std::fs::read_to_string(chip.join("name")).unwrap_or_default();
+        let name = name.trim();
+        for entry in std::fs::read_dir(&chip).into_iter().flatten().flatten() {
+            let file = entry.file_name().to_string_lossy().to_string();
+            let Some(channel) = file
+                .strip_prefix("temp")
+                .and_then(|rest| rest.strip_suffix("_input"))
+            else {
+                continue;
+            };
+            let Some(current) = read_millidegrees(&entry.path()) else {
+                continue;
+            };
+            let label = std::fs::read_to_string(chip.join(format!("temp{channel}_label")))
+                .map(|value| value.trim().to_string())
+                .unwrap_or_else(|_| format!("temp{channel}"));
+            temperatures.push((
+                name.to_string(),
+                Temperature {
+                    label: format!("{name} {label}").trim().to_string(),
+                    current,
+                    max: read_millidegrees(&chip.join(format!("temp{channel}_max"))),
+                    critical: read_millidegrees(&chip.join(format!("temp{channel}_crit"))),
+                },
+            ));
+        }
+    }
+    temperatures
+}
+
+/// Reads every thermal zone along with its critical trip point.
+fn read_thermal(path: &Path) -> Vec<Temperature> {
+    let mut temperatures = Vec::new();
+    for zone in std::fs::read_dir(path).into_iter().flatten().flatten() {
+        let zone = zone.path();
+        // Cooling devices are listed next to the zones
+        let is_zone = zone
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("thermal_zone"));
+        if !is_zone {
+            continue;
+        }
+        let Some(current) = read_millidegrees(&zone.join("temp")) else {
+            continue;
+        };
+        let mut max = None;
+        let mut critical = None;
+        for trip in 0.. {
+            let Ok(kind) = std::fs::read_to_string(zone.join(format!("trip_point_{trip}_type")))
+            else {
+                break;
+            };
+            let temperature = read_millidegrees(&zone.join(format!("trip_point_{trip}_temp")));
+            match kind.trim() {
+                "critical" => critical = temperature,
+                "hot" => max = temperature,
+                _ => {}
+            }
+        }
+        let kind = std::fs::read_to_string(zone.join("type")).unwrap_or_default();
+        temperatures.push(Temperature {
+            label: kind.trim().to_string(),
+            current,
+            max,
+            critical,
+        });
+    }
+    temperatures
+}
+
+/// Reads the temperature sensors from a sysfs class directory.
+///
+/// Thermal zones that also show up as a hardware monitoring chip are only listed once, and
+/// sensors with the same label are numbered.
+fn read_temperatures(hwmon: &Path, thermal: &Path) -> Vec<Temperature> {
+    let chips = read_hwmon(hwmon);
+    let names: Vec<String> = chips.iter().map(|(name, _)| name.clone()).collect();
+    let mut temperatures: Vec<Temperature> = chips
+        .into_iter()
+        .map(|(_, temperature)| temperature)
+        .chain(
+            read_thermal(thermal)
+                .into_iter()
+                .filter(|zone| !names.contains(&zone.label)),
+        )
+        .collect();
+    temperatures.sort_by(|a, b| a.label.cmp(&b.label));
+    let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+    for temperature in &mut temperatures {
+        let count = seen.entry(temperature.label.clone()).or_default();
+        *count += 1;
+        if *count > 1 {
+            temperature.label = format!("{} {}", temperature.label, count);
+        }
+    }
+    temperatures
+}
+
+/// Checks the temperature sensors of the system.
+pub fn check_temperatures() -> Vec<Temperature> {
+    read_temperatures(Path::new(HWMON_PATH), Path::new(THERMAL_PATH))
+}
+
+/// The usage of a single CPU core.
+#[derive(Debug, Clone, Serialize)]
+pub struct CoreUsage {
+    pub name: String,
+    /// The usage in percent.
+    pub usage: f32,
+    /// The frequency in MHz.
+    pub frequency: u64,
+}
+impl CoreUsage {
+    /// The usage rounded to whole percent for display.
+    pub fn percent(&self) -> u8 {
+        self.usage.clamp(0.0, 100.0).round() as u8
+    }
+}
+
+/// The usage of memory or swap in bytes.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct MemoryUsage {
+    pub total: u64,
+    pub used: u64,
+}
+impl MemoryUsage {
+    /// How much is used in percent, 0 if there is none at all.
+    pub fn percent(&self) -> u8 {
+        percent(self.used, self.total)
+    }
+
+    /// Describes the usage for display.
+    pub fn text(&self) -> String {
+        format!(
+            "{} of {}",
+            format_bytes(self.used),
+            format_bytes(self.total)
+        )
+    }
+}
+
+/// The load averages over 1, 5 and 15 minutes.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+/// The state of the system at one point in time.
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemSnapshot {
+    #[serde(with = "time::serde::rfc3339")]
+    pub taken: OffsetDateTime,
+    pub hostname: String,
+    pub kernel: String,
+    pub distro: String,
+    /// The uptime in seconds.
+    pub uptime: u64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub boot_time: OffsetDateTime,
+    pub load: LoadAverage,
+    /// The usage of all cores together in percent.
+    pub cpu_usage: f32,
+    pub cores: Vec<CoreUsage>,
+    pub memory: MemoryUsage,
+    pub swap: MemoryUsage,
+}
+impl SystemSnapshot {
+    /// Reads a snapshot from a system, the CPU usage is only right if it was refreshed twice
+    /// with at least [`sysinfo::MINIMUM_CPU_UPDATE_INTERVAL`] in between.
+    pub fn collect(system: &System) -> Self {
+        let load = System::load_average();
+        Self {
+            taken: OffsetDateTime::now_utc(),
+            hostname: System::host_name().unwrap_or_default(),
+            kernel: System::kernel_version().unwrap_or_default(),
+            distro: System::long_os_version().unwrap_or_default(),
+            uptime: System::uptime(),
+            boot_time: OffsetDateTime::from_unix_timestamp(System::boot_time() as i64)
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+            load: LoadAverage {
+                one: load.one,
+                five: load.five,
+                fifteen: load.fifteen,
+            },
+            cpu_usage: system.global_cpu_usage(),
+            cores: system
+                .cpus()
+                .iter()
+                .map(|cpu| CoreUsage {
+                    name: cpu.name().to_string(),
+                    usage: cpu.cpu_usage(),
+                    frequency: cpu.frequency(),
+                })
+                .collect(),
+            memory: MemoryUsage {
+                total: system.total_memory(),
+                used: system.used_memory(),
+            },
+            swap: MemoryUsage {
+                total: system.total_swap(),
+                used: system.used_swap(),
+            },
+        }
+    }
+
+    /// The usage of all cores together rounded to whole percent for display.
+    pub fn cpu_percent(&self) -> u8 {
+        self.cpu_usage.clamp(0.0, 100.0).round() as u8
+    }
+
+    /// Formats the uptime for display, like `3d 4h 12m`.
+    pub fn uptime_text(&self) -> String {
+        let days = self.uptime / 86_400;
+        let hours = self.uptime % 86_400 / 3_600;
+        let minutes = self.uptime % 3_600 / 60;
+        if days > 0 {
+            format!("{days}d {hours}h {minutes}m")
+        } else if hours > 0 {
+            format!("{hours}h {minutes}m")
+        } else {
+            format!("{minutes}m")
+        }
+    }
+
+    /// Formats the boot time for display.
+    pub fn boot_time_text(&self) -> String {
+        self.boot_time
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| self.boot_time.to_string())
+    }
+
+    /// Formats the load averages for display.
+    pub fn load_text(&self) -> String {
+        format!(
+            "{:.2} {:.2} {:.2}",
+            self.load.one, self.load.five, self.load.fifteen
+        )
+    }
 }
 
-/// Checks the power status of the system.
+/// How long the number of pending updates is cached.
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// When the pending updates were last counted and how many there were.
+static UPDATES: LazyLock<Mutex<Option<(Instant, usize)>>> = LazyLock::new(Default::default);
+/// Makes sure only one check for updates runs at a time.
+static CHECKING_UPDATES: AtomicBool = AtomicBool::new(false);
+
+/// Gets the number of packages with updates.
+///
+/// Asking the package manager is slow, so the count is cached and refreshed in the background
+/// once it is older than [`UPDATE_CHECK_INTERVAL`].
 ///
 /// # Returns
 ///
-/// If the system runs on batteries, it will return the percentage of the battery.
-/// If the system does not run on batteries, it will return `Ok(100)`.
-pub fn check_power() -> Result<u8, Box<dyn std::error::Error + Send + Sync>> {
-    let manager = Manager::new()?;
-    // Use the first available battery (if any); if there is none, assume not low power.
-    if let Some(battery_result) = manager.batteries()?.next() {
-        let battery = battery_result?;
-        Ok((battery.state_of_charge().value * 100.0).round() as u8)
-    } else {
-        // No battery found, assume not applicable.
-        Ok(100)
+/// The count, or `None` if updates are ignored or were not counted yet.
+pub async fn pending_updates(settings: &Settings) -> Option<usize> {
+    if settings.ignore_updates {
+        return None;
+    }
+    let cached = *UPDATES.lock().await;
+    if cached.is_none_or(|(checked, _)| checked.elapsed() > UPDATE_CHECK_INTERVAL)
+        && !CHECKING_UPDATES.swap(true, Ordering::AcqRel)
+    {
+        tokio::spawn(async {
+            match tokio::task::spawn_blocking(update_manager::check_updates).await {
+                Ok(updates) => *UPDATES.lock().await = Some((Instant::now(), updates.len())),
+                Err(error) => log::error!("Failed to check for updates: {}", error),
+            }
+            CHECKING_UPDATES.store(false, Ordering::Release);
+        });
+    }
+    cached.map(|(_, count)| count)
+}
+
+/// The network throughput of all interfaces but loopback in bytes per second.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct NetworkRate {
+    pub received: u64,
+    pub transmitted: u64,
+}
+impl NetworkRate {
+    /// Calculates the throughput from networks refreshed `elapsed` after their last refresh.
+    pub fn measure(networks: &Networks, elapsed: Duration) -> Self {
+        let seconds = elapsed.as_secs_f64();
+        if seconds <= 0.0 {
+            return Self::default();
+        }
+        let (received, transmitted) = networks
+            .iter()
+            .filter(|(name, _)| name.as_str() != "lo")
+            .fold((0, 0), |(received, transmitted), (_, data)| {
+                (received + data.received(), transmitted + data.transmitted())
+            });
+        Self {
+            received: (received as f64 / seconds) as u64,
+            transmitted: (transmitted as f64 / seconds) as u64,
+        }
+    }
+}
+
+/// Everything the dashboard shows about the state of the system.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusReport {
+    pub system: SystemSnapshot,
+    pub mounts: Vec<MountUsage>,
+    pub network: NetworkRate,
+    /// The mount points below their storage threshold.
+    pub low_storage: Vec<String>,
+    pub power: PowerStatus,
+    pub low_power: bool,
+    pub temperatures: Vec<Temperature>,
+    /// The sensors above the temperature threshold.
+    pub high_temperature: Vec<String>,
+    pub restart_pending: bool,
+    /// The number of packages with updates, `None` if it is not known.
+    pub updates: Option<usize>,
+}
+impl StatusReport {
+    /// Checks if a mount is below its storage threshold.
+    pub fn is_low(&self, mount: &MountUsage) -> bool {
+        self.low_storage.contains(&mount.mount_point)
+    }
+
+    /// Checks if a sensor is above the temperature threshold.
+    pub fn is_hot(&self, temperature: &Temperature) -> bool {
+        self.high_temperature.contains(&temperature.label)
+    }
+
+    /// Checks if any package has an update.
+    pub fn updates_available(&self) -> bool {
+        self.updates.is_some_and(|count| count > 0)
+    }
+}
+
+/// Builds a report from a refreshed system and disks, this also checks the batteries, UPS and
+/// temperature sensors.
+pub fn report(
+    system: &System,
+    disks: &Disks,
+    network: NetworkRate,
+    power: PowerStatus,
+    settings: &Settings,
+    updates: Option<usize>,
+) -> StatusReport {
+    let mounts = check_storage(disks, &settings.storage);
+    let low_storage = low_storage(&mounts, &settings.threatsholds)
+        .into_iter()
+        .map(|mount| mount.mount_point)
+        .collect();
+    let temperatures = check_temperatures();
+    let high_temperature = temperatures
+        .iter()
+        .filter(|temperature| temperature.is_high(settings.threatsholds.high_temperature))
+        .map(|temperature| temperature.label.clone())
+        .collect();
+    StatusReport {
+        system: SystemSnapshot::collect(system),
+        mounts,
+        network,
+        low_storage,
+        low_power: power.is_low(settings.threatsholds.low_power),
+        power,
+        temperatures,
+        high_temperature,
+        restart_pending: RESTART_PENDING.load(Ordering::Relaxed),
+        updates,
+    }
+}
+
+#[cfg(test)]
+impl StatusReport {
+    /// A report of a made up system, for tests of what is built from reports.
+    pub fn fixture() -> Self {
+        let taken = time::macros::datetime!(2026-01-01 12:00 UTC);
+        Self {
+            system: SystemSnapshot {
+                taken,
+                hostname: String::from("host"),
+                kernel: String::from("6.1.0"),
+                distro: String::from("Debian GNU/Linux 12"),
+                uptime: 3600,
+                boot_time: taken - time::Duration::hours(1),
+                load: LoadAverage {
+                    one: 0.5,
+                    five: 0.25,
+                    fifteen: 0.125,
+                },
+                cpu_usage: 50.0,
+                cores: vec![CoreUsage {
+                    name: String::from("cpu0"),
+                    usage: 50.0,
+                    frequency: 2400,
+                }],
+                memory: MemoryUsage {
+                    total: 1000,
+                    used: 400,
+                },
+                swap: MemoryUsage::default(),
+            },
+            mounts: vec![MountUsage {
+                mount_point: String::from("/"),
+                device: String::from("/dev/sda1"),
+                fs_type: String::from("ext4"),
+                total: 1000,
+                available: 250,
+                inodes_total: 100,
+                inodes_free: 50,
+                read_only: false,
+            }],
+            network: NetworkRate {
+                received: 1000,
+                transmitted: 500,
+            },
+            low_storage: Vec::new(),
+            power: PowerStatus::default(),
+            low_power: false,
+            temperatures: vec![Temperature {
+                label: String::from("coretemp Package id 0"),
+                current: 45.0,
+                max: Some(80.0),
+                critical: Some(100.0),
+            }],
+            high_temperature: Vec::new(),
+            restart_pending: false,
+            updates: Some(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the files of a fake sysfs tree below `root`.
+    fn write_tree(root: &Path, files: &[(&str, &str)]) {
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, format!("{content}\n")).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_temperature_sensors() {
+        let root = tempfile::tempdir().unwrap();
+        write_tree(
+            root.path(),
+            &[
+                ("hwmon/hwmon0/name", "coretemp"),
+                ("hwmon/hwmon0/temp1_input", "52000"),
+                ("hwmon/hwmon0/temp1_label", "Package id 0"),
+                ("hwmon/hwmon0/temp1_max", "84000"),
+                ("hwmon/hwmon0/temp1_crit", "100000"),
+                ("hwmon/hwmon0/temp2_input", "49500"),
+                ("hwmon/hwmon0/temp2_label", "Core 0"),
+                // Two drives of the same kind get numbered
+                ("hwmon/hwmon1/name", "nvme"),
+                ("hwmon/hwmon1/temp1_input", "38850"),
+                ("hwmon/hwmon1/temp1_label", "Composite"),
+                ("hwmon/hwmon2/name", "nvme"),
+                ("hwmon/hwmon2/temp1_input", "41000"),
+                ("hwmon/hwmon2/temp1_label", "Composite"),
+                // Without a label the channel is named after its file
+                ("hwmon/hwmon3/name", "acpitz"),
+                ("hwmon/hwmon3/temp1_input", "27800"),
+                ("hwmon/hwmon3/temp1_crit", "119000"),
+                // The same sensor as the acpitz chip
+                ("thermal/thermal_zone0/type", "acpitz"),
+                ("thermal/thermal_zone0/temp", "27800"),
+                ("thermal/thermal_zone1/type", "x86_pkg_temp"),
+                ("thermal/thermal_zone1/temp", "53000"),
+                ("thermal/thermal_zone1/trip_point_0_type", "passive"),
+                ("thermal/thermal_zone1/trip_point_0_temp", "70000"),
+                ("thermal/thermal_zone1/trip_point_1_type", "hot"),
+                ("thermal/thermal_zone1/trip_point_1_temp", "90000"),
+                ("thermal/thermal_zone1/trip_point_2_type", "critical"),
+                ("thermal/thermal_zone1/trip_point_2_temp", "105000"),
+                ("thermal/cooling_device0/type", "Processor"),
+                ("thermal/cooling_device0/temp", "0"),
+            ],
+        );
+        let temperatures =
+            read_temperatures(&root.path().join("hwmon"), &root.path().join("thermal"));
+        let found: Vec<(&str, f32, Option<f32>, Option<f32>)> = temperatures
+            .iter()
+            .map(|value| {
+                (
+                    value.label.as_str(),
+                    value.current,
+                    value.max,
+                    value.critical,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("acpitz temp1", 27.8, None, Some(119.0)),
+                ("coretemp Core 0", 49.5, None, None),
+                ("coretemp Package id 0", 52.0, Some(84.0), Some(100.0)),
+                ("nvme Composite", 38.85, None, None),
+                ("nvme Composite 2", 41.0, None, None),
+                ("x86_pkg_temp", 53.0, Some(90.0), Some(105.0)),
+            ]
+        );
+        assert_eq!(
+            temperatures[2].limits_text(),
+            "high 84.0 °C, critical 100.0 °C"
+        );
+    }
+
+    #[test]
+    fn missing_sysfs_has_no_sensors() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(
+            read_temperatures(&root.path().join("hwmon"), &root.path().join("thermal")).is_empty()
+        );
+    }
+
+    #[test]
+    fn flags_sensors_above_the_threshold_or_critical() {
+        let sensor = |current, critical| Temperature {
+            label: String::from("sensor"),
+            current,
+            max: None,
+            critical,
+        };
+        assert!(!sensor(79.9, None).is_high(80));
+        assert!(sensor(80.0, None).is_high(80));
+        // A sensor at its own critical temperature is high whatever the threshold
+        assert!(sensor(70.0, Some(70.0)).is_high(80));
+        assert!(!sensor(69.0, Some(70.0)).is_high(80));
     }
 }
@@ -0,0 +1,207 @@
+//! # Tokens
+//!
+//! This module contains the single-use tokens admins hand out as password reset and invite
+//! links.
+//!
+//! Only a SHA-256 hash of each token is stored, the token itself is shown to the admin once
+//! when it is created.
+use std::{collections::HashMap, io, net::SocketAddr, sync::LazyLock};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::sync::Mutex;
+
+use crate::persist;
+
+/// The path to the tokens file.
+#[cfg(not(debug_assertions))]
+const TOKENS_PATH: &str = "/var/lib/system_manager_server/tokens.json";
+#[cfg(debug_assertions)]
+const TOKENS_PATH: &str = "./tokens.json";
+
+/// Makes sure only one task reads and updates the tokens file at a time.
+static TOKENS_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// What a token can be used for.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    /// Lets an existing user set a new password.
+    Reset,
+    /// Lets a newly created user set their first password.
+    Invite,
+}
+impl TokenKind {
+    /// How long a token of this kind stays valid.
+    pub fn lifetime(&self) -> Duration {
+        match self {
+            Self::Reset => Duration::hours(24),
+            Self::Invite => Duration::days(7),
+        }
+    }
+}
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reset => write!(f, "password reset"),
+            Self::Invite => write!(f, "invite"),
+        }
+    }
+}
+
+/// A stored token, without the token itself.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenRecord {
+    pub kind: TokenKind,
+    pub username: String,
+    /// The admin that created the token.
+    pub created_by: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub used: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub used_ip: Option<String>,
+    /// Set when a newer token for the same user replaced this one.
+    #[serde(default)]
+    pub revoked: bool,
+}
+impl TokenRecord {
+    /// Checks if the token can still be used.
+    pub fn usable(&self) -> bool {
+        self.used.is_none() && !self.revoked && self.expires > OffsetDateTime::now_utc()
+    }
+
+    /// Describes the state of the token for display.
+    pub fn state(&self) -> String {
+        if let Some(used) = self.used {
+            format!(
+                "used {} from {}",
+                format_time(used),
+                self.used_ip.as_deref().unwrap_or("unknown")
+            )
+        } else if self.revoked {
+            "replaced".to_string()
+        } else if self.expires <= OffsetDateTime::now_utc() {
+            "expired".to_string()
+        } else {
+            format!("valid until {}", format_time(self.expires))
+        }
+    }
+
+    /// Formats the creation time for display.
+    pub fn created_time(&self) -> String {
+        format_time(self.created)
+    }
+}
+
+fn format_time(value: OffsetDateTime) -> String {
+    value.format(&Rfc3339).unwrap_or_else(|_| value.to_string())
+}
+
+/// Generates a new random token.
+pub fn generate() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Hashes a token for storage.
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Loads the tokens by hash, the caller must hold [`TOKENS_LOCK`].
+async fn load() -> io::Result<HashMap<String, TokenRecord>> {
+    match persist::read(TOKENS_PATH).await {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Saves the tokens, the caller must hold [`TOKENS_LOCK`].
+async fn save(tokens: &HashMap<String, TokenRecord>) -> io::Result<()> {
+    persist::write(TOKENS_PATH, serde_json::to_vec(tokens)?).await
+}
+
+/// Creates a token for a user, replacing any unused token of the same kind.
+///
+/// # Returns
+///
+/// The token, this is the only time it is available.
+pub async fn create(kind: TokenKind, username: &str, created_by: &str) -> io::Result<String> {
+    let _guard = TOKENS_LOCK.lock().await;
+    let mut tokens = load().await?;
+    let now = OffsetDateTime::now_utc();
+    // Forget tokens that can never be used again after a while
+    tokens.retain(|_, record| record.usable() || now - record.created < Duration::days(90));
+    for record in tokens.values_mut() {
+        if record.username == username && record.kind == kind && record.usable() {
+            record.revoked = true;
+        }
+    }
+    let token = generate();
+    tokens.insert(
+        hash(&token),
+        TokenRecord {
+            kind,
+            username: username.to_string(),
+            created_by: created_by.to_string(),
+            created: now,
+            expires: now + kind.lifetime(),
+            used: None,
+            used_ip: None,
+            revoked: false,
+        },
+    );
+    save(&tokens).await?;
+    Ok(token)
+}
+
+/// Looks up a token that can still be used.
+pub async fn find(token: &str) -> io::Result<Option<TokenRecord>> {
+    let _guard = TOKENS_LOCK.lock().await;
+    Ok(load()
+        .await?
+        .remove(&hash(token))
+        .filter(TokenRecord::usable))
+}
+
+/// Marks a token as used so it can not be used again.
+///
+/// # Returns
+///
+/// The token record, or `None` if the token was not usable (anymore).
+pub async fn redeem(token: &str, address: SocketAddr) -> io::Result<Option<TokenRecord>> {
+    let _guard = TOKENS_LOCK.lock().await;
+    let mut tokens = load().await?;
+    let Some(record) = tokens
+        .get_mut(&hash(token))
+        .filter(|record| record.usable())
+    else {
+        return Ok(None);
+    };
+    record.used = Some(OffsetDateTime::now_utc());
+    record.used_ip = Some(address.ip().to_string());
+    let record = record.clone();
+    save(&tokens).await?;
+    Ok(Some(record))
+}
+
+/// Lists the tokens created for a user, newest first.
+pub async fn list(username: &str) -> io::Result<Vec<TokenRecord>> {
+    let _guard = TOKENS_LOCK.lock().await;
+    let mut tokens: Vec<TokenRecord> = load()
+        .await?
+        .into_values()
+        .filter(|record| record.username == username)
+        .collect();
+    tokens.sort_by_key(|record| std::cmp::Reverse(record.created));
+    Ok(tokens)
+}
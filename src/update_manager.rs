@@ -32,6 +32,7 @@ pub fn run_command(command: &str) -> Option<String> {
 }
 
 // Parses installed packages into a Vec<String>
+#[allow(dead_code)]
 pub fn list_installed_packages() -> Vec<String> {
     let command = match detect_package_manager() {
         Some("apt") => "dpkg --get-selections | awk '{print $1}'",
@@ -72,6 +73,7 @@ pub fn check_updates() -> Vec<(String, String)> {
 }
 
 // Updates packages
+#[allow(dead_code)]
 pub fn update_packages() {
     let updates = check_updates();
     if updates.is_empty() {
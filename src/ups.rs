@@ -0,0 +1,497 @@
+//! # UPS
+//!
+//! This module monitors a UPS through a Network UPS Tools server.
+//!
+//! Servers rarely have batteries of their own but usually sit on a UPS, `upsd` shares its state
+//! over a line based TCP protocol. The state of the UPS counts towards the low power threshold and
+//! the alerts, and the system can be shut down gracefully before the UPS runs out.
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{
+    provision::{CommandExecutor, SystemExecutor},
+    sampler::Reports,
+    settings::{self, ShutdownCommand, UpsSettings},
+    status::format_duration,
+};
+
+/// The port `upsd` listens on by default.
+const DEFAULT_PORT: u16 = 3493;
+/// How long the server may take to connect or answer.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// If the last check failed, so a server that is down is only logged once.
+static UNREACHABLE: AtomicBool = AtomicBool::new(false);
+/// If a shutdown was started, so it is only started once.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// An error that can occur when talking to a NUT server.
+#[derive(Debug)]
+pub enum UpsError {
+    IoError(io::Error),
+    /// The server address could not be resolved.
+    InvalidServer(String),
+    /// The server answered with an error, like `UNKNOWN-UPS`.
+    Server(String),
+    /// The server answered with something that is not part of the protocol.
+    UnexpectedReply(String),
+}
+impl From<io::Error> for UpsError {
+    fn from(value: io::Error) -> Self {
+        Self::IoError(value)
+    }
+}
+impl std::fmt::Display for UpsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(error) => write!(f, "IO error: {error}"),
+            Self::InvalidServer(server) => write!(f, "Invalid UPS server {server}"),
+            Self::Server(error) => write!(f, "The UPS server answered {error}"),
+            Self::UnexpectedReply(line) => {
+                write!(f, "Unexpected reply from the UPS server: {line}")
+            }
+        }
+    }
+}
+impl std::error::Error for UpsError {}
+
+type Result<T> = std::result::Result<T, UpsError>;
+
+/// The receiving end of the UPS states, always holding the latest one.
+pub type UpsReports = watch::Receiver<Option<UpsStatus>>;
+
+/// The state of a UPS.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpsStatus {
+    /// The UPS as `name@server`.
+    pub name: String,
+    pub model: String,
+    /// The raw status flags, like `OB DISCHRG`.
+    pub status: String,
+    /// The charge of the battery in percent.
+    pub charge: Option<u8>,
+    /// The runtime left on battery in seconds.
+    pub runtime: Option<u64>,
+    /// The load in percent of the capacity.
+    pub load: Option<u8>,
+    /// If the UPS runs on its battery because the line power is gone.
+    pub on_battery: bool,
+    /// If the UPS reports its battery as low or is forcing a shutdown.
+    pub low_battery: bool,
+}
+impl UpsStatus {
+    /// Builds the state from the variables of the UPS.
+    fn from_vars(name: String, vars: &HashMap<String, String>) -> Self {
+        let parse = |var: &str| {
+            vars.get(var)
+                .and_then(|value| value.trim().parse::<f64>().ok())
+        };
+        let status = vars.get("ups.status").cloned().unwrap_or_default();
+        let flags: Vec<&str> = status.split_whitespace().collect();
+        let model = [vars.get("ups.mfr"), vars.get("ups.model")]
+            .into_iter()
+            .flatten()
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Self {
+            name,
+            model,
+            charge: parse("battery.charge").map(|value| value.clamp(0.0, 100.0) as u8),
+            runtime: parse("battery.runtime").map(|value| value.max(0.0) as u64),
+            load: parse("ups.load").map(|value| value.clamp(0.0, 255.0) as u8),
+            on_battery: flags.contains(&"OB"),
+            low_battery: flags.contains(&"LB") || flags.contains(&"FSD"),
+            status,
+        }
+    }
+
+    /// Checks if the UPS runs on a battery that is low or below a threshold.
+    pub fn is_low(&self, threshold: u8) -> bool {
+        self.on_battery
+            && (self.low_battery || self.charge.is_some_and(|charge| charge < threshold))
+    }
+
+    /// Formats the power source, load and runtime for display.
+    pub fn state_text(&self) -> String {
+        let mut text = String::from(if self.on_battery {
+            "On battery"
+        } else {
+            "On line power"
+        });
+        if self.low_battery {
+            text.push_str(", battery low");
+        }
+        if let Some(load) = self.load {
+            text.push_str(&format!(", {load}% load"));
+        }
+        if let Some(runtime) = self.runtime {
+            text.push_str(&format!(", {} runtime", format_duration(runtime)));
+        }
+        text
+    }
+}
+
+/// A connection to a NUT server.
+struct Client {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+}
+impl Client {
+    /// Connects to a server given as `host` or `host:port`, IPv6 addresses need brackets.
+    fn connect(server: &str) -> Result<Self> {
+        let (host, port) = match server.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| UpsError::InvalidServer(server.to_string()))?,
+            ),
+            _ => (server, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let address = (host, port)
+            .to_socket_addrs()
+            .map_err(|_| UpsError::InvalidServer(server.to_string()))?
+            .next()
+            .ok_or_else(|| UpsError::InvalidServer(server.to_string()))?;
+        let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+        })
+    }
+
+    /// Sends a command.
+    fn send(&mut self, command: &str) -> Result<()> {
+        self.stream.write_all(format!("{command}\n").as_bytes())?;
+        Ok(())
+    }
+
+    /// Reads a line of the answer.
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(UpsError::IoError(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        match line.strip_prefix("ERR ") {
+            Some(error) => Err(UpsError::Server(error.to_string())),
+            None => Ok(line),
+        }
+    }
+
+    /// Lists the variables of a UPS.
+    fn list_vars(&mut self, ups: &str) -> Result<HashMap<String, String>> {
+        self.send(&format!("LIST VAR {ups}"))?;
+        let line = self.read_line()?;
+        if line != format!("BEGIN LIST VAR {ups}") {
+            return Err(UpsError::UnexpectedReply(line));
+        }
+        let prefix = format!("VAR {ups} ");
+        let end = format!("END LIST VAR {ups}");
+        let mut vars = HashMap::new();
+        loop {
+            let line = self.read_line()?;
+            if line == end {
+                return Ok(vars);
+            }
+            let (name, value) = line
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.split_once(' '))
+                .ok_or_else(|| UpsError::UnexpectedReply(line.clone()))?;
+            vars.insert(name.to_string(), unquote(value));
+        }
+    }
+}
+
+/// Removes the quotes and escapes around a value.
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(char) = chars.next() {
+        if char == '\\' {
+            out.extend(chars.next());
+        } else {
+            out.push(char);
+        }
+    }
+    out
+}
+
+/// Reads the state of the configured UPS.
+///
+/// This blocks for up to a few seconds if the server does not answer.
+pub fn read(settings: &UpsSettings) -> Result<UpsStatus> {
+    let mut client = Client::connect(&settings.server)?;
+    let vars = client.list_vars(&settings.name)?;
+    // The server closes the connection either way
+    let _ = client.send("LOGOUT");
+    Ok(UpsStatus::from_vars(
+        format!("{}@{}", settings.name, settings.server),
+        &vars,
+    ))
+}
+
+/// Checks the configured UPS.
+///
+/// # Returns
+///
+/// `None` if no UPS is configured or the server can not be reached, failures are logged once
+/// until the server answers again.
+pub fn check(settings: &UpsSettings) -> Option<UpsStatus> {
+    if settings.server.is_empty() {
+        return None;
+    }
+    match read(settings) {
+        Ok(value) => {
+            if UNREACHABLE.swap(false, Ordering::Relaxed) {
+                log::info!(
+                    "The UPS {}@{} answers again",
+                    settings.name,
+                    settings.server
+                );
+            }
+            Some(value)
+        }
+        Err(error) => {
+            if !UNREACHABLE.swap(true, Ordering::Relaxed) {
+                log::error!(
+                    "Failed to read the UPS {}@{}: {}",
+                    settings.name,
+                    settings.server,
+                    error
+                );
+            }
+            None
+        }
+    }
+}
+
+/// Checks the configured UPS on a blocking thread.
+async fn check_blocking() -> Option<UpsStatus> {
+    let settings = settings::load_settings().await.ups;
+    tokio::task::spawn_blocking(move || check(&settings))
+        .await
+        .unwrap_or_else(|error| {
+            log::error!("Failed to check the UPS: {}", error);
+            None
+        })
+}
+
+/// Reads the first state of the UPS and keeps polling it in the background.
+///
+/// A server that does not answer takes seconds to time out, so the UPS is polled on its own
+/// task at the sample interval instead of holding back the samples.
+pub async fn poll() -> UpsReports {
+    let (sender, receiver) = watch::channel(check_blocking().await);
+    tokio::spawn(async move {
+        loop {
+            let interval = settings::load_settings().await.status.sample_interval;
+            tokio::time::sleep(Duration::from_secs(interval.max(1))).await;
+            sender.send_replace(check_blocking().await);
+        }
+    });
+    receiver
+}
+
+/// Checks if the system has to be shut down before the UPS runs out.
+fn needs_shutdown(ups: &UpsStatus, settings: &UpsSettings) -> bool {
+    settings.shutdown_runtime > 0
+        && ups.on_battery
+        && (ups.low_battery
+            || ups
+                .runtime
+                .is_some_and(|runtime| runtime < settings.shutdown_runtime))
+}
+
+/// Runs the shutdown command.
+fn shutdown(
+    executor: &dyn CommandExecutor,
+    ups: &UpsStatus,
+    command: ShutdownCommand,
+) -> io::Result<()> {
+    log::warn!(
+        "The UPS {} is on battery with {} left, shutting down",
+        ups.name,
+        ups.runtime.map(format_duration).unwrap_or_default()
+    );
+    let (program, args) = command.argv();
+    let args: Vec<String> = args.iter().map(|value| value.to_string()).collect();
+    let output = executor.run(program, &args)?;
+    if !output.success() {
+        return Err(io::Error::other(format!(
+            "{command} failed: {}",
+            output.stderr
+        )));
+    }
+    Ok(())
+}
+
+/// Watches the reports and shuts the system down when the UPS runs low.
+pub fn spawn(mut reports: Reports) {
+    tokio::spawn(async move {
+        loop {
+            let ups = reports.borrow_and_update().power.ups.clone();
+            if let Some(ups) = ups {
+                let settings = settings::load_settings().await.ups;
+                let command = settings.shutdown_command;
+                if needs_shutdown(&ups, &settings)
+                    && !SHUTTING_DOWN.swap(true, Ordering::AcqRel)
+                    && let Err(error) = tokio::task::spawn_blocking(move || {
+                        shutdown(&SystemExecutor, &ups, command)
+                    })
+                    .await
+                    .unwrap_or_else(|error| Err(io::Error::other(error)))
+                {
+                    log::error!("Failed to shut down: {}", error);
+                    // Tried again with the next report
+                    SHUTTING_DOWN.store(false, Ordering::Release);
+                }
+            }
+            if reports.changed().await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+    use crate::provision::RecordingExecutor;
+
+    /// Starts a fake `upsd` that answers one connection with `reply` and returns its address.
+    fn fake_upsd(reply: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "LIST VAR myups\n");
+            (&stream).write_all(reply.as_bytes()).unwrap();
+        });
+        address
+    }
+
+    fn settings(server: String) -> UpsSettings {
+        UpsSettings {
+            server,
+            name: String::from("myups"),
+            shutdown_runtime: 300,
+            ..Default::default()
+        }
+    }
+
+    fn status(flags: &str, runtime: Option<u64>) -> UpsStatus {
+        UpsStatus {
+            name: String::from("myups@localhost"),
+            model: String::new(),
+            status: flags.to_string(),
+            charge: Some(50),
+            runtime,
+            load: None,
+            on_battery: flags.split_whitespace().any(|flag| flag == "OB"),
+            low_battery: flags
+                .split_whitespace()
+                .any(|flag| flag == "LB" || flag == "FSD"),
+        }
+    }
+
+    #[test]
+    fn reads_the_variables() {
+        let server = fake_upsd(concat!(
+            "BEGIN LIST VAR myups\n",
+            "VAR myups battery.charge \"87\"\n",
+            "VAR myups battery.runtime \"1260\"\n",
+            "VAR myups ups.load \"23\"\n",
+            "VAR myups ups.mfr \"APC\"\n",
+            "VAR myups ups.model \"Back-UPS \\\"XS\\\" 700\"\n",
+            "VAR myups ups.status \"OB DISCHRG\"\n",
+            "END LIST VAR myups\n",
+        ));
+        let ups = read(&settings(server.clone())).unwrap();
+        assert_eq!(ups.name, format!("myups@{server}"));
+        assert_eq!(ups.model, "APC Back-UPS \"XS\" 700");
+        assert_eq!(ups.status, "OB DISCHRG");
+        assert_eq!(ups.charge, Some(87));
+        assert_eq!(ups.runtime, Some(1260));
+        assert_eq!(ups.load, Some(23));
+        assert!(ups.on_battery);
+        assert!(!ups.low_battery);
+    }
+
+    #[test]
+    fn server_errors_are_returned() {
+        let server = fake_upsd("ERR UNKNOWN-UPS\n");
+        match read(&settings(server)) {
+            Err(UpsError::Server(error)) => assert_eq!(error, "UNKNOWN-UPS"),
+            other => panic!("expected a server error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unexpected_replies_are_refused() {
+        let server = fake_upsd("BEGIN LIST VAR myups\nnonsense\n");
+        assert!(matches!(
+            read(&settings(server)),
+            Err(UpsError::UnexpectedReply(line)) if line == "nonsense"
+        ));
+    }
+
+    #[test]
+    fn unquotes_values() {
+        assert_eq!(unquote("\"plain\""), "plain");
+        assert_eq!(unquote("\"say \\\"hi\\\"\""), "say \"hi\"");
+        assert_eq!(unquote("\"back\\\\slash\""), "back\\slash");
+        assert_eq!(unquote("unquoted"), "unquoted");
+    }
+
+    #[test]
+    fn shuts_down_on_low_battery_or_runtime() {
+        let settings = settings(String::new());
+        assert!(needs_shutdown(&status("OB LB", Some(1000)), &settings));
+        assert!(needs_shutdown(&status("OB FSD", None), &settings));
+        assert!(needs_shutdown(&status("OB DISCHRG", Some(299)), &settings));
+        assert!(!needs_shutdown(&status("OB DISCHRG", Some(300)), &settings));
+        assert!(!needs_shutdown(&status("OB DISCHRG", None), &settings));
+        // The UPS reports LB while charging after an outage, that is no reason to shut down
+        assert!(!needs_shutdown(&status("OL CHRG LB", Some(10)), &settings));
+        let never = UpsSettings {
+            shutdown_runtime: 0,
+            ..settings
+        };
+        assert!(!needs_shutdown(&status("OB LB", Some(10)), &never));
+    }
+
+    #[test]
+    fn runs_only_the_chosen_command() {
+        let executor = RecordingExecutor::default();
+        let ups = status("OB LB", Some(10));
+        shutdown(&executor, &ups, ShutdownCommand::Shutdown).unwrap();
+        shutdown(&executor, &ups, ShutdownCommand::Poweroff).unwrap();
+        assert_eq!(
+            *executor.commands.lock().unwrap(),
+            [vec!["shutdown", "-h", "+0"], vec!["systemctl", "poweroff"]]
+        );
+    }
+}
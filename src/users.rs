@@ -2,19 +2,31 @@
 //!
 //! This module contains the logic for managing users.
 use std::{
+    collections::HashMap,
     path::PathBuf,
     str::FromStr,
-    sync::{LazyLock, Once},
+    sync::{LazyLock, Once, RwLock},
+    time::{Duration as StdDuration, Instant},
 };
 
 use argon2::{
-    Argon2, PasswordVerifier,
-    password_hash::{PasswordHashString, PasswordHasher, SaltString, rand_core::OsRng},
+    Algorithm, Argon2, Params, PasswordVerifier, Version,
+    password_hash::{
+        PasswordHash, PasswordHashString, PasswordHasher, SaltString, rand_core::OsRng,
+    },
 };
 use axum::{http::StatusCode, response::IntoResponse};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
 use tokio::{fs, task};
 
+use crate::{
+    password, persist,
+    settings::{Argon2Settings, PasswordPolicy},
+    tokens,
+};
+
 /// The path to the users directory.
 #[cfg(not(debug_assertions))]
 #[cfg(target_os = "linux")]
@@ -27,14 +39,78 @@ compile_error!("Only Linux is supported right now");
 #[cfg(debug_assertions)]
 const USERS_PATH: &str = "./users";
 
-/// The password hasher.
-static PASSWORD_HASHER: LazyLock<Argon2> = LazyLock::new(Argon2::default);
+/// How long a password checked by [`User::authenticate`] is remembered.
+const VERIFIED_LIFETIME: StdDuration = StdDuration::from_secs(5 * 60);
+
+/// Passwords recently checked by [`User::authenticate`] by username, as a hash of the password
+/// and the stored password hash together with when they were checked.
+static VERIFIED: LazyLock<tokio::sync::Mutex<HashMap<String, (String, Instant)>>> =
+    LazyLock::new(Default::default);
+
+/// The parameters new password hashes are created with.
+static HASHER_PARAMS: LazyLock<RwLock<Params>> = LazyLock::new(Default::default);
+
+/// Sets the parameters new password hashes are created with.
+///
+/// Existing hashes keep working and are upgraded the next time their user logs in.
+pub fn configure_hasher(settings: &Argon2Settings) -> Result<()> {
+    let params = Params::new(
+        settings.memory_cost,
+        settings.time_cost,
+        settings.parallelism,
+        None,
+    )
+    .map_err(|error| UserError::Other(format!("Invalid argon2 parameters: {error}")))?;
+    match HASHER_PARAMS.write() {
+        Ok(mut value) => *value = params,
+        Err(error) => *error.into_inner() = params,
+    }
+    Ok(())
+}
+
+/// Gets the password hasher with the configured parameters.
+fn password_hasher() -> Argon2<'static> {
+    let params = match HASHER_PARAMS.read() {
+        Ok(value) => value.clone(),
+        Err(error) => error.into_inner().clone(),
+    };
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// Checks if a stored hash was made with a different algorithm or parameters than configured.
+fn hash_outdated(hashed_password: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hashed_password) else {
+        return true;
+    };
+    let current = password_hasher();
+    let current = current.params();
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || Params::try_from(&hash).is_ok_and(|params| {
+            params.m_cost() != current.m_cost()
+                || params.t_cost() != current.t_cost()
+                || params.p_cost() != current.p_cost()
+        })
+}
 
 /// An error that can occur when managing users.
 #[derive(Debug)]
 pub enum UserError {
     IoError(std::io::Error),
     PasswordError,
+    /// The new password breaks the password policy.
+    PolicyViolation(String),
+    /// The account was disabled by an admin.
+    AccountDisabled,
+    /// The account is past its expiry date.
+    AccountExpired,
+    /// The password has to be changed before the account can be used.
+    PasswordChangeRequired,
+    /// Too many attempts failed recently, holds how long until the next one is allowed.
+    TooManyAttempts(std::time::Duration),
+    /// The username can not be used as a username.
+    InvalidUsername,
+    UserExists,
     UserNotFound,
     Other(String),
 }
@@ -55,19 +131,48 @@ impl std::fmt::Display for UserError {
         match self {
             Self::IoError(error) => write!(f, "IO Error: {error}"),
             Self::PasswordError => write!(f, "Password Error"),
+            Self::PolicyViolation(error) => write!(f, "Password {error}"),
+            Self::AccountDisabled => write!(f, "Account Disabled"),
+            Self::AccountExpired => write!(f, "Account Expired"),
+            Self::PasswordChangeRequired => write!(f, "Password Change Required"),
+            Self::TooManyAttempts(wait) => write!(
+                f,
+                "Too Many Failed Logins, try again in {} minutes",
+                wait.as_secs().div_ceil(60)
+            ),
+            Self::InvalidUsername => write!(
+                f,
+                "Invalid Username (use up to 32 letters, digits, '-', '_' or '.')"
+            ),
+            Self::UserExists => write!(f, "User Already Exists"),
             Self::UserNotFound => write!(f, "User Not Found"),
             Self::Other(error) => write!(f, "{}", error),
         }
     }
 }
 impl std::error::Error for UserError {}
+impl UserError {
+    /// The status code to respond with for this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::IoError(_) | Self::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::PasswordError | Self::PolicyViolation(_) => StatusCode::BAD_REQUEST,
+            Self::AccountDisabled | Self::AccountExpired | Self::PasswordChangeRequired => {
+                StatusCode::FORBIDDEN
+            }
+            Self::InvalidUsername => StatusCode::BAD_REQUEST,
+            Self::UserExists => StatusCode::CONFLICT,
+            Self::UserNotFound => StatusCode::NOT_FOUND,
+            Self::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
 impl IntoResponse for UserError {
     fn into_response(self) -> axum::response::Response {
         match self {
-            Self::IoError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "IO Error").into_response(),
-            Self::PasswordError => (StatusCode::BAD_REQUEST, "Password Error").into_response(),
-            Self::UserNotFound => (StatusCode::NOT_FOUND, "User Not Found").into_response(),
-            Self::Other(error) => (StatusCode::INTERNAL_SERVER_ERROR, error).into_response(),
+            Self::IoError(_) => (self.status(), "IO Error").into_response(),
+            Self::Other(ref error) => (self.status(), error.clone()).into_response(),
+            _ => (self.status(), self.to_string()).into_response(),
         }
     }
 }
@@ -88,6 +193,82 @@ macro_rules! tokio_error {
     };
 }
 
+/// Hashes a password on a blocking thread.
+pub async fn hash_password(password: String) -> Result<String> {
+    let hash = tokio_error!(
+        task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            password_hasher()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|value| value.to_string())
+        })
+        .await
+    );
+    // Never store an empty hash, that would make the account unusable (or worse)
+    hash.map_err(|error| {
+        log::error!("Failed to hash password: {}", error);
+        UserError::Other("Failed to hash password".to_string())
+    })
+}
+
+/// Verifies a password against a stored hash on a blocking thread.
+pub async fn verify_password(password: String, hashed_password: String) -> Result<bool> {
+    Ok(tokio_error!(
+        task::spawn_blocking(move || {
+            // Okay the sytnax here cloud be cleaner but I'm too lazy to clean it up so
+            // I'll just explain it
+            password_hasher()
+                .verify_password(
+                    password.as_bytes(),
+                    //we have to make sure it is borrowed here
+                    &(match PasswordHashString::from_str(&hashed_password) {
+                        // okay here we
+                        // load the password hash from the string
+                        Ok(value) => value, // then if it's okay we use the
+                        // password hash string
+                        Err(_) => return false,
+                    }
+                    .password_hash()), // then we convert the password hash string to a password hash (it's some weird typing thing)
+                )
+                .is_ok()
+        })
+        .await
+    ))
+}
+
+/// Checks a new password against the policy, returning the problems as an error.
+fn check_policy(policy: &PasswordPolicy, password: &str) -> Result<()> {
+    let problems = password::check(policy, password);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(UserError::PolicyViolation(problems.join(", ")))
+    }
+}
+
+/// Checks if a name can be used as a username.
+///
+/// Usernames end up as directory names so anything that could escape the users directory is
+/// refused.
+pub fn valid_username(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && !name.starts_with(['.', '-'])
+        && name
+            .chars()
+            .all(|value| value.is_ascii_alphanumeric() || matches!(value, '-' | '_' | '.'))
+}
+
+/// Gets the directory a user's files are stored in.
+pub fn user_dir(username: &str) -> PathBuf {
+    PathBuf::from(USERS_PATH).join(username.trim())
+}
+
+/// Gets the directory holding the files a user uploads, this is what counts towards their quota.
+pub fn data_dir(username: &str) -> PathBuf {
+    user_dir(username).join("files")
+}
+
 static ENSURE_DIR: Once = Once::new();
 /// A user on within system manager server (not necessarily a system user just a user in our database)
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -96,40 +277,98 @@ pub struct User {
     password: String,
     admin: bool,
     storage: usize, // the amount of storage the user has in byte
+    /// The hashes of previous passwords, newest first.
+    #[serde(default)]
+    password_history: Vec<String>,
+    /// When the password was last changed, `None` for users from before this was tracked.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    password_changed: Option<OffsetDateTime>,
+    /// If the user has to pick a new password before doing anything else.
+    #[serde(default)]
+    must_change_password: bool,
+    /// If the account was disabled, disabled accounts are kept but can not log in.
+    #[serde(default)]
+    disabled: bool,
+    /// When the account stops working.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    expires_at: Option<OffsetDateTime>,
+    /// If the manager created the Linux account of the user, only those are changed or removed.
+    #[serde(default)]
+    provisioned: bool,
+    /// The login shell of the Linux account, `None` for the default shell.
+    #[serde(default)]
+    shell: Option<String>,
+    /// The supplementary groups of the Linux account.
+    #[serde(default)]
+    system_groups: Vec<String>,
 }
 impl User {
     /// Creates a new user from a name and password
-    pub async fn new(name: &str, password: &str) -> Result<Self> {
+    pub async fn new(name: &str, password: &str, policy: &PasswordPolicy) -> Result<Self> {
         ENSURE_DIR.call_once(|| {
             std::fs::create_dir_all(USERS_PATH).unwrap();
         });
         let password = password.trim().to_string();
-        let password_hash = tokio_error!(
-            task::spawn_blocking(move || {
-                let salt = SaltString::generate(&mut OsRng);
-                let password_hash = match PASSWORD_HASHER.hash_password(password.as_bytes(), &salt)
-                {
-                    Ok(value) => value,
-                    Err(error) => {
-                        log::error!("Failed to hash password: {}", error);
-                        return String::new();
-                    }
-                };
-                password_hash.to_string()
-            })
-            .await
-        );
+        check_policy(policy, &password)?;
+        let password_hash = hash_password(password).await?;
+        let name = name.trim();
+        if !valid_username(name) {
+            return Err(UserError::InvalidUsername);
+        }
         let user = Self {
-            name: name.trim().to_string(),
+            name: name.to_string(),
             password: password_hash,
             admin: false,
             storage: 1073741824, // 1 GB is the default storage size
+            password_history: Vec::new(),
+            password_changed: Some(OffsetDateTime::now_utc()),
+            must_change_password: false,
+            disabled: false,
+            expires_at: None,
+            provisioned: false,
+            shell: None,
+            system_groups: Vec::new(),
         };
         user.clone().save().await?; // save the user to the database so we can fetch it later as
         // needed
         Ok(user)
     }
 
+    /// Creates a new user with a random password nobody knows.
+    ///
+    /// The user sets their own password through an invite link.
+    pub async fn invite(name: &str) -> Result<Self> {
+        let name = name.trim();
+        if !valid_username(name) {
+            return Err(UserError::InvalidUsername);
+        }
+        if fs::try_exists(user_dir(name).join("user.json"))
+            .await
+            .unwrap_or(false)
+        {
+            return Err(UserError::UserExists);
+        }
+        ENSURE_DIR.call_once(|| {
+            std::fs::create_dir_all(USERS_PATH).unwrap();
+        });
+        let user = Self {
+            name: name.to_string(),
+            password: hash_password(tokens::generate()).await?,
+            admin: false,
+            storage: 1073741824, // 1 GB is the default storage size
+            password_history: Vec::new(),
+            password_changed: Some(OffsetDateTime::now_utc()),
+            must_change_password: false,
+            disabled: false,
+            expires_at: None,
+            provisioned: false,
+            shell: None,
+            system_groups: Vec::new(),
+        };
+        user.clone().save().await?;
+        Ok(user)
+    }
+
     #[inline]
     pub fn name(&self) -> &str {
         &self.name
@@ -144,6 +383,30 @@ impl User {
         self.storage
     }
     #[inline]
+    pub fn must_change_password(&self) -> bool {
+        self.must_change_password
+    }
+    #[inline]
+    pub fn disabled(&self) -> bool {
+        self.disabled
+    }
+    #[inline]
+    pub fn expires_at(&self) -> Option<OffsetDateTime> {
+        self.expires_at
+    }
+    #[inline]
+    pub fn provisioned(&self) -> bool {
+        self.provisioned
+    }
+    #[inline]
+    pub fn shell(&self) -> Option<&str> {
+        self.shell.as_deref()
+    }
+    #[inline]
+    pub fn system_groups(&self) -> &[String] {
+        &self.system_groups
+    }
+    #[inline]
     pub fn admin_mut(&mut self) -> &mut bool {
         &mut self.admin
     }
@@ -151,40 +414,110 @@ impl User {
     pub fn storage_mut(&mut self) -> &mut usize {
         &mut self.storage
     }
+    #[inline]
+    pub fn must_change_password_mut(&mut self) -> &mut bool {
+        &mut self.must_change_password
+    }
+    #[inline]
+    pub fn disabled_mut(&mut self) -> &mut bool {
+        &mut self.disabled
+    }
+    #[inline]
+    pub fn expires_at_mut(&mut self) -> &mut Option<OffsetDateTime> {
+        &mut self.expires_at
+    }
+    #[inline]
+    pub fn provisioned_mut(&mut self) -> &mut bool {
+        &mut self.provisioned
+    }
+    #[inline]
+    pub fn shell_mut(&mut self) -> &mut Option<String> {
+        &mut self.shell
+    }
+    #[inline]
+    pub fn system_groups_mut(&mut self) -> &mut Vec<String> {
+        &mut self.system_groups
+    }
+
+    /// Takes everything but the name and password from another copy of the user, like the
+    /// changes an admin made or the Linux account that was created.
+    pub fn apply_settings(&mut self, other: &User) {
+        self.admin = other.admin;
+        self.storage = other.storage;
+        self.must_change_password = other.must_change_password;
+        self.disabled = other.disabled;
+        self.expires_at = other.expires_at;
+        self.provisioned = other.provisioned;
+        self.shell = other.shell.clone();
+        self.system_groups = other.system_groups.clone();
+    }
+
+    /// Checks if this user may manage another user, only admins may manage admins.
+    pub fn may_manage(&self, other: &User) -> bool {
+        self.admin || !other.admin
+    }
+
+    /// Checks if the account is past its expiry date.
+    pub fn expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= OffsetDateTime::now_utc())
+    }
+
+    /// Checks if the account may be used, it is neither disabled nor expired.
+    pub fn check_active(&self) -> Result<()> {
+        if self.disabled {
+            Err(UserError::AccountDisabled)
+        } else if self.expired() {
+            Err(UserError::AccountExpired)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks if the password hash uses outdated parameters and will be upgraded on login.
+    pub fn hash_outdated(&self) -> bool {
+        hash_outdated(&self.password)
+    }
+
+    /// Checks if the password is older than the policy allows.
+    pub fn password_expired(&self, policy: &PasswordPolicy) -> bool {
+        match self.password_changed {
+            Some(changed) if policy.max_age_days != 0 => {
+                OffsetDateTime::now_utc() - changed > Duration::days(policy.max_age_days as i64)
+            }
+            _ => false,
+        }
+    }
 
     /// Logs in a user.
     pub async fn login(username: &str, password: &str) -> Result<Self> {
-        let user_file = PathBuf::from(USERS_PATH).join(username).join("user.json");
+        let username = username.trim();
+        if !valid_username(username) {
+            return Err(UserError::UserNotFound);
+        }
+        let user_file = user_dir(username).join("user.json");
         if fs::try_exists(&user_file).await.unwrap_or(false) {
-            let data = fs::read(&user_file).await?;
-            let user: User = serde_json::from_slice(&data)?;
-            let vaild_password = {
-                let hashed_password = user.password.clone();
-                let password = password.to_string();
-                tokio_error!(
-                    task::spawn_blocking(move || {
-                        // Okay the sytnax here cloud be cleaner but I'm too lazy to clean it up so
-                        // I'll just explain it
-                        PASSWORD_HASHER
-                            .verify_password(
-                                password.as_bytes(),
-                                //we have to make sure it is borrowed here
-                                &(match PasswordHashString::from_str(&hashed_password) {
-                                    // okay here we
-                                    // load the password hash from the string
-                                    Ok(value) => value, // then if it's okay we use the
-                                    // password hash string
-                                    Err(_) => return false,
-                                }
-                                .password_hash()), // then we convert the password hash string to a password hash (it's some weird typing thing)
-                            )
-                            .is_ok()
-                    })
-                    .await
-                )
-            };
+            let user = read_user_file(&user_file).await?;
+            let vaild_password =
+                verify_password(password.to_string(), user.password.clone()).await?;
             if !vaild_password {
                 Err(UserError::PasswordError)
+            } else if let Err(error) = user.check_active() {
+                Err(error)
+            } else if user.hash_outdated() {
+                // We only ever see the password here so this is our chance to upgrade the hash
+                let outdated = user.password.clone();
+                let upgraded = hash_password(password.to_string()).await?;
+                let user = Self::update(&user.name, move |user| {
+                    // Unless the password was changed in the meantime
+                    if user.password == outdated {
+                        user.password = upgraded;
+                    }
+                    Ok(())
+                })
+                .await?;
+                log::info!("Upgraded the password hash of {}", user.name);
+                Ok(user)
             } else {
                 Ok(user)
             }
@@ -193,56 +526,167 @@ impl User {
         }
     }
 
+    /// Checks the password of a user for clients that send it with every request.
+    ///
+    /// Hashing the password each time would make every request slow, so a successful check is
+    /// remembered for a few minutes. Changing the password invalidates it right away.
+    ///
+    /// Such clients can not be sent to the password page, so users that have to change their
+    /// password, or whose password is older than the policy allows, are refused.
+    pub async fn authenticate(
+        username: &str,
+        password: &str,
+        policy: &PasswordPolicy,
+    ) -> Result<Self> {
+        let user = Self::fetch_user(username).await?;
+        let key = format!(
+            "{:x}",
+            Sha256::digest(format!("{}\0{}", password, user.password).as_bytes())
+        );
+        let cached = VERIFIED
+            .lock()
+            .await
+            .get(username)
+            .is_some_and(|(value, checked)| *value == key && checked.elapsed() < VERIFIED_LIFETIME);
+        if !cached {
+            if !verify_password(password.to_string(), user.password.clone()).await? {
+                return Err(UserError::PasswordError);
+            }
+            VERIFIED
+                .lock()
+                .await
+                .insert(username.to_string(), (key, Instant::now()));
+        }
+        user.check_active()?;
+        if user.must_change_password || user.password_expired(policy) {
+            return Err(UserError::PasswordChangeRequired);
+        }
+        Ok(user)
+    }
+
     /// Fetches a user from the database.
     pub async fn fetch_user(username: &str) -> Result<User> {
         let username = username.trim();
-        let user_file = PathBuf::from(USERS_PATH).join(username).join("user.json");
+        if !valid_username(username) {
+            return Err(UserError::UserNotFound);
+        }
+        let user_file = user_dir(username).join("user.json");
         if fs::try_exists(&user_file).await.unwrap_or(false) {
-            let data = fs::read(&user_file).await?;
-            let user: User = serde_json::from_slice(&data)?;
-            Ok(user)
+            read_user_file(&user_file).await
         } else {
             Err(UserError::UserNotFound)
         }
     }
 
+    /// Changes a user in the database.
+    ///
+    /// The user is read and written back while its file stays locked, so changes made at the
+    /// same time are not lost.
+    pub async fn update<F>(username: &str, change: F) -> Result<User>
+    where
+        F: FnOnce(&mut User) -> Result<()> + Send + 'static,
+    {
+        let username = username.trim();
+        if !valid_username(username) {
+            return Err(UserError::UserNotFound);
+        }
+        let user_file = user_dir(username).join("user.json");
+        if !fs::try_exists(&user_file).await.unwrap_or(false) {
+            return Err(UserError::UserNotFound);
+        }
+        persist::update(user_file, move |data| {
+            let data = data.ok_or(UserError::UserNotFound)?;
+            let mut user: User = serde_json::from_slice(data)?;
+            change(&mut user)?;
+            Ok((serde_json::to_vec(&user)?, user))
+        })
+        .await
+    }
+
     /// Saves the user to the database.
     pub async fn save(self) -> Result<()> {
         let username = self.name.trim();
-        let user_dir = PathBuf::from(USERS_PATH).join(username);
-        let user_file = user_dir.join("user.json");
-        fs::create_dir_all(&user_dir).await?;
+        let user_file = user_dir(username).join("user.json");
         let data = serde_json::to_string(&self)?;
-        fs::write(&user_file, data.as_bytes()).await?;
+        persist::write(user_file, data).await?;
         Ok(())
     }
 
-    /// Changes the user's password.
-    pub async fn change_password(mut self, new_password: String) -> Result<String> {
-        let hashed_password = {
-            let new_password = new_password.clone();
-            tokio_error!(
-                task::spawn_blocking(move || {
-                    let salt = SaltString::generate(&mut OsRng);
-                    let password_hash =
-                        match PASSWORD_HASHER.hash_password(new_password.as_bytes(), &salt) {
-                            Ok(value) => value,
-                            Err(error) => {
-                                log::error!("Failed to hash password: {}", error);
-                                return String::new();
-                            }
-                        };
-                    password_hash.to_string()
-                })
-                .await
-            )
-        };
-        self.password = hashed_password;
-        self.save().await?;
+    /// Deletes the user and everything stored in its directory.
+    pub async fn delete(self) -> Result<()> {
+        // The name came from a file we read, but never remove anything outside the users directory
+        if !valid_username(&self.name) {
+            return Err(UserError::InvalidUsername);
+        }
+        fs::remove_dir_all(user_dir(&self.name)).await?;
+        Ok(())
+    }
+
+    /// Checks if a new password would be accepted by [`User::change_password`].
+    ///
+    /// The new password has to follow the policy and can not be one of the last
+    /// `policy.history` passwords.
+    pub async fn check_new_password(
+        &self,
+        new_password: &str,
+        policy: &PasswordPolicy,
+    ) -> Result<()> {
+        check_policy(policy, new_password)?;
+        let previous = std::iter::once(&self.password).chain(&self.password_history);
+        for hash in previous.take(policy.history) {
+            if verify_password(new_password.to_string(), hash.clone()).await? {
+                return Err(UserError::PolicyViolation(format!(
+                    "can not be one of your last {} passwords",
+                    policy.history
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the user's password, see [`User::check_new_password`] for the rules it follows.
+    ///
+    /// Only the password and if it has to be changed are saved, any other changes made to this
+    /// copy of the user are not.
+    pub async fn change_password(
+        self,
+        new_password: String,
+        policy: &PasswordPolicy,
+    ) -> Result<String> {
+        self.check_new_password(&new_password, policy).await?;
+        let hashed_password = hash_password(new_password.clone()).await?;
+        let history = policy.history;
+        let must_change_password = self.must_change_password;
+        Self::update(&self.name, move |user| {
+            let old_password = std::mem::replace(&mut user.password, hashed_password);
+            user.password_history.insert(0, old_password);
+            user.password_history.truncate(history);
+            user.password_changed = Some(OffsetDateTime::now_utc());
+            user.must_change_password = must_change_password;
+            Ok(())
+        })
+        .await?;
         Ok(new_password)
     }
 }
 
+/// Reads a user file, falling back to the backup from the last save if it is corrupt.
+async fn read_user_file(user_file: &std::path::Path) -> Result<User> {
+    let data = persist::read(user_file).await?;
+    match serde_json::from_slice(&data) {
+        Ok(user) => Ok(user),
+        Err(error) => {
+            log::error!(
+                "Failed to parse {} ({}), trying the backup",
+                user_file.display(),
+                error
+            );
+            let data = persist::read_backup(user_file).await?;
+            Ok(serde_json::from_slice(&data)?)
+        }
+    }
+}
+
 /// Gets all users from the database.
 pub async fn get_users() -> Result<Vec<User>> {
     let user_dir = PathBuf::from(USERS_PATH);
@@ -259,3 +703,63 @@ pub async fn get_users() -> Result<Vec<User>> {
     }
     Ok(users)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, admin: bool) -> User {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "password": "",
+            "admin": admin,
+            "storage": 0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_admins_manage_admins() {
+        let admin = user("root", true);
+        let manager = user("manager", false);
+        let other = user("other", false);
+        assert!(admin.may_manage(&manager));
+        assert!(admin.may_manage(&user("admin2", true)));
+        assert!(manager.may_manage(&other));
+        assert!(!manager.may_manage(&admin));
+    }
+
+    #[test]
+    fn usernames_stay_in_the_users_directory() {
+        assert!(valid_username("alice"));
+        assert!(valid_username("a.b-c_d"));
+        assert!(!valid_username(""));
+        assert!(!valid_username(".."));
+        assert!(!valid_username("-rf"));
+        assert!(!valid_username("a/b"));
+        assert!(!valid_username(&"a".repeat(33)));
+    }
+
+    #[tokio::test]
+    async fn invalid_usernames_are_never_looked_up() {
+        let policy = PasswordPolicy::default();
+        for name in ["..", "../users", "a/../../etc", ""] {
+            assert!(matches!(
+                User::fetch_user(name).await,
+                Err(UserError::UserNotFound)
+            ));
+            assert!(matches!(
+                User::login(name, "password").await,
+                Err(UserError::UserNotFound)
+            ));
+            assert!(matches!(
+                User::authenticate(name, "password", &policy).await,
+                Err(UserError::UserNotFound)
+            ));
+            assert!(matches!(
+                User::update(name, |_| Ok(())).await,
+                Err(UserError::UserNotFound)
+            ));
+        }
+    }
+}
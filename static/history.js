@@ -0,0 +1,133 @@
+// Draws the stored metrics as line charts
+const SVG = 'http://www.w3.org/2000/svg';
+const WIDTH = 800;
+const HEIGHT = 200;
+const PADDING = { top: 10, right: 10, bottom: 24, left: 70 };
+const COLORS = ['var(--primary)', 'var(--warning)', 'var(--success)', 'var(--error)'];
+const NAMES = {
+   cpu: 'CPU',
+   memory: 'Memory',
+   swap: 'Swap',
+   battery: 'Battery',
+   network_received: 'Received',
+   network_transmitted: 'Transmitted',
+};
+
+function formatBytes(bytes) {
+   const units = ['B', 'KiB', 'MiB', 'GiB', 'TiB'];
+   let value = bytes;
+   let unit = 0;
+   while (value >= 1024 && unit < units.length - 1) {
+      value /= 1024;
+      unit += 1;
+   }
+   return unit === 0 ? `${Math.round(bytes)} B` : `${value.toFixed(1)} ${units[unit]}`;
+}
+
+function formatValue(value, unit) {
+   return unit === 'rate' ? `${formatBytes(value)}/s` : `${Math.round(value)}%`;
+}
+
+function formatTime(seconds, range) {
+   const date = new Date(seconds * 1000);
+   return range <= 86400 ? date.toLocaleTimeString([], { hour: '2-digit', minute: '2-digit' })
+      : date.toLocaleDateString();
+}
+
+function node(tag, attributes, text) {
+   const element = document.createElementNS(SVG, tag);
+   for (const [name, value] of Object.entries(attributes)) {
+      element.setAttribute(name, value);
+   }
+   if (text !== undefined) {
+      element.textContent = text;
+   }
+   return element;
+}
+
+function draw(container, histories, unit, from, to) {
+   const maximum = unit === 'percent' ? 100
+      : Math.max(1, ...histories.flatMap((history) => history.points.map((point) => point.value)));
+   const x = (time) => PADDING.left + (time - from) / (to - from) * (WIDTH - PADDING.left - PADDING.right);
+   const y = (value) => HEIGHT - PADDING.bottom - value / maximum * (HEIGHT - PADDING.top - PADDING.bottom);
+
+   const svg = node('svg', { viewBox: `0 0 ${WIDTH} ${HEIGHT}`, class: 'w-full h-auto' });
+   for (const fraction of [0, 0.5, 1]) {
+      const value = maximum * fraction;
+      svg.appendChild(node('line', {
+         x1: PADDING.left, x2: WIDTH - PADDING.right, y1: y(value), y2: y(value),
+         stroke: 'var(--border)',
+      }));
+      svg.appendChild(node('text', {
+         x: PADDING.left - 6, y: y(value) + 4, 'text-anchor': 'end', 'font-size': 12, fill: 'currentColor',
+      }, formatValue(value, unit)));
+   }
+   for (const [time, anchor] of [[from, 'start'], [to, 'end']]) {
+      svg.appendChild(node('text', {
+         x: x(time), y: HEIGHT - 6, 'text-anchor': anchor, 'font-size': 12, fill: 'currentColor',
+      }, formatTime(time, to - from)));
+   }
+
+   const legend = document.createElement('div');
+   legend.className = 'flex gap-4 text-sm';
+   histories.forEach((history, index) => {
+      const color = COLORS[index % COLORS.length];
+      // Gaps of more than two buckets are left open instead of drawing a line across them
+      const step = { minute: 60, hour: 3600, day: 86400 }[history.resolution];
+      let path = '';
+      let previous = null;
+      for (const point of history.points) {
+         const command = previous === null || point.time - previous > step * 2 ? 'M' : 'L';
+         path += `${command}${x(point.time).toFixed(1)},${y(point.value).toFixed(1)} `;
+         previous = point.time;
+      }
+      svg.appendChild(node('path', { d: path, fill: 'none', stroke: color, 'stroke-width': 2 }));
+
+      const entry = document.createElement('span');
+      const swatch = document.createElement('span');
+      swatch.className = 'inline-block w-3 h-3 rounded mr-1';
+      swatch.style.background = color;
+      entry.appendChild(swatch);
+      const last = history.points[history.points.length - 1];
+      const name = NAMES[history.series] || history.series.replace(/^mount:/, 'Used');
+      entry.appendChild(document.createTextNode(last ? `${name}: ${formatValue(last.value, unit)}` : `${name}: no data`));
+      legend.appendChild(entry);
+   });
+   container.replaceChildren(svg, legend);
+}
+
+async function load(range) {
+   const to = Math.floor(Date.now() / 1000);
+   const from = to - range;
+   for (const container of document.querySelectorAll('.chart')) {
+      const unit = container.dataset.unit;
+      try {
+         const histories = await Promise.all(container.dataset.series.split(',').map(async (series) => {
+            const query = new URLSearchParams({ series, from, to });
+            const response = await fetch(`/api/history?${query}`);
+            if (!response.ok) {
+               throw new Error(await response.text());
+            }
+            return response.json();
+         }));
+         draw(container, histories, unit, from, to);
+      } catch (error) {
+         container.textContent = `Failed to load: ${error.message}`;
+      }
+   }
+}
+
+const buttons = document.querySelectorAll('#ranges button');
+let range = 86400;
+buttons.forEach((button) => {
+   button.addEventListener('click', () => {
+      buttons.forEach((other) => other.classList.remove('bg-primary', 'text-background'));
+      button.classList.add('bg-primary', 'text-background');
+      range = Number(button.dataset.range);
+      load(range);
+   });
+});
+
+load(range);
+// Minutes are stored as they end, so the charts are refreshed once a minute
+setInterval(() => load(range), 60000);